@@ -0,0 +1,15 @@
+-- Add down migration script here
+BEGIN;
+DROP INDEX IF EXISTS block_id_idx;
+DROP INDEX IF EXISTS follow_id_idx;
+DROP INDEX IF EXISTS like_id_idx;
+DROP INDEX IF EXISTS listblock_id_idx;
+DROP INDEX IF EXISTS listitem_id_idx;
+DROP INDEX IF EXISTS repost_id_idx;
+ALTER TABLE "block" DROP COLUMN IF EXISTS id;
+ALTER TABLE follow DROP COLUMN IF EXISTS id;
+ALTER TABLE "like" DROP COLUMN IF EXISTS id;
+ALTER TABLE listblock DROP COLUMN IF EXISTS id;
+ALTER TABLE listitem DROP COLUMN IF EXISTS id;
+ALTER TABLE repost DROP COLUMN IF EXISTS id;
+COMMIT;
@@ -0,0 +1,36 @@
+-- Add the record id ({rkey}_{did_key}) to relation tables, so records can be deleted again
+
+BEGIN;
+
+ALTER TABLE "block" ADD COLUMN IF NOT EXISTS id TEXT;
+ALTER TABLE follow ADD COLUMN IF NOT EXISTS id TEXT;
+ALTER TABLE "like" ADD COLUMN IF NOT EXISTS id TEXT;
+ALTER TABLE listblock ADD COLUMN IF NOT EXISTS id TEXT;
+ALTER TABLE listitem ADD COLUMN IF NOT EXISTS id TEXT;
+ALTER TABLE repost ADD COLUMN IF NOT EXISTS id TEXT;
+
+-- Rows written before this migration have no id, so delete commits can not find them. The rkey is not stored, so
+-- the ids can not be derived. Drop the rows and backfill their repos again, which writes them with ids.
+UPDATE latest_backfill SET at = NULL WHERE of_did_id IN (
+    SELECT blocker_did_id FROM "block" WHERE id IS NULL
+    UNION SELECT follower_did_id FROM follow WHERE id IS NULL
+    UNION SELECT user_id FROM "like" WHERE id IS NULL
+    UNION SELECT blocker_did_id FROM listblock WHERE id IS NULL
+    UNION SELECT substring(list_id FROM '_((?:plc|webx?)_[a-z0-9_]+)$') FROM listitem WHERE id IS NULL
+    UNION SELECT did_id FROM repost WHERE id IS NULL
+);
+DELETE FROM "block" WHERE id IS NULL;
+DELETE FROM follow WHERE id IS NULL;
+DELETE FROM "like" WHERE id IS NULL;
+DELETE FROM listblock WHERE id IS NULL;
+DELETE FROM listitem WHERE id IS NULL;
+DELETE FROM repost WHERE id IS NULL;
+
+CREATE UNIQUE INDEX IF NOT EXISTS block_id_idx ON "block" (id);
+CREATE UNIQUE INDEX IF NOT EXISTS follow_id_idx ON follow (id);
+CREATE UNIQUE INDEX IF NOT EXISTS like_id_idx ON "like" (id);
+CREATE UNIQUE INDEX IF NOT EXISTS listblock_id_idx ON listblock (id);
+CREATE UNIQUE INDEX IF NOT EXISTS listitem_id_idx ON listitem (id);
+CREATE UNIQUE INDEX IF NOT EXISTS repost_id_idx ON repost (id);
+
+COMMIT;
@@ -0,0 +1,6 @@
+BEGIN;
+
+DROP INDEX IF EXISTS did_handle_idx;
+ALTER TABLE did DROP COLUMN IF EXISTS handle;
+
+COMMIT;
@@ -0,0 +1,13 @@
+-- Store the handle of a DID, taken from jetstream identity events
+
+BEGIN;
+
+ALTER TABLE did ADD COLUMN IF NOT EXISTS handle TEXT;
+
+UPDATE did SET handle = jetstream_identity_event.handle
+FROM jetstream_identity_event
+WHERE did.id = jetstream_identity_event.id;
+
+CREATE INDEX IF NOT EXISTS did_handle_idx ON did (handle);
+
+COMMIT;
@@ -0,0 +1,6 @@
+BEGIN;
+
+ALTER TABLE did DROP COLUMN IF EXISTS status_changed_at;
+ALTER TABLE did DROP COLUMN IF EXISTS active;
+
+COMMIT;
@@ -0,0 +1,8 @@
+-- Track the account status reported by jetstream
+
+BEGIN;
+
+ALTER TABLE did ADD COLUMN IF NOT EXISTS active BOOLEAN NOT NULL DEFAULT TRUE;
+ALTER TABLE did ADD COLUMN IF NOT EXISTS status_changed_at TIMESTAMP WITH TIME ZONE;
+
+COMMIT;
@@ -0,0 +1,6 @@
+BEGIN;
+
+DROP TABLE IF EXISTS threadgate_allow CASCADE;
+DROP TABLE IF EXISTS threadgate CASCADE;
+
+COMMIT;
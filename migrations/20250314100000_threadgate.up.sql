@@ -0,0 +1,25 @@
+-- Threadgates restrict who can reply to a post
+
+BEGIN;
+
+CREATE TABLE IF NOT EXISTS threadgate (
+    id TEXT PRIMARY KEY,
+    post_id TEXT NOT NULL, -- REFERENCES post(id) DEFERRABLE,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL,
+    -- If false, everyone can reply. If true, only the allow rules can reply
+    restricts_replies BOOLEAN NOT NULL,
+    extra_data TEXT
+);
+
+CREATE TABLE IF NOT EXISTS threadgate_allow (
+    threadgate_id TEXT NOT NULL REFERENCES threadgate(id) DEFERRABLE,
+    -- One of mention, follower, following or list
+    rule TEXT NOT NULL,
+    list_id TEXT -- REFERENCES list(id) DEFERRABLE
+);
+
+CREATE INDEX IF NOT EXISTS threadgate_post_id_idx ON threadgate (post_id);
+-- Each rule is only stored once, so inserting a threadgate again does not duplicate its rules
+CREATE UNIQUE INDEX IF NOT EXISTS threadgate_allow_unique_idx ON threadgate_allow (threadgate_id, rule, COALESCE(list_id, ''));
+
+COMMIT;
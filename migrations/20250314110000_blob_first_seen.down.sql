@@ -0,0 +1,7 @@
+BEGIN;
+
+UPDATE blob SET size = 0 WHERE size IS NULL;
+ALTER TABLE blob ALTER COLUMN size SET NOT NULL;
+ALTER TABLE blob DROP COLUMN IF EXISTS first_seen_at;
+
+COMMIT;
@@ -0,0 +1,8 @@
+-- Store when a blob was first referenced. Legacy blob refs do not contain a size
+
+BEGIN;
+
+ALTER TABLE blob ADD COLUMN IF NOT EXISTS first_seen_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT now();
+ALTER TABLE blob ALTER COLUMN size DROP NOT NULL;
+
+COMMIT;
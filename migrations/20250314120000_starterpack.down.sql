@@ -0,0 +1,11 @@
+BEGIN;
+
+DROP TABLE IF EXISTS starterpack_feed CASCADE;
+ALTER TABLE starterpack DROP COLUMN IF EXISTS extra_data;
+ALTER TABLE starterpack DROP COLUMN IF EXISTS created_at;
+ALTER TABLE starterpack DROP COLUMN IF EXISTS list_id;
+ALTER TABLE starterpack DROP COLUMN IF EXISTS description;
+ALTER TABLE starterpack DROP COLUMN IF EXISTS name;
+ALTER TABLE starterpack DROP COLUMN IF EXISTS creator_did_id;
+
+COMMIT;
@@ -0,0 +1,26 @@
+-- Store the contents of starterpacks. The table only contained ids before
+
+BEGIN;
+
+-- The existing rows have no values for the new columns. Drop them and backfill their repos again, which writes them
+-- with their contents
+UPDATE latest_backfill SET at = NULL WHERE of_did_id IN (
+    SELECT substring(id FROM '_((?:plc|webx?)_[a-z0-9_]+)$') FROM starterpack
+);
+DELETE FROM starterpack;
+
+ALTER TABLE starterpack ADD COLUMN IF NOT EXISTS creator_did_id TEXT NOT NULL; -- REFERENCES did(id) DEFERRABLE
+ALTER TABLE starterpack ADD COLUMN IF NOT EXISTS name TEXT NOT NULL;
+ALTER TABLE starterpack ADD COLUMN IF NOT EXISTS description TEXT;
+ALTER TABLE starterpack ADD COLUMN IF NOT EXISTS list_id TEXT NOT NULL; -- REFERENCES list(id) DEFERRABLE
+ALTER TABLE starterpack ADD COLUMN IF NOT EXISTS created_at TIMESTAMP WITH TIME ZONE NOT NULL;
+ALTER TABLE starterpack ADD COLUMN IF NOT EXISTS extra_data TEXT;
+
+CREATE TABLE IF NOT EXISTS starterpack_feed (
+    starterpack_id TEXT NOT NULL REFERENCES starterpack(id) DEFERRABLE,
+    feed_id TEXT NOT NULL -- REFERENCES feed(id) DEFERRABLE
+);
+
+CREATE UNIQUE INDEX IF NOT EXISTS starterpack_feed_unique_idx ON starterpack_feed (starterpack_id, feed_id);
+
+COMMIT;
@@ -0,0 +1,15 @@
+-- Preview data of external links embedded in posts
+
+BEGIN;
+
+CREATE TABLE IF NOT EXISTS post_external_embed (
+    post_id TEXT NOT NULL REFERENCES post(id) DEFERRABLE,
+    uri TEXT NOT NULL,
+    title TEXT NOT NULL,
+    description TEXT NOT NULL,
+    thumb_blob_id TEXT -- REFERENCES blob(id)
+);
+
+CREATE INDEX IF NOT EXISTS post_external_embed_post_id_idx ON post_external_embed (post_id);
+
+COMMIT;
@@ -0,0 +1,12 @@
+BEGIN;
+
+DROP TABLE IF EXISTS labeler_label_value CASCADE;
+ALTER TABLE labeler DROP COLUMN IF EXISTS extra_data;
+ALTER TABLE labeler DROP COLUMN IF EXISTS label_value_definitions;
+ALTER TABLE labeler DROP COLUMN IF EXISTS created_at;
+ALTER TABLE labeler DROP COLUMN IF EXISTS did_id;
+DROP TABLE IF EXISTS chat_actor_declaration CASCADE;
+DROP TABLE IF EXISTS postgate_detached_embedding CASCADE;
+DROP TABLE IF EXISTS postgate CASCADE;
+
+COMMIT;
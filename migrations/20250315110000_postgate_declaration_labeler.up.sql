@@ -0,0 +1,47 @@
+-- Tables for postgates, chat actor declarations and labeler services
+
+BEGIN;
+
+CREATE TABLE IF NOT EXISTS postgate (
+    id TEXT PRIMARY KEY,
+    post_id TEXT NOT NULL, -- REFERENCES post(id) DEFERRABLE,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL,
+    disable_embedding BOOLEAN NOT NULL,
+    extra_data TEXT
+);
+
+CREATE TABLE IF NOT EXISTS postgate_detached_embedding (
+    postgate_id TEXT NOT NULL REFERENCES postgate(id) DEFERRABLE,
+    post_id TEXT NOT NULL -- REFERENCES post(id) DEFERRABLE
+);
+
+CREATE INDEX IF NOT EXISTS postgate_post_id_idx ON postgate (post_id);
+CREATE UNIQUE INDEX IF NOT EXISTS postgate_detached_embedding_unique_idx ON postgate_detached_embedding (postgate_id, post_id);
+
+CREATE TABLE IF NOT EXISTS chat_actor_declaration (
+    id TEXT PRIMARY KEY,
+    did_id TEXT NOT NULL, -- REFERENCES did(id) DEFERRABLE,
+    allow_incoming TEXT NOT NULL,
+    extra_data TEXT
+);
+
+-- The labeler table only contained ids before. Drop the existing rows and backfill their repos again, which writes
+-- them with their contents
+UPDATE latest_backfill SET at = NULL WHERE of_did_id IN (
+    SELECT substring(id FROM '_((?:plc|webx?)_[a-z0-9_]+)$') FROM labeler
+);
+DELETE FROM labeler;
+
+ALTER TABLE labeler ADD COLUMN IF NOT EXISTS did_id TEXT NOT NULL; -- REFERENCES did(id) DEFERRABLE
+ALTER TABLE labeler ADD COLUMN IF NOT EXISTS created_at TIMESTAMP WITH TIME ZONE NOT NULL;
+ALTER TABLE labeler ADD COLUMN IF NOT EXISTS label_value_definitions TEXT;
+ALTER TABLE labeler ADD COLUMN IF NOT EXISTS extra_data TEXT;
+
+CREATE TABLE IF NOT EXISTS labeler_label_value (
+    labeler_id TEXT NOT NULL REFERENCES labeler(id) DEFERRABLE,
+    label_value TEXT NOT NULL
+);
+
+CREATE UNIQUE INDEX IF NOT EXISTS labeler_label_value_unique_idx ON labeler_label_value (labeler_id, label_value);
+
+COMMIT;
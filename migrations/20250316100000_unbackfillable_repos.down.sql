@@ -0,0 +1,5 @@
+BEGIN;
+
+ALTER TABLE latest_backfill DROP COLUMN IF EXISTS unbackfillable_reason;
+
+COMMIT;
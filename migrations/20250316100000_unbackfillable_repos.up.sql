@@ -0,0 +1,7 @@
+-- Repos that the PDS reported as permanently missing are not retried by the backfill
+
+BEGIN;
+
+ALTER TABLE latest_backfill ADD COLUMN IF NOT EXISTS unbackfillable_reason TEXT NULL;
+
+COMMIT;
@@ -0,0 +1,13 @@
+-- Dead-letter table for repos that failed to backfill
+
+BEGIN;
+
+CREATE TABLE IF NOT EXISTS backfill_failure (
+    of_did_id TEXT PRIMARY KEY, -- REFERENCES did(id) DEFERRABLE,
+    stage TEXT NOT NULL,
+    error TEXT NOT NULL,
+    attempts INT NOT NULL,
+    last_attempt_at TIMESTAMP WITH TIME ZONE NOT NULL
+);
+
+COMMIT;
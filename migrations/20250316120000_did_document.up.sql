@@ -0,0 +1,12 @@
+-- Cache of resolved DID documents, so the backfill does not ask the plc directory for every repo
+
+BEGIN;
+
+CREATE TABLE IF NOT EXISTS did_document (
+    id TEXT PRIMARY KEY, -- REFERENCES did(id) DEFERRABLE,
+    pds_endpoint TEXT NOT NULL,
+    handle TEXT NULL,
+    fetched_at TIMESTAMP WITH TIME ZONE NOT NULL
+);
+
+COMMIT;
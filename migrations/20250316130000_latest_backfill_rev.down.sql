@@ -0,0 +1,6 @@
+BEGIN;
+
+DROP INDEX IF EXISTS latest_backfill_at_idx;
+ALTER TABLE latest_backfill DROP COLUMN IF EXISTS last_known_rev;
+
+COMMIT;
@@ -0,0 +1,8 @@
+-- Rev of each repo at its last backfill, used to skip unchanged repos when backfilling again
+
+BEGIN;
+
+ALTER TABLE latest_backfill ADD COLUMN IF NOT EXISTS last_known_rev TEXT NULL;
+CREATE INDEX IF NOT EXISTS latest_backfill_at_idx ON latest_backfill (at, id);
+
+COMMIT;
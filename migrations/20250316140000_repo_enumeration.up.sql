@@ -0,0 +1,10 @@
+-- Progress of enumerating the repos of a relay with com.atproto.sync.listRepos
+
+BEGIN;
+
+CREATE TABLE IF NOT EXISTS repo_enumeration (
+    host TEXT PRIMARY KEY,
+    cursor TEXT NULL -- NULL once all repos were enumerated
+);
+
+COMMIT;
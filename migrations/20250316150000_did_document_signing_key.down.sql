@@ -0,0 +1,5 @@
+BEGIN;
+
+ALTER TABLE did_document DROP COLUMN IF EXISTS signing_key;
+
+COMMIT;
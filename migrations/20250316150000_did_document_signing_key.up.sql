@@ -0,0 +1,7 @@
+-- The atproto signing key of a DID, used to verify repo commit signatures
+
+BEGIN;
+
+ALTER TABLE did_document ADD COLUMN IF NOT EXISTS signing_key TEXT NULL;
+
+COMMIT;
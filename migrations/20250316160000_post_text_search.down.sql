@@ -0,0 +1,10 @@
+BEGIN;
+
+DROP INDEX IF EXISTS post_lang_post_id_idx;
+DROP INDEX IF EXISTS post_text_search_idx;
+DROP TRIGGER IF EXISTS post_text_search_trigger ON post;
+DROP FUNCTION IF EXISTS post_text_search_update();
+ALTER TABLE post DROP COLUMN IF EXISTS text_search_config;
+ALTER TABLE post DROP COLUMN IF EXISTS text_search;
+
+COMMIT;
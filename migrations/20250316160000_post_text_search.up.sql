@@ -0,0 +1,29 @@
+-- Full-text search over the text of posts
+
+BEGIN;
+
+-- Plain columns, because adding a generated column rewrites the whole table while holding an exclusive lock. Posts
+-- that already exist are not searchable until their text is updated.
+ALTER TABLE post ADD COLUMN IF NOT EXISTS text_search tsvector;
+-- Text search configuration for the language of the post, written by the indexer
+ALTER TABLE post ADD COLUMN IF NOT EXISTS text_search_config TEXT;
+
+CREATE OR REPLACE FUNCTION post_text_search_update() RETURNS trigger AS $$
+BEGIN
+    IF NEW.text_search_config IS NULL OR NEW.text_search_config = 'simple' THEN
+        NEW.text_search := to_tsvector('simple', NEW.text);
+    ELSE
+        -- The unstemmed words let searches without a language find the post as well
+        NEW.text_search := to_tsvector(NEW.text_search_config::regconfig, NEW.text) || to_tsvector('simple', NEW.text);
+    END IF;
+    RETURN NEW;
+END
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER post_text_search_trigger BEFORE INSERT OR UPDATE OF text, text_search_config ON post
+    FOR EACH ROW EXECUTE FUNCTION post_text_search_update();
+
+CREATE INDEX IF NOT EXISTS post_text_search_idx ON post USING GIN (text_search);
+CREATE INDEX IF NOT EXISTS post_lang_post_id_idx ON post_lang (post_id);
+
+COMMIT;
@@ -0,0 +1,6 @@
+BEGIN;
+
+DROP INDEX IF EXISTS did_did_text_idx;
+ALTER TABLE did DROP COLUMN IF EXISTS did_text;
+
+COMMIT;
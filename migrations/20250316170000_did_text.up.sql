@@ -0,0 +1,17 @@
+-- Store the DID itself next to the key, so consumers do not need to decode keys
+
+BEGIN;
+
+ALTER TABLE did ADD COLUMN IF NOT EXISTS did_text TEXT;
+
+-- Inverse of did_to_key
+UPDATE did SET did_text = CASE
+    WHEN id LIKE 'plc\_%' THEN 'did:plc:' || substr(id, 5)
+    WHEN id LIKE 'webx\_%' THEN 'did:web:' || convert_from(decode(substr(id, 6), 'hex'), 'UTF8')
+    WHEN id LIKE 'web\_%' THEN 'did:web:' || replace(replace(substr(id, 5), '__', '-'), '_', '.')
+END
+WHERE did_text IS NULL;
+
+CREATE INDEX IF NOT EXISTS did_did_text_idx ON did (did_text);
+
+COMMIT;
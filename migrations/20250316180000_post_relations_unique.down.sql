@@ -0,0 +1,10 @@
+BEGIN;
+
+DELETE FROM posts_relation WHERE post_id IN (SELECT post_id FROM replies_relation);
+
+DROP INDEX IF EXISTS replyto_relation_unique_idx;
+DROP INDEX IF EXISTS quotes_relation_unique_idx;
+DROP INDEX IF EXISTS replies_relation_unique_idx;
+DROP INDEX IF EXISTS posts_relation_unique_idx;
+
+COMMIT;
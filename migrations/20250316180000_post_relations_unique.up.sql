@@ -0,0 +1,25 @@
+-- Every post is in posts_relation, replies are additionally in replies_relation and replyto_relation.
+-- The relation tables get unique indexes, so processing a post again does not duplicate its relations.
+
+BEGIN;
+
+DELETE FROM posts_relation a USING posts_relation b
+WHERE a.ctid > b.ctid AND a.did_id = b.did_id AND a.post_id = b.post_id;
+DELETE FROM replies_relation a USING replies_relation b
+WHERE a.ctid > b.ctid AND a.did_id = b.did_id AND a.post_id = b.post_id;
+DELETE FROM quotes_relation a USING quotes_relation b
+WHERE a.ctid > b.ctid AND a.source_post_id = b.source_post_id AND a.target_post_id = b.target_post_id;
+DELETE FROM replyto_relation a USING replyto_relation b
+WHERE a.ctid > b.ctid AND a.source_post_id = b.source_post_id AND a.target_post_id = b.target_post_id;
+
+CREATE UNIQUE INDEX IF NOT EXISTS posts_relation_unique_idx ON posts_relation (did_id, post_id);
+CREATE UNIQUE INDEX IF NOT EXISTS replies_relation_unique_idx ON replies_relation (did_id, post_id);
+CREATE UNIQUE INDEX IF NOT EXISTS quotes_relation_unique_idx ON quotes_relation (source_post_id, target_post_id);
+CREATE UNIQUE INDEX IF NOT EXISTS replyto_relation_unique_idx ON replyto_relation (source_post_id, target_post_id);
+
+-- Replies used to be left out of posts_relation
+INSERT INTO posts_relation (did_id, post_id)
+SELECT did_id, post_id FROM replies_relation
+ON CONFLICT DO NOTHING;
+
+COMMIT;
@@ -0,0 +1,15 @@
+BEGIN;
+
+CREATE OR REPLACE FUNCTION post_text_search_update() RETURNS trigger AS $$
+BEGIN
+    IF NEW.text_search_config IS NULL OR NEW.text_search_config = 'simple' THEN
+        NEW.text_search := to_tsvector('simple', NEW.text);
+    ELSE
+        -- The unstemmed words let searches without a language find the post as well
+        NEW.text_search := to_tsvector(NEW.text_search_config::regconfig, NEW.text) || to_tsvector('simple', NEW.text);
+    END IF;
+    RETURN NEW;
+END
+$$ LANGUAGE plpgsql;
+
+COMMIT;
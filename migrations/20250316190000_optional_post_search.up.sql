@@ -0,0 +1,20 @@
+-- Only maintain the text search column of posts if the indexer runs with --enable-post-search
+
+BEGIN;
+
+CREATE OR REPLACE FUNCTION post_text_search_update() RETURNS trigger AS $$
+BEGIN
+    -- The indexer sets this on every connection if post search is enabled
+    IF current_setting('indexer.post_search', true) IS DISTINCT FROM 'on' THEN
+        NEW.text_search := NULL;
+    ELSIF NEW.text_search_config IS NULL OR NEW.text_search_config = 'simple' THEN
+        NEW.text_search := to_tsvector('simple', NEW.text);
+    ELSE
+        -- The unstemmed words let searches without a language find the post as well
+        NEW.text_search := to_tsvector(NEW.text_search_config::regconfig, NEW.text) || to_tsvector('simple', NEW.text);
+    END IF;
+    RETURN NEW;
+END
+$$ LANGUAGE plpgsql;
+
+COMMIT;
@@ -0,0 +1,23 @@
+-- Labels emitted by labelers through com.atproto.label.subscribeLabels
+
+BEGIN;
+
+-- Only the latest label of a labeler for a value on a subject is kept. A negating label retracts it, so it is stored
+-- with neg set instead of deleting the row, that way an older label that arrives later can not resurrect it
+CREATE TABLE IF NOT EXISTS label (
+    src TEXT NOT NULL,
+    uri TEXT NOT NULL,
+    -- Id of the labeled row, for example a post or did id. NULL if the subject is not indexed by us
+    subject_id TEXT,
+    cid TEXT,
+    val TEXT NOT NULL,
+    neg BOOLEAN NOT NULL DEFAULT false,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL,
+    expires_at TIMESTAMP WITH TIME ZONE
+);
+
+CREATE UNIQUE INDEX IF NOT EXISTS label_src_uri_val_idx ON label (src, uri, val);
+CREATE INDEX IF NOT EXISTS label_uri_idx ON label (uri);
+CREATE INDEX IF NOT EXISTS label_subject_id_idx ON label (subject_id);
+
+COMMIT;
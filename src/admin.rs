@@ -0,0 +1,155 @@
+use crate::{
+    config::ARGS,
+    database::{big_update::queue_backfill, requeue_backfill, utils::did_to_key},
+};
+use hyper::{body::Incoming, header::AUTHORIZATION, HeaderMap, Method, Request, StatusCode};
+use sqlx::PgPool;
+use tracing::{info, warn};
+
+/// Check if a path belongs to an admin route
+pub fn is_admin_path(path: &str) -> bool {
+    path.starts_with("/reindex/") || path.starts_with("/backfill/requeue/")
+}
+
+/// Handle an admin request of the status server
+///
+/// `POST /reindex/<did>` queues a full backfill of the DID, even if it was backfilled before.
+/// `POST /backfill/requeue/<did>` clears the failure record of a DID, so it is backfilled again.
+/// All admin routes require the `--admin-token` as a bearer token.
+pub async fn handle_admin_request(
+    request: &Request<Incoming>,
+    database: &PgPool,
+) -> (StatusCode, String) {
+    if let Err(response) = authorize(ARGS.admin_token.as_deref(), request.headers()) {
+        return response;
+    }
+    if request.method() != Method::POST {
+        return (
+            StatusCode::METHOD_NOT_ALLOWED,
+            "use POST for admin routes".to_string(),
+        );
+    }
+
+    let path = request.uri().path();
+    if let Some(did) = path.strip_prefix("/reindex/") {
+        reindex(database, did).await
+    } else if let Some(did) = path.strip_prefix("/backfill/requeue/") {
+        requeue(database, did).await
+    } else {
+        (StatusCode::NOT_FOUND, "not found".to_string())
+    }
+}
+
+/// Check the bearer token of an admin request against the configured `token`
+fn authorize(token: Option<&str>, headers: &HeaderMap) -> Result<(), (StatusCode, String)> {
+    let Some(token) = token else {
+        return Err((
+            StatusCode::FORBIDDEN,
+            "admin routes are disabled, set --admin-token to enable them".to_string(),
+        ));
+    };
+    let provided = headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    if !provided.is_some_and(|provided| constant_time_eq(provided.as_bytes(), token.as_bytes())) {
+        return Err((StatusCode::UNAUTHORIZED, "invalid admin token".to_string()));
+    }
+    Ok(())
+}
+
+/// Compare two byte strings in time that only depends on their length, so the token can not be guessed byte by byte
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// Queue a full backfill of a DID
+async fn reindex(database: &PgPool, did: &str) -> (StatusCode, String) {
+    let did_key = match did_to_key(did) {
+        Ok(did_key) => did_key,
+        Err(e) => return (StatusCode::BAD_REQUEST, format!("invalid DID: {}", e)),
+    };
+    match queue_backfill(database, did_key).await {
+        Ok(()) => {
+            info!(target: "indexer", "Queued {} for reindexing", did);
+            (StatusCode::ACCEPTED, "queued".to_string())
+        }
+        Err(e) => {
+            warn!(target: "indexer", "Failed to queue {} for reindexing: {:?}", did, e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "failed to queue the DID".to_string(),
+            )
+        }
+    }
+}
+
+/// Clear the failure record of a DID
+async fn requeue(database: &PgPool, did: &str) -> (StatusCode, String) {
+    match requeue_backfill(database, did).await {
+        Ok(true) => (StatusCode::OK, "requeued".to_string()),
+        Ok(false) => (
+            StatusCode::NOT_FOUND,
+            "no failed backfill for this DID".to_string(),
+        ),
+        Err(e) => {
+            warn!(target: "indexer", "Failed to requeue {}: {:?}", did, e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "failed to requeue the DID".to_string(),
+            )
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bearer(token: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(AUTHORIZATION, format!("Bearer {}", token).parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn only_the_configured_token_is_authorized() {
+        assert!(authorize(Some("secret"), &bearer("secret")).is_ok());
+        assert_eq!(
+            authorize(Some("secret"), &bearer("secreT")).unwrap_err().0,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            authorize(Some("secret"), &bearer("secret2")).unwrap_err().0,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            authorize(Some("secret"), &HeaderMap::new()).unwrap_err().0,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            authorize(None, &bearer("secret")).unwrap_err().0,
+            StatusCode::FORBIDDEN
+        );
+    }
+
+    #[sqlx::test]
+    #[ignore = "needs a postgres server in DATABASE_URL"]
+    async fn posting_a_did_inserts_a_pending_backfill(db: PgPool) -> anyhow::Result<()> {
+        let (status, _) = reindex(&db, "did:plc:reindexed").await;
+        assert_eq!(status, StatusCode::ACCEPTED);
+
+        let at: Option<chrono::DateTime<chrono::Utc>> =
+            sqlx::query_scalar("SELECT at FROM latest_backfill WHERE of_did_id = 'plc_reindexed'")
+                .fetch_one(&db)
+                .await?;
+        assert_eq!(at, None);
+
+        let (status, _) = reindex(&db, "not a did").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        Ok(())
+    }
+}
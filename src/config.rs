@@ -1,13 +1,28 @@
-use clap::Parser;
-use std::sync::LazyLock;
+use crate::database::big_update::options::{ApplyOptions, RecordType};
+use crate::database::repo_indexer::HttpClientOptions;
+use crate::websocket::JetstreamHost;
+use chrono::{DateTime, Utc};
+use clap::{Parser, Subcommand};
+use std::{net::SocketAddr, path::PathBuf, sync::LazyLock, time::Duration};
 
 /// Command line arguments
 #[derive(Parser, Debug)]
 #[command(about)]
 pub struct Args {
+    #[command(subcommand)]
+    pub command: Option<Command>,
     /// Path to a certificate to check jetstream server against. By default the bundled ISRG Root X1 certificate is used.
+    /// HTTP requests to PDSs and the directory trust it in addition to the usual root certificates.
     #[arg(short = 'c', long)]
     pub certificate: Option<String>,
+    /// Paths to more CA certificates to trust, for example of a private CA of a PDS. Multiple paths are separated by commas
+    ///
+    /// Used for the jetstream, the firehose, labelers and HTTP requests
+    #[arg(long, value_delimiter = ',')]
+    pub extra_ca_certificate: Vec<String>,
+    /// Do not verify the TLS certificates of HTTP requests to PDSs, the directory and relays. Only for local development
+    #[arg(long, default_value = "false", default_missing_value = "true", num_args=0..=1)]
+    pub insecure_skip_tls_verify: bool,
     /// Set the tokio threadpool size. The default value is the number of cores available to the system.
     #[arg(long)]
     pub threads: Option<usize>,
@@ -21,6 +36,9 @@ pub struct Args {
     /// Size of the database connection pool
     #[arg(long, default_value = "10")]
     pub db_pool_size: u32,
+    /// Fail a database operation if no connection of the pool becomes available within this many seconds
+    #[arg(long, default_value = "60", value_parser = clap::value_parser!(u64).range(1..))]
+    pub db_acquire_timeout: u64,
     /// Username for the database server
     #[arg(short, long, default_value = "root")]
     pub username: String,
@@ -33,24 +51,116 @@ pub struct Args {
     /// Enable attaching to the jetstream for realtime updates
     #[arg(long, default_value = "false", default_missing_value = "true", num_args=0..=1)]
     pub no_jetstream: bool,
+    /// Consume the full firehose of a relay instead of the jetstream
+    #[arg(long, default_value = "false", default_missing_value = "true", num_args=0..=1, conflicts_with = "no_jetstream")]
+    pub firehose: bool,
+    /// Relay to consume the firehose from, for example bsky.network or ws://localhost:2470
+    #[arg(long, default_value = "bsky.network")]
+    pub firehose_host: JetstreamHost,
+    /// Labelers to subscribe to for labels, for example mod.bsky.app. Multiple labelers are separated by commas
+    #[arg(
+        long = "labeler-host",
+        visible_alias = "labeler-hosts",
+        value_delimiter = ','
+    )]
+    pub labeler_hosts: Vec<JetstreamHost>,
+    /// Replay the jetstream of this host between `--replay-from` and `--replay-to` instead of following it live
+    ///
+    /// The stored cursors are neither used nor overwritten. The indexer shuts down once the replay is done
+    #[arg(long, requires_all = ["replay_from", "replay_to"], conflicts_with = "firehose")]
+    pub replay_host: Option<JetstreamHost>,
+    /// Start of the replay, for example 2025-03-01T12:00:00Z
+    #[arg(long, requires = "replay_host")]
+    pub replay_from: Option<DateTime<Utc>>,
+    /// End of the replay, events after this time are not indexed
+    #[arg(long, requires = "replay_host")]
+    pub replay_to: Option<DateTime<Utc>>,
+    /// Publish the rows of applied updates to this NATS server, for example nats://localhost:4222
+    #[arg(long)]
+    pub event_sink: Option<String>,
+    /// Prefix of the subjects the rows are published on, for example `indexer.post`
+    #[arg(long, default_value = "indexer")]
+    pub event_sink_prefix: String,
     /// Capacity of the surrealdb connection. 0 means unbounded
     #[arg(long, default_value = "0")]
     pub surrealdb_capacity: usize,
+    /// Serve backfill and jetstream status as JSON on this address, for example 0.0.0.0:8080
+    #[arg(long)]
+    pub status_listen: Option<SocketAddr>,
+    /// Maintain the text search column of posts, so `/search` of the status server works
+    ///
+    /// Without this, new posts are not searchable, which avoids the cost of updating the search index
+    #[arg(long, default_value = "false", default_missing_value = "true", num_args=0..=1)]
+    pub enable_post_search: bool,
+    /// Token for the admin routes of the status server, they are disabled if this is not set
+    #[arg(long, env = "ADMIN_TOKEN")]
+    pub admin_token: Option<String>,
     /// Enable tokio console support
     #[arg(long, default_value = "false", default_missing_value = "true", num_args=0..=1)]
     pub tokio_console: bool,
+    /// Deployment environment reported in the opentelemetry resource
+    #[arg(long, default_value = "develop")]
+    pub deployment_environment: String,
     /// Enable opentelemetry tracing support
     #[arg(long, default_value = "false", default_missing_value = "true", num_args=0..=1)]
     pub otel_tracing: bool,
+    /// Serve metrics for prometheus on this address, for example 0.0.0.0:9464
+    #[arg(long, visible_alias = "prometheus-addr")]
+    pub metrics_prometheus_listen: Option<SocketAddr>,
+    /// Interval in seconds between two exports of the system metrics
+    #[arg(long, default_value = "2", value_parser = clap::value_parser!(u64).range(1..))]
+    pub system_metrics_interval_secs: u64,
     /// Disable opentelemetry metrics support
     #[arg(long, default_value = "false", default_missing_value = "true", num_args=0..=1)]
     pub no_otel_metrics: bool,
     /// Disable opentelemetry logging support
     #[arg(long, default_value = "false", default_missing_value = "true", num_args=0..=1)]
     pub no_otel_logs: bool,
-    /// Dont write to the database when backfilling
+    /// Download and parse repos when backfilling, but do not write them to the database
+    ///
+    /// Useful to measure the backfill throughput and to find records that can not be converted
+    #[arg(long, alias = "no-write-when-backfilling", default_value = "false", default_missing_value = "true", num_args=0..=1)]
+    pub validate_only: bool,
+    /// Jetstream host to connect to. Can be given multiple times. Use a ws:// prefix for hosts without tls
+    #[arg(
+        long = "jetstream-host",
+        visible_alias = "jetstream-hosts",
+        env = "JETSTREAM_HOSTS",
+        default_value = "jetstream1.us-west.bsky.network,jetstream2.us-east.bsky.network,test-jetstream.skyfeed.moe,jetstream2.us-west.bsky.network,jetstream1.us-east.bsky.network",
+        value_delimiter = ','
+    )]
+    pub jetstream_hosts: Vec<JetstreamHost>,
+    /// Consume all jetstream hosts in parallel instead of failing over between them
+    #[arg(long, default_value = "false", default_missing_value = "true", num_args=0..=1)]
+    pub jetstream_parallel: bool,
+    /// Send a ping to the jetstream after this many seconds without receiving anything
+    #[arg(long, default_value = "30")]
+    pub jetstream_ping_interval: u64,
+    /// Reconnect if the jetstream does not answer a ping within this many seconds
+    #[arg(long, default_value = "10")]
+    pub jetstream_pong_timeout: u64,
+    /// Number of received jetstream events that can wait for the database workers before reading pauses.
+    /// The queue is split evenly between the workers
+    #[arg(long, default_value = "1000", value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    pub jetstream_queue_size: usize,
+    /// Number of tasks that write jetstream events to the database
+    #[arg(long, default_value = "8", value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    pub jetstream_workers: usize,
+    /// Reconnect to the jetstream after this many events in a row failed because of the database
+    #[arg(long, default_value = "50", value_parser = clap::value_parser!(u64).range(1..))]
+    pub jetstream_max_consecutive_failures: u64,
+    /// Reconnect to the jetstream if more than this fraction of the events of the last minute failed because of the database
+    #[arg(long, default_value = "0.5")]
+    pub jetstream_max_failure_ratio: f64,
+    /// Use the zstd compressed jetstream. Requires the jetstream zstd dictionary
     #[arg(long, default_value = "false", default_missing_value = "true", num_args=0..=1)]
-    pub no_write_when_backfilling: bool,
+    pub jetstream_compress: bool,
+    /// Path to the zstd dictionary used by the compressed jetstream. It can be found in the jetstream repository at `pkg/models/zstd_dictionary`
+    #[arg(long, default_value = "zstd_dictionary")]
+    pub jetstream_zstd_dictionary: String,
+    /// Dont update the account status of DIDs when jetstream reports an account event
+    #[arg(long, default_value = "false", default_missing_value = "true", num_args=0..=1)]
+    pub no_track_account_status: bool,
     /// Size of the buffer between each pipeline stage in elements
     #[arg(long, default_value = "200")]
     pub pipeline_buffer_size: usize,
@@ -70,13 +180,89 @@ pub struct Args {
     /// The maximum number of times to attempt to download a repo before giving up
     #[arg(long, default_value = "5")]
     pub download_repo_attempts: u64,
+    /// Maximum number of concurrent repo downloads from a single PDS
+    #[arg(long, default_value = "8", value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    pub max_per_host_downloads: usize,
+    /// Abort the backfill of repos that are larger than this many bytes
+    #[arg(long)]
+    pub max_repo_size_bytes: Option<u64>,
+    /// Base delay between repo download attempts in milliseconds.
+    /// The delay doubles with every attempt and is jittered
+    #[arg(long, default_value = "500")]
+    pub download_repo_retry_delay: u64,
     /// Timeout for downloading information from the directory in seconds.
     /// If this is longer than the pipeline_stage_timeout, the pipeline_stage_timeout will be used
     #[arg(long, default_value = "200")]
     pub directory_download_timeout: u64,
+    /// The maximum number of times to attempt to download a DID document before giving up
+    #[arg(long, default_value = "3")]
+    pub directory_download_attempts: u64,
+    /// User agent of requests to PDSs, the plc directory and relays. Some PDS operators require a way to contact us
+    #[arg(
+        long,
+        default_value = concat!(
+            "indexer/",
+            env!("CARGO_PKG_VERSION"),
+            " (+https://github.com/zebreus/indexer-rust)"
+        )
+    )]
+    pub http_user_agent: String,
+    /// Maximum number of idle connections that are kept open to a single host
+    #[arg(long, default_value = "32")]
+    pub http_pool_idle_per_host: usize,
+    /// Use HTTP/2 with servers that support it
+    #[arg(long, default_value = "true", default_missing_value = "true", num_args=0..=1)]
+    pub http2: bool,
+    /// Timeout for opening a connection in seconds
+    #[arg(long, default_value = "10")]
+    pub http_connect_timeout: u64,
+    /// Timeout for a single read from a connection in seconds, a stalled download fails after this
+    #[arg(long, default_value = "60")]
+    pub http_read_timeout: u64,
+    /// Base URL of the plc directory, can point at a self-hosted mirror
+    #[arg(long, default_value = "https://plc.directory")]
+    pub plc_directory_url: String,
+    /// Time in seconds a resolved PDS endpoint is cached before the DID document is fetched again
+    #[arg(long, default_value = "86400")]
+    pub did_document_ttl: i64,
+    /// Number of resolved DID documents that are also cached in memory
+    #[arg(long, default_value = "100000", value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    pub did_document_cache_capacity: usize,
+    /// Verify the commit signature of backfilled repos against the signing key in the DID document
+    #[arg(long, default_value = "false", default_missing_value = "true", num_args=0..=1)]
+    pub verify_signatures: bool,
+    /// Also backfill repos again that were last backfilled more than this many days ago
+    #[arg(long)]
+    pub rebackfill_older_than: Option<i64>,
+    /// Only index posts in these languages, for example de,en,ja. Likes, follows and reposts are always indexed
+    ///
+    /// A post matches if one of its languages or their primary subtag is in the list. All posts are indexed if empty
+    #[arg(long, value_delimiter = ',')]
+    pub post_language_allowlist: Vec<String>,
+    /// Only index these record types, all record types are indexed if empty
+    ///
+    /// Records of other types are dropped before they are decoded, on the jetstream, the firehose and the backfill.
+    /// Repos that were backfilled with a smaller set of record types have to be backfilled again to get the others
+    #[arg(long, value_delimiter = ',')]
+    pub index_record_types: Vec<RecordType>,
+    /// Index posts that do not declare a language when `--post-language-allowlist` is set
+    #[arg(long, default_value = "true", default_missing_value = "true", num_args=0..=1)]
+    pub index_posts_without_language: bool,
+    /// Discover repos by enumerating all repos of this relay with listRepos, for example bsky.network
+    #[arg(long)]
+    pub enumerate_repos_from: Option<String>,
+    /// Maximum number of listRepos requests per second
+    #[arg(long, default_value = "2")]
+    pub enumerate_repos_rate: f64,
     /// Number of DIDs the RepoStream should prefetch
     #[arg(long, default_value = "5000")]
     pub repo_stream_buffer_size: usize,
+    /// Stop backfilling a repo after this many failed attempts
+    #[arg(long, default_value = "3")]
+    pub backfill_max_attempts: i32,
+    /// Minimum time in seconds between two backfill attempts of a failed repo
+    #[arg(long, default_value = "3600")]
+    pub backfill_retry_cooldown: i64,
     /// Maximum number of concurrent database transactions
     #[arg(long, default_value = "1")]
     pub max_concurrent_transactions: u32,
@@ -86,6 +272,90 @@ pub struct Args {
     /// Minimum number of rows per database transaction
     #[arg(long, default_value = "1000")]
     pub min_rows_per_transaction: usize,
+    /// Apply accumulated small updates after they waited this many seconds, even if there are fewer than min_rows_per_transaction
+    #[arg(long, default_value = "10", value_parser = clap::value_parser!(u64).range(1..))]
+    pub max_accumulator_age_secs: u64,
+    /// Split the backfill of a repo into transactions of at most this many rows
+    ///
+    /// Values below min_rows_per_transaction are raised to it
+    #[arg(long, default_value = "10000")]
+    pub max_rows_per_transaction: usize,
+    /// Insert large batches of follows, likes, reposts and posts with COPY instead of UNNEST
+    #[arg(long, default_value = "false", default_missing_value = "true", num_args=0..=1)]
+    pub use_copy_inserts: bool,
+}
+
+/// Subcommands that run instead of the indexer
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Index repos from CAR files on disk, for example exports of a PDS, then exit
+    ImportCar {
+        /// DID of the account the repos belong to
+        #[arg(long)]
+        did: String,
+        /// Only convert the records and print how many rows they would produce
+        #[arg(long, default_value = "false", default_missing_value = "true", num_args=0..=1)]
+        dry_run: bool,
+        /// CAR files to import
+        #[arg(required = true)]
+        paths: Vec<PathBuf>,
+    },
+    /// Download the repo of a DID and compare it with the database, then exit
+    ///
+    /// Prints a JSON report to stdout and a summary to stderr
+    Verify {
+        /// DID of the repo to check
+        #[arg(long)]
+        did: String,
+        /// Exit with an error if there are more differences than this
+        #[arg(long, default_value = "0")]
+        max_discrepancies: usize,
+    },
+}
+
+impl Args {
+    /// Collections selected with `--index-record-types`, empty if all record types are indexed
+    pub fn wanted_collections(&self) -> Vec<&'static str> {
+        self.index_record_types
+            .iter()
+            .flat_map(|record_type| record_type.collections())
+            .copied()
+            .collect()
+    }
+
+    /// Options for converting records and applying updates
+    pub fn apply_options(&self) -> ApplyOptions {
+        ApplyOptions {
+            min_rows_per_transaction: self.min_rows_per_transaction,
+            max_accumulator_age_secs: self.max_accumulator_age_secs,
+            min_concurrent_transactions: self.min_concurrent_transactions,
+            max_concurrent_transactions: self.max_concurrent_transactions,
+            max_retries: ApplyOptions::default().max_retries,
+            use_copy_inserts: self.use_copy_inserts,
+            track_account_status: !self.no_track_account_status,
+            record_types: self.index_record_types.clone(),
+            post_language_allowlist: self.post_language_allowlist.clone(),
+            index_posts_without_language: self.index_posts_without_language,
+        }
+    }
+
+    /// Settings of the HTTP client for requests to PDSs, the plc directory and relays
+    pub fn http_client_options(&self) -> HttpClientOptions {
+        HttpClientOptions {
+            user_agent: self.http_user_agent.clone(),
+            pool_idle_per_host: self.http_pool_idle_per_host,
+            http2: self.http2,
+            connect_timeout: Duration::from_secs(self.http_connect_timeout),
+            read_timeout: Duration::from_secs(self.http_read_timeout),
+            root_certificates: self
+                .certificate
+                .iter()
+                .chain(&self.extra_ca_certificate)
+                .cloned()
+                .collect(),
+            insecure_skip_tls_verify: self.insecure_skip_tls_verify,
+        }
+    }
 }
 
 pub static ARGS: LazyLock<Args> = LazyLock::new(Args::parse);
@@ -1,8 +1,14 @@
-use super::utils::{self, at_uri_to_record_id, blob_ref_to_record_id, did_to_key};
-use crate::config::ARGS;
-use anyhow::Result;
+use super::definitions::JetstreamCursor;
+use super::error::DbError;
+use super::utils::{
+    self, at_uri_to_record_id, blob_ref_to_record_id, did_to_key, UnsupportedCollection,
+};
+use crate::event_sink::{publish_deletes, publish_rows};
+use crate::observability::instrument_name;
+use crate::websocket::events::{Account, Identity};
+use anyhow::{Context, Result};
+use atrium_api::app::bsky::feed::threadgate::RecordAllowItem;
 use atrium_api::app::bsky::richtext::facet::MainFeaturesItem;
-use atrium_api::types::Object;
 use atrium_api::{
     app::bsky::embed::video,
     record::KnownRecord,
@@ -14,37 +20,47 @@ use atrium_api::{
 use chrono::{DateTime, Utc};
 use futures::lock::Mutex;
 use info::BigUpdateInfo;
-use opentelemetry::global;
+use ipld_core::ipld::Ipld;
 use opentelemetry::metrics::{Counter, Gauge, Histogram};
+use opentelemetry::{global, KeyValue};
+use options::options;
 use queries::{
-    insert_blocks, insert_feeds, insert_follows, insert_latest_backfills, insert_likes,
-    insert_listblocks, insert_listitems, insert_lists, insert_posts, insert_posts_relations,
+    insert_actordeclarations, insert_blobs, insert_blocks, insert_feeds, insert_follows,
+    insert_labelerservices, insert_latest_backfills, insert_likes, insert_listblocks,
+    insert_listitems, insert_lists, insert_postgates, insert_posts, insert_posts_relations,
     insert_profiles, insert_quotes_relations, insert_replies_relations, insert_reply_to_relations,
-    insert_reposts, upsert_latest_backfills,
+    insert_reposts, insert_starterpacks, insert_threadgates, upsert_latest_backfills, LikeTarget,
 };
-use serde::Serialize;
+use serde::{de::DeserializeOwned, Serialize};
 use sqlx::sqlite::any;
 use sqlx::PgPool;
+use std::collections::{HashMap, HashSet};
 use std::sync::atomic::{AtomicU32, Ordering};
 use std::sync::LazyLock;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 use surrealdb::RecordId;
-use tokio::sync::Semaphore;
+use tokio::sync::{RwLock, Semaphore};
+use tokio::time::{interval, MissedTickBehavior};
+use tokio_util::sync::CancellationToken;
 use tracing::{instrument, trace, warn};
 use types::{
-    BskyBlock, BskyDid, BskyFeed, BskyFollow, BskyLatestBackfill, BskyLike, BskyList,
-    BskyListBlock, BskyListItem, BskyPost, BskyPostImage, BskyPostMediaAspectRatio, BskyPostVideo,
-    BskyPostVideoBlob, BskyPostsRelation, BskyQuote, BskyRepliesRelation, BskyReplyToRelation,
-    BskyRepost, WithId,
+    BskyActorDeclaration, BskyBlob, BskyBlock, BskyDid, BskyFeed, BskyFollow, BskyLabelerService,
+    BskyLatestBackfill, BskyLike, BskyList, BskyListBlock, BskyListItem, BskyPost,
+    BskyPostExternal, BskyPostImage, BskyPostMediaAspectRatio, BskyPostVideo, BskyPostVideoBlob,
+    BskyPostVideoCaption, BskyPostgate, BskyPostsRelation, BskyQuote, BskyRepliesRelation,
+    BskyReplyToRelation, BskyRepost, BskyStarterpack, BskyThreadgate, BskyThreadgateAllow,
+    JetstreamAccountEvent, JetstreamIdentityEvent, WithId,
 };
 
+mod copy;
 mod info;
+pub mod options;
 mod queries;
-mod types;
+pub mod types;
 
-static QUERY_DURATION_METRIC: LazyLock<Histogram<u64>> = LazyLock::new(|| {
+static UPDATE_DURATION_METRIC: LazyLock<Histogram<u64>> = LazyLock::new(|| {
     global::meter("indexer")
-        .u64_histogram("indexer.database.insert_duration")
+        .u64_histogram(instrument_name("indexer.database.update_duration"))
         .with_unit("ms")
         .with_description("Big update duration")
         .with_boundaries(vec![
@@ -54,42 +70,119 @@ static QUERY_DURATION_METRIC: LazyLock<Histogram<u64>> = LazyLock::new(|| {
         ])
         .build()
 });
+static INSERT_DURATION_METRIC: LazyLock<Histogram<u64>> = LazyLock::new(|| {
+    global::meter("indexer")
+        .u64_histogram(instrument_name("indexer.database.insert_duration"))
+        .with_unit("ms")
+        .with_description("Duration of the inserts into a single table during a big update")
+        .with_boundaries(vec![
+            0.0, 5.0, 10.0, 25.0, 50.0, 75.0, 100.0, 250.0, 500.0, 750.0, 1000.0, 2500.0, 5000.0,
+            7500.0, 10000.0, 25000.0, 50000.0, 75000.0, 100000.0, 250000.0, 500000.0, 750000.0,
+            1000000.0, 2500000.0,
+        ])
+        .build()
+});
 static NEWLY_DISCOVERED_DIDS_METRIC: LazyLock<Counter<u64>> = LazyLock::new(|| {
     global::meter("indexer")
-        .u64_counter("indexer.database.newly_discovered_dids")
+        .u64_counter(instrument_name("indexer.database.newly_discovered_dids"))
         .with_unit("{DID}")
         .with_description("Number of newly discovered DIDs")
         .build()
 });
 static FAILED_BIG_UPDATES_METRIC: LazyLock<Counter<u64>> = LazyLock::new(|| {
     global::meter("indexer")
-        .u64_counter("indexer.database.failed_big_updates")
+        .u64_counter(instrument_name("indexer.database.failed_big_updates"))
         .with_unit("{update}")
         .with_description("Number of failed big updates. Should be always 0")
         .build()
 });
+static SKIPPED_RECORDS_METRIC: LazyLock<Counter<u64>> = LazyLock::new(|| {
+    global::meter("indexer")
+        .u64_counter(instrument_name("indexer.records.skipped"))
+        .with_unit("{record}")
+        .with_description("Number of records that could not be converted and were left out")
+        .build()
+});
+static IGNORED_RECORDS_METRIC: LazyLock<Counter<u64>> = LazyLock::new(|| {
+    global::meter("indexer")
+        .u64_counter(instrument_name("indexer.database.ignored_records"))
+        .with_unit("{record}")
+        .with_description(
+            "Number of records and embeds that were ignored because their type is not handled",
+        )
+        .build()
+});
+static POSTS_SKIPPED_BY_LANGUAGE_METRIC: LazyLock<Counter<u64>> = LazyLock::new(|| {
+    global::meter("indexer")
+        .u64_counter(instrument_name(
+            "indexer.database.posts_skipped_by_language",
+        ))
+        .with_unit("{post}")
+        .with_description(
+            "Number of posts that were not indexed because of --post-language-allowlist",
+        )
+        .build()
+});
+static DELETED_ROWS_METRIC: LazyLock<Counter<u64>> = LazyLock::new(|| {
+    global::meter("indexer")
+        .u64_counter(instrument_name("indexer.database.deleted_rows"))
+        .with_unit("{row}")
+        .with_description("Number of rows deleted because of delete commits")
+        .build()
+});
 static TRANSACTION_TICKETS_COST_METRIC: LazyLock<Gauge<u64>> = LazyLock::new(|| {
     global::meter("indexer")
-        .u64_gauge("indexer.database.transaction_cost")
-        .with_unit("{cost}")
-        .with_description("The current cost of holding a database transaction")
+        .u64_gauge(instrument_name("indexer.database.transaction_cost"))
+        .with_unit("{ticket}")
+        .with_description("Number of transaction tickets a database transaction currently costs")
         .build()
 });
 static TRANSACTION_TICKETS_AVAILABLE_METRIC: LazyLock<Gauge<u64>> = LazyLock::new(|| {
     global::meter("indexer")
-        .u64_gauge("indexer.database.transaction_cost")
-        .with_unit("{cost}")
-        .with_description("The current cost of holding a database transaction")
+        .u64_gauge(instrument_name(
+            "indexer.database.transaction_tickets_available",
+        ))
+        .with_unit("{ticket}")
+        .with_description("The number of transaction tickets that are currently available")
         .build()
 });
 static COLLECTED_UPDATE_SIZE_METRIC: LazyLock<Gauge<u64>> = LazyLock::new(|| {
     global::meter("indexer")
-        .u64_gauge("indexer.database.collected_update_size")
-        .with_unit("{elements}")
-        .with_description("The current cost of holding a database transaction")
+        .u64_gauge(instrument_name("indexer.database.collected_update_size"))
+        .with_unit("{row}")
+        .with_description("Number of rows waiting in the small update accumulator")
         .build()
 });
 
+/// Rows written and time spent per table by a single big update
+#[derive(Debug, Default)]
+struct ApplyStats {
+    /// Table, written rows and duration of each insert statement
+    inserts: Vec<(&'static str, u64, Duration)>,
+    newly_discovered_dids: u64,
+}
+
+impl ApplyStats {
+    fn record_metrics(&self) {
+        for (table, _, duration) in &self.inserts {
+            INSERT_DURATION_METRIC.record(
+                duration.as_millis() as u64,
+                &[KeyValue::new("table", *table)],
+            );
+        }
+    }
+}
+
+/// Await an insert, add its row count and duration to the stats and evaluate to the row count
+macro_rules! timed {
+    ($stats:ident, $table:literal, $insert:expr) => {{
+        let start = Instant::now();
+        let rows = $insert.await?;
+        $stats.inserts.push(($table, rows, start.elapsed()));
+        rows
+    }};
+}
+
 #[derive(Debug, Clone)]
 enum UpdateState {
     /// Update was applied
@@ -98,9 +191,55 @@ enum UpdateState {
     Retry,
 }
 
+/// Decide if a failed apply is retried, errors that can not go away by retrying are returned
+fn failed_apply_state(error: DbError) -> Result<UpdateState, DbError> {
+    if !error.is_retryable() {
+        return Err(error);
+    }
+    trace!("Transaction can be retried: {}", error);
+    Ok(UpdateState::Retry)
+}
+
 // Accumulates small updates until a big update is triggered
-static SMALL_UPDATE_ACCUMULATOR: LazyLock<Mutex<(usize, BigUpdate)>> =
-    LazyLock::new(|| Mutex::new((0, BigUpdate::default())));
+static SMALL_UPDATE_ACCUMULATOR: LazyLock<Mutex<Accumulator>> =
+    LazyLock::new(|| Mutex::new(Accumulator::default()));
+
+/// Held for reading while rows taken from the accumulator are applied
+///
+/// Taking it for writing waits until all rows that left the accumulator before are in the database
+static ACCUMULATOR_FLUSHES: RwLock<()> = RwLock::const_new(());
+
+/// Small updates that wait to be applied together
+#[derive(Default)]
+struct Accumulator {
+    rows: usize,
+    update: BigUpdate,
+    /// When the oldest waiting row was added
+    since: Option<Instant>,
+}
+
+impl Accumulator {
+    /// Add the rows of a small update
+    fn add(&mut self, update: BigUpdate, rows: usize) {
+        self.rows += rows;
+        self.since.get_or_insert_with(Instant::now);
+        COLLECTED_UPDATE_SIZE_METRIC.record(self.rows as u64, &[]);
+        self.update.merge(update);
+    }
+
+    /// Whether the oldest waiting row is older than `max_age`
+    fn is_stale(&self, max_age: Duration) -> bool {
+        self.since.is_some_and(|since| since.elapsed() >= max_age)
+    }
+
+    /// Take all waiting rows out of the accumulator
+    fn take(&mut self) -> BigUpdate {
+        self.rows = 0;
+        self.since = None;
+        COLLECTED_UPDATE_SIZE_METRIC.record(0, &[]);
+        std::mem::take(&mut self.update)
+    }
+}
 
 #[derive(Default, Clone, Serialize)]
 pub struct BigUpdate {
@@ -117,17 +256,37 @@ pub struct BigUpdate {
     listitems: Vec<WithId<BskyListItem>>,
     feeds: Vec<WithId<BskyFeed>>,
     lists: Vec<WithId<BskyList>>,
-    threadgates: Vec<WithId<Box<Object<atrium_api::app::bsky::feed::threadgate::RecordData>>>>,
-    starterpacks: Vec<WithId<Box<Object<atrium_api::app::bsky::graph::starterpack::RecordData>>>>,
-    postgates: Vec<WithId<Box<Object<atrium_api::app::bsky::feed::postgate::RecordData>>>>,
-    actordeclarations:
-        Vec<WithId<Box<Object<atrium_api::chat::bsky::actor::declaration::RecordData>>>>,
-    labelerservices: Vec<WithId<Box<Object<atrium_api::app::bsky::labeler::service::RecordData>>>>,
+    threadgates: Vec<WithId<BskyThreadgate>>,
+    starterpacks: Vec<WithId<BskyStarterpack>>,
+    postgates: Vec<WithId<BskyPostgate>>,
+    actordeclarations: Vec<WithId<BskyActorDeclaration>>,
+    labelerservices: Vec<WithId<BskyLabelerService>>,
+    blobs: Vec<WithId<BskyBlob>>,
     quotes: Vec<WithId<BskyQuote>>,
     posts: Vec<WithId<BskyPost>>,
     replies_relations: Vec<WithId<BskyRepliesRelation>>,
     reply_to_relations: Vec<WithId<BskyReplyToRelation>>,
     posts_relations: Vec<WithId<BskyPostsRelation>>,
+    /// Ids of deleted records, applied after all inserts
+    delete_posts: Vec<String>,
+    delete_follows: Vec<String>,
+    delete_likes: Vec<String>,
+    delete_reposts: Vec<String>,
+    delete_blocks: Vec<String>,
+    delete_listblocks: Vec<String>,
+    delete_listitems: Vec<String>,
+    delete_feeds: Vec<String>,
+    delete_lists: Vec<String>,
+    delete_starterpacks: Vec<String>,
+    delete_labelerservices: Vec<String>,
+    delete_threadgates: Vec<String>,
+    delete_postgates: Vec<String>,
+    delete_actordeclarations: Vec<String>,
+    /// Table and id of deleted records that a later operation re-creates, applied before all inserts
+    replaced: Vec<(&'static str, String)>,
+    /// Collection, rkey and error of records that could not be converted
+    #[serde(skip)]
+    pub skipped: Vec<(String, String, String)>,
 }
 
 // async fn write(
@@ -160,7 +319,39 @@ pub struct BigUpdate {
 // }
 
 impl BigUpdate {
-    pub fn merge(&mut self, other: BigUpdate) {
+    pub fn merge(&mut self, mut other: BigUpdate) {
+        // Deletes run after the inserts, so a pending delete of a record that `other` re-creates has to run first
+        macro_rules! recreated {
+            ($($deletes:ident => $rows:ident: $table:literal),* $(,)?) => {$(
+                if !self.$deletes.is_empty() && !other.$rows.is_empty() {
+                    let ids = other.$rows.iter().map(|row| row.id.as_str()).collect::<HashSet<_>>();
+                    self.$deletes.retain(|id| {
+                        if !ids.contains(id.as_str()) {
+                            return true;
+                        }
+                        self.replaced.push(($table, id.clone()));
+                        false
+                    });
+                }
+            )*};
+        }
+        recreated!(
+            delete_posts => posts: "post",
+            delete_follows => follows: "follow",
+            delete_likes => likes: "like",
+            delete_reposts => reposts: "repost",
+            delete_blocks => blocks: "block",
+            delete_listblocks => listblocks: "listblock",
+            delete_listitems => listitems: "listitem",
+            delete_feeds => feeds: "feed",
+            delete_lists => lists: "list",
+            delete_starterpacks => starterpacks: "starterpack",
+            delete_labelerservices => labelerservices: "labeler",
+            delete_threadgates => threadgates: "threadgate",
+            delete_postgates => postgates: "postgate",
+            delete_actordeclarations => actordeclarations: "chat_actor_declaration",
+        );
+        self.replaced.append(&mut other.replaced);
         self.did.extend(other.did);
         self.follows.extend(other.follows);
         self.latest_backfills.extend(other.latest_backfills);
@@ -176,6 +367,7 @@ impl BigUpdate {
         self.postgates.extend(other.postgates);
         self.actordeclarations.extend(other.actordeclarations);
         self.labelerservices.extend(other.labelerservices);
+        self.blobs.extend(other.blobs);
         self.quotes.extend(other.quotes);
         self.posts.extend(other.posts);
         self.replies_relations.extend(other.replies_relations);
@@ -183,14 +375,206 @@ impl BigUpdate {
         self.posts_relations.extend(other.posts_relations);
         self.overwrite_latest_backfills
             .extend(other.overwrite_latest_backfills);
+        self.delete_posts.extend(other.delete_posts);
+        self.delete_follows.extend(other.delete_follows);
+        self.delete_likes.extend(other.delete_likes);
+        self.delete_reposts.extend(other.delete_reposts);
+        self.delete_blocks.extend(other.delete_blocks);
+        self.delete_listblocks.extend(other.delete_listblocks);
+        self.delete_listitems.extend(other.delete_listitems);
+        self.delete_feeds.extend(other.delete_feeds);
+        self.delete_lists.extend(other.delete_lists);
+        self.delete_starterpacks.extend(other.delete_starterpacks);
+        self.delete_labelerservices
+            .extend(other.delete_labelerservices);
+        self.delete_threadgates.extend(other.delete_threadgates);
+        self.delete_postgates.extend(other.delete_postgates);
+        self.delete_actordeclarations
+            .extend(other.delete_actordeclarations);
+        self.skipped.extend(other.skipped);
+    }
+
+    /// Create an update that only records a skipped record
+    pub(crate) fn skip(
+        collection: &str,
+        rkey: &str,
+        reason: &'static str,
+        error: anyhow::Error,
+    ) -> Self {
+        SKIPPED_RECORDS_METRIC.add(1, &[KeyValue::new("reason", reason)]);
+        BigUpdate {
+            skipped: vec![(
+                collection.to_string(),
+                rkey.to_string(),
+                format!("{:#}", error),
+            )],
+            ..Default::default()
+        }
     }
 
-    pub fn add_timestamp(&mut self, did: &str, time: DateTime<Utc>) {
+    /// Count the rows this update would write, without touching the database
+    ///
+    /// Used by `--validate-only` instead of [BigUpdate::apply]
+    pub fn validate(mut self) -> u64 {
+        let info = tokio::task::block_in_place(|| {
+            self.dedup();
+            BigUpdateInfo::new(&self)
+        });
+        trace!("Validated update: {:?}", info);
+        info.all().count
+    }
+
+    /// Table and id of every record row in this update
+    ///
+    /// Only covers the tables that have one row per record, not the profile in did or the relations
+    pub fn record_ids(&self) -> Vec<(&'static str, &str)> {
+        fn ids<'a, T: Serialize>(
+            table: &'static str,
+            rows: &'a [WithId<T>],
+        ) -> impl Iterator<Item = (&'static str, &'a str)> {
+            rows.iter().map(move |row| (table, row.id.as_str()))
+        }
+        ids("post", &self.posts)
+            .chain(ids("follow", &self.follows))
+            .chain(ids("like", &self.likes))
+            .chain(ids("repost", &self.reposts))
+            .chain(ids("block", &self.blocks))
+            .chain(ids("listblock", &self.listblocks))
+            .chain(ids("listitem", &self.listitems))
+            .chain(ids("list", &self.lists))
+            .chain(ids("feed", &self.feeds))
+            .chain(ids("starterpack", &self.starterpacks))
+            .chain(ids("threadgate", &self.threadgates))
+            .chain(ids("postgate", &self.postgates))
+            .chain(ids("labeler", &self.labelerservices))
+            .chain(ids("chat_actor_declaration", &self.actordeclarations))
+            .collect()
+    }
+
+    /// Posts in this update
+    pub fn posts(&self) -> &[WithId<BskyPost>] {
+        &self.posts
+    }
+
+    /// Profiles in this update, they are stored in the did table
+    pub fn profiles(&self) -> &[WithId<BskyDid>] {
+        &self.did
+    }
+
+    /// Log a summary of the skipped records of `did` and forget them
+    pub fn log_skipped(&mut self, did: &str) {
+        let Some((collection, rkey, error)) = self.skipped.first() else {
+            return;
+        };
+        warn!(target: "indexer", "Skipped {} invalid records of {}, first {}/{}: {}",
+            self.skipped.len(), did, collection, rkey, error);
+        self.skipped.clear();
+    }
+
+    /// Remove rows with duplicate ids, keeping the last occurrence
+    ///
+    /// Merged updates can contain the same record multiple times, for example from the jetstream and a backfill
+    pub fn dedup(&mut self) {
+        dedup_by_id(&mut self.did);
+        dedup_by_id(&mut self.follows);
+        dedup_by_id(&mut self.latest_backfills);
+        dedup_by_id(&mut self.overwrite_latest_backfills);
+        dedup_by_id(&mut self.likes);
+        dedup_by_id(&mut self.reposts);
+        dedup_by_id(&mut self.blocks);
+        dedup_by_id(&mut self.listblocks);
+        dedup_by_id(&mut self.listitems);
+        dedup_by_id(&mut self.feeds);
+        dedup_by_id(&mut self.lists);
+        dedup_by_id(&mut self.threadgates);
+        dedup_by_id(&mut self.starterpacks);
+        dedup_by_id(&mut self.postgates);
+        dedup_by_id(&mut self.actordeclarations);
+        dedup_by_id(&mut self.labelerservices);
+        dedup_by_id(&mut self.blobs);
+        dedup_by_id(&mut self.quotes);
+        dedup_by_id(&mut self.posts);
+        dedup_by_id(&mut self.replies_relations);
+        dedup_by_id(&mut self.reply_to_relations);
+        dedup_by_id(&mut self.posts_relations);
+        for deletes in [
+            &mut self.delete_posts,
+            &mut self.delete_follows,
+            &mut self.delete_likes,
+            &mut self.delete_reposts,
+            &mut self.delete_blocks,
+            &mut self.delete_listblocks,
+            &mut self.delete_listitems,
+            &mut self.delete_feeds,
+            &mut self.delete_lists,
+            &mut self.delete_starterpacks,
+            &mut self.delete_labelerservices,
+            &mut self.delete_threadgates,
+            &mut self.delete_postgates,
+            &mut self.delete_actordeclarations,
+        ] {
+            deletes.sort_unstable();
+            deletes.dedup();
+        }
+    }
+
+    /// Number of rows inserted, updated or deleted by this update
+    pub fn row_count(&self) -> usize {
+        self.did.len()
+            + self.follows.len()
+            + self.latest_backfills.len()
+            + self.overwrite_latest_backfills.len()
+            + self.likes.len()
+            + self.reposts.len()
+            + self.blocks.len()
+            + self.listblocks.len()
+            + self.listitems.len()
+            + self.feeds.len()
+            + self.lists.len()
+            + self.threadgates.len()
+            + self.starterpacks.len()
+            + self.postgates.len()
+            + self.actordeclarations.len()
+            + self.labelerservices.len()
+            + self.blobs.len()
+            + self.quotes.len()
+            + self.posts.len()
+            + self.replies_relations.len()
+            + self.reply_to_relations.len()
+            + self.posts_relations.len()
+            + self.deleted_ids().count()
+    }
+
+    /// Iterate over the ids of all records that are deleted by this update
+    fn deleted_ids(&self) -> impl Iterator<Item = &String> {
+        [
+            &self.delete_posts,
+            &self.delete_follows,
+            &self.delete_likes,
+            &self.delete_reposts,
+            &self.delete_blocks,
+            &self.delete_listblocks,
+            &self.delete_listitems,
+            &self.delete_feeds,
+            &self.delete_lists,
+            &self.delete_starterpacks,
+            &self.delete_labelerservices,
+            &self.delete_threadgates,
+            &self.delete_postgates,
+            &self.delete_actordeclarations,
+        ]
+        .into_iter()
+        .flatten()
+    }
+
+    /// Mark the repo of `did_key` as backfilled at `time` with the given rev
+    pub fn add_timestamp(&mut self, did_key: &str, time: DateTime<Utc>, rev: Option<String>) {
         self.overwrite_latest_backfills.push(WithId {
-            id: did.to_string(),
+            id: did_key.to_string(),
             data: BskyLatestBackfill {
-                of: RecordId::from(("did", did)),
+                of: RecordId::from(("did", did_key)),
                 at: Some(time),
+                rev,
             },
         });
     }
@@ -308,7 +692,11 @@ impl BigUpdate {
     //     permits
     // }
 
-    async fn actually_attempt_apply(self, database: PgPool) -> Result<()> {
+    /// Write this update in a single transaction
+    ///
+    /// The inserts run one after another. A postgres connection executes one statement at a time, so they can not
+    /// overlap inside the transaction, and spreading them over multiple connections would give up atomicity.
+    async fn actually_attempt_apply(self, database: PgPool) -> Result<ApplyStats, DbError> {
         let BigUpdate {
             did,
             follows,
@@ -325,15 +713,33 @@ impl BigUpdate {
             postgates,
             actordeclarations,
             labelerservices,
+            blobs,
             quotes,
             posts,
             replies_relations,
             reply_to_relations,
             posts_relations,
             overwrite_latest_backfills,
+            delete_posts,
+            delete_follows,
+            delete_likes,
+            delete_reposts,
+            delete_blocks,
+            delete_listblocks,
+            delete_listitems,
+            delete_feeds,
+            delete_lists,
+            delete_starterpacks,
+            delete_labelerservices,
+            delete_threadgates,
+            delete_postgates,
+            delete_actordeclarations,
+            replaced,
+            skipped: _,
         } = self;
 
-        let mut transaction = database.begin().await.unwrap();
+        let mut stats = ApplyStats::default();
+        let mut transaction = database.begin().await?;
 
         // sqlx::query!("SET LOCAL synchronous_commit = 'off'")
         //     .execute(&mut *transaction)
@@ -347,32 +753,184 @@ impl BigUpdate {
             .execute(&mut *transaction)
             .await?;
 
-        insert_profiles(&did, &mut transaction).await?;
-        insert_follows(&follows, &mut transaction).await?;
-        insert_likes(&likes, &mut transaction).await?;
-        insert_reposts(&reposts, &mut transaction).await?;
-        insert_blocks(&blocks, &mut transaction).await?;
-        insert_listblocks(&listblocks, &mut transaction).await?;
-        insert_listitems(&listitems, &mut transaction).await?;
-        insert_feeds(&feeds, &mut transaction).await?;
-        insert_lists(&lists, &mut transaction).await?;
-        // insert_threadgates(&threadgates, &mut transaction).await?;
-        // insert_starterpacks(&starterpacks, &mut transaction).await?;
-        // insert_postgates(&postgates, &mut transaction).await?;
-        // insert_actordeclarations(&actordeclarations, &mut transaction).await?;
-        // insert_labelerservices(&labelerservices, &mut transaction).await?;
-        insert_quotes_relations(&quotes, &mut transaction).await?;
-        insert_replies_relations(&replies_relations, &mut transaction).await?;
-        insert_reply_to_relations(&reply_to_relations, &mut transaction).await?;
-        insert_posts(&posts, &mut transaction).await?;
-        insert_posts_relations(&posts_relations, &mut transaction).await?;
-        sqlx::query!("LOCK latest_backfill")
-            .execute(&mut *transaction)
-            .await?;
-        insert_latest_backfills(&latest_backfills, &mut transaction).await?;
-        upsert_latest_backfills(&overwrite_latest_backfills, &mut transaction).await?;
+        delete_replaced(replaced, &mut transaction).await?;
+        timed!(stats, "blob", insert_blobs(&blobs, &mut transaction));
+        timed!(stats, "did", insert_profiles(&did, &mut transaction));
+        timed!(stats, "follow", insert_follows(&follows, &mut transaction));
+        timed!(stats, "like", insert_likes(&likes, &mut transaction));
+        timed!(stats, "repost", insert_reposts(&reposts, &mut transaction));
+        timed!(stats, "block", insert_blocks(&blocks, &mut transaction));
+        timed!(
+            stats,
+            "listblock",
+            insert_listblocks(&listblocks, &mut transaction)
+        );
+        timed!(
+            stats,
+            "listitem",
+            insert_listitems(&listitems, &mut transaction)
+        );
+        timed!(stats, "feed", insert_feeds(&feeds, &mut transaction));
+        timed!(stats, "list", insert_lists(&lists, &mut transaction));
+        timed!(
+            stats,
+            "threadgate",
+            insert_threadgates(&threadgates, &mut transaction)
+        );
+        timed!(
+            stats,
+            "starterpack",
+            insert_starterpacks(&starterpacks, &mut transaction)
+        );
+        timed!(
+            stats,
+            "postgate",
+            insert_postgates(&postgates, &mut transaction)
+        );
+        timed!(
+            stats,
+            "chat_actor_declaration",
+            insert_actordeclarations(&actordeclarations, &mut transaction)
+        );
+        timed!(
+            stats,
+            "labeler",
+            insert_labelerservices(&labelerservices, &mut transaction)
+        );
+        timed!(
+            stats,
+            "quotes_relation",
+            insert_quotes_relations(&quotes, &mut transaction)
+        );
+        timed!(
+            stats,
+            "replies_relation",
+            insert_replies_relations(&replies_relations, &mut transaction)
+        );
+        timed!(
+            stats,
+            "replyto_relation",
+            insert_reply_to_relations(&reply_to_relations, &mut transaction)
+        );
+        timed!(stats, "post", insert_posts(&posts, &mut transaction));
+        timed!(
+            stats,
+            "posts_relation",
+            insert_posts_relations(&posts_relations, &mut transaction)
+        );
+        // Deletes run last, so a record created and deleted in the same update is gone afterwards
+        let deleted_rows = [
+            (
+                "app.bsky.feed.post",
+                queries::delete_posts(&delete_posts, &mut transaction).await?,
+            ),
+            (
+                "app.bsky.graph.follow",
+                queries::delete_follows(&delete_follows, &mut transaction).await?,
+            ),
+            (
+                "app.bsky.feed.like",
+                queries::delete_likes(&delete_likes, &mut transaction).await?,
+            ),
+            (
+                "app.bsky.feed.repost",
+                queries::delete_reposts(&delete_reposts, &mut transaction).await?,
+            ),
+            (
+                "app.bsky.graph.block",
+                queries::delete_blocks(&delete_blocks, &mut transaction).await?,
+            ),
+            (
+                "app.bsky.graph.listblock",
+                queries::delete_listblocks(&delete_listblocks, &mut transaction).await?,
+            ),
+            (
+                "app.bsky.graph.listitem",
+                queries::delete_listitems(&delete_listitems, &mut transaction).await?,
+            ),
+            (
+                "app.bsky.feed.generator",
+                queries::delete_feeds(&delete_feeds, &mut transaction).await?,
+            ),
+            (
+                "app.bsky.graph.list",
+                queries::delete_lists(&delete_lists, &mut transaction).await?,
+            ),
+            (
+                "app.bsky.graph.starterpack",
+                queries::delete_starterpacks(&delete_starterpacks, &mut transaction).await?,
+            ),
+            (
+                "app.bsky.labeler.service",
+                queries::delete_labelerservices(&delete_labelerservices, &mut transaction).await?,
+            ),
+            (
+                "app.bsky.feed.threadgate",
+                queries::delete_threadgates(&delete_threadgates, &mut transaction).await?,
+            ),
+            (
+                "app.bsky.feed.postgate",
+                queries::delete_postgates(&delete_postgates, &mut transaction).await?,
+            ),
+            (
+                "chat.bsky.actor.declaration",
+                queries::delete_actordeclarations(&delete_actordeclarations, &mut transaction)
+                    .await?,
+            ),
+        ];
+        // Both statements lock their rows in id order and the upsert never moves a backfill back in time, so
+        // concurrent updates can neither deadlock nor overwrite each other with older state
+        // Existing rows are skipped, so every inserted row is a newly discovered DID
+        stats.newly_discovered_dids = timed!(
+            stats,
+            "latest_backfill",
+            insert_latest_backfills(&latest_backfills, &mut transaction)
+        );
+        timed!(
+            stats,
+            "latest_backfill",
+            upsert_latest_backfills(&overwrite_latest_backfills, &mut transaction)
+        );
         transaction.commit().await?;
-        Ok(())
+
+        // Only durable changes are published, relations and backfill state are internal. Rows that already existed
+        // are published again, so consumers get every row at least once
+        publish_rows("did", &did);
+        publish_rows("follow", &follows);
+        publish_rows("like", &likes);
+        publish_rows("repost", &reposts);
+        publish_rows("block", &blocks);
+        publish_rows("listblock", &listblocks);
+        publish_rows("listitem", &listitems);
+        publish_rows("feed", &feeds);
+        publish_rows("list", &lists);
+        publish_rows("threadgate", &threadgates);
+        publish_rows("starterpack", &starterpacks);
+        publish_rows("postgate", &postgates);
+        publish_rows("chat_actor_declaration", &actordeclarations);
+        publish_rows("labeler", &labelerservices);
+        publish_rows("post", &posts);
+        publish_deletes("post", &delete_posts);
+        publish_deletes("follow", &delete_follows);
+        publish_deletes("like", &delete_likes);
+        publish_deletes("repost", &delete_reposts);
+        publish_deletes("block", &delete_blocks);
+        publish_deletes("listblock", &delete_listblocks);
+        publish_deletes("listitem", &delete_listitems);
+        publish_deletes("feed", &delete_feeds);
+        publish_deletes("list", &delete_lists);
+        publish_deletes("starterpack", &delete_starterpacks);
+        publish_deletes("labeler", &delete_labelerservices);
+        publish_deletes("threadgate", &delete_threadgates);
+        publish_deletes("postgate", &delete_postgates);
+        publish_deletes("chat_actor_declaration", &delete_actordeclarations);
+
+        for (collection, rows) in deleted_rows {
+            if rows > 0 {
+                DELETED_ROWS_METRIC.add(rows, &[KeyValue::new("collection", collection)]);
+            }
+        }
+        Ok(stats)
     }
 
     /// Apply this update to the database
@@ -401,10 +959,10 @@ impl BigUpdate {
         static MIN_COST: u32 = 20;
         // Maximum cost for a transaction in permits
         static MAX_COST: LazyLock<u32> =
-            LazyLock::new(|| MIN_COST * ARGS.max_concurrent_transactions);
+            LazyLock::new(|| MIN_COST * options().max_concurrent_transactions);
         // Semaphore for limiting the number of concurrent transactions by permits
         static SEMAPHORE: LazyLock<Semaphore> = LazyLock::new(|| {
-            Semaphore::new(*MAX_COST as usize * ARGS.min_concurrent_transactions as usize)
+            Semaphore::new(*MAX_COST as usize * options().min_concurrent_transactions as usize)
         });
         // The current cost of a transaction in permits
         static TRANSACTION_COST: AtomicU32 = AtomicU32::new(MIN_COST);
@@ -416,7 +974,7 @@ impl BigUpdate {
         let transaction_cost_multiplier = f64::log10(10.0 + info.all().count as f64).floor() as u32;
         let transaction_cost = std::cmp::min(*MAX_COST, base_cost * transaction_cost_multiplier);
 
-        let result: anyhow::Result<()> = {
+        let result: Result<ApplyStats, DbError> = {
             let cloned = self.clone();
             let _permit = SEMAPHORE.acquire_many(transaction_cost).await.unwrap();
             tokio::task::spawn(async move { cloned.actually_attempt_apply(database).await })
@@ -425,20 +983,21 @@ impl BigUpdate {
         .unwrap();
         // let errors = result.take_errors();
         // Return retry if the transaction can be retried
-        if let Err(error) = &result {
-            let can_be_retried = format!("{:?}", error).contains("deadlock");
-            if can_be_retried {
-                // Raise the cost for each retry
-                TRANSACTION_COST
-                    .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |x| {
-                        Some(std::cmp::min(*MAX_COST, x * 2))
-                    })
-                    .unwrap();
-
-                trace!("Transaction can be retried");
-                return Ok(UpdateState::Retry);
-            }
-        }
+        let result = match result {
+            Ok(stats) => Ok(stats),
+            Err(error) => match failed_apply_state(error) {
+                Ok(state) => {
+                    // Raise the cost for each retry
+                    TRANSACTION_COST
+                        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |x| {
+                            Some(std::cmp::min(*MAX_COST, x * 2))
+                        })
+                        .unwrap();
+                    return Ok(state);
+                }
+                Err(error) => Err(error),
+            },
+        };
 
         // Lower the cost for each successful transaction
         TRANSACTION_COST
@@ -452,34 +1011,40 @@ impl BigUpdate {
         );
 
         let update_duration = after_update.elapsed();
-        QUERY_DURATION_METRIC.record(update_duration.as_millis() as u64, &[]);
+        UPDATE_DURATION_METRIC.record(update_duration.as_millis() as u64, &[]);
 
         // // Return error if there are any errors
-        if let Err(error) = result {
-            // tracing::error!("Database error!!!!!!!!!!!!!!!!!!!!!! {:?}", &error);
-            FAILED_BIG_UPDATES_METRIC.add(1, &[]);
-            return Err(error.into());
-
-            // let mut sorted_errors = errors.into_iter().collect::<Vec<_>>();
-            // sorted_errors.sort_by(|(a, _), (b, _)| a.cmp(b));
-            // for error in &sorted_errors {
-            //     warn!("Database error: {:?}", error);
-            // }
-            // let first_error = &sorted_errors.first().unwrap().1;
-            // return Err(anyhow::anyhow!("Database error: {:?}", first_error));
-        }
+        let stats = match result {
+            Ok(stats) => stats,
+            Err(error) => {
+                // tracing::error!("Database error!!!!!!!!!!!!!!!!!!!!!! {:?}", &error);
+                FAILED_BIG_UPDATES_METRIC.add(1, &[]);
+                return Err(error.into());
+
+                // let mut sorted_errors = errors.into_iter().collect::<Vec<_>>();
+                // sorted_errors.sort_by(|(a, _), (b, _)| a.cmp(b));
+                // for error in &sorted_errors {
+                //     warn!("Database error: {:?}", error);
+                // }
+                // let first_error = &sorted_errors.first().unwrap().1;
+                // return Err(anyhow::anyhow!("Database error: {:?}", first_error));
+            }
+        };
 
         // At this point, we know that the update was successful
 
         // Record metrics
         info.record_metrics(source);
 
-        // // Record stats about newly discovered DIDs
-        // let newly_discovered_dids = result.take::<Vec<IgnoredAny>>(0).unwrap().len();
-        // // warn!("Newly discovered DIDs: {}", newly_discovered_dids);
-        // if newly_discovered_dids > 0 {
-        //     NEWLY_DISCOVERED_DIDS_METRIC.add(newly_discovered_dids as u64, &[]);
-        // }
+        stats.record_metrics();
+
+        // Record stats about newly discovered DIDs
+        if stats.newly_discovered_dids > 0 {
+            NEWLY_DISCOVERED_DIDS_METRIC.add(
+                stats.newly_discovered_dids,
+                &[KeyValue::new("source", source.to_string())],
+            );
+        }
 
         trace!(
             "Applied updated: {} elements, {}MB, {:03}ms applying",
@@ -487,6 +1052,15 @@ impl BigUpdate {
             info.all().size as f64 / 1024.0 / 1024.0,
             update_duration.as_millis(),
         );
+        trace!(
+            "Inserted rows per table: {:?}",
+            stats
+                .inserts
+                .iter()
+                .filter(|(_, rows, _)| *rows > 0)
+                .map(|(table, rows, duration)| (table, rows, duration.as_millis()))
+                .collect::<Vec<_>>()
+        );
         // debug!("Detailed infos: {:?}", info);
 
         Ok(UpdateState::Applied)
@@ -809,40 +1383,52 @@ impl BigUpdate {
     /// Apply this update to the database
     ///
     /// `source` is a string describing the source of the update, used for metrics
-    pub async fn apply(self, database: PgPool, source: &str) -> Result<()> {
+    pub async fn apply(mut self, database: PgPool, source: &str) -> Result<()> {
         // If updates are too small, we add them into an accumulator and return here.
         // The accumulated updates will be flushed when it is big enough.
-        let (mut update, info) = {
-            let info = tokio::task::block_in_place(|| BigUpdateInfo::new(&self));
+        let (update, info, _flush_guard) = {
+            let info = tokio::task::block_in_place(|| {
+                self.dedup();
+                BigUpdateInfo::new(&self)
+            });
 
             let all = info.all();
-            if all.count < ARGS.min_rows_per_transaction as u64 {
+            if all.count < options().min_rows_per_transaction as u64 {
                 // Small update
                 let mut lock = SMALL_UPDATE_ACCUMULATOR.lock().await;
-                let (count, update) = &mut *lock;
-                *count += all.count as usize;
-                COLLECTED_UPDATE_SIZE_METRIC.record(*count as u64, &[]);
-                update.merge(self);
-                if *count < ARGS.min_rows_per_transaction {
+                lock.add(self, all.count as usize);
+                if lock.rows < options().min_rows_per_transaction {
                     return Ok(());
                 }
-                let update = std::mem::take(update);
-                *count = 0;
+                let mut update = lock.take();
+                let flush_guard = ACCUMULATOR_FLUSHES.read().await;
                 drop(lock);
-                let info = tokio::task::block_in_place(|| BigUpdateInfo::new(&update));
+                let info = tokio::task::block_in_place(|| {
+                    update.dedup();
+                    BigUpdateInfo::new(&update)
+                });
 
-                (update, info)
+                (update, info, Some(flush_guard))
             } else {
-                (self, info)
+                (self, info, None)
             }
         };
 
+        update.apply_with_retries(database, source, &info).await
+    }
+
+    /// Apply this update to the database, retrying until it succeeds
+    async fn apply_with_retries(
+        mut self,
+        database: PgPool,
+        source: &str,
+        info: &BigUpdateInfo,
+    ) -> Result<()> {
         // This number is really big, because updates should always succeed after a few retries
-        let mut attempts_left = 100;
+        let max_retries = options().max_retries.max(1);
+        let mut attempts_left = max_retries;
         loop {
-            let state = update
-                .attempt_apply(database.clone(), source, &info)
-                .await?;
+            let state = self.attempt_apply(database.clone(), source, info).await?;
             match state {
                 UpdateState::Applied => {
                     break;
@@ -852,20 +1438,287 @@ impl BigUpdate {
                     attempts_left -= 1;
                     if attempts_left == 0 {
                         return Err(anyhow::anyhow!(
-                            "Failed to apply an update after 100 retries. This needs investigation."
+                            "Failed to apply an update after {} retries. This needs investigation.",
+                            max_retries
                         ));
                     }
                 }
             }
         }
-        if attempts_left < 100 {
-            trace!("Update successful after {} retries", 100 - attempts_left);
+        if attempts_left < max_retries {
+            trace!(
+                "Update successful after {} retries",
+                max_retries - attempts_left
+            );
         }
 
         Ok(())
     }
 }
 
+/// Remove rows with duplicate ids, keeping the last occurrence and the order of the remaining rows
+fn dedup_by_id<R: Serialize>(rows: &mut Vec<WithId<R>>) {
+    if rows.len() < 2 {
+        return;
+    }
+    let keep = {
+        let mut last = HashMap::with_capacity(rows.len());
+        for (index, row) in rows.iter().enumerate() {
+            last.insert(row.id.as_str(), index);
+        }
+        rows.iter()
+            .enumerate()
+            .map(|(index, row)| last[row.id.as_str()] == index)
+            .collect::<Vec<_>>()
+    };
+    let mut keep = keep.into_iter();
+    rows.retain(|_| keep.next().unwrap_or_default());
+}
+
+/// Delete records that were deleted and then re-created, so the inserts that follow write the new contents
+async fn delete_replaced(
+    replaced: Vec<(&'static str, String)>,
+    database: &mut sqlx::PgTransaction<'_>,
+) -> Result<(), DbError> {
+    let mut by_table: HashMap<&'static str, Vec<String>> = HashMap::new();
+    for (table, id) in replaced {
+        by_table.entry(table).or_default().push(id);
+    }
+    for (table, ids) in by_table {
+        match table {
+            "post" => queries::delete_posts(&ids, database).await?,
+            "follow" => queries::delete_follows(&ids, database).await?,
+            "like" => queries::delete_likes(&ids, database).await?,
+            "repost" => queries::delete_reposts(&ids, database).await?,
+            "block" => queries::delete_blocks(&ids, database).await?,
+            "listblock" => queries::delete_listblocks(&ids, database).await?,
+            "listitem" => queries::delete_listitems(&ids, database).await?,
+            "feed" => queries::delete_feeds(&ids, database).await?,
+            "list" => queries::delete_lists(&ids, database).await?,
+            "starterpack" => queries::delete_starterpacks(&ids, database).await?,
+            "labeler" => queries::delete_labelerservices(&ids, database).await?,
+            "threadgate" => queries::delete_threadgates(&ids, database).await?,
+            "postgate" => queries::delete_postgates(&ids, database).await?,
+            "chat_actor_declaration" => queries::delete_actordeclarations(&ids, database).await?,
+            _ => unreachable!("no deletes for table {}", table),
+        };
+    }
+    Ok(())
+}
+
+/// Number of rows that are waiting in the small update accumulator
+pub async fn accumulated_rows() -> usize {
+    SMALL_UPDATE_ACCUMULATOR.lock().await.rows
+}
+
+/// Apply all updates that are still waiting in the small update accumulator
+///
+/// Used on shutdown, so no accumulated rows get lost
+pub async fn flush_small_updates(database: PgPool) -> Result<()> {
+    flush_accumulator(database, "shutdown").await
+}
+
+/// Take the waiting rows out of the accumulator and apply them
+async fn flush_accumulator(database: PgPool, source: &str) -> Result<()> {
+    let (mut update, _flush_guard) = {
+        let mut lock = SMALL_UPDATE_ACCUMULATOR.lock().await;
+        if lock.rows == 0 {
+            return Ok(());
+        }
+        (lock.take(), ACCUMULATOR_FLUSHES.read().await)
+    };
+    let info = tokio::task::block_in_place(|| {
+        update.dedup();
+        BigUpdateInfo::new(&update)
+    });
+    trace!(
+        "Flushing {} accumulated rows to the database",
+        info.all().count
+    );
+    update.apply_with_retries(database, source, &info).await
+}
+
+/// Flush the accumulator whenever its oldest row waited longer than `--max-accumulator-age-secs`
+///
+/// Without this, rows could wait forever on a quiet instance. Runs until shutdown is requested.
+pub async fn flush_stale_small_updates(
+    database: PgPool,
+    shutdown: CancellationToken,
+) -> Result<()> {
+    let max_age = Duration::from_secs(options().max_accumulator_age_secs);
+    let mut ticker = interval(Duration::from_secs(1));
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => {}
+            _ = shutdown.cancelled() => return Ok(()),
+        }
+        if SMALL_UPDATE_ACCUMULATOR.lock().await.is_stale(max_age) {
+            if let Err(e) = flush_accumulator(database.clone(), "timer").await {
+                warn!("Failed to flush stale accumulated rows: {:?}", e);
+            }
+        }
+    }
+}
+
+/// Write a cursor once everything that was handled before is in the database
+///
+/// Flushes the accumulator and waits for flushes that other tasks already started, so a crash can never skip events
+/// whose rows were still waiting in memory.
+pub async fn write_cursor_when_applied(database: &PgPool, cursor: JetstreamCursor) -> Result<()> {
+    flush_accumulator(database.clone(), "cursor").await?;
+    wait_for_running_flushes().await;
+    super::write_cursor(database, cursor).await
+}
+
+/// Wait until all rows that left the accumulator before are in the database
+async fn wait_for_running_flushes() {
+    drop(ACCUMULATOR_FLUSHES.write().await);
+}
+
+/// Insert DIDs that were not seen before as pending backfills
+///
+/// DIDs that already have a backfill row are left untouched
+pub async fn insert_pending_backfills(database: &PgPool, did_keys: Vec<String>) -> Result<u64> {
+    let backfills = did_keys
+        .into_iter()
+        .map(|did_key| WithId {
+            id: did_key.clone(),
+            data: BskyLatestBackfill {
+                of: RecordId::from(("did", did_key)),
+                at: None,
+                rev: None,
+            },
+        })
+        .collect::<Vec<_>>();
+
+    let mut transaction = database.begin().await?;
+    let rows_affected = insert_latest_backfills(&backfills, &mut transaction).await?;
+    transaction.commit().await?;
+
+    if rows_affected > 0 {
+        NEWLY_DISCOVERED_DIDS_METRIC.add(rows_affected, &[KeyValue::new("source", "enumeration")]);
+    }
+
+    Ok(rows_affected)
+}
+
+/// Queue the repo of a DID for a full backfill
+///
+/// Resets the backfill timestamp and rev and clears failed attempts, so the repo stream picks the DID up on its next pass
+pub async fn queue_backfill(database: &PgPool, did_key: String) -> Result<()> {
+    let backfill = WithId {
+        id: did_key.clone(),
+        data: BskyLatestBackfill {
+            of: RecordId::from(("did", did_key.clone())),
+            at: None,
+            rev: None,
+        },
+    };
+
+    let mut transaction = database.begin().await?;
+    upsert_latest_backfills(&vec![backfill], &mut transaction).await?;
+    sqlx::query!(
+        "DELETE FROM backfill_failure WHERE of_did_id = $1::TEXT",
+        &did_key
+    )
+    .execute(&mut *transaction)
+    .await?;
+    sqlx::query!(
+        "UPDATE latest_backfill SET unbackfillable_reason = NULL WHERE of_did_id = $1::TEXT AND unbackfillable_reason IS NOT NULL",
+        &did_key
+    )
+    .execute(&mut *transaction)
+    .await?;
+    transaction.commit().await?;
+
+    Ok(())
+}
+
+/// Store the latest identity event of a DID and update its handle
+///
+/// Identity events are rare, so they are written directly instead of going through a big update
+pub async fn apply_identity_event(
+    database: &PgPool,
+    did_key: String,
+    time_us: i64,
+    identity: Identity,
+) -> Result<()> {
+    let event = WithId {
+        id: did_key,
+        data: JetstreamIdentityEvent {
+            time_us,
+            handle: identity.handle.to_string(),
+            seq: i64::try_from(identity.seq).context("Identity event seq does not fit into i64")?,
+            time: identity.time,
+        },
+    };
+
+    let mut transaction = database.begin().await?;
+    queries::upsert_jetstream_identity_event(&event, &mut transaction).await?;
+    queries::update_did_handle(&event.id, &event.data.handle, &mut transaction).await?;
+    transaction.commit().await?;
+    Ok(())
+}
+
+/// Store the latest account event of a DID
+///
+/// Unless disabled, the account status is also written to the did table. Deleted and taken down accounts are purged,
+/// and queued for a full backfill again once they are reactivated.
+pub async fn apply_account_event(
+    database: &PgPool,
+    did_key: String,
+    time_us: i64,
+    account: Account,
+) -> Result<()> {
+    let status_changed_at = DateTime::from_timestamp_micros(time_us)
+        .context("Account event time_us is out of range")?;
+    // Deleted and taken down accounts lose all their data
+    let purge_reason = match account.status.as_deref() {
+        Some(status @ ("deleted" | "takendown")) if !account.active => Some(status.to_string()),
+        _ => None,
+    };
+    let event = WithId {
+        id: did_key,
+        data: JetstreamAccountEvent {
+            time_us,
+            active: account.active,
+            seq: i64::try_from(account.seq).context("Account event seq does not fit into i64")?,
+            time: account.time,
+        },
+    };
+
+    let mut transaction = database.begin().await?;
+    queries::upsert_jetstream_account_event(&event, &mut transaction).await?;
+    if options().track_account_status {
+        queries::update_did_status(
+            &event.id,
+            event.data.active,
+            status_changed_at,
+            &mut transaction,
+        )
+        .await?;
+    }
+    if event.data.active {
+        // Only undo the mark set by the purge, other reasons still apply
+        sqlx::query!(
+            "UPDATE latest_backfill SET at = NULL, last_known_rev = NULL, unbackfillable_reason = NULL WHERE of_did_id = $1::TEXT AND unbackfillable_reason IN ('deleted', 'takendown')",
+            &event.id
+        )
+        .execute(&mut *transaction)
+        .await?;
+    }
+    transaction.commit().await?;
+
+    if let Some(reason) = purge_reason {
+        // Accumulated rows of the DID would otherwise be written after the purge
+        flush_accumulator(database.clone(), "purge").await?;
+        wait_for_running_flushes().await;
+        super::deletion::delete_did_cascade(database, &event.id, &reason).await?;
+    }
+    Ok(())
+}
+
 impl core::fmt::Debug for BigUpdate {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         let info = BigUpdateInfo::new(self);
@@ -873,7 +1726,55 @@ impl core::fmt::Debug for BigUpdate {
     }
 }
 
+/// If the new commit is a delete, handle it
+///
+/// Deletes with an invalid rkey are recorded in `skipped`
+#[instrument]
+pub fn create_big_delete(
+    did: Did,
+    did_key: String,
+    collection: String,
+    rkey: RecordKey,
+) -> BigUpdate {
+    if !options().indexes_collection(&collection) {
+        return BigUpdate::default();
+    }
+    if let Err(e) = utils::ensure_valid_rkey(rkey.to_string()) {
+        return BigUpdate::skip(&collection, rkey.as_str(), "invalid_rkey", e);
+    }
+
+    let mut big_update = BigUpdate::default();
+    let id = format!("{}_{}", rkey.as_str(), did_key);
+
+    match collection.as_str() {
+        "app.bsky.graph.follow" => big_update.delete_follows.push(id),
+        "app.bsky.feed.repost" => big_update.delete_reposts.push(id),
+        "app.bsky.feed.like" => big_update.delete_likes.push(id),
+        "app.bsky.graph.block" => big_update.delete_blocks.push(id),
+        "app.bsky.graph.listblock" => big_update.delete_listblocks.push(id),
+        "app.bsky.feed.post" => big_update.delete_posts.push(id),
+        "app.bsky.graph.listitem" => big_update.delete_listitems.push(id),
+        "app.bsky.feed.generator" => big_update.delete_feeds.push(id),
+        "app.bsky.graph.list" => big_update.delete_lists.push(id),
+        "app.bsky.graph.starterpack" => big_update.delete_starterpacks.push(id),
+        "app.bsky.labeler.service" => big_update.delete_labelerservices.push(id),
+        "app.bsky.feed.threadgate" => big_update.delete_threadgates.push(id),
+        "app.bsky.feed.postgate" => big_update.delete_postgates.push(id),
+        "chat.bsky.actor.declaration" => big_update.delete_actordeclarations.push(id),
+        _ => {
+            record_ignored(&collection);
+            warn!(target: "indexer", "could not handle operation {} {} {} {}",
+                did.as_str(), "delete", collection, rkey.as_str());
+        }
+    }
+
+    big_update
+}
+
 /// If the new commit is a create or update, handle it
+///
+/// Records that can not be converted, for example because they reference an invalid AT URI, are recorded in `skipped`
+/// instead of failing, so one bad record does not throw away the rest of a repo.
 #[instrument(skip(record))]
 pub fn create_big_update(
     did: Did,
@@ -881,23 +1782,42 @@ pub fn create_big_update(
     collection: String,
     rkey: RecordKey,
     record: KnownRecord,
-) -> Result<BigUpdate> {
-    utils::ensure_valid_rkey(rkey.to_string())?;
+) -> BigUpdate {
+    // Record types we do not index are usually dropped before they are decoded already
+    if !options().indexes_collection(&collection) {
+        return BigUpdate::default();
+    }
+    if let Err(e) = utils::ensure_valid_rkey(rkey.to_string()) {
+        return BigUpdate::skip(&collection, rkey.as_str(), "invalid_rkey", e);
+    }
+    match convert_record(&did, &did_key, &collection, &rkey, record) {
+        Ok(big_update) => big_update,
+        Err(e) if e.chain().any(|cause| cause.is::<UnsupportedCollection>()) => {
+            BigUpdate::skip(&collection, rkey.as_str(), "unsupported_collection", e)
+        }
+        Err(e) => BigUpdate::skip(&collection, rkey.as_str(), "invalid_record", e),
+    }
+}
 
+/// Convert a record into the rows of an update
+fn convert_record(
+    did: &Did,
+    did_key: &str,
+    collection: &str,
+    rkey: &RecordKey,
+    record: KnownRecord,
+) -> Result<BigUpdate> {
     let mut big_update = BigUpdate::default();
 
     match record {
         KnownRecord::AppBskyActorProfile(d) => {
-            // NOTE: using .ok() here isn't optimal, incorrect data should
-            // probably not be entered into the database at all, but for now
-            // we'll just ignore it.
             let profile = WithId {
-                id: did_key.clone(),
+                id: did_key.to_string(),
                 data: BskyDid {
                     display_name: d.display_name.clone(),
                     description: d.description.clone(),
-                    avatar: None, // TODO Implement
-                    banner: None, // TODO Implement
+                    avatar: d.avatar.as_ref().map(blob_ref_to_record_id),
+                    banner: d.banner.as_ref().map(blob_ref_to_record_id),
                     created_at: d
                         .created_at
                         .as_ref()
@@ -906,24 +1826,30 @@ pub fn create_big_update(
                     joined_via_starter_pack: d
                         .joined_via_starter_pack
                         .as_ref()
-                        .and_then(|d| utils::strong_ref_to_record_id(d).ok()),
-                    // TODO if strong_ref_to_record_id fails, it should return an error result instead of being empty
+                        .map(utils::strong_ref_to_record_id)
+                        .transpose()?,
                     pinned_post: d
                         .pinned_post
                         .as_ref()
-                        .and_then(|d| utils::strong_ref_to_record_id(d).ok()),
+                        .map(utils::strong_ref_to_record_id)
+                        .transpose()?,
                     labels: d
                         .labels
                         .as_ref()
                         .map(utils::extract_self_labels_profile)
                         .unwrap_or_default(),
                     extra_data: process_extra_data(&d.extra_data)?,
+                    handle: None,
+                    did_text: did.as_str().to_string(),
                 },
             };
             big_update.did.push(profile);
+            big_update
+                .blobs
+                .extend(d.avatar.iter().chain(d.banner.iter()).map(process_blob));
         }
         KnownRecord::AppBskyGraphFollow(d) => {
-            // TODO ensure_valid_rkey_strict(rkey.as_str())?;
+            utils::ensure_valid_rkey_strict(rkey.as_str())?;
             let from = utils::did_to_key(did.as_str())?;
             let id = format!("{}_{}", rkey.as_str(), from);
             let to = utils::did_to_key(d.subject.as_str())?;
@@ -943,14 +1869,15 @@ pub fn create_big_update(
                 data: BskyLatestBackfill {
                     of: RecordId::from(("did", to)),
                     at: None,
+                    rev: None,
                 },
             });
         }
         KnownRecord::AppBskyFeedLike(d) => {
-            // TODO ensure_valid_rkey_strict(rkey.as_str())?;
+            utils::ensure_valid_rkey_strict(rkey.as_str())?;
             let from = utils::did_to_key(did.as_str())?;
             let id = format!("{}_{}", rkey.as_str(), from);
-            let to = utils::at_uri_to_record_id(&d.subject.uri)?;
+            let to = like_target_to_record_id(&d.subject.uri)?;
             let created_at = d.created_at.as_ref().to_utc();
 
             big_update.likes.push(WithId {
@@ -963,10 +1890,10 @@ pub fn create_big_update(
             });
         }
         KnownRecord::AppBskyFeedRepost(d) => {
-            // TODO ensure_valid_rkey_strict(rkey.as_str())?;
+            utils::ensure_valid_rkey_strict(rkey.as_str())?;
             let from = utils::did_to_key(did.as_str())?;
             let id = format!("{}_{}", rkey.as_str(), from);
-            let to = utils::at_uri_to_record_id(&d.subject.uri)?;
+            let to = record_id_in_table(&d.subject.uri, "post")?;
             let created_at = d.created_at.as_ref().to_utc();
 
             big_update.reposts.push(WithId {
@@ -979,7 +1906,7 @@ pub fn create_big_update(
             });
         }
         KnownRecord::AppBskyGraphBlock(d) => {
-            // TODO ensure_valid_rkey_strict(rkey.as_str())?;
+            utils::ensure_valid_rkey_strict(rkey.as_str())?;
             let from = utils::did_to_key(did.as_str())?;
             let id = format!("{}_{}", rkey.as_str(), from);
             let to = utils::did_to_key(d.subject.as_str())?;
@@ -995,10 +1922,10 @@ pub fn create_big_update(
             });
         }
         KnownRecord::AppBskyGraphListblock(d) => {
-            // TODO ensure_valid_rkey_strict(rkey.as_str())?;
+            utils::ensure_valid_rkey_strict(rkey.as_str())?;
             let from = utils::did_to_key(did.as_str())?;
             let id = format!("{}_{}", rkey.as_str(), from);
-            let to = utils::at_uri_to_record_id(&d.subject)?;
+            let to = like_target_to_record_id(&d.subject)?;
             let created_at = d.created_at.as_ref().to_utc();
 
             big_update.listblocks.push(WithId {
@@ -1011,11 +1938,11 @@ pub fn create_big_update(
             });
         }
         KnownRecord::AppBskyGraphListitem(d) => {
-            // TODO ensure_valid_rkey_strict(rkey.as_str())?;
+            utils::ensure_valid_rkey_strict(rkey.as_str())?;
             let from = utils::did_to_key(did.as_str())?;
             let id = format!("{}_{}", rkey.as_str(), from);
 
-            let from = utils::at_uri_to_record_id(&d.list)?;
+            let from = record_id_in_table(&d.list, "list")?;
             let to = utils::did_to_key(&d.subject)?;
             let created_at = d.created_at.as_ref().to_utc();
 
@@ -1035,7 +1962,7 @@ pub fn create_big_update(
                 id,
                 data: BskyFeed {
                     author: RecordId::from_table_key("did", did_key),
-                    avatar: None, // TODO implement
+                    avatar: d.avatar.as_ref().map(blob_ref_to_record_id),
                     created_at: d.created_at.as_ref().to_utc(),
                     description: d.description.clone(),
                     did: d.did.to_string(),
@@ -1050,6 +1977,7 @@ pub fn create_big_update(
                 },
             };
             big_update.feeds.push(feed);
+            big_update.blobs.extend(d.avatar.iter().map(process_blob));
         }
         KnownRecord::AppBskyGraphList(d) => {
             let did_key = utils::did_to_key(did.as_str())?;
@@ -1059,7 +1987,7 @@ pub fn create_big_update(
                 id,
                 data: BskyList {
                     name: d.name.clone(),
-                    avatar: None, // TODO implement
+                    avatar: d.avatar.as_ref().map(blob_ref_to_record_id),
                     created_at: d.created_at.as_ref().to_utc(),
                     description: d.description.clone(),
                     labels: d.labels.as_ref().and_then(utils::extract_self_labels_list),
@@ -1068,42 +1996,181 @@ pub fn create_big_update(
                 },
             };
             big_update.lists.push(list);
+            big_update.blobs.extend(d.avatar.iter().map(process_blob));
         }
         KnownRecord::AppBskyFeedThreadgate(d) => {
             let did_key = utils::did_to_key(did.as_str())?;
             let id = format!("{}_{}", rkey.as_str(), did_key);
-            big_update.threadgates.push(WithId { id, data: d });
+
+            let allow = d
+                .allow
+                .as_ref()
+                .map(|allow| {
+                    allow
+                        .iter()
+                        .map(|rule| {
+                            let rule = match rule {
+                                atrium_api::types::Union::Refs(r) => r,
+                                // The follower rule is newer than the lexicons of atrium
+                                atrium_api::types::Union::Unknown(u)
+                                    if u.r#type == "app.bsky.feed.threadgate#followerRule" =>
+                                {
+                                    return Ok(Some(BskyThreadgateAllow {
+                                        rule: "follower".to_string(),
+                                        list: None,
+                                    }));
+                                }
+                                atrium_api::types::Union::Unknown(u) => {
+                                    record_ignored(&u.r#type);
+                                    return Ok(None);
+                                }
+                            };
+                            Ok(Some(match rule {
+                                RecordAllowItem::MentionRule(_) => BskyThreadgateAllow {
+                                    rule: "mention".to_string(),
+                                    list: None,
+                                },
+                                RecordAllowItem::FollowingRule(_) => BskyThreadgateAllow {
+                                    rule: "following".to_string(),
+                                    list: None,
+                                },
+                                RecordAllowItem::ListRule(l) => BskyThreadgateAllow {
+                                    rule: "list".to_string(),
+                                    list: Some(record_id_in_table(&l.list, "list")?),
+                                },
+                            }))
+                        })
+                        .filter_map(Result::transpose)
+                        .collect::<Result<Vec<_>>>()
+                })
+                .transpose()?;
+
+            big_update.threadgates.push(WithId {
+                id,
+                data: BskyThreadgate {
+                    post: record_id_in_table(&d.post, "post")?,
+                    created_at: d.created_at.as_ref().to_utc(),
+                    allow,
+                    extra_data: process_extra_data(&d.extra_data)?,
+                },
+            });
         }
         KnownRecord::AppBskyGraphStarterpack(d) => {
             let did_key = utils::did_to_key(did.as_str())?;
             let id = format!("{}_{}", rkey.as_str(), did_key);
-            big_update.starterpacks.push(WithId { id, data: d });
+
+            let starterpack = WithId {
+                id,
+                data: BskyStarterpack {
+                    creator: RecordId::from_table_key("did", did_key),
+                    name: d.name.clone(),
+                    description: d.description.clone(),
+                    list: record_id_in_table(&d.list, "list")?,
+                    feeds: d
+                        .feeds
+                        .as_ref()
+                        .map(|feeds| {
+                            feeds
+                                .iter()
+                                .map(|f| record_id_in_table(&f.uri, "feed"))
+                                .collect::<Result<Vec<_>>>()
+                        })
+                        .transpose()?,
+                    created_at: d.created_at.as_ref().to_utc(),
+                    extra_data: process_extra_data(&d.extra_data)?,
+                },
+            };
+            big_update.starterpacks.push(starterpack);
         }
         KnownRecord::AppBskyFeedPostgate(d) => {
             let did_key = utils::did_to_key(did.as_str())?;
             let id = format!("{}_{}", rkey.as_str(), did_key);
-            big_update.postgates.push(WithId { id, data: d });
+
+            let disable_embedding = d.embedding_rules.iter().flatten().any(|rule| {
+                matches!(
+                    rule,
+                    atrium_api::types::Union::Refs(
+                        atrium_api::app::bsky::feed::postgate::RecordEmbeddingRulesItem::DisableRule(_)
+                    )
+                )
+            });
+
+            big_update.postgates.push(WithId {
+                id,
+                data: BskyPostgate {
+                    post: record_id_in_table(&d.post, "post")?,
+                    created_at: d.created_at.as_ref().to_utc(),
+                    disable_embedding,
+                    detached_embeddings: d
+                        .detached_embedding_uris
+                        .as_ref()
+                        .map(|uris| {
+                            uris.iter()
+                                .map(|uri| record_id_in_table(uri, "post"))
+                                .collect::<Result<Vec<_>>>()
+                        })
+                        .transpose()?,
+                    extra_data: process_extra_data(&d.extra_data)?,
+                },
+            });
         }
         KnownRecord::ChatBskyActorDeclaration(d) => {
             let did_key = utils::did_to_key(did.as_str())?;
             let id = format!("{}_{}", rkey.as_str(), did_key);
-            big_update.actordeclarations.push(WithId { id, data: d });
+            big_update.actordeclarations.push(WithId {
+                id,
+                data: BskyActorDeclaration {
+                    did: RecordId::from_table_key("did", did_key),
+                    allow_incoming: d.allow_incoming.clone(),
+                    extra_data: process_extra_data(&d.extra_data)?,
+                },
+            });
         }
         KnownRecord::AppBskyLabelerService(d) => {
             let did_key = utils::did_to_key(did.as_str())?;
             let id = format!("{}_{}", rkey.as_str(), did_key);
-            big_update.labelerservices.push(WithId { id, data: d });
+            big_update.labelerservices.push(WithId {
+                id,
+                data: BskyLabelerService {
+                    did: RecordId::from_table_key("did", did_key),
+                    created_at: d.created_at.as_ref().to_utc(),
+                    label_values: d.policies.label_values.clone(),
+                    label_value_definitions: d
+                        .policies
+                        .label_value_definitions
+                        .as_ref()
+                        .map(canonical_json)
+                        .transpose()?,
+                    extra_data: process_extra_data(&d.extra_data)?,
+                },
+            });
         }
-        KnownRecord::AppBskyFeedPost(d) => {
+        KnownRecord::AppBskyFeedPost(mut d) => {
+            let langs = d.langs.as_ref().map(|langs| {
+                langs
+                    .iter()
+                    .map(|l| l.as_ref().to_string())
+                    .collect::<Vec<_>>()
+            });
+            if let Some(reason) = options().post_language_skip_reason(langs.as_deref()) {
+                POSTS_SKIPPED_BY_LANGUAGE_METRIC.add(1, &[KeyValue::new("reason", reason)]);
+                return Ok(big_update);
+            }
             let did_key = utils::did_to_key(did.as_str())?;
             let id = format!("{}_{}", rkey.as_str(), did_key);
 
+            // Set by Bridgy Fed and other clients, but not part of the lexicon
+            let via = take_extra_field::<String>(&mut d.extra_data, "via");
+            let bridgy_original_url =
+                take_extra_field::<String>(&mut d.extra_data, "bridgyOriginalUrl");
+
             let mut images: Vec<BskyPostImage> = vec![];
             let mut links: Vec<String> = vec![];
             let mut mentions: Vec<RecordId> = vec![];
             let mut record: Option<RecordId> = None;
             let mut tags: Vec<String> = vec![];
             let mut video: Option<BskyPostVideo> = None;
+            let mut external: Option<BskyPostExternal> = None;
 
             let mut post_images: Vec<atrium_api::app::bsky::embed::images::Image> = vec![];
 
@@ -1112,8 +2179,8 @@ pub fn create_big_update(
                     atrium_api::types::Union::Refs(e) => {
                         match e {
                       atrium_api::app::bsky::feed::post::RecordEmbedRefs::AppBskyEmbedExternalMain(m)=>{
-                        // TODO index preview too
                         links.push(m.external.uri.clone());
+                        external = Some(process_external(&m.external, &mut big_update));
                       },
                         atrium_api::app::bsky::feed::post::RecordEmbedRefs::AppBskyEmbedImagesMain(m) => {
                           post_images=m.images.clone();
@@ -1122,16 +2189,16 @@ pub fn create_big_update(
                           video = Some(process_video(m)?);
                         },
                         atrium_api::app::bsky::feed::post::RecordEmbedRefs::AppBskyEmbedRecordMain(m) => {
-                          record = Some(at_uri_to_record_id(&m.record.uri)?);
+                          record = Some(like_target_to_record_id(&m.record.uri)?);
                         },
                         atrium_api::app::bsky::feed::post::RecordEmbedRefs::AppBskyEmbedRecordWithMediaMain(m) => {
-                          record = Some(at_uri_to_record_id(&m.record.record.uri)?);
+                          record = Some(like_target_to_record_id(&m.record.record.uri)?);
 
                           match &m.media{
                             atrium_api::types::Union::Refs(r)=>match r{
                               atrium_api::app::bsky::embed::record_with_media::MainMediaRefs::AppBskyEmbedExternalMain(m)=>{
-                                // TODO index preview too
                                 links.push(m.external.uri.clone());
+                                external = Some(process_external(&m.external, &mut big_update));
                               }
                               atrium_api::app::bsky::embed::record_with_media::MainMediaRefs::AppBskyEmbedImagesMain(m)=>{
                                 post_images=m.images.clone();
@@ -1141,12 +2208,12 @@ pub fn create_big_update(
                                 video = Some(process_video(m)?);
                               }
                             }
-                            atrium_api::types::Union::Unknown(_)=>{}
+                            atrium_api::types::Union::Unknown(u)=>record_ignored(&u.r#type),
                           }
                         },
                     }
                     }
-                    atrium_api::types::Union::Unknown(_) => {}
+                    atrium_api::types::Union::Unknown(u) => record_ignored(&u.r#type),
                 }
             };
 
@@ -1178,7 +2245,7 @@ pub fn create_big_update(
                                     tags.push(t.tag.clone());
                                 }
                             },
-                            atrium_api::types::Union::Unknown(_) => {}
+                            atrium_api::types::Union::Unknown(u) => record_ignored(&u.r#type),
                         }
                     }
                 }
@@ -1204,15 +2271,12 @@ pub fn create_big_update(
                 id: id.clone(),
                 data: BskyPost {
                     author: RecordId::from_table_key("did", did_key.clone()),
-                    bridgy_original_url: None,
-                    via: None,
+                    bridgy_original_url,
+                    via,
                     created_at: d.created_at.as_ref().to_utc(),
                     labels: d.labels.as_ref().and_then(utils::extract_self_labels_post),
                     text: d.text.clone(),
-                    langs: d
-                        .langs
-                        .as_ref()
-                        .map(|d| d.iter().map(|l| l.as_ref().to_string()).collect()),
+                    langs,
                     root: d
                         .reply
                         .as_ref()
@@ -1224,6 +2288,7 @@ pub fn create_big_update(
                         .map(|r| utils::strong_ref_to_record_id(&r.parent))
                         .transpose()?,
                     video,
+                    external,
                     tags: if tags.is_empty() { None } else { Some(tags) },
                     links: if links.is_empty() { None } else { Some(links) },
                     mentions: if mentions.is_empty() {
@@ -1244,7 +2309,15 @@ pub fn create_big_update(
             let parent = post.data.parent.clone();
             big_update.posts.push(post);
 
-            if parent.is_some() {
+            // Every post is in posts_relation, replies are additionally in the reply relations
+            big_update.posts_relations.push(WithId {
+                id: id.clone(),
+                data: BskyPostsRelation {
+                    from: RecordId::from_table_key("did", did_key.clone()),
+                    to: RecordId::from_table_key("post", id.clone()),
+                },
+            });
+            if let Some(parent) = parent {
                 big_update.replies_relations.push(WithId {
                     id: id.clone(),
                     data: BskyRepliesRelation {
@@ -1257,20 +2330,13 @@ pub fn create_big_update(
                     id: id.clone(),
                     data: BskyReplyToRelation {
                         from: RecordId::from_table_key("post", id.clone()),
-                        to: parent.unwrap(),
-                    },
-                });
-            } else {
-                big_update.posts_relations.push(WithId {
-                    id: id.clone(),
-                    data: BskyPostsRelation {
-                        from: RecordId::from_table_key("did", did_key.clone()),
-                        to: RecordId::from_table_key("post", id.clone()),
+                        to: parent,
                     },
                 });
             }
         }
         _ => {
+            record_ignored(collection);
             warn!(target: "indexer", "ignored create_or_update {} {} {}",
                 did.as_str(), collection, rkey.as_str());
         }
@@ -1279,6 +2345,32 @@ pub fn create_big_update(
     Ok(big_update)
 }
 
+/// Convert the subject of a like or listblock, which can only point to a [LikeTarget]
+fn like_target_to_record_id(uri: &str) -> Result<RecordId> {
+    let to = at_uri_to_record_id(uri)?;
+    if LikeTarget::try_from(to.table()).is_err() {
+        return Err(unsupported_collection(uri));
+    }
+    Ok(to)
+}
+
+/// Converts an AT URI that has to point to a record in `table` to a record ID
+fn record_id_in_table(uri: &str, table: &str) -> Result<RecordId> {
+    let to = at_uri_to_record_id(uri)?;
+    if to.table() != table {
+        return Err(unsupported_collection(uri));
+    }
+    Ok(to)
+}
+
+fn unsupported_collection(uri: &str) -> anyhow::Error {
+    UnsupportedCollection {
+        collection: uri.split('/').nth(3).unwrap_or_default().to_string(),
+        uri: uri.to_string(),
+    }
+    .into()
+}
+
 fn process_video(vid: &video::Main) -> Result<BskyPostVideo> {
     let blob = extract_video_blob(&vid.video)?;
     let v = BskyPostVideo {
@@ -1292,10 +2384,61 @@ fn process_video(vid: &video::Main) -> Result<BskyPostVideo> {
             media_type: blob.mime_type,
             size: blob.size as u64,
         },
-        captions: None, // TODO implement
+        captions: vid.captions.as_ref().map(|captions| {
+            captions
+                .iter()
+                .filter_map(|caption| match extract_video_blob(&caption.file) {
+                    Ok(blob) => Some(BskyPostVideoCaption {
+                        lang: caption.lang.as_ref().to_string(),
+                        blob: blob.r#ref.0.to_string(),
+                    }),
+                    Err(error) => {
+                        warn!("Skipping video caption with invalid blob: {:?}", error);
+                        None
+                    }
+                })
+                .collect()
+        }),
     };
     Ok(v)
 }
+/// Collect the details of a referenced blob. The id matches `blob_ref_to_record_id`
+fn process_blob(blob: &BlobRef) -> WithId<BskyBlob> {
+    let (cid, media_type, size) = match blob {
+        BlobRef::Typed(atrium_api::types::TypedBlobRef::Blob(b)) => (
+            b.r#ref.0.to_string(),
+            b.mime_type.clone(),
+            Some(b.size as u64),
+        ),
+        BlobRef::Untyped(a) => (a.cid.clone(), a.mime_type.clone(), None),
+    };
+    WithId {
+        id: cid.clone(),
+        data: BskyBlob {
+            cid,
+            media_type,
+            size,
+            first_seen_at: Utc::now(),
+        },
+    }
+}
+
+/// Convert the preview of an external embed and collect its thumbnail blob
+fn process_external(
+    external: &atrium_api::app::bsky::embed::external::External,
+    big_update: &mut BigUpdate,
+) -> BskyPostExternal {
+    if let Some(thumb) = &external.thumb {
+        big_update.blobs.push(process_blob(thumb));
+    }
+    BskyPostExternal {
+        uri: external.uri.clone(),
+        title: external.title.clone(),
+        description: external.description.clone(),
+        thumb: external.thumb.as_ref().map(blob_ref_to_record_id),
+    }
+}
+
 fn extract_video_blob(blob: &BlobRef) -> Result<Blob> {
     match blob {
         atrium_api::types::BlobRef::Typed(a) => match a {
@@ -1305,7 +2448,745 @@ fn extract_video_blob(blob: &BlobRef) -> Result<Blob> {
     }
 }
 
-fn process_extra_data(ipld: &ipld_core::ipld::Ipld) -> Result<Option<String>> {
-    let str = simd_json::serde::to_string(ipld)?;
-    Ok(if str == "{}" { None } else { Some(str) })
+/// Count a record, embed or facet that is ignored because its type is not handled
+///
+/// `collection` is the NSID of the record or the `$type` of the union member
+pub fn record_ignored(collection: &str) {
+    IGNORED_RECORDS_METRIC.add(1, &[KeyValue::new("collection", collection.to_string())]);
+}
+
+/// Take a field that is not part of the lexicon out of the unknown fields of a record
+///
+/// The field is only taken if it has the expected type, otherwise it stays in the extra data
+fn take_extra_field<T: DeserializeOwned>(extra_data: &mut Ipld, field: &str) -> Option<T> {
+    let Ipld::Map(fields) = extra_data else {
+        return None;
+    };
+    let value = fields.remove(field)?;
+    match ipld_core::serde::from_ipld(value.clone()) {
+        Ok(typed) => Some(typed),
+        Err(_) => {
+            fields.insert(field.to_string(), value);
+            None
+        }
+    }
+}
+
+/// Serialize the unknown fields of a record, returns None if there are none
+///
+/// Object keys are sorted, so the same record always produces the same string. Fields that are null count as missing.
+fn process_extra_data(ipld: &Ipld) -> Result<Option<String>> {
+    let mut value = serde_json::to_value(ipld)?;
+    let is_empty = match &value {
+        serde_json::Value::Null => true,
+        serde_json::Value::Object(fields) => fields.values().all(serde_json::Value::is_null),
+        _ => false,
+    };
+    if is_empty {
+        return Ok(None);
+    }
+    value.sort_all_objects();
+    Ok(Some(serde_json::to_string(&value)?))
+}
+
+/// Serialize to JSON with sorted object keys, so equal values are stored as equal strings
+fn canonical_json<T: Serialize>(value: &T) -> Result<String> {
+    let mut value = serde_json::to_value(value)?;
+    value.sort_all_objects();
+    Ok(serde_json::to_string(&value)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn post(id: &str, text: &str) -> WithId<BskyPost> {
+        WithId {
+            id: id.to_string(),
+            data: BskyPost {
+                author: RecordId::from_table_key("did", "plc_author"),
+                bridgy_original_url: None,
+                created_at: DateTime::UNIX_EPOCH,
+                images: None,
+                labels: None,
+                langs: None,
+                links: None,
+                mentions: None,
+                parent: None,
+                record: None,
+                root: None,
+                tags: None,
+                text: text.to_string(),
+                via: None,
+                video: None,
+                external: None,
+                extra_data: None,
+            },
+        }
+    }
+
+    #[test]
+    fn likes_of_unknown_collections_are_skipped() {
+        let record: KnownRecord = serde_json::from_value(serde_json::json!({
+            "$type": "app.bsky.feed.like",
+            "subject": {
+                "uri": "at://did:plc:author/com.example.record/3jzfcijpj2z2a",
+                "cid": "bafkreie5737gdxlw5i64vzichcalba3z2v5n6icifvx5xytvske7mr3hpm",
+            },
+            "createdAt": "2025-01-01T00:00:00.000Z",
+        }))
+        .unwrap();
+        let update = create_big_update(
+            Did::new("did:plc:liker".to_string()).unwrap(),
+            "plc_liker".to_string(),
+            "app.bsky.feed.like".to_string(),
+            RecordKey::new("3jzfcijpj2z2b".to_string()).unwrap(),
+            record,
+        );
+        assert!(update.likes.is_empty());
+        assert_eq!(update.skipped.len(), 1);
+        let (collection, rkey, error) = &update.skipped[0];
+        assert_eq!(collection, "app.bsky.feed.like");
+        assert_eq!(rkey, "3jzfcijpj2z2b");
+        assert!(error.contains("com.example.record"), "{}", error);
+    }
+
+    #[test]
+    fn references_to_the_wrong_table_are_skipped() {
+        let strong_ref = |uri: &str| {
+            serde_json::json!({
+                "uri": uri,
+                "cid": "bafkreie5737gdxlw5i64vzichcalba3z2v5n6icifvx5xytvske7mr3hpm",
+            })
+        };
+        let list = "at://did:plc:author/app.bsky.graph.list/3jzfcijpj2z2a";
+        let like = "at://did:plc:author/app.bsky.feed.like/3jzfcijpj2z2a";
+        let post = "at://did:plc:author/app.bsky.feed.post/3jzfcijpj2z2a";
+        for (collection, record) in [
+            (
+                "app.bsky.feed.repost",
+                serde_json::json!({
+                    "$type": "app.bsky.feed.repost",
+                    "subject": strong_ref(list),
+                    "createdAt": "2025-01-01T00:00:00.000Z",
+                }),
+            ),
+            (
+                "app.bsky.feed.post",
+                serde_json::json!({
+                    "$type": "app.bsky.feed.post",
+                    "text": "quoting a like",
+                    "embed": {
+                        "$type": "app.bsky.embed.record",
+                        "record": strong_ref(like),
+                    },
+                    "createdAt": "2025-01-01T00:00:00.000Z",
+                }),
+            ),
+            (
+                "app.bsky.feed.threadgate",
+                serde_json::json!({
+                    "$type": "app.bsky.feed.threadgate",
+                    "post": list,
+                    "createdAt": "2025-01-01T00:00:00.000Z",
+                }),
+            ),
+            (
+                "app.bsky.feed.threadgate",
+                serde_json::json!({
+                    "$type": "app.bsky.feed.threadgate",
+                    "post": post,
+                    "allow": [{ "$type": "app.bsky.feed.threadgate#listRule", "list": post }],
+                    "createdAt": "2025-01-01T00:00:00.000Z",
+                }),
+            ),
+        ] {
+            let update = create_big_update(
+                Did::new("did:plc:author".to_string()).unwrap(),
+                "plc_author".to_string(),
+                collection.to_string(),
+                RecordKey::new("3jzfcijpj2z2b".to_string()).unwrap(),
+                serde_json::from_value(record).unwrap(),
+            );
+            assert!(update.reposts.is_empty());
+            assert!(update.posts.is_empty());
+            assert!(update.quotes.is_empty());
+            assert!(update.threadgates.is_empty());
+            assert_eq!(update.skipped.len(), 1, "{}", collection);
+            let error = &update.skipped[0].2;
+            assert!(error.contains("Unsupported collection"), "{}", error);
+        }
+    }
+
+    fn ids<R: Serialize>(rows: &[WithId<R>]) -> Vec<&str> {
+        rows.iter().map(|row| row.id.as_str()).collect()
+    }
+
+    #[test]
+    fn dedup_by_id_keeps_the_last_occurrence() {
+        let mut rows = vec![
+            post("a", "first"),
+            post("b", "other"),
+            post("a", "second"),
+            post("c", "other"),
+            post("a", "third"),
+        ];
+        dedup_by_id(&mut rows);
+        assert_eq!(ids(&rows), ["b", "c", "a"]);
+        assert_eq!(rows[2].data.text, "third");
+    }
+
+    #[test]
+    fn dedup_keeps_the_last_version_of_a_post() {
+        let mut update = BigUpdate::default();
+        update
+            .posts
+            .push(post("3abc_plc_author", "before the edit"));
+        update.posts.push(post("3abc_plc_author", "after the edit"));
+        update.delete_posts = vec!["b".to_string(), "a".to_string(), "b".to_string()];
+
+        update.dedup();
+
+        assert_eq!(update.posts.len(), 1);
+        assert_eq!(update.posts[0].data.text, "after the edit");
+        assert_eq!(update.delete_posts, ["a", "b"]);
+    }
+
+    #[test]
+    fn merging_an_overlapping_follow_yields_one_row() {
+        let follow = || WithId {
+            id: "3abc_plc_author".to_string(),
+            data: BskyFollow {
+                from: RecordId::from_table_key("did", "plc_author"),
+                to: RecordId::from_table_key("did", "plc_friend"),
+                created_at: DateTime::UNIX_EPOCH,
+            },
+        };
+        let mut update = BigUpdate {
+            follows: vec![follow()],
+            ..Default::default()
+        };
+        update.merge(BigUpdate {
+            follows: vec![follow()],
+            ..Default::default()
+        });
+        update.dedup();
+
+        assert_eq!(ids(&update.follows), ["3abc_plc_author"]);
+        assert_eq!(BigUpdateInfo::new(&update).follows.count, 1);
+    }
+
+    #[test]
+    fn merge_runs_the_delete_of_a_recreated_record_first() {
+        let mut update = BigUpdate::default();
+        update.posts.push(post("3abc_plc_author", "original"));
+        update.merge(BigUpdate {
+            delete_posts: vec!["3abc_plc_author".to_string()],
+            ..Default::default()
+        });
+        assert_eq!(update.delete_posts, ["3abc_plc_author"]);
+
+        update.merge(BigUpdate {
+            posts: vec![post("3abc_plc_author", "recreated")],
+            ..Default::default()
+        });
+        update.dedup();
+
+        assert!(update.delete_posts.is_empty());
+        assert_eq!(update.replaced, [("post", "3abc_plc_author".to_string())]);
+        assert_eq!(update.posts.len(), 1);
+        assert_eq!(update.posts[0].data.text, "recreated");
+    }
+
+    #[test]
+    fn accumulated_rows_become_stale_after_the_max_age() {
+        let mut accumulator = Accumulator::default();
+        assert!(!accumulator.is_stale(Duration::ZERO));
+
+        accumulator.add(
+            BigUpdate {
+                posts: vec![post("3abc_plc_author", "waiting")],
+                ..Default::default()
+            },
+            1,
+        );
+        assert!(accumulator.is_stale(Duration::ZERO));
+        assert!(!accumulator.is_stale(Duration::from_secs(3600)));
+
+        let update = accumulator.take();
+        assert_eq!(update.posts.len(), 1);
+        assert_eq!(accumulator.rows, 0);
+        assert!(!accumulator.is_stale(Duration::ZERO));
+    }
+
+    #[tokio::test]
+    async fn cursors_wait_for_running_flushes() {
+        // Held by a flush that took rows out of the accumulator and is still applying them
+        let flush = ACCUMULATOR_FLUSHES.read().await;
+        let cursor = tokio::spawn(wait_for_running_flushes());
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!cursor.is_finished());
+
+        drop(flush);
+        tokio::time::timeout(Duration::from_secs(5), cursor)
+            .await
+            .expect("the cursor is written once the flush is done")
+            .unwrap();
+    }
+
+    #[test]
+    fn serialization_failures_are_retried() {
+        use crate::database::error::testing::database_error;
+
+        let state = failed_apply_state(database_error("40001").into());
+        assert!(matches!(state, Ok(UpdateState::Retry)), "{:?}", state);
+        let state = failed_apply_state(database_error("40P01").into());
+        assert!(matches!(state, Ok(UpdateState::Retry)), "{:?}", state);
+        let state = failed_apply_state(database_error("23505").into());
+        assert!(
+            matches!(state, Err(DbError::ConstraintViolation(_))),
+            "{:?}",
+            state
+        );
+    }
+
+    #[test]
+    fn transaction_ticket_instruments_have_distinct_names() {
+        // instrument_name panics in debug builds if the second gauge reuses the name of the first
+        LazyLock::force(&TRANSACTION_TICKETS_COST_METRIC);
+        LazyLock::force(&TRANSACTION_TICKETS_AVAILABLE_METRIC);
+    }
+
+    /// Held by tests that use the accumulator, which is shared by all tests and their databases
+    static ACCUMULATOR_TEST_LOCK: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+    /// Run `test` with a pool for `options` on a multi-threaded runtime
+    ///
+    /// `#[sqlx::test]` runs on a current thread runtime, but applying accumulated rows needs `block_in_place`
+    fn on_multi_thread_runtime<F, Fut>(
+        options: sqlx::postgres::PgConnectOptions,
+        test: F,
+    ) -> anyhow::Result<()>
+    where
+        F: FnOnce(PgPool) -> Fut + Send + 'static,
+        Fut: std::future::Future<Output = anyhow::Result<()>>,
+    {
+        std::thread::spawn(move || {
+            tokio::runtime::Builder::new_multi_thread()
+                .enable_all()
+                .build()?
+                .block_on(async move {
+                    let _lock = ACCUMULATOR_TEST_LOCK.lock().await;
+                    test(PgPool::connect_with(options).await?).await
+                })
+        })
+        .join()
+        .unwrap()
+    }
+
+    #[sqlx::test]
+    #[ignore = "needs a postgres server in DATABASE_URL"]
+    async fn shutdown_flushes_a_pending_small_update(
+        _: sqlx::postgres::PgPoolOptions,
+        options: sqlx::postgres::PgConnectOptions,
+    ) -> anyhow::Result<()> {
+        on_multi_thread_runtime(options, |db| async move {
+            let count = || sqlx::query_scalar::<_, i64>("SELECT count(*) FROM post").fetch_one(&db);
+            let update = BigUpdate {
+                posts: vec![post("3abc_plc_author", "waiting for the flush")],
+                ..Default::default()
+            };
+            update.apply(db.clone(), "test").await?;
+            assert_eq!(count().await?, 0, "small updates wait in the accumulator");
+
+            flush_small_updates(db.clone()).await?;
+            assert_eq!(count().await?, 1);
+            assert_eq!(accumulated_rows().await, 0);
+            Ok(())
+        })
+    }
+
+    #[sqlx::test]
+    #[ignore = "needs a postgres server in DATABASE_URL"]
+    async fn purged_accounts_are_backfilled_again_after_reactivation(
+        _: sqlx::postgres::PgPoolOptions,
+        options: sqlx::postgres::PgConnectOptions,
+    ) -> anyhow::Result<()> {
+        on_multi_thread_runtime(options, |db| async move {
+            let account = |active: bool, status: Option<&str>| Account {
+                active,
+                did: Did::new("did:plc:author".to_string()).unwrap(),
+                seq: 1,
+                time: "2025-01-01T00:00:00.000Z".to_string(),
+                status: status.map(str::to_string),
+            };
+            let backfill = || {
+                sqlx::query_as::<_, (Option<DateTime<Utc>>, Option<String>)>(
+                    "SELECT at, unbackfillable_reason FROM latest_backfill WHERE of_did_id = 'plc_author'",
+                )
+                .fetch_one(&db)
+            };
+            sqlx::query(
+                "INSERT INTO latest_backfill (id, of_did_id, at) VALUES ('plc_author', 'plc_author', now())",
+            )
+            .execute(&db)
+            .await?;
+            BigUpdate {
+                posts: vec![post("3abc_plc_author", "still in the accumulator")],
+                ..Default::default()
+            }
+            .apply(db.clone(), "test")
+            .await?;
+
+            apply_account_event(
+                &db,
+                "plc_author".to_string(),
+                1,
+                account(false, Some("deleted")),
+            )
+            .await?;
+            // The accumulated post was written before the purge, so it is gone as well
+            assert_eq!(accumulated_rows().await, 0);
+            let posts: i64 = sqlx::query_scalar("SELECT count(*) FROM post")
+                .fetch_one(&db)
+                .await?;
+            assert_eq!(posts, 0);
+            let (at, reason) = backfill().await?;
+            assert!(at.is_some());
+            assert_eq!(reason.as_deref(), Some("deleted"));
+
+            apply_account_event(&db, "plc_author".to_string(), 2, account(true, None)).await?;
+            assert_eq!(backfill().await?, (None, None));
+            Ok(())
+        })
+    }
+
+    /// Receives the messages of all updates applied by tests
+    static PUBLISHED: std::sync::Mutex<Vec<crate::event_sink::Message>> =
+        std::sync::Mutex::new(Vec::new());
+
+    struct CaptureSink;
+
+    impl crate::event_sink::EventSink for CaptureSink {
+        fn publish(&self, message: crate::event_sink::Message) {
+            PUBLISHED.lock().unwrap().push(message);
+        }
+    }
+
+    #[sqlx::test]
+    #[ignore = "needs a postgres server in DATABASE_URL"]
+    async fn applied_follows_are_published(db: PgPool) -> anyhow::Result<()> {
+        crate::event_sink::set_event_sink("indexer".to_string(), CaptureSink)?;
+        let mut update = BigUpdate::default();
+        update.follows.push(WithId {
+            id: "3lfollow_plc_follower".to_string(),
+            data: BskyFollow {
+                from: RecordId::from_table_key("did", "plc_follower"),
+                to: RecordId::from_table_key("did", "plc_followed"),
+                created_at: DateTime::UNIX_EPOCH,
+            },
+        });
+        update
+            .delete_follows
+            .push("3lunfollowed_plc_follower".to_string());
+
+        update.actually_attempt_apply(db).await?;
+
+        // Other tests that run at the same time publish their follows to the same sink
+        let published = PUBLISHED.lock().unwrap();
+        let rows_of = |subject: &str, id: &str| {
+            published
+                .iter()
+                .filter(|message| message.subject == subject)
+                .map(|message| {
+                    serde_json::from_slice::<Vec<serde_json::Value>>(&message.payload).unwrap()
+                })
+                .find(|rows| rows.iter().any(|row| row == id || row["id"] == id))
+                .unwrap_or_else(|| panic!("{} was not published to {}", id, subject))
+        };
+        let rows = rows_of("indexer.follow", "3lfollow_plc_follower");
+        assert_eq!(rows.len(), 1);
+        let deleted = rows_of("indexer.follow.deleted", "3lunfollowed_plc_follower");
+        assert_eq!(deleted, vec!["3lunfollowed_plc_follower"]);
+        Ok(())
+    }
+
+    #[sqlx::test]
+    #[ignore = "needs a postgres server in DATABASE_URL"]
+    async fn deleting_a_follow_removes_the_row(db: PgPool) -> anyhow::Result<()> {
+        let did = Did::new("did:plc:follower".to_string()).unwrap();
+        let rkey = RecordKey::new("3lfollow".to_string()).unwrap();
+        let record = KnownRecord::AppBskyGraphFollow(Box::new(
+            atrium_api::app::bsky::graph::follow::RecordData {
+                created_at: atrium_api::types::string::Datetime::new(
+                    DateTime::UNIX_EPOCH.fixed_offset(),
+                ),
+                subject: Did::new("did:plc:followed".to_string()).unwrap(),
+            }
+            .into(),
+        ));
+        let count = || {
+            sqlx::query_scalar::<_, i64>("SELECT count(*) FROM follow WHERE id = $1")
+                .bind("3lfollow_plc_follower")
+                .fetch_one(&db)
+        };
+
+        let update = create_big_update(
+            did.clone(),
+            "plc_follower".to_string(),
+            "app.bsky.graph.follow".to_string(),
+            rkey.clone(),
+            record,
+        );
+        update.actually_attempt_apply(db.clone()).await?;
+        assert_eq!(count().await?, 1);
+
+        let delete = create_big_delete(
+            did,
+            "plc_follower".to_string(),
+            "app.bsky.graph.follow".to_string(),
+            rkey,
+        );
+        delete.actually_attempt_apply(db.clone()).await?;
+        assert_eq!(count().await?, 0);
+        Ok(())
+    }
+
+    #[sqlx::test]
+    #[ignore = "needs a postgres server in DATABASE_URL"]
+    async fn every_post_gets_its_relations_once(db: PgPool) -> anyhow::Result<()> {
+        let root = serde_json::json!({
+            "uri": "at://did:plc:author/app.bsky.feed.post/3jzfcijpj2z2a",
+            "cid": "bafkreie5737gdxlw5i64vzichcalba3z2v5n6icifvx5xytvske7mr3hpm",
+        });
+        let repo = || {
+            [
+                (
+                    "3jzfcijpj2z2a",
+                    serde_json::json!({
+                        "$type": "app.bsky.feed.post",
+                        "text": "root",
+                        "createdAt": "2025-01-01T00:00:00.000Z",
+                    }),
+                ),
+                (
+                    "3jzfcijpj2z2b",
+                    serde_json::json!({
+                        "$type": "app.bsky.feed.post",
+                        "text": "reply",
+                        "reply": { "root": root, "parent": root },
+                        "createdAt": "2025-01-01T00:00:01.000Z",
+                    }),
+                ),
+                (
+                    "3jzfcijpj2z2c",
+                    serde_json::json!({
+                        "$type": "app.bsky.feed.post",
+                        "text": "quote",
+                        "embed": { "$type": "app.bsky.embed.record", "record": root },
+                        "createdAt": "2025-01-01T00:00:02.000Z",
+                    }),
+                ),
+            ]
+            .into_iter()
+            .fold(BigUpdate::default(), |mut update, (rkey, record)| {
+                update.merge(create_big_update(
+                    Did::new("did:plc:author".to_string()).unwrap(),
+                    "plc_author".to_string(),
+                    "app.bsky.feed.post".to_string(),
+                    RecordKey::new(rkey.to_string()).unwrap(),
+                    serde_json::from_value(record).unwrap(),
+                ));
+                update
+            })
+        };
+        // Indexing the same repo again must not duplicate any relation
+        repo().actually_attempt_apply(db.clone()).await?;
+        repo().actually_attempt_apply(db.clone()).await?;
+
+        let rows =
+            |query: &'static str| sqlx::query_as::<_, (String, String)>(query).fetch_all(&db);
+        assert_eq!(
+            rows("SELECT did_id, post_id FROM posts_relation ORDER BY post_id").await?,
+            [
+                ("plc_author".into(), "3jzfcijpj2z2a_plc_author".into()),
+                ("plc_author".into(), "3jzfcijpj2z2b_plc_author".into()),
+                ("plc_author".into(), "3jzfcijpj2z2c_plc_author".into()),
+            ]
+        );
+        assert_eq!(
+            rows("SELECT did_id, post_id FROM replies_relation").await?,
+            [("plc_author".into(), "3jzfcijpj2z2b_plc_author".into())]
+        );
+        assert_eq!(
+            rows("SELECT source_post_id, target_post_id FROM replyto_relation").await?,
+            [(
+                "3jzfcijpj2z2b_plc_author".into(),
+                "3jzfcijpj2z2a_plc_author".into()
+            )]
+        );
+        assert_eq!(
+            rows("SELECT source_post_id, target_post_id FROM quotes_relation").await?,
+            [(
+                "3jzfcijpj2z2c_plc_author".into(),
+                "3jzfcijpj2z2a_plc_author".into()
+            )]
+        );
+        Ok(())
+    }
+
+    #[sqlx::test]
+    #[ignore = "needs a postgres server in DATABASE_URL"]
+    async fn feed_generators_store_their_avatar(db: PgPool) -> anyhow::Result<()> {
+        let cid = "bafkreie5737gdxlw5i64vzichcalba3z2v5n6icifvx5xytvske7mr3hpm";
+        let record = KnownRecord::AppBskyFeedGenerator(Box::new(
+            atrium_api::app::bsky::feed::generator::RecordData {
+                accepts_interactions: None,
+                avatar: Some(BlobRef::Typed(atrium_api::types::TypedBlobRef::Blob(
+                    Blob {
+                        r#ref: atrium_api::types::CidLink(cid.parse()?),
+                        mime_type: "image/jpeg".to_string(),
+                        size: 1234,
+                    },
+                ))),
+                content_mode: None,
+                created_at: atrium_api::types::string::Datetime::new(
+                    DateTime::UNIX_EPOCH.fixed_offset(),
+                ),
+                description: None,
+                description_facets: None,
+                did: Did::new("did:web:feeds.example.com".to_string()).unwrap(),
+                display_name: "Example".to_string(),
+                labels: None,
+            }
+            .into(),
+        ));
+        let update = create_big_update(
+            Did::new("did:plc:author".to_string()).unwrap(),
+            "plc_author".to_string(),
+            "app.bsky.feed.generator".to_string(),
+            RecordKey::new("example".to_string()).unwrap(),
+            record,
+        );
+        update.actually_attempt_apply(db.clone()).await?;
+
+        let avatar: Option<String> =
+            sqlx::query_scalar("SELECT avatar FROM feed WHERE id = 'example_plc_author'")
+                .fetch_one(&db)
+                .await?;
+        assert_eq!(avatar.as_deref(), Some(cid));
+        let blobs: i64 = sqlx::query_scalar("SELECT count(*) FROM blob WHERE id = $1")
+            .bind(cid)
+            .fetch_one(&db)
+            .await?;
+        assert_eq!(blobs, 1);
+        Ok(())
+    }
+
+    fn map<const N: usize>(fields: [(&str, Ipld); N]) -> Ipld {
+        Ipld::Map(
+            fields
+                .into_iter()
+                .map(|(key, value)| (key.to_string(), value))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn extra_fields_are_only_taken_with_the_expected_type() {
+        let mut extra_data = map([
+            ("via", Ipld::String("Bridgy Fed".to_string())),
+            ("bridgyOriginalUrl", Ipld::Integer(1)),
+            ("other", Ipld::Bool(true)),
+        ]);
+        assert_eq!(
+            take_extra_field::<String>(&mut extra_data, "via").as_deref(),
+            Some("Bridgy Fed")
+        );
+        assert_eq!(
+            take_extra_field::<String>(&mut extra_data, "bridgyOriginalUrl"),
+            None
+        );
+        assert_eq!(take_extra_field::<i64>(&mut extra_data, "missing"), None);
+        assert_eq!(
+            extra_data,
+            map([
+                ("bridgyOriginalUrl", Ipld::Integer(1)),
+                ("other", Ipld::Bool(true))
+            ])
+        );
+        assert_eq!(take_extra_field::<bool>(&mut Ipld::Null, "other"), None);
+    }
+
+    #[test]
+    fn bridged_posts_keep_via_and_their_original_url() {
+        let record = serde_json::json!({
+            "$type": "app.bsky.feed.post",
+            "text": "bridged",
+            "via": "Bridgy Fed",
+            "bridgyOriginalUrl": "https://example.com/notes/1",
+            "createdAt": "2025-01-01T00:00:00.000Z",
+        });
+        let update = create_big_update(
+            Did::new("did:plc:author".to_string()).unwrap(),
+            "plc_author".to_string(),
+            "app.bsky.feed.post".to_string(),
+            RecordKey::new("3jzfcijpj2z2a".to_string()).unwrap(),
+            serde_json::from_value(record).unwrap(),
+        );
+        let post = &update.posts[0].data;
+        assert_eq!(post.via.as_deref(), Some("Bridgy Fed"));
+        assert_eq!(
+            post.bridgy_original_url.as_deref(),
+            Some("https://example.com/notes/1")
+        );
+        assert_eq!(post.extra_data, None);
+    }
+
+    #[test]
+    fn extra_data_does_not_depend_on_the_field_order() {
+        let a = map([
+            ("zeta", Ipld::Integer(1)),
+            ("alpha", map([("y", Ipld::Bool(true)), ("b", Ipld::Null)])),
+        ]);
+        let b = map([
+            ("alpha", map([("b", Ipld::Null), ("y", Ipld::Bool(true))])),
+            ("zeta", Ipld::Integer(1)),
+        ]);
+        let extra_data = process_extra_data(&a).unwrap();
+        assert_eq!(extra_data, process_extra_data(&b).unwrap());
+        assert_eq!(
+            extra_data.as_deref(),
+            Some(r#"{"alpha":{"b":null,"y":true},"zeta":1}"#)
+        );
+    }
+
+    #[test]
+    fn label_value_definitions_are_stored_with_sorted_keys() {
+        let definition = atrium_api::types::Object {
+            data: atrium_api::com::atproto::label::defs::LabelValueDefinitionData {
+                adult_only: None,
+                blurs: "none".to_string(),
+                default_setting: None,
+                identifier: "spam".to_string(),
+                locales: Vec::new(),
+                severity: "alert".to_string(),
+            },
+            extra_data: map([("zeta", Ipld::Integer(1)), ("alpha", Ipld::Bool(true))]),
+        };
+        assert_eq!(
+            canonical_json(&vec![definition]).unwrap(),
+            r#"[{"alpha":true,"blurs":"none","identifier":"spam","locales":[],"severity":"alert","zeta":1}]"#
+        );
+    }
+
+    #[test]
+    fn extra_data_without_values_is_none() {
+        assert_eq!(process_extra_data(&Ipld::Null).unwrap(), None);
+        assert_eq!(process_extra_data(&map([])).unwrap(), None);
+        assert_eq!(
+            process_extra_data(&map([("a", Ipld::Null), ("b", Ipld::Null)])).unwrap(),
+            None
+        );
+    }
 }
@@ -0,0 +1,117 @@
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use sqlx::PgTransaction;
+
+/// Microseconds between the unix epoch and the postgres epoch (2000-01-01)
+const POSTGRES_EPOCH_MICROS: i64 = 946_684_800_000_000;
+
+/// Encoder for the binary COPY format
+///
+/// https://www.postgresql.org/docs/current/sql-copy.html#id-1.9.3.55.9.4
+pub struct CopyWriter {
+    buffer: Vec<u8>,
+}
+
+impl CopyWriter {
+    pub fn new() -> CopyWriter {
+        let mut buffer = Vec::new();
+        buffer.extend_from_slice(b"PGCOPY\n\xff\r\n\0");
+        // Flags and header extension length
+        buffer.extend_from_slice(&0i32.to_be_bytes());
+        buffer.extend_from_slice(&0i32.to_be_bytes());
+        CopyWriter { buffer }
+    }
+
+    /// Start a new row with `fields` fields
+    pub fn row(&mut self, fields: i16) {
+        self.buffer.extend_from_slice(&fields.to_be_bytes());
+    }
+
+    fn field(&mut self, value: Option<&[u8]>) {
+        match value {
+            Some(value) => {
+                self.buffer
+                    .extend_from_slice(&(value.len() as i32).to_be_bytes());
+                self.buffer.extend_from_slice(value);
+            }
+            None => self.buffer.extend_from_slice(&(-1i32).to_be_bytes()),
+        }
+    }
+
+    /// Write a TEXT field, enums use the same encoding
+    pub fn text(&mut self, value: &str) {
+        self.field(Some(value.as_bytes()));
+    }
+
+    pub fn nullable_text(&mut self, value: Option<&str>) {
+        self.field(value.map(str::as_bytes));
+    }
+
+    /// Write a TIMESTAMP or TIMESTAMPTZ field
+    pub fn timestamp(&mut self, value: DateTime<Utc>) {
+        self.nullable_timestamp(Some(value));
+    }
+
+    pub fn nullable_timestamp(&mut self, value: Option<DateTime<Utc>>) {
+        let micros =
+            value.map(|value| (value.timestamp_micros() - POSTGRES_EPOCH_MICROS).to_be_bytes());
+        self.field(micros.as_ref().map(|micros| &micros[..]));
+    }
+
+    /// Write a JSONB field
+    pub fn jsonb(&mut self, value: &serde_json::Value) {
+        // JSONB is prefixed with a version byte
+        let mut jsonb = vec![1];
+        serde_json::to_writer(&mut jsonb, value).unwrap();
+        self.field(Some(&jsonb));
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        self.buffer.extend_from_slice(&(-1i16).to_be_bytes());
+        self.buffer
+    }
+}
+
+/// Insert rows with a binary COPY
+///
+/// The rows are copied into a temporary table first, so existing rows can be skipped like in the UNNEST path.
+/// They are inserted ordered by the first column, so concurrent inserts lock conflicting rows in the same order.
+pub async fn copy_insert(
+    table: &str,
+    columns: &[&str],
+    rows: CopyWriter,
+    database: &mut PgTransaction<'_>,
+) -> Result<u64> {
+    let columns = columns
+        .iter()
+        .map(|column| format!("\"{}\"", column))
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    sqlx::query(&format!(
+        "CREATE TEMPORARY TABLE \"copy_{table}\" (LIKE \"{table}\") ON COMMIT DROP"
+    ))
+    .execute(&mut **database)
+    .await?;
+
+    let mut copy = database
+        .copy_in_raw(&format!(
+            "COPY \"copy_{table}\" ({columns}) FROM STDIN (FORMAT BINARY)"
+        ))
+        .await?;
+    copy.send(rows.finish()).await?;
+    copy.finish().await?;
+
+    let rows_affected = sqlx::query(&format!(
+        "INSERT INTO \"{table}\" ({columns}) SELECT {columns} FROM \"copy_{table}\" ORDER BY 1 ON CONFLICT DO NOTHING"
+    ))
+    .execute(&mut **database)
+    .await?
+    .rows_affected();
+
+    sqlx::query(&format!("DROP TABLE \"copy_{table}\""))
+        .execute(&mut **database)
+        .await?;
+
+    Ok(rows_affected)
+}
@@ -3,24 +3,25 @@ use opentelemetry::{global, KeyValue};
 use std::sync::LazyLock;
 
 use super::BigUpdate;
+use crate::observability::instrument_name;
 
 static INSERTED_ROWS_METRIC: LazyLock<Counter<u64>> = LazyLock::new(|| {
     global::meter("indexer")
-        .u64_counter("indexer.database.inserted_elements")
+        .u64_counter(instrument_name("indexer.database.inserted_elements"))
         .with_unit("{row}")
         .with_description("Inserted or updated rows")
         .build()
 });
 static INSERTED_SIZE_METRIC: LazyLock<Counter<u64>> = LazyLock::new(|| {
     global::meter("indexer")
-        .u64_counter("indexer.database.inserted_bytes")
+        .u64_counter(instrument_name("indexer.database.inserted_bytes"))
         .with_unit("By")
         .with_description("Inserted or updated bytes (approximation)")
         .build()
 });
 static TRANSACTIONS_METRIC: LazyLock<Counter<u64>> = LazyLock::new(|| {
     global::meter("indexer")
-        .u64_counter("indexer.database.transactions")
+        .u64_counter(instrument_name("indexer.database.transactions"))
         .with_unit("{transaction}")
         .with_description("Number of transactions")
         .build()
@@ -56,12 +57,15 @@ pub(super) struct BigUpdateInfo {
     pub(super) postgates: BigUpdateInfoRow,
     pub(super) actordeclarations: BigUpdateInfoRow,
     pub(super) labelerservices: BigUpdateInfoRow,
+    pub(super) blobs: BigUpdateInfoRow,
     pub(super) quotes: BigUpdateInfoRow,
     pub(super) posts: BigUpdateInfoRow,
     pub(super) replies_relations: BigUpdateInfoRow,
     pub(super) reply_to_relations: BigUpdateInfoRow,
     pub(super) posts_relations: BigUpdateInfoRow,
     pub(super) overwrite_latest_backfills: BigUpdateInfoRow,
+    // Info about deleted records of all tables
+    pub(super) deletes: BigUpdateInfoRow,
 }
 
 impl BigUpdateInfo {
@@ -187,6 +191,14 @@ impl BigUpdateInfo {
                     .map(|e| serde_ipld_dagcbor::to_vec(e).unwrap().len() as u64)
                     .sum(),
             },
+            blobs: BigUpdateInfoRow {
+                count: update.blobs.len() as u64,
+                size: update
+                    .blobs
+                    .iter()
+                    .map(|e| serde_ipld_dagcbor::to_vec(e).unwrap().len() as u64)
+                    .sum(),
+            },
             quotes: BigUpdateInfoRow {
                 count: update.quotes.len() as u64,
                 size: update
@@ -235,6 +247,10 @@ impl BigUpdateInfo {
                     .map(|e| serde_ipld_dagcbor::to_vec(e).unwrap().len() as u64)
                     .sum(),
             },
+            deletes: BigUpdateInfoRow {
+                count: update.deleted_ids().count() as u64,
+                size: update.deleted_ids().map(|id| id.len() as u64).sum(),
+            },
         }
     }
     pub fn all_relations(&self) -> BigUpdateInfoRow {
@@ -271,6 +287,7 @@ impl BigUpdateInfo {
                 + self.postgates.count
                 + self.actordeclarations.count
                 + self.labelerservices.count
+                + self.blobs.count
                 + self.posts.count,
             size: self.did.size
                 + self.feeds.size
@@ -280,23 +297,30 @@ impl BigUpdateInfo {
                 + self.postgates.size
                 + self.actordeclarations.size
                 + self.labelerservices.size
+                + self.blobs.size
                 + self.posts.size,
         }
     }
-    pub fn all(&self) -> BigUpdateInfoRow {
+    pub fn all_inserts(&self) -> BigUpdateInfoRow {
         BigUpdateInfoRow {
             count: self.all_relations().count + self.all_tables().count,
             size: self.all_relations().size + self.all_tables().size,
         }
     }
+    pub fn all(&self) -> BigUpdateInfoRow {
+        BigUpdateInfoRow {
+            count: self.all_inserts().count + self.deletes.count,
+            size: self.all_inserts().size + self.deletes.size,
+        }
+    }
 
     pub fn record_metrics(&self, source: &str) {
         INSERTED_ROWS_METRIC.add(
-            self.all().count,
+            self.all_inserts().count,
             &[KeyValue::new("source", source.to_string())],
         );
         INSERTED_SIZE_METRIC.add(
-            self.all().size,
+            self.all_inserts().size,
             &[KeyValue::new("source", source.to_string())],
         );
         TRANSACTIONS_METRIC.add(1, &[]);
@@ -321,6 +345,7 @@ impl core::fmt::Debug for BigUpdateInfo {
             .entry(&"postgates", &self.postgates)
             .entry(&"actordeclarations", &self.actordeclarations)
             .entry(&"labelerservices", &self.labelerservices)
+            .entry(&"blobs", &self.blobs)
             .entry(&"quotes", &self.quotes)
             .entry(&"posts", &self.posts)
             .entry(&"replies_relations", &self.replies_relations)
@@ -330,6 +355,7 @@ impl core::fmt::Debug for BigUpdateInfo {
                 &"overwrite_latest_backfills",
                 &self.overwrite_latest_backfills,
             )
+            .entry(&"deletes", &self.deletes)
             .finish()
     }
 }
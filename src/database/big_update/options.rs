@@ -0,0 +1,206 @@
+use clap::ValueEnum;
+use std::sync::OnceLock;
+
+/// A group of collections that can be selected with `--index-record-types`
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RecordType {
+    Profiles,
+    Posts,
+    Likes,
+    Reposts,
+    Follows,
+    Blocks,
+    /// Lists, their items and blocks of lists
+    Lists,
+    Feeds,
+    Starterpacks,
+    Threadgates,
+    Postgates,
+    Labelers,
+    ChatDeclarations,
+}
+
+impl RecordType {
+    /// The NSIDs of the collections of this record type
+    pub fn collections(self) -> &'static [&'static str] {
+        match self {
+            RecordType::Profiles => &["app.bsky.actor.profile"],
+            RecordType::Posts => &["app.bsky.feed.post"],
+            RecordType::Likes => &["app.bsky.feed.like"],
+            RecordType::Reposts => &["app.bsky.feed.repost"],
+            RecordType::Follows => &["app.bsky.graph.follow"],
+            RecordType::Blocks => &["app.bsky.graph.block"],
+            RecordType::Lists => &[
+                "app.bsky.graph.list",
+                "app.bsky.graph.listitem",
+                "app.bsky.graph.listblock",
+            ],
+            RecordType::Feeds => &["app.bsky.feed.generator"],
+            RecordType::Starterpacks => &["app.bsky.graph.starterpack"],
+            RecordType::Threadgates => &["app.bsky.feed.threadgate"],
+            RecordType::Postgates => &["app.bsky.feed.postgate"],
+            RecordType::Labelers => &["app.bsky.labeler.service"],
+            RecordType::ChatDeclarations => &["chat.bsky.actor.declaration"],
+        }
+    }
+}
+
+/// Settings for converting records and applying updates
+///
+/// The accumulator and the transaction limits are shared by the whole process, so the options are set once with
+/// `configure` instead of being passed to every call. The defaults match the defaults of the command line.
+#[derive(Debug, Clone)]
+pub struct ApplyOptions {
+    /// Smaller updates are collected in the accumulator until it has this many rows
+    pub min_rows_per_transaction: usize,
+    /// Apply accumulated updates after they waited this many seconds
+    pub max_accumulator_age_secs: u64,
+    pub min_concurrent_transactions: u32,
+    pub max_concurrent_transactions: u32,
+    /// Give up on an update after this many retries
+    pub max_retries: u32,
+    /// Insert large batches of follows, likes, reposts and posts with COPY instead of UNNEST
+    pub use_copy_inserts: bool,
+    /// Update the status columns of the did table from account events
+    pub track_account_status: bool,
+    /// Only index these record types, all record types are indexed if empty
+    pub record_types: Vec<RecordType>,
+    /// Only index posts in these languages, all posts are indexed if empty
+    pub post_language_allowlist: Vec<String>,
+    /// Index posts that do not declare a language when the allowlist is set
+    pub index_posts_without_language: bool,
+}
+
+impl Default for ApplyOptions {
+    fn default() -> Self {
+        ApplyOptions {
+            min_rows_per_transaction: 1000,
+            max_accumulator_age_secs: 10,
+            min_concurrent_transactions: 1,
+            max_concurrent_transactions: 1,
+            max_retries: 100,
+            use_copy_inserts: false,
+            track_account_status: true,
+            record_types: Vec::new(),
+            post_language_allowlist: Vec::new(),
+            index_posts_without_language: true,
+        }
+    }
+}
+
+impl ApplyOptions {
+    /// Check if records of a collection are indexed
+    pub fn indexes_collection(&self, collection: &str) -> bool {
+        self.record_types.is_empty()
+            || self
+                .record_types
+                .iter()
+                .any(|record_type| record_type.collections().contains(&collection))
+    }
+
+    /// Check a post against the language allowlist, returns why the post should not be indexed
+    pub fn post_language_skip_reason(&self, langs: Option<&[String]>) -> Option<&'static str> {
+        if self.post_language_allowlist.is_empty() {
+            return None;
+        }
+        let langs = langs.unwrap_or_default();
+        if langs.is_empty() {
+            return (!self.index_posts_without_language).then_some("no_language");
+        }
+        let allowed = langs.iter().any(|lang| {
+            let primary = lang.split('-').next().unwrap_or_default();
+            self.post_language_allowlist.iter().any(|allowed| {
+                allowed.eq_ignore_ascii_case(lang) || allowed.eq_ignore_ascii_case(primary)
+            })
+        });
+        (!allowed).then_some("language")
+    }
+}
+
+static OPTIONS: OnceLock<ApplyOptions> = OnceLock::new();
+
+#[cfg(test)]
+thread_local! {
+    /// Overrides the options on a test thread, see [testing::with_options]
+    static TEST_OPTIONS: std::cell::Cell<Option<&'static ApplyOptions>> =
+        const { std::cell::Cell::new(None) };
+}
+
+/// Set the options for all updates of this process
+///
+/// Has to be called before the first update is converted or applied, later calls fail
+pub fn configure(options: ApplyOptions) -> anyhow::Result<()> {
+    OPTIONS
+        .set(options)
+        .map_err(|_| anyhow::anyhow!("The update options were already set"))
+}
+
+/// The options set with `configure`, or the defaults if it was not called
+pub fn options() -> &'static ApplyOptions {
+    #[cfg(test)]
+    if let Some(options) = TEST_OPTIONS.get() {
+        return options;
+    }
+    OPTIONS.get_or_init(ApplyOptions::default)
+}
+
+#[cfg(test)]
+pub(crate) mod testing {
+    use super::{ApplyOptions, TEST_OPTIONS};
+
+    /// Run `f` with `options` on this thread, the process wide options can only be set once
+    pub fn with_options<R>(options: ApplyOptions, f: impl FnOnce() -> R) -> R {
+        let previous = TEST_OPTIONS.replace(Some(Box::leak(Box::new(options))));
+        let result = f();
+        TEST_OPTIONS.set(previous);
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn allowlist(languages: &[&str], index_posts_without_language: bool) -> ApplyOptions {
+        ApplyOptions {
+            post_language_allowlist: languages.iter().map(|lang| lang.to_string()).collect(),
+            index_posts_without_language,
+            ..Default::default()
+        }
+    }
+
+    fn skip_reason(options: &ApplyOptions, langs: &[&str]) -> Option<&'static str> {
+        let langs: Vec<String> = langs.iter().map(|lang| lang.to_string()).collect();
+        options.post_language_skip_reason(Some(&langs))
+    }
+
+    #[test]
+    fn posts_with_one_allowed_language_are_indexed() {
+        let options = allowlist(&["de", "en"], true);
+        assert_eq!(skip_reason(&options, &["ja", "en"]), None);
+        assert_eq!(skip_reason(&options, &["de", "en"]), None);
+        assert_eq!(skip_reason(&options, &["fr", "pt-BR", "DE"]), None);
+        assert_eq!(skip_reason(&options, &["fr", "ja"]), Some("language"));
+    }
+
+    #[test]
+    fn regional_languages_match_their_primary_language() {
+        let options = allowlist(&["en", "pt-BR"], true);
+        assert_eq!(skip_reason(&options, &["ja", "en-GB"]), None);
+        assert_eq!(skip_reason(&options, &["pt-br"]), None);
+        // Only the listed region is allowed
+        assert_eq!(skip_reason(&options, &["pt-PT"]), Some("language"));
+    }
+
+    #[test]
+    fn posts_without_a_language_follow_their_own_setting() {
+        assert_eq!(skip_reason(&allowlist(&["en"], true), &[]), None);
+        assert_eq!(
+            allowlist(&["en"], false).post_language_skip_reason(None),
+            Some("no_language")
+        );
+        // Without an allowlist every post is indexed
+        assert_eq!(skip_reason(&allowlist(&[], false), &["ja"]), None);
+        assert_eq!(allowlist(&[], false).post_language_skip_reason(None), None);
+    }
+}
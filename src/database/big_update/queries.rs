@@ -1,11 +1,17 @@
 use anyhow::Result;
+use chrono::{DateTime, Utc};
 use sqlx::PgTransaction;
+use surrealdb::RecordId;
 
+use super::copy::{copy_insert, CopyWriter};
+use super::options::options;
 use super::types::{
-    BskyBlock, BskyDid, BskyFeed, BskyFollow, BskyLatestBackfill, BskyLike, BskyList,
-    BskyListBlock, BskyListItem, BskyPost, BskyPostsRelation, BskyQuote, BskyRepliesRelation,
-    BskyReplyToRelation, BskyRepost, JetstreamIdentityEvent, WithId,
+    BskyActorDeclaration, BskyBlob, BskyBlock, BskyDid, BskyFeed, BskyFollow, BskyLabelerService,
+    BskyLatestBackfill, BskyLike, BskyList, BskyListBlock, BskyListItem, BskyPost, BskyPostgate,
+    BskyPostsRelation, BskyQuote, BskyRepliesRelation, BskyReplyToRelation, BskyRepost,
+    BskyStarterpack, BskyThreadgate, JetstreamAccountEvent, JetstreamIdentityEvent, WithId,
 };
+use crate::database::search::text_search_config;
 
 macro_rules! get_column {
     ($thing:expr, $field:ident) => {
@@ -164,14 +170,58 @@ macro_rules! get_columns {
 //     return Ok(rows_affected);
 // }
 
+/// Number of rows above which rows are inserted with COPY instead of UNNEST
+///
+/// COPY has to go through a temporary table to skip existing rows, which only pays off for large inserts. The value
+/// is a conservative estimate, the exact crossover depends on the database.
+const COPY_THRESHOLD: usize = 5000;
+
+/// Check if a batch of rows should be inserted with COPY
+///
+/// latest_backfill always uses COPY for large batches, the other tables only with `--use-copy-inserts`
+fn use_copy(rows: usize, always: bool) -> bool {
+    rows > COPY_THRESHOLD && (always || options().use_copy_inserts)
+}
+
+/// Insert backfill rows with COPY, existing rows are skipped
+async fn copy_latest_backfills(
+    update: &[WithId<BskyLatestBackfill>],
+    database: &mut PgTransaction<'_>,
+) -> Result<u64> {
+    let mut rows = CopyWriter::new();
+    for backfill in update {
+        rows.row(3);
+        rows.text(&backfill.id);
+        rows.text(&backfill.data.of.key().to_string());
+        rows.nullable_timestamp(backfill.data.at);
+    }
+    copy_insert(
+        "latest_backfill",
+        &["id", "of_did_id", "at"],
+        rows,
+        database,
+    )
+    .await
+}
+
 pub async fn insert_latest_backfills(
-    update: &Vec<WithId<BskyLatestBackfill>>,
+    update: &[WithId<BskyLatestBackfill>],
     database: &mut PgTransaction<'_>,
 ) -> Result<u64> {
     if update.len() == 0 {
         return Ok(0);
     }
+    if use_copy(update.len(), true) {
+        return copy_latest_backfills(update, database).await;
+    }
+    unnest_latest_backfills(update, database).await
+}
 
+/// Insert backfill rows with UNNEST, existing rows are skipped
+async fn unnest_latest_backfills(
+    update: &[WithId<BskyLatestBackfill>],
+    database: &mut PgTransaction<'_>,
+) -> Result<u64> {
     let ids = get_column!(update, id);
     let of_did_ids = get_column!(update, data.of, record);
     let timestamps = get_column!(update, data.at, nullable_timestamp);
@@ -186,7 +236,7 @@ INSERT INTO latest_backfill (
     $1::TEXT[],
     $2::TEXT[],
     $3::TIMESTAMP[]
-) ON CONFLICT DO NOTHING",
+) ORDER BY 1 ON CONFLICT DO NOTHING",
         ids.as_slice(),
         of_did_ids.as_slice(),
         timestamps.as_slice() as _
@@ -195,9 +245,12 @@ INSERT INTO latest_backfill (
     .await?
     .rows_affected();
 
-    return Ok(rows_affected);
+    Ok(rows_affected)
 }
 
+/// Insert or overwrite backfill rows
+///
+/// A row only moves forward in time, unless the new row has no timestamp, which queues the repo for a new backfill
 pub async fn upsert_latest_backfills(
     update: &Vec<WithId<BskyLatestBackfill>>,
     database: &mut PgTransaction<'_>,
@@ -209,21 +262,27 @@ pub async fn upsert_latest_backfills(
     let ids = get_column!(update, id);
     let of_did_ids = get_column!(update, data.of, record);
     let timestamps = get_column!(update, data.at, nullable_timestamp);
+    let revs = get_column!(update, data.rev);
 
     let rows_affected = sqlx::query!(
         r"
 INSERT INTO latest_backfill (
     id,
     of_did_id,
-    at
+    at,
+    last_known_rev
 ) SELECT * FROM UNNEST(
     $1::TEXT[],
     $2::TEXT[],
-    $3::TIMESTAMP[]
-) ON CONFLICT (id) DO UPDATE SET at = EXCLUDED.at",
+    $3::TIMESTAMP[],
+    $4::TEXT[]
+) ORDER BY 1 ON CONFLICT (id) DO UPDATE SET
+    at = CASE WHEN latest_backfill.at > EXCLUDED.at THEN latest_backfill.at ELSE EXCLUDED.at END,
+    last_known_rev = CASE WHEN latest_backfill.at > EXCLUDED.at THEN latest_backfill.last_known_rev ELSE EXCLUDED.last_known_rev END",
         ids.as_slice(),
         of_did_ids.as_slice(),
-        timestamps.as_slice() as _
+        timestamps.as_slice() as _,
+        revs.as_slice() as _
     )
     .execute(&mut **database)
     .await?
@@ -232,14 +291,69 @@ INSERT INTO latest_backfill (
     return Ok(rows_affected);
 }
 
-pub async fn insert_posts<'a>(
-    update: &Vec<WithId<BskyPost>>,
-    database: &mut PgTransaction<'a>,
-) -> Result<u64> {
-    if update.len() == 0 {
-        return Ok(0);
+/// Insert the post rows with COPY, the other post tables are always written with UNNEST
+async fn copy_posts(update: &[WithId<BskyPost>], database: &mut PgTransaction<'_>) -> Result<u64> {
+    let mut rows = CopyWriter::new();
+    for post in update {
+        rows.row(12);
+        rows.text(&post.id);
+        rows.text(&post.data.author.key().to_string());
+        rows.nullable_text(post.data.bridgy_original_url.as_deref());
+        rows.timestamp(post.data.created_at);
+        rows.nullable_text(
+            post.data
+                .parent
+                .as_ref()
+                .map(|x| x.key().to_string())
+                .as_deref(),
+        );
+        rows.nullable_text(
+            post.data
+                .record
+                .as_ref()
+                .map(|x| x.key().to_string())
+                .as_deref(),
+        );
+        rows.nullable_text(
+            post.data
+                .root
+                .as_ref()
+                .map(|x| x.key().to_string())
+                .as_deref(),
+        );
+        rows.text(&post.data.text);
+        rows.nullable_text(post.data.via.as_deref());
+        rows.jsonb(&serde_json::to_value(&post.data.video).unwrap());
+        rows.nullable_text(post.data.extra_data.as_deref());
+        rows.text(post_text_search_config(&post.data));
     }
+    copy_insert(
+        "post",
+        &[
+            "id",
+            "author",
+            "bridgy_original_url",
+            "created_at",
+            "parent",
+            "record",
+            "root",
+            "text",
+            "via",
+            "video",
+            "extra_data",
+            "text_search_config",
+        ],
+        rows,
+        database,
+    )
+    .await
+}
 
+/// Insert the post rows with UNNEST, the other post tables are written by insert_posts
+async fn unnest_posts(
+    update: &[WithId<BskyPost>],
+    database: &mut PgTransaction<'_>,
+) -> Result<u64> {
     let ids = get_column!(update, id);
     let authors = get_column!(update, data.author, record);
     let bridgys = get_column!(update, data.bridgy_original_url);
@@ -251,26 +365,12 @@ pub async fn insert_posts<'a>(
     let vias = get_column!(update, data.via);
     let videos = get_column!(update, data.video, |x| serde_json::to_value(x).unwrap());
     let extra_data = get_column!(update, data.extra_data);
-
-    let (tag_post_ids, tag_values) = get_columns!(update, data.tags);
-    let (lang_post_ids, lang_values) = get_columns!(update, data.langs);
-    let (link_post_ids, link_values) = get_columns!(update, data.links);
-    let (label_post_ids, label_values) = get_columns!(update, data.labels);
-
-    let (images_post_ids, images_unprocessed) = get_columns!(update, data.images);
-    let images_alt = get_column!(images_unprocessed, alt);
-    let images_blobs = get_column!(images_unprocessed, blob, record);
-    let images_aspectratios = get_column!(images_unprocessed, aspect_ratio);
-    let images_aspectratios_widths = images_aspectratios
+    let text_search_configs = update
         .iter()
-        .map(|x| x.clone().map(|x| x.width as i64))
-        .collect::<Vec<_>>();
-    let images_aspectratios_heights = images_aspectratios
-        .iter()
-        .map(|x| x.clone().map(|x| x.height as i64))
+        .map(|post| post_text_search_config(&post.data))
         .collect::<Vec<_>>();
 
-    sqlx::query!(
+    let rows_affected = sqlx::query!(
         r"
 INSERT INTO post (
 id,
@@ -283,7 +383,8 @@ root,
 text,
 via,
 video,
-extra_data
+extra_data,
+text_search_config
 ) SELECT * FROM UNNEST(
     $1::TEXT[],
     $2::TEXT[],
@@ -295,7 +396,8 @@ extra_data
     $8::TEXT[],
     $9::TEXT[],
     $10::JSONB[],
-    $11::TEXT[]
+    $11::TEXT[],
+    $12::TEXT[]
 ) ON CONFLICT DO NOTHING",
         ids.as_slice(),
         authors.as_slice(),
@@ -307,11 +409,61 @@ extra_data
         texts.as_slice(),
         vias.as_slice() as _,
         videos.as_slice(),
-        extra_data.as_slice() as _
+        extra_data.as_slice() as _,
+        text_search_configs.as_slice() as _
     )
     .execute(&mut **database)
-    .await
-    .unwrap();
+    .await?
+    .rows_affected();
+
+    Ok(rows_affected)
+}
+
+/// Text search configuration for the first language of a post
+fn post_text_search_config(post: &BskyPost) -> &'static str {
+    text_search_config(post.langs.iter().flatten().next().map(String::as_str))
+}
+
+pub async fn insert_posts<'a>(
+    update: &[WithId<BskyPost>],
+    database: &mut PgTransaction<'a>,
+) -> Result<u64> {
+    if update.len() == 0 {
+        return Ok(0);
+    }
+
+    let (tag_post_ids, tag_values) = get_columns!(update, data.tags);
+    let (lang_post_ids, lang_values) = get_columns!(update, data.langs);
+    let (link_post_ids, link_values) = get_columns!(update, data.links);
+    let (label_post_ids, label_values) = get_columns!(update, data.labels);
+
+    let (images_post_ids, images_unprocessed) = get_columns!(update, data.images);
+    let images_alt = get_column!(images_unprocessed, alt);
+    let images_blobs = get_column!(images_unprocessed, blob, record);
+    let images_aspectratios = get_column!(images_unprocessed, aspect_ratio);
+    let images_aspectratios_widths = images_aspectratios
+        .iter()
+        .map(|x| x.clone().map(|x| x.width as i64))
+        .collect::<Vec<_>>();
+    let images_aspectratios_heights = images_aspectratios
+        .iter()
+        .map(|x| x.clone().map(|x| x.height as i64))
+        .collect::<Vec<_>>();
+
+    let (externals_post_ids, externals_unprocessed) = get_columns!(update, data.external, notnull);
+    let externals_uris = get_column!(externals_unprocessed, uri);
+    let externals_titles = get_column!(externals_unprocessed, title);
+    let externals_descriptions = get_column!(externals_unprocessed, description);
+    let externals_thumbs = externals_unprocessed
+        .iter()
+        .map(|x| x.thumb.as_ref().map(|x| x.key().to_string()))
+        .collect::<Vec<_>>();
+
+    let rows_affected = if use_copy(update.len(), false) {
+        copy_posts(update, database).await?
+    } else {
+        unnest_posts(update, database).await?
+    };
 
     sqlx::query!(
         r"
@@ -326,8 +478,7 @@ label
         label_values.as_slice()
     )
     .execute(&mut **database)
-    .await
-    .unwrap();
+    .await?;
 
     sqlx::query!(
         r"
@@ -342,8 +493,7 @@ lang
         lang_values.as_slice()
     )
     .execute(&mut **database)
-    .await
-    .unwrap();
+    .await?;
 
     sqlx::query!(
         r"
@@ -358,8 +508,7 @@ link
         link_values.as_slice()
     )
     .execute(&mut **database)
-    .await
-    .unwrap();
+    .await?;
 
     sqlx::query!(
         r"
@@ -374,8 +523,7 @@ tag
         tag_values.as_slice()
     )
     .execute(&mut **database)
-    .await
-    .unwrap();
+    .await?;
 
     sqlx::query!(
         r"
@@ -399,20 +547,76 @@ tag
         images_aspectratios_heights.as_slice() as _
     )
     .execute(&mut **database)
-    .await
-    .unwrap();
+    .await?;
+
+    sqlx::query!(
+        r"
+INSERT INTO post_external_embed (
+    post_id,
+    uri,
+    title,
+    description,
+    thumb_blob_id
+) SELECT * FROM UNNEST(
+    $1::TEXT[],
+    $2::TEXT[],
+    $3::TEXT[],
+    $4::TEXT[],
+    $5::TEXT[]
+) ON CONFLICT DO NOTHING",
+        externals_post_ids.as_slice(),
+        externals_uris.as_slice(),
+        externals_titles.as_slice(),
+        externals_descriptions.as_slice(),
+        externals_thumbs.as_slice() as _
+    )
+    .execute(&mut **database)
+    .await?;
 
-    return Ok(0);
+    Ok(rows_affected)
 }
 
 pub async fn insert_follows(
-    update: &Vec<WithId<BskyFollow>>,
+    update: &[WithId<BskyFollow>],
     database: &mut PgTransaction<'_>,
 ) -> Result<u64> {
     if update.len() == 0 {
         return Ok(0);
     }
+    if use_copy(update.len(), false) {
+        return copy_follows(update, database).await;
+    }
+    unnest_follows(update, database).await
+}
+
+/// Insert follow rows with COPY, existing rows are skipped
+async fn copy_follows(
+    update: &[WithId<BskyFollow>],
+    database: &mut PgTransaction<'_>,
+) -> Result<u64> {
+    let mut rows = CopyWriter::new();
+    for follow in update {
+        rows.row(4);
+        rows.text(&follow.id);
+        rows.text(&follow.data.from.key().to_string());
+        rows.text(&follow.data.to.key().to_string());
+        rows.timestamp(follow.data.created_at);
+    }
+    copy_insert(
+        "follow",
+        &["id", "follower_did_id", "followed_did_id", "created_at"],
+        rows,
+        database,
+    )
+    .await
+}
 
+/// Insert follow rows with UNNEST, existing rows are skipped
+async fn unnest_follows(
+    update: &[WithId<BskyFollow>],
+    database: &mut PgTransaction<'_>,
+) -> Result<u64> {
+    let ids = get_column!(update, id);
     let follower_did_ids = get_column!(update, data.from, record);
     let followed_did_ids = get_column!(update, data.to, record);
     let created_ats = get_column!(update, data.created_at, timestamp);
@@ -420,14 +624,17 @@ pub async fn insert_follows(
     let rows_affected = sqlx::query!(
         r"
 INSERT INTO follow (
+    id,
     follower_did_id,
     followed_did_id,
     created_at
 ) SELECT * FROM UNNEST(
     $1::TEXT[],
     $2::TEXT[],
-    $3::TIMESTAMP[]
+    $3::TEXT[],
+    $4::TIMESTAMP[]
 ) ON CONFLICT DO NOTHING",
+        ids.as_slice(),
         follower_did_ids.as_slice(),
         followed_did_ids.as_slice(),
         created_ats.as_slice()
@@ -436,12 +643,13 @@ INSERT INTO follow (
     .await?
     .rows_affected();
 
-    return Ok(rows_affected);
+    Ok(rows_affected)
 }
 
+/// Tables a like or listblock can point to
 #[derive(sqlx::Type, Debug)]
 #[sqlx(rename_all = "lowercase", type_name = "like_target")]
-enum LikeTarget {
+pub(super) enum LikeTarget {
     Post,
     Feed,
     List,
@@ -449,35 +657,75 @@ enum LikeTarget {
     Labeler,
 }
 
-impl From<&str> for LikeTarget {
-    fn from(s: &str) -> Self {
-        match s {
+impl TryFrom<&str> for LikeTarget {
+    type Error = anyhow::Error;
+
+    fn try_from(table: &str) -> Result<Self> {
+        Ok(match table {
             "post" => LikeTarget::Post,
             "feed" => LikeTarget::Feed,
             "list" => LikeTarget::List,
             "starterpack" => LikeTarget::Starterpack,
             "labeler" => LikeTarget::Labeler,
-            _ => panic!("Invalid like target"),
-        }
+            _ => anyhow::bail!("Invalid like target {}", table),
+        })
     }
 }
 
+/// Get the like target of each row
+fn like_targets<'a>(targets: impl Iterator<Item = &'a RecordId>) -> Result<Vec<LikeTarget>> {
+    targets.map(|to| LikeTarget::try_from(to.table())).collect()
+}
+
 pub async fn insert_likes(
-    update: &Vec<WithId<BskyLike>>,
+    update: &[WithId<BskyLike>],
     database: &mut PgTransaction<'_>,
 ) -> Result<u64> {
     if update.len() == 0 {
         return Ok(0);
     }
+    if use_copy(update.len(), false) {
+        return copy_likes(update, database).await;
+    }
+    unnest_likes(update, database).await
+}
+
+/// Insert like rows with COPY, existing rows are skipped
+async fn copy_likes(update: &[WithId<BskyLike>], database: &mut PgTransaction<'_>) -> Result<u64> {
+    let mut rows = CopyWriter::new();
+    for like in update {
+        rows.row(5);
+        rows.text(&like.id);
+        rows.text(&like.data.from.key().to_string());
+        rows.text(&like.data.to.key().to_string());
+        // The binary format of an enum is its label
+        rows.text(like.data.to.table());
+        rows.timestamp(like.data.created_at);
+    }
+    copy_insert(
+        "like",
+        &["id", "user_id", "target_id", "target_type", "created_at"],
+        rows,
+        database,
+    )
+    .await
+}
 
+/// Insert like rows with UNNEST, existing rows are skipped
+async fn unnest_likes(
+    update: &[WithId<BskyLike>],
+    database: &mut PgTransaction<'_>,
+) -> Result<u64> {
+    let ids = get_column!(update, id);
     let liker_did_ids = get_column!(update, data.from, record);
     let liked_ids = get_column!(update, data.to, record);
-    let liked_types: Vec<LikeTarget> = get_column!(update, data.to, |r| r.table().into());
+    let liked_types = like_targets(update.iter().map(|like| &like.data.to))?;
     let created_ats = get_column!(update, data.created_at, timestamp);
 
     let rows_affected = sqlx::query(
         r#"
 INSERT INTO "like" (
+    id,
     user_id,
     target_id,
     target_type,
@@ -485,10 +733,12 @@ INSERT INTO "like" (
 ) SELECT * FROM UNNEST(
     $1::TEXT[],
     $2::TEXT[],
-    $3::LIKE_TARGET[],
-    $4::TIMESTAMP[]
+    $3::TEXT[],
+    $4::LIKE_TARGET[],
+    $5::TIMESTAMP[]
 ) ON CONFLICT DO NOTHING"#,
     )
+    .bind(ids.as_slice())
     .bind(liker_did_ids.as_slice())
     .bind(liked_ids.as_slice())
     .bind(liked_types.as_slice())
@@ -497,7 +747,7 @@ INSERT INTO "like" (
     .await?
     .rows_affected();
 
-    return Ok(rows_affected);
+    Ok(rows_affected)
 }
 
 pub async fn insert_listblocks(
@@ -508,14 +758,16 @@ pub async fn insert_listblocks(
         return Ok(0);
     }
 
+    let ids = get_column!(update, id);
     let blocker_did_ids = get_column!(update, data.from, record);
     let target_ids = get_column!(update, data.to, record);
-    let target_types: Vec<LikeTarget> = get_column!(update, data.to, |r| r.table().into());
+    let target_types = like_targets(update.iter().map(|listblock| &listblock.data.to))?;
     let created_ats = get_column!(update, data.created_at, timestamp);
 
     let rows_affected = sqlx::query(
         r#"
 INSERT INTO listblock (
+    id,
     blocker_did_id,
     target_id,
     target_type,
@@ -523,10 +775,12 @@ INSERT INTO listblock (
 ) SELECT * FROM UNNEST(
     $1::TEXT[],
     $2::TEXT[],
-    $3::LIKE_TARGET[],
-    $4::TIMESTAMP[]
+    $3::TEXT[],
+    $4::LIKE_TARGET[],
+    $5::TIMESTAMP[]
 ) ON CONFLICT DO NOTHING"#,
     )
+    .bind(ids.as_slice())
     .bind(blocker_did_ids.as_slice())
     .bind(target_ids.as_slice())
     .bind(target_types.as_slice())
@@ -546,6 +800,7 @@ pub async fn insert_listitems(
         return Ok(0);
     }
 
+    let ids = get_column!(update, id);
     let list_ids = get_column!(update, data.from, record);
     let did_ids = get_column!(update, data.to, record);
     let created_ats = get_column!(update, data.created_at, timestamp);
@@ -553,14 +808,17 @@ pub async fn insert_listitems(
     let rows_affected = sqlx::query!(
         r"
 INSERT INTO listitem (
+    id,
     list_id,
     did_id,
     created_at
 ) SELECT * FROM UNNEST(
     $1::TEXT[],
     $2::TEXT[],
-    $3::TIMESTAMP[]
+    $3::TEXT[],
+    $4::TIMESTAMP[]
 ) ON CONFLICT DO NOTHING",
+        ids.as_slice(),
         list_ids.as_slice(),
         did_ids.as_slice(),
         created_ats.as_slice()
@@ -573,13 +831,46 @@ INSERT INTO listitem (
 }
 
 pub async fn insert_reposts(
-    update: &Vec<WithId<BskyRepost>>,
+    update: &[WithId<BskyRepost>],
     database: &mut PgTransaction<'_>,
 ) -> Result<u64> {
     if update.len() == 0 {
         return Ok(0);
     }
+    if use_copy(update.len(), false) {
+        return copy_reposts(update, database).await;
+    }
+    unnest_reposts(update, database).await
+}
+
+/// Insert repost rows with COPY, existing rows are skipped
+async fn copy_reposts(
+    update: &[WithId<BskyRepost>],
+    database: &mut PgTransaction<'_>,
+) -> Result<u64> {
+    let mut rows = CopyWriter::new();
+    for repost in update {
+        rows.row(4);
+        rows.text(&repost.id);
+        rows.text(&repost.data.from.key().to_string());
+        rows.text(&repost.data.to.key().to_string());
+        rows.timestamp(repost.data.created_at);
+    }
+    copy_insert(
+        "repost",
+        &["id", "did_id", "post_id", "created_at"],
+        rows,
+        database,
+    )
+    .await
+}
 
+/// Insert repost rows with UNNEST, existing rows are skipped
+async fn unnest_reposts(
+    update: &[WithId<BskyRepost>],
+    database: &mut PgTransaction<'_>,
+) -> Result<u64> {
+    let ids = get_column!(update, id);
     let reposter_did_ids = get_column!(update, data.from, record);
     let reposted_ids = get_column!(update, data.to, record);
     let created_ats = get_column!(update, data.created_at, timestamp);
@@ -587,14 +878,17 @@ pub async fn insert_reposts(
     let rows_affected = sqlx::query!(
         r"
 INSERT INTO repost (
+    id,
     did_id,
     post_id,
     created_at
 ) SELECT * FROM UNNEST(
     $1::TEXT[],
     $2::TEXT[],
-    $3::TIMESTAMP[]
+    $3::TEXT[],
+    $4::TIMESTAMP[]
 ) ON CONFLICT DO NOTHING",
+        ids.as_slice(),
         reposter_did_ids.as_slice(),
         reposted_ids.as_slice(),
         created_ats.as_slice()
@@ -603,7 +897,7 @@ INSERT INTO repost (
     .await?
     .rows_affected();
 
-    return Ok(rows_affected);
+    Ok(rows_affected)
 }
 
 pub async fn insert_blocks(
@@ -614,6 +908,7 @@ pub async fn insert_blocks(
         return Ok(0);
     }
 
+    let ids = get_column!(update, id);
     let blocker_ids = get_column!(update, data.from, record);
     let blocked_ids = get_column!(update, data.to, record);
     let created_ats = get_column!(update, data.created_at, timestamp);
@@ -621,14 +916,17 @@ pub async fn insert_blocks(
     let rows_affected = sqlx::query!(
         r#"
 INSERT INTO "block" (
+    id,
     blocker_did_id,
     blocked_did_id,
     created_at
 ) SELECT * FROM UNNEST(
     $1::TEXT[],
     $2::TEXT[],
-    $3::TIMESTAMP[]
+    $3::TEXT[],
+    $4::TIMESTAMP[]
 ) ON CONFLICT DO NOTHING"#,
+        ids.as_slice(),
         blocker_ids.as_slice(),
         blocked_ids.as_slice(),
         created_ats.as_slice()
@@ -640,6 +938,47 @@ INSERT INTO "block" (
     return Ok(rows_affected);
 }
 
+pub async fn insert_blobs(
+    update: &[WithId<BskyBlob>],
+    database: &mut PgTransaction<'_>,
+) -> Result<u64> {
+    if update.is_empty() {
+        return Ok(0);
+    }
+    let ids = get_column!(update, id);
+    let cids = get_column!(update, data.cid);
+    let media_types = get_column!(update, data.media_type);
+    let sizes = get_column!(update, data.size, |s| s.map(|s| s as i64));
+    let first_seen_ats = get_column!(update, data.first_seen_at, timestamp);
+
+    let rows_affected = sqlx::query!(
+        r"
+INSERT INTO blob (
+    id,
+    cid,
+    media_type,
+    size,
+    first_seen_at
+) SELECT * FROM UNNEST(
+    $1::TEXT[],
+    $2::TEXT[],
+    $3::TEXT[],
+    $4::BIGINT[],
+    $5::TIMESTAMP[]
+) ON CONFLICT DO NOTHING",
+        ids.as_slice(),
+        cids.as_slice(),
+        media_types.as_slice(),
+        sizes.as_slice() as _,
+        first_seen_ats.as_slice()
+    )
+    .execute(&mut **database)
+    .await?
+    .rows_affected();
+
+    Ok(rows_affected)
+}
+
 pub async fn insert_profiles(
     update: &Vec<WithId<BskyDid>>,
     database: &mut PgTransaction<'_>,
@@ -659,6 +998,8 @@ pub async fn insert_profiles(
         get_column!(update, data.joined_via_starter_pack, nullable_record);
     let pinned_posts = get_column!(update, data.pinned_post, nullable_record);
     let extra_datas = get_column!(update, data.extra_data);
+    let handles = get_column!(update, data.handle);
+    let did_texts = get_column!(update, data.did_text);
 
     let (label_profile_ids, label_values) = get_columns!(update, data.labels, notnull);
 
@@ -674,8 +1015,23 @@ INSERT INTO did (
     created_at,
     seen_at,
     pinned_post,
-    extra_data
-) SELECT * FROM UNNEST(
+    extra_data,
+    handle,
+    did_text
+) SELECT
+    u.id,
+    u.display_name,
+    u.description,
+    u.avatar,
+    u.banner,
+    u.joined_via_starter_pack,
+    u.created_at,
+    u.seen_at,
+    u.pinned_post,
+    u.extra_data,
+    COALESCE(u.handle, e.handle),
+    u.did_text
+FROM UNNEST(
     $1::TEXT[],
     $2::TEXT[],
     $3::TEXT[],
@@ -685,8 +1041,25 @@ INSERT INTO did (
     $7::TIMESTAMP[],
     $8::TIMESTAMP[],
     $9::TEXT[],
-    $10::TEXT[]
-) ON CONFLICT DO NOTHING",
+    $10::TEXT[],
+    $11::TEXT[],
+    $12::TEXT[]
+) AS u (
+    id,
+    display_name,
+    description,
+    avatar,
+    banner,
+    joined_via_starter_pack,
+    created_at,
+    seen_at,
+    pinned_post,
+    extra_data,
+    handle,
+    did_text
+)
+LEFT JOIN jetstream_identity_event e ON e.id = u.id
+ON CONFLICT DO NOTHING",
         ids.as_slice(),
         display_names.as_slice() as _,
         descriptions.as_slice() as _,
@@ -696,7 +1069,9 @@ INSERT INTO did (
         created_ats.as_slice() as _,
         seen_ats.as_slice(),
         pinned_posts.as_slice() as _,
-        extra_datas.as_slice() as _
+        extra_datas.as_slice() as _,
+        handles.as_slice() as _,
+        did_texts.as_slice()
     )
     .execute(&mut **database)
     .await?
@@ -788,8 +1163,8 @@ pub async fn insert_quotes_relations(
         return Ok(0);
     }
 
-    let from_did_ids = get_column!(update, data.from, record);
-    let to_post_ids = get_column!(update, data.to, record);
+    let source_post_ids = get_column!(update, data.from, record);
+    let target_post_ids = get_column!(update, data.to, record);
 
     let rows_affected = sqlx::query!(
         r"
@@ -800,8 +1175,8 @@ INSERT INTO quotes_relation (
     $1::TEXT[],
     $2::TEXT[]
 ) ON CONFLICT DO NOTHING",
-        from_did_ids.as_slice(),
-        to_post_ids.as_slice()
+        source_post_ids.as_slice(),
+        target_post_ids.as_slice()
     )
     .execute(&mut **database)
     .await?
@@ -818,8 +1193,8 @@ pub async fn insert_reply_to_relations(
         return Ok(0);
     }
 
-    let from_did_ids = get_column!(update, data.from, record);
-    let to_post_ids = get_column!(update, data.to, record);
+    let source_post_ids = get_column!(update, data.from, record);
+    let target_post_ids = get_column!(update, data.to, record);
 
     let rows_affected = sqlx::query!(
         r"
@@ -830,8 +1205,8 @@ INSERT INTO replyto_relation (
     $1::TEXT[],
     $2::TEXT[]
 ) ON CONFLICT DO NOTHING",
-        from_did_ids.as_slice(),
-        to_post_ids.as_slice()
+        source_post_ids.as_slice(),
+        target_post_ids.as_slice()
     )
     .execute(&mut **database)
     .await?
@@ -966,31 +1341,628 @@ extra_data
 //     time TEXT NOT NULL
 // );
 
-pub async fn upsert_jetstream_identity_event(
-    update: &WithId<JetstreamIdentityEvent>,
+pub async fn insert_starterpacks(
+    update: &[WithId<BskyStarterpack>],
     database: &mut PgTransaction<'_>,
 ) -> Result<u64> {
+    if update.is_empty() {
+        return Ok(0);
+    }
+    let ids = get_column!(update, id);
+    let creators = get_column!(update, data.creator, record);
+    let names = get_column!(update, data.name);
+    let descriptions = get_column!(update, data.description);
+    let list_ids = get_column!(update, data.list, record);
+    let created_ats = get_column!(update, data.created_at, timestamp);
+    let extra_datas = get_column!(update, data.extra_data);
+
+    let (feed_starterpack_ids, feeds) = get_columns!(update, data.feeds);
+    let feed_ids = feeds
+        .iter()
+        .map(|f| f.key().to_string())
+        .collect::<Vec<_>>();
+
     let rows_affected = sqlx::query!(
         r"
-INSERT INTO jetstream_identity_event (
+INSERT INTO starterpack (
     id,
-    time_us,
-    handle,
-    seq,
-    time
-) VALUES (
-    $1,
-    $2,
-    $3,
-    $4,
-    $5
-) ON CONFLICT (id) DO UPDATE SET
-    time_us = EXCLUDED.time_us,
-    handle = EXCLUDED.handle,
-    seq = EXCLUDED.seq,
-    time = EXCLUDED.time",
-        update.id,
-        update.data.time_us,
+    creator_did_id,
+    name,
+    description,
+    list_id,
+    created_at,
+    extra_data
+) SELECT * FROM UNNEST(
+    $1::TEXT[],
+    $2::TEXT[],
+    $3::TEXT[],
+    $4::TEXT[],
+    $5::TEXT[],
+    $6::TIMESTAMP[],
+    $7::TEXT[]
+) ON CONFLICT DO NOTHING",
+        ids.as_slice(),
+        creators.as_slice(),
+        names.as_slice(),
+        descriptions.as_slice() as _,
+        list_ids.as_slice(),
+        created_ats.as_slice(),
+        extra_datas.as_slice() as _
+    )
+    .execute(&mut **database)
+    .await?
+    .rows_affected();
+
+    sqlx::query!(
+        r"
+INSERT INTO starterpack_feed (
+    starterpack_id,
+    feed_id
+) SELECT * FROM UNNEST(
+    $1::TEXT[],
+    $2::TEXT[]
+) ON CONFLICT DO NOTHING",
+        feed_starterpack_ids.as_slice(),
+        feed_ids.as_slice()
+    )
+    .execute(&mut **database)
+    .await?;
+
+    Ok(rows_affected)
+}
+
+pub async fn insert_postgates(
+    update: &[WithId<BskyPostgate>],
+    database: &mut PgTransaction<'_>,
+) -> Result<u64> {
+    if update.is_empty() {
+        return Ok(0);
+    }
+    let ids = get_column!(update, id);
+    let post_ids = get_column!(update, data.post, record);
+    let created_ats = get_column!(update, data.created_at, timestamp);
+    let disable_embeddings = get_column!(update, data.disable_embedding);
+    let extra_datas = get_column!(update, data.extra_data);
+
+    let (detached_postgate_ids, detached) = get_columns!(update, data.detached_embeddings);
+    let detached_post_ids = detached
+        .iter()
+        .map(|p| p.key().to_string())
+        .collect::<Vec<_>>();
+
+    let rows_affected = sqlx::query!(
+        r"
+INSERT INTO postgate (
+    id,
+    post_id,
+    created_at,
+    disable_embedding,
+    extra_data
+) SELECT * FROM UNNEST(
+    $1::TEXT[],
+    $2::TEXT[],
+    $3::TIMESTAMP[],
+    $4::BOOLEAN[],
+    $5::TEXT[]
+) ON CONFLICT DO NOTHING",
+        ids.as_slice(),
+        post_ids.as_slice(),
+        created_ats.as_slice(),
+        disable_embeddings.as_slice(),
+        extra_datas.as_slice() as _
+    )
+    .execute(&mut **database)
+    .await?
+    .rows_affected();
+
+    sqlx::query!(
+        r"
+INSERT INTO postgate_detached_embedding (
+    postgate_id,
+    post_id
+) SELECT * FROM UNNEST(
+    $1::TEXT[],
+    $2::TEXT[]
+) ON CONFLICT DO NOTHING",
+        detached_postgate_ids.as_slice(),
+        detached_post_ids.as_slice()
+    )
+    .execute(&mut **database)
+    .await?;
+
+    Ok(rows_affected)
+}
+
+pub async fn insert_actordeclarations(
+    update: &[WithId<BskyActorDeclaration>],
+    database: &mut PgTransaction<'_>,
+) -> Result<u64> {
+    if update.is_empty() {
+        return Ok(0);
+    }
+    let ids = get_column!(update, id);
+    let did_ids = get_column!(update, data.did, record);
+    let allow_incomings = get_column!(update, data.allow_incoming);
+    let extra_datas = get_column!(update, data.extra_data);
+
+    let rows_affected = sqlx::query!(
+        r"
+INSERT INTO chat_actor_declaration (
+    id,
+    did_id,
+    allow_incoming,
+    extra_data
+) SELECT * FROM UNNEST(
+    $1::TEXT[],
+    $2::TEXT[],
+    $3::TEXT[],
+    $4::TEXT[]
+) ON CONFLICT DO NOTHING",
+        ids.as_slice(),
+        did_ids.as_slice(),
+        allow_incomings.as_slice(),
+        extra_datas.as_slice() as _
+    )
+    .execute(&mut **database)
+    .await?
+    .rows_affected();
+
+    Ok(rows_affected)
+}
+
+pub async fn insert_labelerservices(
+    update: &[WithId<BskyLabelerService>],
+    database: &mut PgTransaction<'_>,
+) -> Result<u64> {
+    if update.is_empty() {
+        return Ok(0);
+    }
+    let ids = get_column!(update, id);
+    let did_ids = get_column!(update, data.did, record);
+    let created_ats = get_column!(update, data.created_at, timestamp);
+    let label_value_definitions = get_column!(update, data.label_value_definitions);
+    let extra_datas = get_column!(update, data.extra_data);
+
+    let (label_value_labeler_ids, label_values) = get_columns!(update, data.label_values, notnull);
+
+    let rows_affected = sqlx::query!(
+        r"
+INSERT INTO labeler (
+    id,
+    did_id,
+    created_at,
+    label_value_definitions,
+    extra_data
+) SELECT * FROM UNNEST(
+    $1::TEXT[],
+    $2::TEXT[],
+    $3::TIMESTAMP[],
+    $4::TEXT[],
+    $5::TEXT[]
+) ON CONFLICT DO NOTHING",
+        ids.as_slice(),
+        did_ids.as_slice(),
+        created_ats.as_slice(),
+        label_value_definitions.as_slice() as _,
+        extra_datas.as_slice() as _
+    )
+    .execute(&mut **database)
+    .await?
+    .rows_affected();
+
+    sqlx::query!(
+        r"
+INSERT INTO labeler_label_value (
+    labeler_id,
+    label_value
+) SELECT * FROM UNNEST(
+    $1::TEXT[],
+    $2::TEXT[]
+) ON CONFLICT DO NOTHING",
+        label_value_labeler_ids.as_slice(),
+        label_values.as_slice()
+    )
+    .execute(&mut **database)
+    .await?;
+
+    Ok(rows_affected)
+}
+
+pub async fn insert_threadgates(
+    update: &[WithId<BskyThreadgate>],
+    database: &mut PgTransaction<'_>,
+) -> Result<u64> {
+    if update.is_empty() {
+        return Ok(0);
+    }
+    let ids = get_column!(update, id);
+    let post_ids = get_column!(update, data.post, record);
+    let created_ats = get_column!(update, data.created_at, timestamp);
+    let restricts_replies = get_column!(update, data.allow, |a| a.is_some());
+    let extra_datas = get_column!(update, data.extra_data);
+
+    let (allow_threadgate_ids, allows) = get_columns!(update, data.allow);
+    let allow_rules = allows.iter().map(|a| a.rule.clone()).collect::<Vec<_>>();
+    let allow_list_ids = allows
+        .iter()
+        .map(|a| a.list.as_ref().map(|l| l.key().to_string()))
+        .collect::<Vec<_>>();
+
+    let rows_affected = sqlx::query!(
+        r"
+INSERT INTO threadgate (
+    id,
+    post_id,
+    created_at,
+    restricts_replies,
+    extra_data
+) SELECT * FROM UNNEST(
+    $1::TEXT[],
+    $2::TEXT[],
+    $3::TIMESTAMP[],
+    $4::BOOLEAN[],
+    $5::TEXT[]
+) ON CONFLICT DO NOTHING",
+        ids.as_slice(),
+        post_ids.as_slice(),
+        created_ats.as_slice(),
+        restricts_replies.as_slice(),
+        extra_datas.as_slice() as _
+    )
+    .execute(&mut **database)
+    .await?
+    .rows_affected();
+
+    sqlx::query!(
+        r"
+INSERT INTO threadgate_allow (
+    threadgate_id,
+    rule,
+    list_id
+) SELECT * FROM UNNEST(
+    $1::TEXT[],
+    $2::TEXT[],
+    $3::TEXT[]
+) ON CONFLICT DO NOTHING",
+        allow_threadgate_ids.as_slice(),
+        allow_rules.as_slice(),
+        allow_list_ids.as_slice() as _
+    )
+    .execute(&mut **database)
+    .await?;
+
+    Ok(rows_affected)
+}
+
+pub async fn delete_follows(ids: &[String], database: &mut PgTransaction<'_>) -> Result<u64> {
+    if ids.is_empty() {
+        return Ok(0);
+    }
+
+    let rows_affected = sqlx::query!(r"DELETE FROM follow WHERE id = ANY($1::TEXT[])", ids)
+        .execute(&mut **database)
+        .await?
+        .rows_affected();
+
+    Ok(rows_affected)
+}
+
+pub async fn delete_likes(ids: &[String], database: &mut PgTransaction<'_>) -> Result<u64> {
+    if ids.is_empty() {
+        return Ok(0);
+    }
+
+    let rows_affected = sqlx::query!(r#"DELETE FROM "like" WHERE id = ANY($1::TEXT[])"#, ids)
+        .execute(&mut **database)
+        .await?
+        .rows_affected();
+
+    Ok(rows_affected)
+}
+
+pub async fn delete_reposts(ids: &[String], database: &mut PgTransaction<'_>) -> Result<u64> {
+    if ids.is_empty() {
+        return Ok(0);
+    }
+
+    let rows_affected = sqlx::query!(r"DELETE FROM repost WHERE id = ANY($1::TEXT[])", ids)
+        .execute(&mut **database)
+        .await?
+        .rows_affected();
+
+    Ok(rows_affected)
+}
+
+pub async fn delete_blocks(ids: &[String], database: &mut PgTransaction<'_>) -> Result<u64> {
+    if ids.is_empty() {
+        return Ok(0);
+    }
+
+    let rows_affected = sqlx::query!(r#"DELETE FROM "block" WHERE id = ANY($1::TEXT[])"#, ids)
+        .execute(&mut **database)
+        .await?
+        .rows_affected();
+
+    Ok(rows_affected)
+}
+
+pub async fn delete_listblocks(ids: &[String], database: &mut PgTransaction<'_>) -> Result<u64> {
+    if ids.is_empty() {
+        return Ok(0);
+    }
+
+    let rows_affected = sqlx::query!(r"DELETE FROM listblock WHERE id = ANY($1::TEXT[])", ids)
+        .execute(&mut **database)
+        .await?
+        .rows_affected();
+
+    Ok(rows_affected)
+}
+
+pub async fn delete_listitems(ids: &[String], database: &mut PgTransaction<'_>) -> Result<u64> {
+    if ids.is_empty() {
+        return Ok(0);
+    }
+
+    let rows_affected = sqlx::query!(r"DELETE FROM listitem WHERE id = ANY($1::TEXT[])", ids)
+        .execute(&mut **database)
+        .await?
+        .rows_affected();
+
+    Ok(rows_affected)
+}
+
+pub async fn delete_feeds(ids: &[String], database: &mut PgTransaction<'_>) -> Result<u64> {
+    if ids.is_empty() {
+        return Ok(0);
+    }
+
+    let rows_affected = sqlx::query!(r"DELETE FROM feed WHERE id = ANY($1::TEXT[])", ids)
+        .execute(&mut **database)
+        .await?
+        .rows_affected();
+
+    Ok(rows_affected)
+}
+
+pub async fn delete_starterpacks(ids: &[String], database: &mut PgTransaction<'_>) -> Result<u64> {
+    if ids.is_empty() {
+        return Ok(0);
+    }
+
+    let mut rows_affected = sqlx::query!(
+        r"DELETE FROM starterpack_feed WHERE starterpack_id = ANY($1::TEXT[])",
+        ids
+    )
+    .execute(&mut **database)
+    .await?
+    .rows_affected();
+    rows_affected += sqlx::query!(r"DELETE FROM starterpack WHERE id = ANY($1::TEXT[])", ids)
+        .execute(&mut **database)
+        .await?
+        .rows_affected();
+
+    Ok(rows_affected)
+}
+
+pub async fn delete_labelerservices(
+    ids: &[String],
+    database: &mut PgTransaction<'_>,
+) -> Result<u64> {
+    if ids.is_empty() {
+        return Ok(0);
+    }
+
+    let mut rows_affected = sqlx::query!(
+        r"DELETE FROM labeler_label_value WHERE labeler_id = ANY($1::TEXT[])",
+        ids
+    )
+    .execute(&mut **database)
+    .await?
+    .rows_affected();
+    rows_affected += sqlx::query!(r"DELETE FROM labeler WHERE id = ANY($1::TEXT[])", ids)
+        .execute(&mut **database)
+        .await?
+        .rows_affected();
+
+    Ok(rows_affected)
+}
+
+pub async fn delete_postgates(ids: &[String], database: &mut PgTransaction<'_>) -> Result<u64> {
+    if ids.is_empty() {
+        return Ok(0);
+    }
+
+    let mut rows_affected = sqlx::query!(
+        r"DELETE FROM postgate_detached_embedding WHERE postgate_id = ANY($1::TEXT[])",
+        ids
+    )
+    .execute(&mut **database)
+    .await?
+    .rows_affected();
+    rows_affected += sqlx::query!(r"DELETE FROM postgate WHERE id = ANY($1::TEXT[])", ids)
+        .execute(&mut **database)
+        .await?
+        .rows_affected();
+
+    Ok(rows_affected)
+}
+
+pub async fn delete_actordeclarations(
+    ids: &[String],
+    database: &mut PgTransaction<'_>,
+) -> Result<u64> {
+    if ids.is_empty() {
+        return Ok(0);
+    }
+
+    let rows_affected = sqlx::query!(
+        r"DELETE FROM chat_actor_declaration WHERE id = ANY($1::TEXT[])",
+        ids
+    )
+    .execute(&mut **database)
+    .await?
+    .rows_affected();
+
+    Ok(rows_affected)
+}
+
+pub async fn delete_threadgates(ids: &[String], database: &mut PgTransaction<'_>) -> Result<u64> {
+    if ids.is_empty() {
+        return Ok(0);
+    }
+
+    let mut rows_affected = sqlx::query!(
+        r"DELETE FROM threadgate_allow WHERE threadgate_id = ANY($1::TEXT[])",
+        ids
+    )
+    .execute(&mut **database)
+    .await?
+    .rows_affected();
+    rows_affected += sqlx::query!(r"DELETE FROM threadgate WHERE id = ANY($1::TEXT[])", ids)
+        .execute(&mut **database)
+        .await?
+        .rows_affected();
+
+    Ok(rows_affected)
+}
+
+pub async fn delete_lists(ids: &[String], database: &mut PgTransaction<'_>) -> Result<u64> {
+    if ids.is_empty() {
+        return Ok(0);
+    }
+
+    let mut rows_affected = sqlx::query!(
+        r"DELETE FROM list_label WHERE list_id = ANY($1::TEXT[])",
+        ids
+    )
+    .execute(&mut **database)
+    .await?
+    .rows_affected();
+    rows_affected += sqlx::query!(r"DELETE FROM list WHERE id = ANY($1::TEXT[])", ids)
+        .execute(&mut **database)
+        .await?
+        .rows_affected();
+
+    Ok(rows_affected)
+}
+
+/// Delete posts together with all rows that belong to them
+///
+/// Rows referencing the post are deleted first
+pub async fn delete_posts(ids: &[String], database: &mut PgTransaction<'_>) -> Result<u64> {
+    if ids.is_empty() {
+        return Ok(0);
+    }
+
+    let mut rows_affected = 0;
+    rows_affected += sqlx::query!(
+        r"DELETE FROM post_label WHERE post_id = ANY($1::TEXT[])",
+        ids
+    )
+    .execute(&mut **database)
+    .await?
+    .rows_affected();
+    rows_affected += sqlx::query!(
+        r"DELETE FROM post_lang WHERE post_id = ANY($1::TEXT[])",
+        ids
+    )
+    .execute(&mut **database)
+    .await?
+    .rows_affected();
+    rows_affected += sqlx::query!(
+        r"DELETE FROM post_link WHERE post_id = ANY($1::TEXT[])",
+        ids
+    )
+    .execute(&mut **database)
+    .await?
+    .rows_affected();
+    rows_affected += sqlx::query!(r"DELETE FROM post_tag WHERE post_id = ANY($1::TEXT[])", ids)
+        .execute(&mut **database)
+        .await?
+        .rows_affected();
+    rows_affected += sqlx::query!(
+        r"DELETE FROM post_image WHERE post_id = ANY($1::TEXT[])",
+        ids
+    )
+    .execute(&mut **database)
+    .await?
+    .rows_affected();
+    rows_affected += sqlx::query!(
+        r"DELETE FROM post_external_embed WHERE post_id = ANY($1::TEXT[])",
+        ids
+    )
+    .execute(&mut **database)
+    .await?
+    .rows_affected();
+    rows_affected += sqlx::query!(
+        r"DELETE FROM post_mention WHERE post_id = ANY($1::TEXT[])",
+        ids
+    )
+    .execute(&mut **database)
+    .await?
+    .rows_affected();
+    rows_affected += sqlx::query!(
+        r"DELETE FROM posts_relation WHERE post_id = ANY($1::TEXT[])",
+        ids
+    )
+    .execute(&mut **database)
+    .await?
+    .rows_affected();
+    rows_affected += sqlx::query!(
+        r"DELETE FROM replies_relation WHERE post_id = ANY($1::TEXT[])",
+        ids
+    )
+    .execute(&mut **database)
+    .await?
+    .rows_affected();
+    rows_affected += sqlx::query!(
+        r"DELETE FROM replyto_relation WHERE source_post_id = ANY($1::TEXT[])",
+        ids
+    )
+    .execute(&mut **database)
+    .await?
+    .rows_affected();
+    rows_affected += sqlx::query!(
+        r"DELETE FROM quotes_relation WHERE source_post_id = ANY($1::TEXT[])",
+        ids
+    )
+    .execute(&mut **database)
+    .await?
+    .rows_affected();
+    rows_affected += sqlx::query!(r"DELETE FROM post WHERE id = ANY($1::TEXT[])", ids)
+        .execute(&mut **database)
+        .await?
+        .rows_affected();
+
+    Ok(rows_affected)
+}
+
+pub async fn upsert_jetstream_identity_event(
+    update: &WithId<JetstreamIdentityEvent>,
+    database: &mut PgTransaction<'_>,
+) -> Result<u64> {
+    let rows_affected = sqlx::query!(
+        r"
+INSERT INTO jetstream_identity_event (
+    id,
+    time_us,
+    handle,
+    seq,
+    time
+) VALUES (
+    $1,
+    $2,
+    $3,
+    $4,
+    $5
+) ON CONFLICT (id) DO UPDATE SET
+    time_us = EXCLUDED.time_us,
+    handle = EXCLUDED.handle,
+    seq = EXCLUDED.seq,
+    time = EXCLUDED.time",
+        update.id,
+        update.data.time_us,
         update.data.handle,
         update.data.seq,
         update.data.time
@@ -1001,3 +1973,367 @@ INSERT INTO jetstream_identity_event (
 
     return Ok(rows_affected);
 }
+
+pub async fn upsert_jetstream_account_event(
+    update: &WithId<JetstreamAccountEvent>,
+    database: &mut PgTransaction<'_>,
+) -> Result<u64> {
+    let rows_affected = sqlx::query!(
+        r"
+INSERT INTO jetstream_account_event (
+    id,
+    time_us,
+    active,
+    seq,
+    time
+) VALUES (
+    $1::TEXT,
+    $2::BIGINT,
+    $3::BOOLEAN,
+    $4::BIGINT,
+    $5::TEXT
+) ON CONFLICT (id) DO UPDATE SET
+    time_us = EXCLUDED.time_us,
+    active = EXCLUDED.active,
+    seq = EXCLUDED.seq,
+    time = EXCLUDED.time",
+        update.id,
+        update.data.time_us,
+        update.data.active,
+        update.data.seq,
+        update.data.time
+    )
+    .execute(&mut **database)
+    .await?
+    .rows_affected();
+
+    Ok(rows_affected)
+}
+
+/// Set the account status of a DID, if the DID is already known
+pub async fn update_did_status(
+    did_id: &str,
+    active: bool,
+    status_changed_at: DateTime<Utc>,
+    database: &mut PgTransaction<'_>,
+) -> Result<u64> {
+    let rows_affected = sqlx::query!(
+        r"
+UPDATE did SET
+    active = $2::BOOLEAN,
+    status_changed_at = $3::TIMESTAMPTZ
+WHERE id = $1::TEXT",
+        did_id,
+        active,
+        status_changed_at
+    )
+    .execute(&mut **database)
+    .await?
+    .rows_affected();
+
+    Ok(rows_affected)
+}
+
+/// Set the handle of a DID, if the DID is already known
+pub async fn update_did_handle(
+    did_id: &str,
+    handle: &str,
+    database: &mut PgTransaction<'_>,
+) -> Result<u64> {
+    let rows_affected = sqlx::query!(
+        r"UPDATE did SET handle = $2::TEXT WHERE id = $1::TEXT",
+        did_id,
+        handle
+    )
+    .execute(&mut **database)
+    .await?
+    .rows_affected();
+
+    Ok(rows_affected)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::big_update::types::{BskyPostVideo, BskyPostVideoBlob};
+    use sqlx::PgPool;
+    use std::time::Instant;
+
+    fn backfills(count: usize) -> Vec<WithId<BskyLatestBackfill>> {
+        (0..count)
+            .map(|i| {
+                let key = format!("plc_{:024}", i);
+                WithId {
+                    id: key.clone(),
+                    data: BskyLatestBackfill {
+                        of: RecordId::from_table_key("did", key),
+                        at: (i % 2 == 0).then(|| {
+                            DateTime::from_timestamp(1_700_000_000 + i as i64, 0).unwrap()
+                        }),
+                        rev: None,
+                    },
+                }
+            })
+            .collect()
+    }
+
+    /// All rows of a table ordered by id
+    async fn table_contents(
+        table: &str,
+        database: &mut PgTransaction<'_>,
+    ) -> Result<Vec<serde_json::Value>> {
+        Ok(sqlx::query_scalar(&format!(
+            r#"SELECT to_jsonb(t) FROM "{table}" t ORDER BY id"#
+        ))
+        .fetch_all(&mut **database)
+        .await?)
+    }
+
+    fn did(i: usize) -> RecordId {
+        RecordId::from_table_key("did", format!("plc_{:024}", i % 1000))
+    }
+
+    fn time(i: usize) -> DateTime<Utc> {
+        DateTime::from_timestamp(1_700_000_000 + i as i64, 0).unwrap()
+    }
+
+    fn follows(count: usize) -> Vec<WithId<BskyFollow>> {
+        (0..count)
+            .map(|i| WithId {
+                id: format!("{}_follow{}", did(i).key(), i),
+                data: BskyFollow {
+                    from: did(i),
+                    to: did(i + 1),
+                    created_at: time(i),
+                },
+            })
+            .collect()
+    }
+
+    fn likes(count: usize) -> Vec<WithId<BskyLike>> {
+        let tables = ["post", "feed", "list", "starterpack", "labeler"];
+        (0..count)
+            .map(|i| WithId {
+                id: format!("{}_like{}", did(i).key(), i),
+                data: BskyLike {
+                    from: did(i),
+                    to: RecordId::from_table_key(tables[i % tables.len()], format!("target{}", i)),
+                    created_at: time(i),
+                },
+            })
+            .collect()
+    }
+
+    fn reposts(count: usize) -> Vec<WithId<BskyRepost>> {
+        (0..count)
+            .map(|i| WithId {
+                id: format!("{}_repost{}", did(i).key(), i),
+                data: BskyRepost {
+                    from: did(i),
+                    to: RecordId::from_table_key("post", format!("post{}", i + 1)),
+                    created_at: time(i),
+                },
+            })
+            .collect()
+    }
+
+    fn posts(count: usize) -> Vec<WithId<BskyPost>> {
+        (0..count)
+            .map(|i| WithId {
+                id: format!("{}_post{}", did(i).key(), i),
+                data: BskyPost {
+                    author: did(i),
+                    bridgy_original_url: (i % 3 == 0).then(|| format!("https://example.com/{}", i)),
+                    created_at: time(i),
+                    images: None,
+                    labels: None,
+                    langs: None,
+                    links: None,
+                    mentions: None,
+                    parent: (i % 2 == 1)
+                        .then(|| RecordId::from_table_key("post", format!("post{}", i - 1))),
+                    record: None,
+                    root: (i % 2 == 1)
+                        .then(|| RecordId::from_table_key("post", format!("post{}", i - 1))),
+                    tags: None,
+                    text: format!("Post number {} with \"quotes\", tabs\tand ünïcödé", i),
+                    via: (i % 5 == 0).then(|| "bridgy".to_string()),
+                    video: (i % 4 == 0).then(|| BskyPostVideo {
+                        alt: Some("a video".to_string()),
+                        aspect_ratio: None,
+                        blob: BskyPostVideoBlob {
+                            cid: format!("bafy{}", i),
+                            media_type: "video/mp4".to_string(),
+                            size: i as u64,
+                        },
+                        captions: None,
+                    }),
+                    external: None,
+                    extra_data: (i % 7 == 0).then(|| r#"{"a":1}"#.to_string()),
+                },
+            })
+            .collect()
+    }
+
+    /// Insert the first ten rows and then all rows with both paths and compare the table contents
+    macro_rules! assert_same_rows {
+        ($db:expr, $table:expr, $rows:expr, $copy:ident, $unnest:ident) => {{
+            let rows = $rows;
+
+            let mut transaction = $db.begin().await?;
+            assert_eq!($unnest(&rows[..10], &mut transaction).await?, 10);
+            assert_eq!($unnest(&rows, &mut transaction).await?, 90);
+            let unnest = table_contents($table, &mut transaction).await?;
+            transaction.rollback().await?;
+
+            let mut transaction = $db.begin().await?;
+            assert_eq!($copy(&rows[..10], &mut transaction).await?, 10);
+            assert_eq!($copy(&rows, &mut transaction).await?, 90);
+            let copy = table_contents($table, &mut transaction).await?;
+            transaction.rollback().await?;
+
+            assert_eq!(copy.len(), 100);
+            assert_eq!(copy, unnest);
+        }};
+    }
+
+    #[sqlx::test]
+    #[ignore = "needs a postgres server in DATABASE_URL"]
+    async fn copy_and_unnest_insert_the_same_backfills(db: PgPool) -> Result<()> {
+        assert_same_rows!(
+            db,
+            "latest_backfill",
+            backfills(100),
+            copy_latest_backfills,
+            unnest_latest_backfills
+        );
+        Ok(())
+    }
+
+    #[sqlx::test]
+    #[ignore = "needs a postgres server in DATABASE_URL"]
+    async fn copy_and_unnest_insert_the_same_records(db: PgPool) -> Result<()> {
+        assert_same_rows!(db, "post", posts(100), copy_posts, unnest_posts);
+        assert_same_rows!(db, "follow", follows(100), copy_follows, unnest_follows);
+        assert_same_rows!(db, "like", likes(100), copy_likes, unnest_likes);
+        assert_same_rows!(db, "repost", reposts(100), copy_reposts, unnest_reposts);
+        Ok(())
+    }
+
+    #[sqlx::test]
+    #[ignore = "needs a postgres server in DATABASE_URL"]
+    async fn concurrent_backfill_upserts_keep_the_newest_row(db: PgPool) -> Result<()> {
+        const ROUNDS: i64 = 50;
+        let writer = |task: i64| {
+            let db = db.clone();
+            tokio::spawn(async move {
+                for round in 0..ROUNDS {
+                    let mut rows = backfills(200);
+                    for row in rows.iter_mut() {
+                        let at = 1_700_000_000 + round * 2 + task;
+                        row.data.at = DateTime::from_timestamp(at, 0);
+                        row.data.rev = Some(format!("{}-{}", task, round));
+                    }
+                    // The rows are locked in id order, whatever order they come in
+                    if task == 0 {
+                        rows.reverse();
+                    }
+                    let mut transaction = db.begin().await?;
+                    upsert_latest_backfills(&rows, &mut transaction).await?;
+                    transaction.commit().await?;
+                }
+                anyhow::Ok(())
+            })
+        };
+        let (first, second) = tokio::join!(writer(0), writer(1));
+        first??;
+        second??;
+
+        let rows: Vec<(Option<DateTime<Utc>>, Option<String>)> =
+            sqlx::query_as("SELECT at, last_known_rev FROM latest_backfill")
+                .fetch_all(&db)
+                .await?;
+        assert_eq!(rows.len(), 200);
+        let newest = DateTime::from_timestamp(1_700_000_000 + (ROUNDS - 1) * 2 + 1, 0);
+        let newest_rev = format!("1-{}", ROUNDS - 1);
+        for (at, rev) in rows {
+            assert_eq!(at, newest);
+            assert_eq!(rev.as_ref(), Some(&newest_rev));
+        }
+        Ok(())
+    }
+
+    /// Time both insert paths of a table
+    macro_rules! bench_inserts {
+        ($db:expr, $table:expr, $rows:ident, $copy:ident, $unnest:ident) => {{
+            for count in [1_000, 5_000, 20_000, 300_000] {
+                let rows = $rows(count);
+                let mut transaction = $db.begin().await?;
+                let start = Instant::now();
+                $unnest(&rows, &mut transaction).await?;
+                let unnest = start.elapsed();
+                transaction.rollback().await?;
+
+                let mut transaction = $db.begin().await?;
+                let start = Instant::now();
+                $copy(&rows, &mut transaction).await?;
+                let copy = start.elapsed();
+                transaction.rollback().await?;
+
+                println!(
+                    "{:<15} {:>7} rows: UNNEST {:>9.1?} COPY {:>9.1?}",
+                    $table, count, unnest, copy
+                );
+            }
+        }};
+    }
+
+    /// Time both insert paths, run with `cargo test --lib bench_ -- --ignored --nocapture`
+    #[sqlx::test]
+    #[ignore = "benchmark, needs a postgres server in DATABASE_URL"]
+    async fn bench_copy_and_unnest_inserts(db: PgPool) -> Result<()> {
+        bench_inserts!(
+            db,
+            "latest_backfill",
+            backfills,
+            copy_latest_backfills,
+            unnest_latest_backfills
+        );
+        bench_inserts!(db, "post", posts, copy_posts, unnest_posts);
+        bench_inserts!(db, "follow", follows, copy_follows, unnest_follows);
+        bench_inserts!(db, "like", likes, copy_likes, unnest_likes);
+        bench_inserts!(db, "repost", reposts, copy_reposts, unnest_reposts);
+        Ok(())
+    }
+
+    /// Time inserting posts with and without `--enable-post-search`, run like [bench_copy_and_unnest_inserts]
+    #[sqlx::test]
+    #[ignore = "benchmark, needs a postgres server in DATABASE_URL"]
+    async fn bench_post_search_overhead(db: PgPool) -> Result<()> {
+        for count in [100_000, 300_000] {
+            // Half of the posts get a stemmer, the other half only the simple config
+            let mut rows = posts(count);
+            for (i, row) in rows.iter_mut().enumerate() {
+                row.data.langs = ["en", "de"].get(i % 4).map(|lang| vec![lang.to_string()]);
+            }
+
+            let mut times = Vec::new();
+            for search in ["off", "on"] {
+                let mut transaction = db.begin().await?;
+                sqlx::query(&format!("SET LOCAL indexer.post_search = '{}'", search))
+                    .execute(&mut *transaction)
+                    .await?;
+                let start = Instant::now();
+                unnest_posts(&rows, &mut transaction).await?;
+                times.push(start.elapsed());
+                transaction.rollback().await?;
+            }
+
+            println!(
+                "post {:>7} rows: search off {:>9.1?} search on {:>9.1?}",
+                count, times[0], times[1]
+            );
+        }
+        Ok(())
+    }
+}
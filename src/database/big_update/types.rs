@@ -24,6 +24,22 @@ pub struct BskyDid {
     pub pinned_post: Option<RecordId>,
     #[serde(alias = "extraData")]
     pub extra_data: Option<String>,
+    /// Handle of the DID. Filled from jetstream identity events if not known
+    pub handle: Option<String>,
+    /// The DID itself, the id is only its key
+    pub did_text: String,
+}
+
+/// Database struct for a blob
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BskyBlob {
+    pub cid: String,
+    #[serde(rename = "mediaType")]
+    pub media_type: String,
+    /// Untyped legacy blob refs do not contain a size
+    pub size: Option<u64>,
+    #[serde(rename = "firstSeenAt")]
+    pub first_seen_at: DateTime<Utc>,
 }
 
 /// Database struct for a jetstream cursor
@@ -71,6 +87,7 @@ pub struct BskyPost {
     pub text: String,
     pub via: Option<String>,
     pub video: Option<BskyPostVideo>,
+    pub external: Option<BskyPostExternal>,
     #[serde(rename = "extraData")]
     pub extra_data: Option<String>,
 }
@@ -84,6 +101,15 @@ pub struct BskyPostImage {
     pub aspect_ratio: Option<BskyPostMediaAspectRatio>,
 }
 
+/// Database struct for the preview of an external link embedded in a post
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BskyPostExternal {
+    pub uri: String,
+    pub title: String,
+    pub description: String,
+    pub thumb: Option<RecordId>,
+}
+
 /// Database struct for a bluesky post video
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct BskyPostVideo {
@@ -95,7 +121,11 @@ pub struct BskyPostVideo {
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct BskyPostVideoCaption {}
+pub struct BskyPostVideoCaption {
+    pub lang: String,
+    /// Cid of the caption file, also used as the key in the blob table
+    pub blob: String,
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct BskyPostVideoBlob {
@@ -141,11 +171,89 @@ pub struct BskyList {
     pub extra_data: Option<String>,
 }
 
+/// Database struct for a bluesky starterpack
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BskyStarterpack {
+    pub creator: RecordId,
+    pub name: String,
+    pub description: Option<String>,
+    pub list: RecordId,
+    pub feeds: Option<Vec<RecordId>>,
+    #[serde(rename = "createdAt")]
+    pub created_at: DateTime<Utc>,
+    #[serde(rename = "extraData")]
+    pub extra_data: Option<String>,
+}
+
+/// Database struct for a bluesky postgate
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BskyPostgate {
+    pub post: RecordId,
+    #[serde(rename = "createdAt")]
+    pub created_at: DateTime<Utc>,
+    /// Whether the post may not be embedded by other posts
+    #[serde(rename = "disableEmbedding")]
+    pub disable_embedding: bool,
+    /// Posts whose embedding of this post was detached by the author
+    #[serde(rename = "detachedEmbeddings")]
+    pub detached_embeddings: Option<Vec<RecordId>>,
+    #[serde(rename = "extraData")]
+    pub extra_data: Option<String>,
+}
+
+/// Database struct for a bluesky chat actor declaration
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BskyActorDeclaration {
+    pub did: RecordId,
+    #[serde(rename = "allowIncoming")]
+    pub allow_incoming: String,
+    #[serde(rename = "extraData")]
+    pub extra_data: Option<String>,
+}
+
+/// Database struct for a bluesky labeler service
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BskyLabelerService {
+    pub did: RecordId,
+    #[serde(rename = "createdAt")]
+    pub created_at: DateTime<Utc>,
+    #[serde(rename = "labelValues")]
+    pub label_values: Vec<String>,
+    /// JSON encoded custom label value definitions
+    #[serde(rename = "labelValueDefinitions")]
+    pub label_value_definitions: Option<String>,
+    #[serde(rename = "extraData")]
+    pub extra_data: Option<String>,
+}
+
+/// Database struct for a bluesky threadgate
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BskyThreadgate {
+    pub post: RecordId,
+    #[serde(rename = "createdAt")]
+    pub created_at: DateTime<Utc>,
+    /// `None` allows everyone to reply, an empty list allows nobody to reply
+    pub allow: Option<Vec<BskyThreadgateAllow>>,
+    #[serde(rename = "extraData")]
+    pub extra_data: Option<String>,
+}
+
+/// Database struct for a single allow rule of a threadgate
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BskyThreadgateAllow {
+    /// One of `mention`, `follower`, `following` or `list`
+    pub rule: String,
+    /// Only set for `list` rules
+    pub list: Option<RecordId>,
+}
+
 #[skip_serializing_none]
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct BskyLatestBackfill {
     pub of: RecordId,
     pub at: Option<DateTime<Utc>>,
+    /// Rev of the repo at the time of the backfill
+    pub rev: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
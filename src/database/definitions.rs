@@ -18,6 +18,13 @@ pub struct JetstreamCursor {
     pub time_us: i64,
 }
 
+/// Database struct for a cached DID document
+#[derive(Debug, Clone)]
+pub struct CachedDidDocument {
+    pub pds_endpoint: String,
+    pub signing_key: Option<String>,
+}
+
 /// Database struct for a jetstream account event
 #[derive(Debug, Serialize, Deserialize)]
 pub struct JetstreamAccountEvent {
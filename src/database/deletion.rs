@@ -0,0 +1,298 @@
+use anyhow::Result;
+use sqlx::PgPool;
+use tracing::info;
+
+/// Delete everything the DID with the key `did_key` owns
+///
+/// Used when an account is deleted or taken down. Child rows are deleted before the rows they reference. Records that
+/// have no owner column are found by their id, which ends with `_<did key>`. The repo is marked as unbackfillable with
+/// `reason`, so it is not indexed again. Returns the number of deleted rows.
+pub async fn delete_did_cascade(db: &PgPool, did_key: &str, reason: &str) -> Result<u64> {
+    let mut transaction = db.begin().await?;
+    let mut rows_affected = 0;
+
+    // Posts and everything attached to them
+    rows_affected += sqlx::query!(
+        "DELETE FROM post_label WHERE post_id IN (SELECT id FROM post WHERE author = $1::TEXT)",
+        did_key
+    )
+    .execute(&mut *transaction)
+    .await?
+    .rows_affected();
+    rows_affected += sqlx::query!(
+        "DELETE FROM post_lang WHERE post_id IN (SELECT id FROM post WHERE author = $1::TEXT)",
+        did_key
+    )
+    .execute(&mut *transaction)
+    .await?
+    .rows_affected();
+    rows_affected += sqlx::query!(
+        "DELETE FROM post_link WHERE post_id IN (SELECT id FROM post WHERE author = $1::TEXT)",
+        did_key
+    )
+    .execute(&mut *transaction)
+    .await?
+    .rows_affected();
+    rows_affected += sqlx::query!(
+        "DELETE FROM post_tag WHERE post_id IN (SELECT id FROM post WHERE author = $1::TEXT)",
+        did_key
+    )
+    .execute(&mut *transaction)
+    .await?
+    .rows_affected();
+    rows_affected += sqlx::query!(
+        "DELETE FROM post_image WHERE post_id IN (SELECT id FROM post WHERE author = $1::TEXT)",
+        did_key
+    )
+    .execute(&mut *transaction)
+    .await?
+    .rows_affected();
+    rows_affected += sqlx::query!("DELETE FROM post_external_embed WHERE post_id IN (SELECT id FROM post WHERE author = $1::TEXT)", did_key)
+        .execute(&mut *transaction)
+        .await?
+        .rows_affected();
+    rows_affected += sqlx::query!(
+        "DELETE FROM post_mention WHERE post_id IN (SELECT id FROM post WHERE author = $1::TEXT)",
+        did_key
+    )
+    .execute(&mut *transaction)
+    .await?
+    .rows_affected();
+    rows_affected += sqlx::query!("DELETE FROM quotes_relation WHERE source_post_id IN (SELECT id FROM post WHERE author = $1::TEXT)", did_key)
+        .execute(&mut *transaction)
+        .await?
+        .rows_affected();
+    rows_affected += sqlx::query!("DELETE FROM replyto_relation WHERE source_post_id IN (SELECT id FROM post WHERE author = $1::TEXT)", did_key)
+        .execute(&mut *transaction)
+        .await?
+        .rows_affected();
+    rows_affected += sqlx::query!(
+        "DELETE FROM posts_relation WHERE did_id = $1::TEXT",
+        did_key
+    )
+    .execute(&mut *transaction)
+    .await?
+    .rows_affected();
+    rows_affected += sqlx::query!(
+        "DELETE FROM replies_relation WHERE did_id = $1::TEXT",
+        did_key
+    )
+    .execute(&mut *transaction)
+    .await?
+    .rows_affected();
+    rows_affected += sqlx::query!("DELETE FROM threadgate_allow WHERE threadgate_id IN (SELECT id FROM threadgate WHERE right(id, length($1::TEXT) + 1) = '_' || $1::TEXT)", did_key)
+        .execute(&mut *transaction)
+        .await?
+        .rows_affected();
+    rows_affected += sqlx::query!(
+        "DELETE FROM threadgate WHERE right(id, length($1::TEXT) + 1) = '_' || $1::TEXT",
+        did_key
+    )
+    .execute(&mut *transaction)
+    .await?
+    .rows_affected();
+    rows_affected += sqlx::query!("DELETE FROM postgate_detached_embedding WHERE postgate_id IN (SELECT id FROM postgate WHERE right(id, length($1::TEXT) + 1) = '_' || $1::TEXT)", did_key)
+        .execute(&mut *transaction)
+        .await?
+        .rows_affected();
+    rows_affected += sqlx::query!(
+        "DELETE FROM postgate WHERE right(id, length($1::TEXT) + 1) = '_' || $1::TEXT",
+        did_key
+    )
+    .execute(&mut *transaction)
+    .await?
+    .rows_affected();
+    rows_affected += sqlx::query!("DELETE FROM post WHERE author = $1::TEXT", did_key)
+        .execute(&mut *transaction)
+        .await?
+        .rows_affected();
+
+    // Graph records
+    rows_affected += sqlx::query!(
+        "DELETE FROM follow WHERE follower_did_id = $1::TEXT",
+        did_key
+    )
+    .execute(&mut *transaction)
+    .await?
+    .rows_affected();
+    rows_affected += sqlx::query!(r#"DELETE FROM "like" WHERE user_id = $1::TEXT"#, did_key)
+        .execute(&mut *transaction)
+        .await?
+        .rows_affected();
+    rows_affected += sqlx::query!("DELETE FROM repost WHERE did_id = $1::TEXT", did_key)
+        .execute(&mut *transaction)
+        .await?
+        .rows_affected();
+    rows_affected += sqlx::query!(
+        r#"DELETE FROM "block" WHERE blocker_did_id = $1::TEXT"#,
+        did_key
+    )
+    .execute(&mut *transaction)
+    .await?
+    .rows_affected();
+    rows_affected += sqlx::query!(
+        "DELETE FROM listblock WHERE blocker_did_id = $1::TEXT",
+        did_key
+    )
+    .execute(&mut *transaction)
+    .await?
+    .rows_affected();
+    rows_affected += sqlx::query!(
+        "DELETE FROM listitem WHERE right(id, length($1::TEXT) + 1) = '_' || $1::TEXT",
+        did_key
+    )
+    .execute(&mut *transaction)
+    .await?
+    .rows_affected();
+
+    // Lists, starterpacks, feeds and labelers
+    rows_affected += sqlx::query!("DELETE FROM starterpack_feed WHERE starterpack_id IN (SELECT id FROM starterpack WHERE creator_did_id = $1::TEXT)", did_key)
+        .execute(&mut *transaction)
+        .await?
+        .rows_affected();
+    rows_affected += sqlx::query!(
+        "DELETE FROM starterpack WHERE creator_did_id = $1::TEXT",
+        did_key
+    )
+    .execute(&mut *transaction)
+    .await?
+    .rows_affected();
+    rows_affected += sqlx::query!("DELETE FROM list_label WHERE list_id IN (SELECT id FROM list WHERE right(id, length($1::TEXT) + 1) = '_' || $1::TEXT)", did_key)
+        .execute(&mut *transaction)
+        .await?
+        .rows_affected();
+    rows_affected += sqlx::query!(
+        "DELETE FROM list WHERE right(id, length($1::TEXT) + 1) = '_' || $1::TEXT",
+        did_key
+    )
+    .execute(&mut *transaction)
+    .await?
+    .rows_affected();
+    rows_affected += sqlx::query!("DELETE FROM feed WHERE author = $1::TEXT", did_key)
+        .execute(&mut *transaction)
+        .await?
+        .rows_affected();
+    rows_affected += sqlx::query!("DELETE FROM labeler_label_value WHERE labeler_id IN (SELECT id FROM labeler WHERE did_id = $1::TEXT)", did_key)
+        .execute(&mut *transaction)
+        .await?
+        .rows_affected();
+    rows_affected += sqlx::query!("DELETE FROM labeler WHERE did_id = $1::TEXT", did_key)
+        .execute(&mut *transaction)
+        .await?
+        .rows_affected();
+    rows_affected += sqlx::query!(
+        "DELETE FROM chat_actor_declaration WHERE did_id = $1::TEXT",
+        did_key
+    )
+    .execute(&mut *transaction)
+    .await?
+    .rows_affected();
+
+    // The DID itself
+    rows_affected += sqlx::query!("DELETE FROM did_label WHERE did_id = $1::TEXT", did_key)
+        .execute(&mut *transaction)
+        .await?
+        .rows_affected();
+    rows_affected += sqlx::query!("DELETE FROM did_document WHERE id = $1::TEXT", did_key)
+        .execute(&mut *transaction)
+        .await?
+        .rows_affected();
+    rows_affected += sqlx::query!(
+        "DELETE FROM backfill_failure WHERE of_did_id = $1::TEXT",
+        did_key
+    )
+    .execute(&mut *transaction)
+    .await?
+    .rows_affected();
+    rows_affected += sqlx::query!("DELETE FROM did WHERE id = $1::TEXT", did_key)
+        .execute(&mut *transaction)
+        .await?
+        .rows_affected();
+
+    sqlx::query!(
+        "UPDATE latest_backfill SET unbackfillable_reason = $2::TEXT WHERE of_did_id = $1::TEXT",
+        did_key,
+        reason
+    )
+    .execute(&mut *transaction)
+    .await?;
+    transaction.commit().await?;
+
+    info!(target: "indexer", "Purged {} rows of {} because the account was {}", rows_affected, did_key, reason);
+    Ok(rows_affected)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::delete_did_cascade;
+    use sqlx::PgPool;
+
+    async fn count(db: &PgPool, query: &str) -> i64 {
+        sqlx::query_scalar(query).fetch_one(db).await.unwrap()
+    }
+
+    #[sqlx::test]
+    #[ignore = "needs a postgres server in DATABASE_URL"]
+    async fn purging_a_did_removes_its_posts_and_follows(db: PgPool) -> anyhow::Result<()> {
+        sqlx::query(
+            "INSERT INTO did (id, seen_at) VALUES ('plc_purged', now()), ('plc_other', now())",
+        )
+        .execute(&db)
+        .await?;
+        sqlx::query(
+            "INSERT INTO post (id, author, created_at, text) VALUES
+                ('3abc_plc_purged', 'plc_purged', now(), 'gone'),
+                ('3abc_plc_other', 'plc_other', now(), 'kept')",
+        )
+        .execute(&db)
+        .await?;
+        sqlx::query("INSERT INTO post_lang (post_id, lang) VALUES ('3abc_plc_purged', 'en')")
+            .execute(&db)
+            .await?;
+        sqlx::query(
+            "INSERT INTO follow (id, follower_did_id, followed_did_id, created_at) VALUES
+                ('3def_plc_purged', 'plc_purged', 'plc_other', now()),
+                ('3def_plc_other', 'plc_other', 'plc_purged', now())",
+        )
+        .execute(&db)
+        .await?;
+        sqlx::query(
+            "INSERT INTO latest_backfill (id, of_did_id, at) VALUES ('plc_purged', 'plc_purged', now())",
+        )
+        .execute(&db)
+        .await?;
+
+        let deleted = delete_did_cascade(&db, "plc_purged", "deleted").await?;
+
+        assert_eq!(deleted, 4);
+        assert_eq!(
+            count(&db, "SELECT count(*) FROM post WHERE author = 'plc_purged'").await,
+            0
+        );
+        assert_eq!(count(&db, "SELECT count(*) FROM post_lang").await, 0);
+        assert_eq!(
+            count(
+                &db,
+                "SELECT count(*) FROM follow WHERE follower_did_id = 'plc_purged'"
+            )
+            .await,
+            0
+        );
+        assert_eq!(
+            count(&db, "SELECT count(*) FROM did WHERE id = 'plc_purged'").await,
+            0
+        );
+        // Records of other accounts stay, even if they reference the purged DID
+        assert_eq!(count(&db, "SELECT count(*) FROM post").await, 1);
+        assert_eq!(count(&db, "SELECT count(*) FROM follow").await, 1);
+        assert_eq!(
+            count(
+                &db,
+                "SELECT count(*) FROM latest_backfill WHERE unbackfillable_reason = 'deleted'"
+            )
+            .await,
+            1
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,177 @@
+use thiserror::Error;
+
+/// A failed database operation, classified by how it can be handled
+#[derive(Debug, Error)]
+pub enum DbError {
+    /// The transaction was chosen as the victim of a deadlock (40P01)
+    #[error("Deadlock detected: {0}")]
+    Deadlock(#[source] sqlx::Error),
+    /// The transaction conflicted with a concurrent transaction (40001)
+    #[error("Serialization failure: {0}")]
+    SerializationFailure(#[source] sqlx::Error),
+    /// A lock could not be acquired in time (55P03)
+    #[error("Lock not available: {0}")]
+    LockNotAvailable(#[source] sqlx::Error),
+    /// The connection to the database was lost
+    #[error("Connection failed: {0}")]
+    Connection(#[source] sqlx::Error),
+    /// No connection of the pool became free within `--db-acquire-timeout`
+    #[error("No database connection became available in time, the connection pool is exhausted. Consider raising --db-pool-size or --db-acquire-timeout: {0}")]
+    PoolTimedOut(#[source] sqlx::Error),
+    /// An integrity constraint like a unique or foreign key was violated (23xxx)
+    #[error("Constraint violation: {0}")]
+    ConstraintViolation(#[source] sqlx::Error),
+    #[error(transparent)]
+    Other(anyhow::Error),
+}
+
+impl DbError {
+    /// Whether the operation can succeed when it is tried again
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            DbError::Deadlock(_)
+                | DbError::SerializationFailure(_)
+                | DbError::LockNotAvailable(_)
+                | DbError::Connection(_)
+                | DbError::PoolTimedOut(_)
+        )
+    }
+}
+
+impl From<sqlx::Error> for DbError {
+    fn from(error: sqlx::Error) -> Self {
+        if matches!(error, sqlx::Error::PoolTimedOut) {
+            return DbError::PoolTimedOut(error);
+        }
+        if matches!(error, sqlx::Error::Io(_)) {
+            return DbError::Connection(error);
+        }
+        let code = match &error {
+            sqlx::Error::Database(database_error) => {
+                database_error.code().map(|code| code.into_owned())
+            }
+            _ => None,
+        };
+        match code.as_deref() {
+            Some("40P01") => DbError::Deadlock(error),
+            Some("40001") => DbError::SerializationFailure(error),
+            Some("55P03") => DbError::LockNotAvailable(error),
+            Some(code) if code.starts_with("23") => DbError::ConstraintViolation(error),
+            _ => DbError::Other(error.into()),
+        }
+    }
+}
+
+impl From<anyhow::Error> for DbError {
+    fn from(error: anyhow::Error) -> Self {
+        match error.downcast::<sqlx::Error>() {
+            Ok(error) => error.into(),
+            Err(error) => DbError::Other(error),
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod testing {
+    use sqlx::error::{DatabaseError, ErrorKind};
+    use std::{borrow::Cow, error::Error as StdError};
+
+    /// A database error that only has a SQLSTATE
+    #[derive(Debug, thiserror::Error)]
+    #[error("error with SQLSTATE {code}")]
+    struct FakeDatabaseError {
+        code: &'static str,
+    }
+
+    impl DatabaseError for FakeDatabaseError {
+        fn message(&self) -> &str {
+            "fake database error"
+        }
+
+        fn code(&self) -> Option<Cow<'_, str>> {
+            Some(Cow::Borrowed(self.code))
+        }
+
+        fn as_error(&self) -> &(dyn StdError + Send + Sync + 'static) {
+            self
+        }
+
+        fn as_error_mut(&mut self) -> &mut (dyn StdError + Send + Sync + 'static) {
+            self
+        }
+
+        fn into_error(self: Box<Self>) -> Box<dyn StdError + Send + Sync + 'static> {
+            self
+        }
+
+        fn kind(&self) -> ErrorKind {
+            ErrorKind::Other
+        }
+    }
+
+    /// A database error like postgres returns it for `code`
+    pub(crate) fn database_error(code: &'static str) -> sqlx::Error {
+        sqlx::Error::Database(Box::new(FakeDatabaseError { code }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{testing::database_error, DbError};
+
+    #[test]
+    fn conflicts_and_lost_connections_are_retried() {
+        for code in ["40001", "40P01", "55P03"] {
+            assert!(
+                DbError::from(database_error(code)).is_retryable(),
+                "{}",
+                code
+            );
+        }
+        assert!(DbError::from(sqlx::Error::PoolTimedOut).is_retryable());
+        let io_error = std::io::Error::from(std::io::ErrorKind::ConnectionReset);
+        assert!(DbError::from(sqlx::Error::Io(io_error)).is_retryable());
+    }
+
+    #[test]
+    fn sqlstates_map_to_their_variant() {
+        assert!(matches!(
+            DbError::from(database_error("40P01")),
+            DbError::Deadlock(_)
+        ));
+        assert!(matches!(
+            DbError::from(database_error("40001")),
+            DbError::SerializationFailure(_)
+        ));
+        assert!(matches!(
+            DbError::from(database_error("55P03")),
+            DbError::LockNotAvailable(_)
+        ));
+        assert!(matches!(
+            DbError::from(database_error("23505")),
+            DbError::ConstraintViolation(_)
+        ));
+        assert!(matches!(
+            DbError::from(database_error("42P01")),
+            DbError::Other(_)
+        ));
+        // Errors wrapped in anyhow are classified the same way
+        assert!(matches!(
+            DbError::from(anyhow::Error::from(database_error("40001"))),
+            DbError::SerializationFailure(_)
+        ));
+    }
+
+    #[test]
+    fn constraint_violations_are_permanent() {
+        for code in ["23505", "23503", "42P01"] {
+            assert!(
+                !DbError::from(database_error(code)).is_retryable(),
+                "{}",
+                code
+            );
+        }
+        assert!(!DbError::from(sqlx::Error::RowNotFound).is_retryable());
+    }
+}
@@ -1,8 +1,15 @@
-use super::big_update::create_big_update;
+use super::big_update::{
+    apply_account_event, apply_identity_event, create_big_delete, create_big_update,
+    options::options, record_ignored, BigUpdate,
+};
+use super::repo_indexer::read_car_blocks;
 use super::utils;
-use crate::websocket::events::{Commit, Kind};
-use anyhow::Result;
+use crate::firehose_consumer::events::{self as firehose, parse_time_us};
+use crate::websocket::events::{Commit, Identity, Kind};
+use anyhow::{Context, Result};
+use atrium_api::{record::KnownRecord, types::string::RecordKey};
 use sqlx::PgPool;
+use tracing::{trace, warn};
 
 /// Handle a new websocket event on the database
 pub async fn handle_event(database: PgPool, event: Kind) -> Result<()> {
@@ -22,16 +29,17 @@ pub async fn handle_event(database: PgPool, event: Kind) -> Result<()> {
                     record,
                     ..
                 } => {
-                    let big_update = create_big_update(did, did_key, collection, rkey, record)?;
+                    let mut big_update =
+                        create_big_update(did.clone(), did_key, collection, rkey, record);
+                    big_update.log_skipped(did.as_str());
                     big_update.apply(database.clone(), "jetstream").await?;
                 }
                 Commit::Delete {
-                    rev,
-                    collection,
-                    rkey,
+                    collection, rkey, ..
                 } => {
-                    // TODO: Implement delete
-                    // on_commit_event_delete(db, did, time_us, did_key, rev, collection, rkey).await?
+                    let mut big_update = create_big_delete(did.clone(), did_key, collection, rkey);
+                    big_update.log_skipped(did.as_str());
+                    big_update.apply(database.clone(), "jetstream").await?;
                 }
             }
         }
@@ -41,15 +49,7 @@ pub async fn handle_event(database: PgPool, event: Kind) -> Result<()> {
             identity,
         } => {
             let did_key = utils::did_to_key(did.as_str())?;
-            // let _: Option<Record> = db
-            //     .upsert(("jetstream_identity", did_key))
-            //     .content(JetstreamIdentityEvent {
-            //         time_us,
-            //         handle: identity.handle.to_string(),
-            //         seq: identity.seq,
-            //         time: identity.time,
-            //     })
-            //     .await?;
+            apply_identity_event(&database, did_key, time_us, identity).await?;
         }
         Kind::Key {
             did,
@@ -57,84 +57,203 @@ pub async fn handle_event(database: PgPool, event: Kind) -> Result<()> {
             account,
         } => {
             let did_key = utils::did_to_key(did.as_str())?;
-            // let _: Option<Record> = db
-            //     .upsert(("jetstream_account", did_key))
-            //     .content(JetstreamAccountEvent {
-            //         time_us,
-            //         active: account.active,
-            //         seq: account.seq,
-            //         time: account.time,
-            //     })
-            //     .await?;
+            apply_account_event(&database, did_key, time_us, account).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Handle a new firehose event on the database
+pub async fn handle_firehose_event(database: PgPool, event: firehose::Event) -> Result<()> {
+    match event {
+        firehose::Event::Commit(commit) => {
+            // Too big commits do not contain their blocks
+            if commit.too_big {
+                warn!(
+                    "Skipping too big commit {} of {}",
+                    commit.rev,
+                    commit.repo.as_str()
+                );
+                return Ok(());
+            }
+            let did = commit.repo.clone();
+            let mut big_update = create_firehose_update(commit)?;
+            big_update.log_skipped(did.as_str());
+            big_update.apply(database, "firehose").await?;
+        }
+        firehose::Event::Identity(identity) => {
+            // Identity events without a handle only ask to refresh the DID document
+            let Some(handle) = identity.handle else {
+                return Ok(());
+            };
+            let did_key = utils::did_to_key(identity.did.as_str())?;
+            let time_us = parse_time_us(&identity.time)?;
+            let identity = Identity {
+                did: identity.did,
+                handle,
+                seq: identity.seq as u64,
+                time: identity.time,
+            };
+            apply_identity_event(&database, did_key, time_us, identity).await?;
+        }
+        firehose::Event::Account(account) => {
+            let did_key = utils::did_to_key(account.did.as_str())?;
+            let time_us = parse_time_us(&account.time)?;
+            apply_account_event(&database, did_key, time_us, account).await?;
         }
     }
 
     Ok(())
 }
 
-// /// If the new commit is a delete, handle it
-// async fn on_commit_event_delete(
-//     db: &Surreal<Any>,
-//     did: Did,
-//     _time_us: u64,
-//     _did_key: String,
-//     _rev: String,
-//     collection: String,
-//     rkey: RecordKey,
-// ) -> Result<()> {
-//     utils::ensure_valid_rkey(rkey.to_string())?;
+/// Convert the operations of a firehose commit into a database update
+///
+/// Operations with an invalid path or without their block are recorded in `skipped`, so they do not fail the other
+/// operations of the commit
+pub fn create_firehose_update(commit: firehose::Commit) -> Result<BigUpdate> {
+    let blocks = read_car_blocks(&commit.blocks).context("Failed to read commit blocks")?;
+    let did_key = utils::did_to_key(commit.repo.as_str())?;
+
+    Ok(commit
+        .ops
+        .into_iter()
+        .fold(BigUpdate::default(), |mut big_update, op| {
+            let Some((collection, rkey)) = op.path.split_once('/') else {
+                let error = anyhow::anyhow!("Invalid record path {}", op.path);
+                big_update.merge(BigUpdate::skip(&op.path, "", "invalid_path", error));
+                return big_update;
+            };
+            if !options().indexes_collection(collection) {
+                return big_update;
+            }
+            let rkey = match RecordKey::new(rkey.to_string()) {
+                Ok(rkey) => rkey,
+                Err(e) => {
+                    let error = anyhow::anyhow!(e);
+                    big_update.merge(BigUpdate::skip(collection, rkey, "invalid_rkey", error));
+                    return big_update;
+                }
+            };
+            let update = match (op.action.as_str(), op.cid) {
+                ("create" | "update", Some(cid)) => {
+                    let Some(block) = blocks.get(&cid) else {
+                        let error = anyhow::anyhow!("Missing block for {}", op.path);
+                        big_update.merge(BigUpdate::skip(
+                            collection,
+                            rkey.as_str(),
+                            "missing_block",
+                            error,
+                        ));
+                        return big_update;
+                    };
+                    // Skip records we do not know
+                    let Ok(record) = serde_ipld_dagcbor::from_slice::<KnownRecord>(block) else {
+                        record_ignored(collection);
+                        trace!("Skipping unknown record {}", op.path);
+                        return big_update;
+                    };
+                    create_big_update(
+                        commit.repo.clone(),
+                        did_key.clone(),
+                        collection.to_string(),
+                        rkey,
+                        record,
+                    )
+                }
+                ("delete", _) => create_big_delete(
+                    commit.repo.clone(),
+                    did_key.clone(),
+                    collection.to_string(),
+                    rkey,
+                ),
+                _ => {
+                    warn!("Unexpected firehose operation {} on {}", op.action, op.path);
+                    return big_update;
+                }
+            };
+            big_update.merge(update);
+            big_update
+        }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::repo_indexer::car_file::testing::{block, car, cid_of};
+    use ipld_core::{cid::Cid, ipld::Ipld};
 
-//     let id = format!("{}_{}", rkey.as_str(), utils::did_to_key(did.as_str())?);
-//     match collection.as_str() {
-//         "app.bsky.graph.follow" => {
-//             delete_record(db, "follow", &id).await?;
-//         }
-//         "app.bsky.feed.repost" => {
-//             delete_record(db, "repost", &id).await?;
-//         }
-//         "app.bsky.feed.like" => {
-//             delete_record(db, "like", &id).await?;
-//         }
-//         "app.bsky.graph.block" => {
-//             delete_record(db, "block", &id).await?;
-//         }
-//         "app.bsky.graph.listblock" => {
-//             delete_record(db, "listblock", &id).await?;
-//         }
-//         "app.bsky.feed.post" => {
-//             for table in ["post", "posts", "replies", "replyto", "quotes"] {
-//                 delete_record(db, table, &id).await?;
-//             }
-//         }
-//         "app.bsky.graph.listitem" => {
-//             delete_record(db, "listitem", &id).await?;
-//         }
-//         "app.bsky.feed.threadgate" => {
-//             delete_record(db, "threadgate", &id).await?;
-//         }
-//         "app.bsky.feed.generator" => {
-//             delete_record(db, "feed", &id).await?;
-//         }
-//         "app.bsky.graph.list" => {
-//             delete_record(db, "list", &id).await?;
-//         }
-//         "app.bsky.feed.postgate" => {
-//             delete_record(db, "postgate", &id).await?;
-//         }
-//         "app.bsky.graph.starterpack" => {
-//             delete_record(db, "starterpack", &id).await?;
-//         }
-//         "app.bsky.labeler.service" => {
-//             delete_record(db, "labeler", &id).await?;
-//         }
-//         "chat.bsky.actor.declaration" => {
-//             delete_record(db, "chat_bsky_actor_declaration", &id).await?;
-//         }
-//         _ => {
-//             warn!(target: "indexer", "could not handle operation {} {} {} {}",
-//                 did.as_str(), "delete", collection, rkey.as_str());
-//         }
-//     }
+    fn map<const N: usize>(fields: [(&str, Ipld); N]) -> Ipld {
+        Ipld::Map(
+            fields
+                .into_iter()
+                .map(|(key, value)| (key.to_string(), value))
+                .collect(),
+        )
+    }
+
+    fn op(action: &str, path: &str, cid: Option<Cid>) -> Ipld {
+        map([
+            ("action", Ipld::String(action.to_string())),
+            ("path", Ipld::String(path.to_string())),
+            ("cid", cid.map_or(Ipld::Null, Ipld::Link)),
+        ])
+    }
+
+    #[test]
+    fn commit_frames_are_decoded_into_an_update() {
+        let (follow_cid, follow) = block(&map([
+            ("$type", Ipld::String("app.bsky.graph.follow".to_string())),
+            (
+                "createdAt",
+                Ipld::String("2024-01-01T00:00:00.000Z".to_string()),
+            ),
+            ("subject", Ipld::String("did:plc:followed".to_string())),
+        ]));
+        let missing = cid_of(b"not in the commit");
+        let body = map([
+            ("seq", Ipld::Integer(42)),
+            ("repo", Ipld::String("did:plc:follower".to_string())),
+            ("rev", Ipld::String("3lrev".to_string())),
+            ("time", Ipld::String("2024-01-01T00:00:00.000Z".to_string())),
+            (
+                "blocks",
+                Ipld::Bytes(car(follow_cid, &[(follow_cid, follow)])),
+            ),
+            (
+                "ops",
+                Ipld::List(vec![
+                    op("create", "app.bsky.graph.follow/3lfollow", Some(follow_cid)),
+                    op("create", "app.bsky.feed.like/3lmissing", Some(missing)),
+                    op("delete", "not-a-path", None),
+                    op("delete", "app.bsky.feed.like/3lunliked", None),
+                ]),
+            ),
+            ("tooBig", Ipld::Bool(false)),
+        ]);
+        let mut frame = serde_ipld_dagcbor::to_vec(&map([
+            ("op", Ipld::Integer(1)),
+            ("t", Ipld::String("#commit".to_string())),
+        ]))
+        .unwrap();
+        frame.extend(serde_ipld_dagcbor::to_vec(&body).unwrap());
 
-//     Ok(())
-// }
+        let Some(firehose::Event::Commit(commit)) = firehose::parse_frame(&frame).unwrap() else {
+            panic!("frame is not a commit");
+        };
+        let update = create_firehose_update(commit).unwrap();
+
+        assert_eq!(update.record_ids(), [("follow", "3lfollow_plc_follower")]);
+        let skipped = update
+            .skipped
+            .iter()
+            .map(|(collection, rkey, _)| format!("{}/{}", collection, rkey))
+            .collect::<Vec<_>>();
+        assert_eq!(skipped, ["app.bsky.feed.like/3lmissing", "not-a-path/"]);
+        let update = serde_json::to_value(&update).unwrap();
+        assert_eq!(
+            update["delete_likes"],
+            serde_json::json!(["3lunliked_plc_follower"])
+        );
+    }
+}
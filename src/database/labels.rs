@@ -0,0 +1,149 @@
+use std::collections::HashMap;
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use sqlx::PgPool;
+
+use super::utils::{at_uri_to_record_id, did_to_key};
+
+/// A label emitted by a labeler
+///
+/// https://atproto.com/specs/label
+#[derive(Debug, Clone)]
+pub struct Label {
+    /// DID of the labeler
+    pub src: String,
+    /// AT URI of the labeled record or the DID of the labeled account
+    pub uri: String,
+    /// Only set if the label applies to a specific version of the record
+    pub cid: Option<String>,
+    pub val: String,
+    /// The label retracts an earlier label with the same src, uri and val
+    pub neg: bool,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+/// Get the id of the row a label applies to, for example `<rkey>_<DID key>` for posts or the DID key for accounts
+///
+/// Returns None if the subject is not something we index
+pub fn label_subject_id(uri: &str) -> Option<String> {
+    if uri.starts_with("did:") {
+        return did_to_key(uri).ok();
+    }
+    at_uri_to_record_id(uri)
+        .ok()
+        .map(|record| record.key().to_string())
+}
+
+/// Insert labels, a newer label replaces the label of the same labeler with the same value on the same subject
+///
+/// Negating labels are stored with `neg` set, so a label counts if its latest row is not negated and not expired
+pub async fn insert_labels(db: &PgPool, labels: &[Label]) -> Result<u64> {
+    // A row can only be updated once per statement, so only the latest label per key is inserted
+    let mut latest: HashMap<(&str, &str, &str), &Label> = HashMap::new();
+    for label in labels {
+        let key = (label.src.as_str(), label.uri.as_str(), label.val.as_str());
+        match latest.get(&key) {
+            Some(existing) if existing.created_at > label.created_at => {}
+            _ => {
+                latest.insert(key, label);
+            }
+        }
+    }
+    if latest.is_empty() {
+        return Ok(0);
+    }
+    let labels = latest.into_values().collect::<Vec<_>>();
+
+    let srcs = labels.iter().map(|l| l.src.clone()).collect::<Vec<_>>();
+    let uris = labels.iter().map(|l| l.uri.clone()).collect::<Vec<_>>();
+    let subject_ids = labels
+        .iter()
+        .map(|l| label_subject_id(&l.uri))
+        .collect::<Vec<_>>();
+    let cids = labels.iter().map(|l| l.cid.clone()).collect::<Vec<_>>();
+    let vals = labels.iter().map(|l| l.val.clone()).collect::<Vec<_>>();
+    let negs = labels.iter().map(|l| l.neg).collect::<Vec<_>>();
+    let created_ats = labels.iter().map(|l| l.created_at).collect::<Vec<_>>();
+    let expires_ats = labels.iter().map(|l| l.expires_at).collect::<Vec<_>>();
+
+    let result = sqlx::query!(
+        r#"INSERT INTO label (src, uri, subject_id, cid, val, neg, created_at, expires_at) SELECT * FROM UNNEST($1::TEXT[], $2::TEXT[], $3::TEXT[], $4::TEXT[], $5::TEXT[], $6::BOOLEAN[], $7::TIMESTAMPTZ[], $8::TIMESTAMPTZ[]) ON CONFLICT (src, uri, val) DO UPDATE SET subject_id = EXCLUDED.subject_id, cid = EXCLUDED.cid, neg = EXCLUDED.neg, created_at = EXCLUDED.created_at, expires_at = EXCLUDED.expires_at WHERE label.created_at <= EXCLUDED.created_at"#,
+        srcs.as_slice(),
+        uris.as_slice(),
+        subject_ids.as_slice() as _,
+        cids.as_slice() as _,
+        vals.as_slice(),
+        negs.as_slice(),
+        created_ats.as_slice(),
+        expires_ats.as_slice() as _
+    )
+    .execute(db)
+    .await?;
+
+    Ok(result.rows_affected())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const POST: &str = "at://did:plc:author/app.bsky.feed.post/3jzfcijpj2z2a";
+
+    fn label(val: &str, neg: bool, seconds: i64) -> Label {
+        Label {
+            src: "did:plc:labeler".to_string(),
+            uri: POST.to_string(),
+            cid: None,
+            val: val.to_string(),
+            neg,
+            created_at: DateTime::from_timestamp(seconds, 0).unwrap(),
+            expires_at: None,
+        }
+    }
+
+    #[test]
+    fn subjects_map_to_their_row_id() {
+        assert_eq!(
+            label_subject_id(POST).as_deref(),
+            Some("3jzfcijpj2z2a_plc_author")
+        );
+        assert_eq!(
+            label_subject_id("did:plc:author").as_deref(),
+            Some("plc_author")
+        );
+        assert_eq!(
+            label_subject_id("at://did:plc:author/com.example.record/3jzfcijpj2z2a"),
+            None
+        );
+    }
+
+    #[sqlx::test]
+    #[ignore = "needs a postgres server in DATABASE_URL"]
+    async fn negating_labels_retract_the_label(db: PgPool) -> Result<()> {
+        let labels = || {
+            sqlx::query_as::<_, (String, String, bool)>(
+                "SELECT subject_id, val, neg FROM label ORDER BY val",
+            )
+            .fetch_all(&db)
+        };
+        insert_labels(&db, &[label("spam", false, 1), label("porn", false, 1)]).await?;
+        insert_labels(&db, &[label("spam", true, 2)]).await?;
+        let subject = "3jzfcijpj2z2a_plc_author".to_string();
+        assert_eq!(
+            labels().await?,
+            [
+                (subject.clone(), "porn".to_string(), false),
+                (subject.clone(), "spam".to_string(), true)
+            ]
+        );
+
+        // The original label arriving again does not resurrect it, a newer one does
+        insert_labels(&db, &[label("spam", false, 1)]).await?;
+        assert!(labels().await?[1].2);
+        insert_labels(&db, &[label("spam", false, 3), label("spam", true, 2)]).await?;
+        assert!(!labels().await?[1].2);
+        Ok(())
+    }
+}
@@ -1,31 +1,98 @@
-use std::time::Duration;
+use std::{sync::LazyLock, time::Duration};
 
 use anyhow::Result;
-use definitions::JetstreamCursor;
+use definitions::{CachedDidDocument, JetstreamCursor};
+use opentelemetry::{global, metrics::Gauge, KeyValue};
 use sqlx::{postgres::PgPoolOptions, PgPool};
+use tokio::time::{interval, MissedTickBehavior};
+use tokio_util::sync::CancellationToken;
 
-use crate::config::ARGS;
+use crate::{config::ARGS, observability::instrument_name};
 
 pub mod big_update;
 pub mod definitions;
+pub mod deletion;
+pub mod error;
 pub mod handlers;
+pub mod labels;
+pub mod repo_enumerator;
 pub mod repo_indexer;
-mod utils;
+pub mod search;
+pub mod utils;
+
+static POOL_CONNECTIONS_METRIC: LazyLock<Gauge<u64>> = LazyLock::new(|| {
+    global::meter("indexer")
+        .u64_gauge(instrument_name("indexer.database.pool_connections"))
+        .with_unit("{connection}")
+        .with_description("Number of open database connections by state, in use or idle")
+        .build()
+});
+static POOL_MAX_CONNECTIONS_METRIC: LazyLock<Gauge<u64>> = LazyLock::new(|| {
+    global::meter("indexer")
+        .u64_gauge(instrument_name("indexer.database.pool_max_connections"))
+        .with_unit("{connection}")
+        .with_description("Maximum number of connections of the database pool")
+        .build()
+});
 
 /// Connect to the database
+///
+/// Waiting for a connection of the pool fails after `--db-acquire-timeout`, so an exhausted pool surfaces as an error
+/// instead of hanging forever
 pub async fn connect() -> anyhow::Result<PgPool> {
     // connect to the database
-    let database = PgPoolOptions::new()
-        .max_connections(ARGS.db_pool_size)
-        .acquire_slow_threshold(Duration::from_secs(20))
-        .connect(&ARGS.db)
-        .await?;
+    let database = pool_options(
+        ARGS.db_pool_size,
+        Duration::from_secs(ARGS.db_acquire_timeout),
+        ARGS.enable_post_search,
+    )
+    .connect(&ARGS.db)
+    .await?;
 
     sqlx::migrate!("./migrations").run(&database).await?;
 
     Ok(database)
 }
 
+/// Options of the connection pool
+fn pool_options(pool_size: u32, acquire_timeout: Duration, post_search: bool) -> PgPoolOptions {
+    PgPoolOptions::new()
+        .max_connections(pool_size)
+        .acquire_slow_threshold(Duration::from_secs(20))
+        .acquire_timeout(acquire_timeout)
+        .after_connect(move |connection, _| {
+            Box::pin(async move {
+                // Read by the trigger that maintains the text search column of posts
+                if post_search {
+                    sqlx::query("SET indexer.post_search = 'on'")
+                        .execute(connection)
+                        .await?;
+                }
+                Ok(())
+            })
+        })
+}
+
+/// Record the utilization of the connection pool every few seconds until shutdown is requested
+pub async fn export_pool_metrics(database: PgPool, shutdown: CancellationToken) -> Result<()> {
+    let mut ticker = interval(Duration::from_secs(5));
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => {}
+            _ = shutdown.cancelled() => return Ok(()),
+        }
+        let size = database.size();
+        let idle = database.num_idle() as u32;
+        POOL_CONNECTIONS_METRIC.record(
+            size.saturating_sub(idle) as u64,
+            &[KeyValue::new("state", "in_use")],
+        );
+        POOL_CONNECTIONS_METRIC.record(idle as u64, &[KeyValue::new("state", "idle")]);
+        POOL_MAX_CONNECTIONS_METRIC.record(database.options().get_max_connections() as u64, &[]);
+    }
+}
+
 // /// Connect to the database
 // pub async fn connect_surreal(db_endpoint: &str) -> anyhow::Result<Surreal<Any>> {
 //     // connect to the database
@@ -78,3 +145,187 @@ pub async fn write_cursor(db: impl sqlx::PgExecutor<'_>, cursor: JetstreamCursor
 
     Ok(())
 }
+
+/// Mark a DID as unbackfillable, so the repo stream stops picking it up
+pub async fn mark_unbackfillable(
+    db: impl sqlx::PgExecutor<'_>,
+    did: &str,
+    reason: &str,
+) -> Result<()> {
+    let did_key = utils::did_to_key(did)?;
+    sqlx::query!(
+        "UPDATE latest_backfill SET unbackfillable_reason = $2::TEXT WHERE of_did_id = $1::TEXT",
+        &did_key,
+        reason
+    )
+    .execute(db)
+    .await?;
+
+    Ok(())
+}
+
+/// Record a failed backfill attempt in the dead-letter table
+pub async fn record_backfill_failure(
+    db: impl sqlx::PgExecutor<'_>,
+    did: &str,
+    stage: &str,
+    error: &str,
+) -> Result<()> {
+    let did_key = utils::did_to_key(did)?;
+    sqlx::query!(
+        "INSERT INTO backfill_failure (of_did_id, stage, error, attempts, last_attempt_at) VALUES ($1::TEXT, $2::TEXT, $3::TEXT, 1, NOW()) ON CONFLICT (of_did_id) DO UPDATE SET stage = EXCLUDED.stage, error = EXCLUDED.error, attempts = backfill_failure.attempts + 1, last_attempt_at = EXCLUDED.last_attempt_at",
+        &did_key,
+        stage,
+        error
+    )
+    .execute(db)
+    .await?;
+
+    Ok(())
+}
+
+/// Requeue a DID for backfilling by clearing its failure record and unbackfillable mark
+///
+/// Returns false if the DID was neither failed nor unbackfillable
+pub async fn requeue_backfill(db: &PgPool, did: &str) -> Result<bool> {
+    let did_key = utils::did_to_key(did)?;
+    let mut transaction = db.begin().await?;
+    let failures = sqlx::query!(
+        "DELETE FROM backfill_failure WHERE of_did_id = $1::TEXT",
+        &did_key
+    )
+    .execute(&mut *transaction)
+    .await?
+    .rows_affected();
+    let unbackfillable = sqlx::query!(
+        "UPDATE latest_backfill SET unbackfillable_reason = NULL WHERE of_did_id = $1::TEXT AND unbackfillable_reason IS NOT NULL",
+        &did_key
+    )
+    .execute(&mut *transaction)
+    .await?
+    .rows_affected();
+    transaction.commit().await?;
+
+    Ok(failures + unbackfillable > 0)
+}
+
+/// Get the cached DID document of a DID if it was fetched less than `ttl` seconds ago
+pub async fn fetch_cached_did_document(
+    db: impl sqlx::PgExecutor<'_>,
+    did: &str,
+    ttl: i64,
+) -> Result<Option<CachedDidDocument>> {
+    let did_key = utils::did_to_key(did)?;
+    let document = sqlx::query_as!(
+        CachedDidDocument,
+        "SELECT pds_endpoint, signing_key FROM did_document WHERE id = $1::TEXT AND fetched_at > NOW() - $2::BIGINT * INTERVAL '1 second'",
+        &did_key,
+        ttl
+    )
+    .fetch_optional(db)
+    .await?;
+
+    Ok(document)
+}
+
+/// Cache the PDS endpoint, handle and signing key of a DID
+pub async fn write_did_document(
+    db: impl sqlx::PgExecutor<'_>,
+    did: &str,
+    pds_endpoint: &str,
+    handle: Option<&str>,
+    signing_key: Option<&str>,
+) -> Result<()> {
+    let did_key = utils::did_to_key(did)?;
+    sqlx::query!(
+        "INSERT INTO did_document (id, pds_endpoint, handle, signing_key, fetched_at) VALUES ($1::TEXT, $2::TEXT, $3::TEXT, $4::TEXT, NOW()) ON CONFLICT (id) DO UPDATE SET pds_endpoint = EXCLUDED.pds_endpoint, handle = EXCLUDED.handle, signing_key = EXCLUDED.signing_key, fetched_at = EXCLUDED.fetched_at",
+        &did_key,
+        pds_endpoint,
+        handle,
+        signing_key
+    )
+    .execute(db)
+    .await?;
+
+    Ok(())
+}
+
+/// Get the rev of the repo of a DID at its last backfill
+pub async fn fetch_last_known_rev(
+    db: impl sqlx::PgExecutor<'_>,
+    did: &str,
+) -> Result<Option<String>> {
+    let did_key = utils::did_to_key(did)?;
+    let rev = sqlx::query_scalar!(
+        "SELECT last_known_rev FROM latest_backfill WHERE id = $1::TEXT",
+        &did_key
+    )
+    .fetch_optional(db)
+    .await?;
+
+    Ok(rev.flatten())
+}
+
+/// Fetch the listRepos cursor of a relay
+///
+/// Returns None if the enumeration has not started yet and `Some(None)` if it is complete
+pub async fn fetch_enumeration_cursor(
+    db: impl sqlx::PgExecutor<'_>,
+    host: &str,
+) -> Result<Option<Option<String>>> {
+    let cursor = sqlx::query_scalar!(
+        "SELECT cursor FROM repo_enumeration WHERE host = $1::TEXT",
+        host
+    )
+    .fetch_optional(db)
+    .await?;
+
+    Ok(cursor)
+}
+
+/// Write the listRepos cursor of a relay, None marks the enumeration as complete
+pub async fn write_enumeration_cursor(
+    db: impl sqlx::PgExecutor<'_>,
+    host: &str,
+    cursor: Option<&str>,
+) -> Result<()> {
+    sqlx::query!(
+        "INSERT INTO repo_enumeration (host, cursor) VALUES ($1::TEXT, $2::TEXT) ON CONFLICT (host) DO UPDATE SET cursor = EXCLUDED.cursor",
+        host,
+        cursor
+    )
+    .execute(db)
+    .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use error::DbError;
+    use sqlx::postgres::PgConnectOptions;
+
+    #[sqlx::test]
+    #[ignore = "needs a postgres server in DATABASE_URL"]
+    async fn an_exhausted_pool_times_out(
+        _: PgPoolOptions,
+        options: PgConnectOptions,
+    ) -> anyhow::Result<()> {
+        let database = pool_options(1, Duration::from_millis(200), false)
+            .connect_with(options)
+            .await?;
+        let _held = database.acquire().await?;
+
+        let waiting = sqlx::query("SELECT 1").execute(&database);
+        let result = tokio::time::timeout(Duration::from_secs(10), waiting)
+            .await
+            .expect("waiting for the exhausted pool hangs");
+
+        let error = DbError::from(result.unwrap_err());
+        assert!(matches!(error, DbError::PoolTimedOut(_)), "{:?}", error);
+        assert!(error.is_retryable());
+        assert!(error.to_string().contains("--db-pool-size"), "{}", error);
+        Ok(())
+    }
+}
@@ -0,0 +1,131 @@
+use super::{
+    big_update::insert_pending_backfills, repo_indexer::build_http_client, utils::did_to_key,
+};
+use crate::config::ARGS;
+use anyhow::Context;
+use serde::Deserialize;
+use sqlx::PgPool;
+use std::time::Duration;
+use tokio::time::{interval, sleep, MissedTickBehavior};
+use tokio_util::sync::CancellationToken;
+use tracing::{info, trace, warn};
+
+/// Number of repos requested per listRepos page, the maximum allowed by the lexicon
+const PAGE_SIZE: &str = "1000";
+
+/// Response of com.atproto.sync.listRepos
+#[derive(Deserialize, Debug)]
+struct ListReposResponse {
+    cursor: Option<String>,
+    repos: Vec<ListedRepo>,
+}
+
+/// A repo in a listRepos response
+#[derive(Deserialize, Debug)]
+struct ListedRepo {
+    did: String,
+    /// Missing on older relays, which only list active repos
+    active: Option<bool>,
+}
+
+/// Discover repos by paging through listRepos of a relay until shutdown is requested
+///
+/// Discovered DIDs are inserted as pending backfills. The cursor is stored in `repo_enumeration`, so the enumeration
+/// resumes after a restart. Once the relay has no more repos, the task stays idle. Delete the row of the relay to
+/// enumerate it again.
+pub async fn enumerate_repos(
+    database: PgPool,
+    relay: String,
+    shutdown: CancellationToken,
+) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        ARGS.enumerate_repos_rate > 0.0,
+        "The listRepos request rate must be positive"
+    );
+    let base_url = if relay.contains("://") {
+        relay.trim_end_matches('/').to_string()
+    } else {
+        format!("https://{}", relay.trim_end_matches('/'))
+    };
+    let http_client = build_http_client()?;
+
+    let mut cursor = match super::fetch_enumeration_cursor(&database, &relay)
+        .await
+        .context("Failed to fetch the listRepos cursor")?
+    {
+        Some(None) => {
+            info!(target: "indexer", "All repos of {} were already enumerated", relay);
+            shutdown.cancelled().await;
+            return Ok(());
+        }
+        Some(cursor) => cursor,
+        None => None,
+    };
+
+    let mut rate_limit = interval(Duration::from_secs_f64(1.0 / ARGS.enumerate_repos_rate));
+    rate_limit.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    info!(target: "indexer", "Enumerating repos of {} starting at cursor {:?}", relay, cursor);
+    loop {
+        tokio::select! {
+            _ = rate_limit.tick() => {}
+            _ = shutdown.cancelled() => return Ok(()),
+        }
+
+        let mut request = http_client
+            .get(format!("{}/xrpc/com.atproto.sync.listRepos", base_url))
+            .query(&[("limit", PAGE_SIZE)])
+            .timeout(Duration::from_secs(ARGS.directory_download_timeout));
+        if let Some(cursor) = &cursor {
+            request = request.query(&[("cursor", cursor)]);
+        }
+        let page = match request.send().await.and_then(|r| r.error_for_status()) {
+            Ok(response) => response.json::<ListReposResponse>().await,
+            Err(e) => Err(e),
+        };
+        let page = match page {
+            Ok(page) => page,
+            Err(e) => {
+                warn!(target: "indexer", "Failed to list repos of {}: {}", relay, e);
+                tokio::select! {
+                    _ = sleep(Duration::from_secs(10)) => {}
+                    _ = shutdown.cancelled() => return Ok(()),
+                }
+                continue;
+            }
+        };
+
+        // Inactive repos can not be downloaded, the jetstream tells us when they come back
+        let did_keys = page
+            .repos
+            .iter()
+            .filter(|repo| repo.active != Some(false))
+            .filter_map(|repo| match did_to_key(&repo.did) {
+                Ok(did_key) => Some(did_key),
+                Err(e) => {
+                    trace!("Skipping listed repo {}: {}", repo.did, e);
+                    None
+                }
+            })
+            .collect::<Vec<_>>();
+        let inserted = insert_pending_backfills(&database, did_keys)
+            .await
+            .context("Failed to insert discovered repos")?;
+        trace!(
+            "Listed {} repos of {}, {} were new",
+            page.repos.len(),
+            relay,
+            inserted
+        );
+
+        // The relay omits the cursor on the last page
+        cursor = page.cursor.filter(|_| !page.repos.is_empty());
+        super::write_enumeration_cursor(&database, &relay, cursor.as_deref())
+            .await
+            .context("Failed to write the listRepos cursor")?;
+        if cursor.is_none() {
+            info!(target: "indexer", "Enumerated all repos of {}", relay);
+            shutdown.cancelled().await;
+            return Ok(());
+        }
+    }
+}
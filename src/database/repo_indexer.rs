@@ -3,13 +3,23 @@ use futures::StreamExt;
 use index_repo::DownloadService;
 use pipeline::{create_stage, next_stage};
 use repo_stream::RepoStream;
-use reqwest::Client;
 use sqlx::PgPool;
-use tracing::error;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info};
 
+pub(crate) mod car_file;
+pub mod consistency;
+mod http_client;
+mod import_car;
 mod index_repo;
 mod pipeline;
 mod repo_stream;
+mod verify;
+
+pub use http_client::{build_http_client, HttpClientOptions};
+pub use import_car::import_car_files;
+pub use index_repo::read_car_blocks;
+pub use pipeline::pipeline_locations;
 
 macro_rules! unordered {
     ($concurrency:expr) => {
@@ -17,8 +27,14 @@ macro_rules! unordered {
     };
 }
 
-pub async fn start_full_repo_indexer(database: PgPool) -> anyhow::Result<()> {
-    let http_client = Client::new();
+/// Backfill all repos until shutdown is requested
+///
+/// Repos that are still in the pipeline on shutdown are not marked as backfilled, so they will be picked up again on the next start
+pub async fn start_full_repo_indexer(
+    database: PgPool,
+    shutdown: CancellationToken,
+) -> anyhow::Result<()> {
+    let http_client = build_http_client()?;
 
     let buffer_size = ARGS.pipeline_buffer_size;
     let download_concurrency_multiplier = ARGS.pipeline_download_concurrency_multiplier;
@@ -51,7 +67,14 @@ pub async fn start_full_repo_indexer(database: PgPool) -> anyhow::Result<()> {
 
     // Process items
     loop {
-        let Some(_result) = output_receiver.recv().await else {
+        let result = tokio::select! {
+            result = output_receiver.recv() => result,
+            _ = shutdown.cancelled() => {
+                info!("Stopping the backfill pipeline");
+                return Ok(());
+            }
+        };
+        let Some(_result) = result else {
             error!("Backfill pipeline ran out of items. This should never happen.");
             panic!("Backfill pipeline ran out of items. This should never happen.");
         };
@@ -0,0 +1,143 @@
+use anyhow::Context;
+use ipld_core::cid::Cid;
+use std::{
+    cell::Cell,
+    collections::HashMap,
+    fs::File,
+    io::{BufReader, Read, Seek, SeekFrom},
+    rc::Rc,
+};
+
+/// A CAR file on disk with an index of its blocks
+///
+/// Only the location of each block is kept in memory, the block data is read from the file when it is needed.
+pub struct CarFile {
+    file: File,
+    roots: Vec<Cid>,
+    /// Offset and length of every block in the file
+    blocks: HashMap<Cid, (u64, usize)>,
+}
+
+/// Reader that keeps track of the number of bytes read so far
+struct CountingReader<R> {
+    inner: R,
+    position: Rc<Cell<u64>>,
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.position.set(self.position.get() + read as u64);
+        Ok(read)
+    }
+}
+
+impl CarFile {
+    /// Read through a CAR file once and remember where each block is
+    ///
+    /// The block hashes are validated while indexing.
+    pub fn index(mut file: File) -> anyhow::Result<CarFile> {
+        file.seek(SeekFrom::Start(0))?;
+        let (roots, blocks) = {
+            let position = Rc::new(Cell::new(0));
+            let mut reader = CountingReader {
+                inner: BufReader::new(&mut file),
+                position: position.clone(),
+            };
+            let mut car_reader =
+                rs_car_sync::CarReader::new(&mut reader, true).context("Invalid CAR header")?;
+
+            let roots = car_reader
+                .header
+                .roots
+                .iter()
+                .map(|cid| Cid::read_bytes(cid.to_bytes().as_slice()))
+                .collect::<Result<Vec<_>, _>>()?;
+            let mut blocks = HashMap::new();
+            for entry in &mut car_reader {
+                let (cid, data) = entry.context("Invalid CAR block")?;
+                let cid = Cid::read_bytes(cid.to_bytes().as_slice())?;
+                // The block data is the end of the section we just read
+                let offset = position.get() - data.len() as u64;
+                blocks.insert(cid, (offset, data.len()));
+            }
+            (roots, blocks)
+        };
+
+        Ok(CarFile {
+            file,
+            roots,
+            blocks,
+        })
+    }
+
+    /// The root CIDs from the CAR header
+    pub fn roots(&self) -> &[Cid] {
+        &self.roots
+    }
+
+    /// Read a block from the file, returns None if the CAR file does not contain it
+    pub fn get(&mut self, cid: &Cid) -> anyhow::Result<Option<Vec<u8>>> {
+        let Some(&(offset, len)) = self.blocks.get(cid) else {
+            return Ok(None);
+        };
+        let mut data = vec![0; len];
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file
+            .read_exact(&mut data)
+            .with_context(|| format!("Failed to read block {}", cid))?;
+        Ok(Some(data))
+    }
+}
+
+/// Build CAR files for tests
+#[cfg(test)]
+pub(crate) mod testing {
+    use ipld_core::{
+        cid::{multihash::Multihash, Cid},
+        ipld::Ipld,
+    };
+    use k256::sha2::{Digest, Sha256};
+
+    /// CID of a DAG-CBOR block
+    pub fn cid_of(block: &[u8]) -> Cid {
+        let digest = Multihash::wrap(0x12, &Sha256::digest(block)).unwrap();
+        Cid::new_v1(0x71, digest)
+    }
+
+    /// Encode a value as DAG-CBOR block
+    pub fn block(value: &Ipld) -> (Cid, Vec<u8>) {
+        let block = serde_ipld_dagcbor::to_vec(value).unwrap();
+        (cid_of(&block), block)
+    }
+
+    /// Append a length prefixed section
+    fn push_section(car: &mut Vec<u8>, section: &[u8]) {
+        let mut len = section.len();
+        while len >= 0x80 {
+            car.push((len as u8 & 0x7f) | 0x80);
+            len >>= 7;
+        }
+        car.push(len as u8);
+        car.extend_from_slice(section);
+    }
+
+    /// A CAR file with a single root and the given blocks
+    pub fn car(root: Cid, blocks: &[(Cid, Vec<u8>)]) -> Vec<u8> {
+        let header = Ipld::Map(
+            [
+                ("roots".to_string(), Ipld::List(vec![Ipld::Link(root)])),
+                ("version".to_string(), Ipld::Integer(1)),
+            ]
+            .into(),
+        );
+        let mut car = Vec::new();
+        push_section(&mut car, &serde_ipld_dagcbor::to_vec(&header).unwrap());
+        for (cid, block) in blocks {
+            let mut section = cid.to_bytes();
+            section.extend_from_slice(block);
+            push_section(&mut car, &section);
+        }
+        car
+    }
+}
@@ -0,0 +1,264 @@
+use super::{
+    build_http_client,
+    index_repo::{attempt_download, convert_repo_to_update, get_repo_url, DownloadService},
+    pipeline::Stage,
+};
+use crate::{
+    config::ARGS,
+    database::{
+        big_update::{options::options, BigUpdate},
+        utils::did_to_key,
+    },
+};
+use anyhow::Context;
+use chrono::Utc;
+use serde::Serialize;
+use sqlx::PgPool;
+use std::{
+    collections::{HashMap, HashSet},
+    time::Duration,
+};
+use tokio::task::spawn_blocking;
+
+/// A record that is only in the repo or only in the database
+#[derive(Debug, Serialize)]
+pub struct RecordRef {
+    pub table: String,
+    pub id: String,
+}
+
+/// A field of a record that differs between the repo and the database
+#[derive(Debug, Serialize)]
+pub struct FieldMismatch {
+    pub table: &'static str,
+    pub id: String,
+    pub field: &'static str,
+    pub repo: Option<String>,
+    pub database: Option<String>,
+}
+
+/// Differences between the repo of a DID and its rows in the database
+#[derive(Debug, Serialize)]
+pub struct ConsistencyReport {
+    pub did: String,
+    /// Number of records in the repo that should be in the database
+    pub repo_records: usize,
+    /// Number of records of the DID in the database
+    pub database_records: usize,
+    /// Records in the repo that are not in the database
+    pub missing: Vec<RecordRef>,
+    /// Records in the database that are not in the repo anymore, usually missed deletes
+    pub extra: Vec<RecordRef>,
+    /// Posts and profiles whose fields differ
+    pub mismatches: Vec<FieldMismatch>,
+}
+
+impl ConsistencyReport {
+    /// Total number of differences
+    pub fn discrepancies(&self) -> usize {
+        self.missing.len() + self.extra.len() + self.mismatches.len()
+    }
+}
+
+/// Collection of the records stored in a table
+fn table_collection(table: &str) -> &'static str {
+    match table {
+        "post" => "app.bsky.feed.post",
+        "follow" => "app.bsky.graph.follow",
+        "like" => "app.bsky.feed.like",
+        "repost" => "app.bsky.feed.repost",
+        "block" => "app.bsky.graph.block",
+        "listblock" => "app.bsky.graph.listblock",
+        "listitem" => "app.bsky.graph.listitem",
+        "list" => "app.bsky.graph.list",
+        "feed" => "app.bsky.feed.generator",
+        "starterpack" => "app.bsky.graph.starterpack",
+        "threadgate" => "app.bsky.feed.threadgate",
+        "postgate" => "app.bsky.feed.postgate",
+        "labeler" => "app.bsky.labeler.service",
+        "chat_actor_declaration" => "chat.bsky.actor.declaration",
+        _ => "",
+    }
+}
+
+/// Download the repo of a DID and compare it with the database, used by the `verify` subcommand
+///
+/// The repo is resolved, downloaded and converted like in the backfill, but the rows are compared instead of written.
+/// Records that are created or deleted while the check runs can show up as differences
+pub async fn check_repo_consistency(
+    database: &PgPool,
+    did: &str,
+) -> anyhow::Result<ConsistencyReport> {
+    let did_key = did_to_key(did)?;
+    let http_client = build_http_client()?;
+
+    let service = DownloadService::new(database.clone(), http_client.clone(), did.to_string())
+        .run()
+        .await
+        .with_context(|| format!("Failed to resolve the DID document of {}", did))?;
+    // A full download, the backfill would only request the blocks since the last known rev
+    let (file, _) = attempt_download(
+        &http_client,
+        &get_repo_url(&service.pds_endpoint, did, None),
+        Duration::from_secs(ARGS.download_repo_timeout),
+    )
+    .await
+    .map_err(|e| anyhow::anyhow!("Failed to download the repo of {}: {}", did, e))?;
+
+    let signing_key = service.signing_key.filter(|_| ARGS.verify_signatures);
+    let owned_did = did.to_string();
+    let updates = spawn_blocking(move || {
+        convert_repo_to_update(file, &owned_did, signing_key.as_deref(), false, Utc::now())
+    })
+    .await??;
+    let mut repo = BigUpdate::default();
+    for update in updates {
+        repo.merge(update);
+    }
+    repo.dedup();
+
+    // Compare the ids of all records
+    let expected = repo.record_ids().into_iter().collect::<HashSet<_>>();
+    let stored = sqlx::query!(
+        r#"SELECT 'post' AS "table!", id AS "id!" FROM post WHERE author = $1::TEXT UNION ALL SELECT 'follow', id FROM follow WHERE follower_did_id = $1::TEXT AND id IS NOT NULL UNION ALL SELECT 'like', id FROM "like" WHERE user_id = $1::TEXT AND id IS NOT NULL UNION ALL SELECT 'repost', id FROM repost WHERE did_id = $1::TEXT AND id IS NOT NULL UNION ALL SELECT 'block', id FROM "block" WHERE blocker_did_id = $1::TEXT AND id IS NOT NULL UNION ALL SELECT 'listblock', id FROM listblock WHERE blocker_did_id = $1::TEXT AND id IS NOT NULL UNION ALL SELECT 'listitem', id FROM listitem WHERE right(id, length($1::TEXT) + 1) = '_' || $1::TEXT UNION ALL SELECT 'list', id FROM list WHERE right(id, length($1::TEXT) + 1) = '_' || $1::TEXT UNION ALL SELECT 'feed', id FROM feed WHERE author = $1::TEXT UNION ALL SELECT 'starterpack', id FROM starterpack WHERE creator_did_id = $1::TEXT UNION ALL SELECT 'threadgate', id FROM threadgate WHERE right(id, length($1::TEXT) + 1) = '_' || $1::TEXT UNION ALL SELECT 'postgate', id FROM postgate WHERE right(id, length($1::TEXT) + 1) = '_' || $1::TEXT UNION ALL SELECT 'labeler', id FROM labeler WHERE did_id = $1::TEXT UNION ALL SELECT 'chat_actor_declaration', id FROM chat_actor_declaration WHERE did_id = $1::TEXT"#,
+        did_key
+    )
+    .fetch_all(database)
+    .await
+    .context("Failed to fetch the records of the DID")?
+    .into_iter()
+    .map(|row| (row.table, row.id))
+    .collect::<HashSet<_>>();
+
+    let mut missing = expected
+        .iter()
+        .filter(|(table, id)| !stored.contains(&(table.to_string(), id.to_string())))
+        .map(|(table, id)| RecordRef {
+            table: table.to_string(),
+            id: id.to_string(),
+        })
+        .collect::<Vec<_>>();
+    // Records of types that are not indexed are not in the repo update, so they would all count as extra
+    let mut extra = stored
+        .iter()
+        .filter(|(table, _)| options().indexes_collection(table_collection(table)))
+        .filter(|(table, id)| !expected.contains(&(table.as_str(), id.as_str())))
+        .map(|(table, id)| RecordRef {
+            table: table.clone(),
+            id: id.clone(),
+        })
+        .collect::<Vec<_>>();
+    missing.sort_by(|a, b| (&a.table, &a.id).cmp(&(&b.table, &b.id)));
+    extra.sort_by(|a, b| (&a.table, &a.id).cmp(&(&b.table, &b.id)));
+
+    // Compare the fields of posts and the profile
+    let mut mismatches = Vec::new();
+    let post_ids = repo
+        .posts()
+        .iter()
+        .map(|post| post.id.clone())
+        .collect::<Vec<_>>();
+    let stored_posts = sqlx::query!(
+        "SELECT id, text, created_at FROM post WHERE id = ANY($1::TEXT[])",
+        post_ids.as_slice()
+    )
+    .fetch_all(database)
+    .await
+    .context("Failed to fetch the posts of the DID")?
+    .into_iter()
+    .map(|row| (row.id, (row.text, row.created_at)))
+    .collect::<HashMap<_, _>>();
+    for post in repo.posts() {
+        let Some((text, created_at)) = stored_posts.get(&post.id) else {
+            continue;
+        };
+        if *text != post.data.text {
+            mismatches.push(FieldMismatch {
+                table: "post",
+                id: post.id.clone(),
+                field: "text",
+                repo: Some(post.data.text.clone()),
+                database: Some(text.clone()),
+            });
+        }
+        // postgres only stores microseconds
+        if created_at.timestamp_micros() != post.data.created_at.timestamp_micros() {
+            mismatches.push(FieldMismatch {
+                table: "post",
+                id: post.id.clone(),
+                field: "created_at",
+                repo: Some(post.data.created_at.to_rfc3339()),
+                database: Some(created_at.to_rfc3339()),
+            });
+        }
+    }
+
+    if let Some(profile) = repo.profiles().first() {
+        let stored_profile = sqlx::query!(
+            "SELECT display_name, description FROM did WHERE id = $1::TEXT",
+            did_key
+        )
+        .fetch_optional(database)
+        .await
+        .context("Failed to fetch the profile of the DID")?;
+        let (display_name, description) = stored_profile
+            .map(|row| (row.display_name, row.description))
+            .unwrap_or_default();
+        let fields = [
+            ("display_name", &profile.data.display_name, display_name),
+            ("description", &profile.data.description, description),
+        ];
+        for (field, repo_value, database_value) in fields {
+            if *repo_value != database_value {
+                mismatches.push(FieldMismatch {
+                    table: "did",
+                    id: did_key.clone(),
+                    field,
+                    repo: repo_value.clone(),
+                    database: database_value,
+                });
+            }
+        }
+    }
+
+    Ok(ConsistencyReport {
+        did: did.to_string(),
+        repo_records: expected.len(),
+        database_records: stored.len(),
+        missing,
+        extra,
+        mismatches,
+    })
+}
+
+/// Format a report for humans, one line per difference after a summary line
+pub fn format_report(report: &ConsistencyReport) -> String {
+    let mut lines = vec![format!(
+        "{}: {} records in the repo, {} in the database, {} missing, {} extra, {} mismatched fields",
+        report.did,
+        report.repo_records,
+        report.database_records,
+        report.missing.len(),
+        report.extra.len(),
+        report.mismatches.len()
+    )];
+    lines.extend(
+        report
+            .missing
+            .iter()
+            .map(|record| format!("missing {} {}", record.table, record.id)),
+    );
+    lines.extend(
+        report
+            .extra
+            .iter()
+            .map(|record| format!("extra {} {}", record.table, record.id)),
+    );
+    lines.extend(report.mismatches.iter().map(|mismatch| {
+        format!(
+            "mismatch {} {} {}: repo {:?}, database {:?}",
+            mismatch.table, mismatch.id, mismatch.field, mismatch.repo, mismatch.database
+        )
+    }));
+    lines.join("\n")
+}
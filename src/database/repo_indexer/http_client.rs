@@ -0,0 +1,181 @@
+use crate::config::ARGS;
+use anyhow::Context;
+use reqwest::{Certificate, Client};
+use std::time::Duration;
+use tracing::{info, warn};
+
+/// Send TCP keepalives on idle connections after this long, so connections to a PDS survive between downloads
+const TCP_KEEPALIVE: Duration = Duration::from_secs(60);
+/// Close pooled connections that were not used for this long
+const POOL_IDLE_TIMEOUT: Duration = Duration::from_secs(90);
+
+/// Settings of the HTTP client
+#[derive(Debug, Clone)]
+pub struct HttpClientOptions {
+    pub user_agent: String,
+    /// Maximum number of idle connections that are kept open to a single host
+    pub pool_idle_per_host: usize,
+    /// Use HTTP/2 with servers that support it
+    pub http2: bool,
+    pub connect_timeout: Duration,
+    pub read_timeout: Duration,
+    /// Paths of certificates to trust in addition to the bundled root certificates
+    pub root_certificates: Vec<String>,
+    /// Do not verify TLS certificates at all
+    pub insecure_skip_tls_verify: bool,
+}
+
+/// Build the HTTP client for requests to PDSs, the plc directory and relays
+///
+/// The client keeps a pool of connections per host, so it should be created once and cloned. HTTP/2 is negotiated
+/// with servers that support it unless `--http2 false` is given. The connect and read timeouts only bound single
+/// operations, the overall time of a request is still bounded by the timeouts of the pipeline stages.
+///
+/// `--certificate` and `--extra-ca-certificate` are trusted in addition to the bundled root certificates
+pub fn build_http_client() -> anyhow::Result<Client> {
+    build_http_client_with(&ARGS.http_client_options())
+}
+
+/// Build an HTTP client with the given settings
+fn build_http_client_with(options: &HttpClientOptions) -> anyhow::Result<Client> {
+    let mut builder = Client::builder()
+        .user_agent(&options.user_agent)
+        .pool_max_idle_per_host(options.pool_idle_per_host)
+        .pool_idle_timeout(POOL_IDLE_TIMEOUT)
+        .tcp_keepalive(TCP_KEEPALIVE)
+        .connect_timeout(options.connect_timeout)
+        .read_timeout(options.read_timeout);
+    if !options.http2 {
+        builder = builder.http1_only();
+    }
+    for path in &options.root_certificates {
+        let pem = std::fs::read(path)
+            .with_context(|| format!("Unable to read certificate from: {}", path))?;
+        let certificate = Certificate::from_pem(&pem)
+            .with_context(|| format!("Unable to parse certificate from: {}", path))?;
+        builder = builder.add_root_certificate(certificate);
+    }
+    if options.insecure_skip_tls_verify {
+        warn!(target: "indexer", "TLS certificates of HTTP requests are not verified, because --insecure-skip-tls-verify is set");
+        builder = builder.danger_accept_invalid_certs(true);
+    }
+    let client = builder.build().context("Failed to build the HTTP client")?;
+
+    info!(
+        target: "indexer",
+        "HTTP client: user agent {:?}, {} idle connections per host, HTTP/2 {}, connect timeout {:?}, read timeout {:?}",
+        options.user_agent,
+        options.pool_idle_per_host,
+        if options.http2 { "enabled" } else { "disabled" },
+        options.connect_timeout,
+        options.read_timeout
+    );
+    Ok(client)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+    use tokio_rustls::{
+        rustls::{
+            crypto::aws_lc_rs::default_provider,
+            pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+            ServerConfig,
+        },
+        TlsAcceptor,
+    };
+
+    /// Self-signed certificate for localhost and 127.0.0.1
+    const CERTIFICATE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/localhost.pem");
+    const KEY: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/localhost.key");
+
+    fn options() -> HttpClientOptions {
+        HttpClientOptions {
+            user_agent: "indexer-test".to_string(),
+            pool_idle_per_host: 1,
+            http2: true,
+            connect_timeout: Duration::from_secs(5),
+            read_timeout: Duration::from_secs(5),
+            root_certificates: Vec::new(),
+            insecure_skip_tls_verify: false,
+        }
+    }
+
+    /// Serve `ok` over TLS with the self-signed certificate, returns the URL of the server
+    async fn serve_self_signed() -> String {
+        let config = ServerConfig::builder_with_provider(Arc::new(default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_no_client_auth()
+            .with_single_cert(
+                vec![CertificateDer::from_pem_file(CERTIFICATE).unwrap()],
+                PrivateKeyDer::from_pem_file(KEY).unwrap(),
+            )
+            .unwrap();
+        let acceptor = TlsAcceptor::from(Arc::new(config));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!(
+            "https://127.0.0.1:{}/",
+            listener.local_addr().unwrap().port()
+        );
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                // Clients that reject the certificate fail the handshake
+                let Ok(mut stream) = acceptor.accept(stream).await else {
+                    continue;
+                };
+                let mut request = [0; 1024];
+                let _ = stream.read(&mut request).await.unwrap();
+                stream
+                    .write_all(
+                        b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok",
+                    )
+                    .await
+                    .unwrap();
+                stream.shutdown().await.unwrap();
+            }
+        });
+        url
+    }
+
+    #[tokio::test]
+    async fn self_signed_certificates_are_rejected_by_default() {
+        let url = serve_self_signed().await;
+        let client = build_http_client_with(&options()).unwrap();
+
+        let error = client.get(&url).send().await.unwrap_err();
+        assert!(error.is_connect(), "{:?}", error);
+    }
+
+    #[tokio::test]
+    async fn configured_certificates_are_trusted() {
+        let url = serve_self_signed().await;
+        let client = build_http_client_with(&HttpClientOptions {
+            root_certificates: vec![CERTIFICATE.to_string()],
+            ..options()
+        })
+        .unwrap();
+
+        let response = client.get(&url).send().await.unwrap();
+        assert_eq!(response.text().await.unwrap(), "ok");
+    }
+
+    #[tokio::test]
+    async fn skipping_verification_accepts_self_signed_certificates() {
+        let url = serve_self_signed().await;
+        let client = build_http_client_with(&HttpClientOptions {
+            insecure_skip_tls_verify: true,
+            ..options()
+        })
+        .unwrap();
+
+        let response = client.get(&url).send().await.unwrap();
+        assert_eq!(response.text().await.unwrap(), "ok");
+    }
+}
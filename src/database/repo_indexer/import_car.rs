@@ -0,0 +1,53 @@
+use super::index_repo::convert_repo_to_update;
+use crate::database::big_update::{flush_small_updates, BigUpdate};
+use anyhow::Context;
+use chrono::Utc;
+use sqlx::PgPool;
+use std::{fs::File, path::PathBuf};
+use tokio::task::spawn_blocking;
+use tracing::info;
+
+/// Index repos from CAR files on disk, used by the `import-car` subcommand
+///
+/// Every file has to be a full repo export of `did`. The commit signature is not verified. Without a database the
+/// records are only converted and counted. Logs a summary of the converted rows at the end, so a copy of the rows is
+/// kept until then
+pub async fn import_car_files(
+    database: Option<PgPool>,
+    did: &str,
+    paths: &[PathBuf],
+) -> anyhow::Result<()> {
+    let mut summary = BigUpdate::default();
+    for path in paths {
+        let file =
+            File::open(path).with_context(|| format!("Unable to open {}", path.display()))?;
+        let owned_did = did.to_string();
+        let updates = spawn_blocking(move || {
+            convert_repo_to_update(file, &owned_did, None, false, Utc::now())
+        })
+        .await?
+        .with_context(|| format!("Failed to import {} as the repo of {}", path.display(), did))?;
+
+        for update in updates {
+            let Some(database) = &database else {
+                summary.merge(update);
+                continue;
+            };
+            summary.merge(update.clone());
+            update.apply(database.clone(), "import").await?;
+        }
+        info!(target: "indexer", "Converted {}", path.display());
+    }
+
+    // Small repos end up in the accumulator
+    if let Some(database) = database {
+        flush_small_updates(database)
+            .await
+            .context("Failed to flush accumulated updates")?;
+    }
+
+    summary.dedup();
+    info!(target: "indexer", "Converted {} rows of {} from {} files: {:?}",
+        summary.row_count(), did, paths.len(), summary);
+    Ok(())
+}
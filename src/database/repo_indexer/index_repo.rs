@@ -1,10 +1,17 @@
-use super::pipeline::Stage;
+use super::{
+    car_file::CarFile,
+    pipeline::Stage,
+    verify::{verify_commit, MstWalker},
+};
 use crate::{
     config::ARGS,
     database::{
-        big_update::{create_big_update, BigUpdate},
+        big_update::{create_big_update, options::options, record_ignored, BigUpdate},
+        definitions::CachedDidDocument,
         repo_indexer::pipeline::NoNextStage,
+        utils::did_to_key,
     },
+    observability::instrument_name,
 };
 use atrium_api::{
     record::KnownRecord,
@@ -12,26 +19,46 @@ use atrium_api::{
 };
 use chrono::{DateTime, Utc};
 use ipld_core::cid::Cid;
-use opentelemetry::{global, metrics::Counter};
-use reqwest::Client;
+use lru::LruCache;
+use opentelemetry::{global, metrics::Counter, KeyValue};
+use reqwest::{header::RETRY_AFTER, Client, StatusCode};
 use serde::Deserialize;
 use serde_ipld_dagcbor::from_reader;
 use sqlx::PgPool;
-use std::{collections::HashMap, sync::LazyLock, time::Duration};
-use tokio::task::spawn_blocking;
-use tracing::{instrument, span, trace, warn, Level, Span};
+use std::{
+    collections::HashMap,
+    fs::File,
+    num::NonZeroUsize,
+    sync::{Arc, LazyLock, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::{io::AsyncWriteExt, sync::Semaphore, task::spawn_blocking};
+use tracing::{debug, instrument, span, trace, warn, Level, Span};
 
+/// DID document as served by the plc directory or a did:web host
 #[derive(Deserialize, Debug)]
 #[allow(dead_code)]
-struct PlcDirectoryDidResponse {
-    #[serde(rename = "alsoKnownAs")]
+struct DidDocument {
+    #[serde(rename = "alsoKnownAs", default)]
     also_known_as: Vec<String>,
-    service: Vec<PlcDirectoryDidResponseService>,
+    #[serde(rename = "verificationMethod", default)]
+    verification_method: Vec<DidDocumentVerificationMethod>,
+    service: Vec<DidDocumentService>,
+}
+
+#[derive(Deserialize, Debug)]
+#[allow(dead_code)]
+struct DidDocumentVerificationMethod {
+    id: String,
+    #[serde(rename = "type")]
+    type_: String,
+    #[serde(rename = "publicKeyMultibase")]
+    public_key_multibase: Option<String>,
 }
 
 #[derive(Deserialize, Debug)]
 #[allow(dead_code)]
-struct PlcDirectoryDidResponseService {
+struct DidDocumentService {
     #[serde(rename = "serviceEndpoint")]
     service_endpoint: String,
     #[serde(rename = "type")]
@@ -69,70 +96,127 @@ pub struct NodeData {
     pub entries: Vec<TreeEntry>,
 }
 
-/// Convert downloaded files into a database update
-#[instrument(skip_all)]
-fn convert_repo_to_update(
-    repo: Vec<u8>,
-    did: &str,
-    retrieval_time: DateTime<Utc>,
-) -> anyhow::Result<BigUpdate> {
+/// Response of com.atproto.sync.getLatestCommit
+#[derive(Deserialize, Debug)]
+#[allow(dead_code)]
+struct LatestCommit {
+    cid: String,
+    rev: String,
+}
+
+/// Read all blocks of a CAR file into a map from CID to block data
+pub fn read_car_blocks(car: &[u8]) -> anyhow::Result<HashMap<Cid, Vec<u8>>> {
     // Deserialize CAR file
-    let (entries, _) = rs_car_sync::car_read_all(&mut repo.as_slice(), true)?;
+    let (entries, _) = rs_car_sync::car_read_all(&mut &car[..], true)?;
 
     // Store the entries in a hashmap for easier access
-    let files = entries
+    entries
         .into_iter()
         .try_fold(HashMap::new(), |mut files, (cid, data)| {
-            let cid = Cid::read_bytes(cid.to_bytes().as_slice()).unwrap();
+            let cid = Cid::read_bytes(cid.to_bytes().as_slice())?;
             files.insert(cid, data);
             anyhow::Result::<HashMap<Cid, Vec<u8>>>::Ok(files)
-        })?;
-
-    // Create references to the files and the did, so we can use them in the closure
-    let files_ref = &files;
-    let did_key = &crate::database::utils::did_to_key(did)?;
-
-    let mut update = files_ref
-        .iter()
-        // Convert to NodeData
-        .filter_map(|(_, data)| from_reader::<NodeData, _>(&data[..]).ok())
-        // Convert to Updates
-        .flat_map(|node_data| {
-            // TODO: Understand this logic
-            let mut key = "".to_string();
-            node_data.entries.into_iter().filter_map(move |entry| {
-                let k = match String::from_utf8(entry.key_suffix) {
-                    Ok(k) => k,
-                    Err(e) => return Some(Err(anyhow::Error::from(e))),
-                };
-                key = format!("{}{}", key.split_at(entry.prefix_len as usize).0, k);
-
-                let block = files_ref.get(&entry.value)?;
-                let record = from_reader::<KnownRecord, _>(&block[..]).ok()?;
-                let mut parts = key.split("/");
-
-                let collection = parts.next()?.to_string();
-                let rkey = RecordKey::new(parts.next()?.to_string()).ok()?;
-                let update = create_big_update(
-                    Did::new(did.to_string()).unwrap(),
-                    did_key.clone(),
-                    collection,
-                    rkey,
-                    record,
-                );
-                Some(update)
-            })
         })
-        // Merge the updates
-        .try_fold(BigUpdate::default(), |mut acc, update| {
-            acc.merge(update?);
-            anyhow::Result::<BigUpdate>::Ok(acc)
-        })?;
+}
 
-    // Add the timestamp of when we retrieved the repo to the update
-    update.add_timestamp(did, retrieval_time);
+/// Convert a downloaded CAR file into database updates
+///
+/// The updates are split into chunks of about `--max-rows-per-transaction` rows. The rows of a record are never split
+/// across chunks. Only the last chunk marks the repo as backfilled, so a repo that was only partially written is
+/// backfilled again.
+///
+/// Only records reachable from the commit at the root of the CAR file are indexed. If a signing key is given, the
+/// commit signature is verified first. Records are converted while the MST is walked and blocks are read from the
+/// file as they are needed, so only the index of the CAR file and the update are kept in memory.
+///
+/// A `partial` CAR file was downloaded with `since` and only contains the blocks that changed since then. Missing
+/// nodes and records are unchanged and skipped. If the PDS ignored `since`, the full repo is indexed.
+#[instrument(skip_all)]
+pub(super) fn convert_repo_to_update(
+    repo: File,
+    did: &str,
+    signing_key: Option<&str>,
+    partial: bool,
+    retrieval_time: DateTime<Utc>,
+) -> anyhow::Result<Vec<BigUpdate>> {
+    let max_rows = ARGS
+        .max_rows_per_transaction
+        .max(ARGS.min_rows_per_transaction);
+    split_repo_into_updates(repo, did, signing_key, partial, retrieval_time, max_rows)
+}
+
+/// Convert a CAR file into updates of about `max_rows` rows, see [convert_repo_to_update]
+fn split_repo_into_updates(
+    repo: File,
+    did: &str,
+    signing_key: Option<&str>,
+    partial: bool,
+    retrieval_time: DateTime<Utc>,
+    max_rows: usize,
+) -> anyhow::Result<Vec<BigUpdate>> {
+    let mut car = CarFile::index(repo)?;
+    let did_key = &did_to_key(did)?;
+
+    let verification_failed =
+        |e: anyhow::Error| anyhow::anyhow!("Repo verification failed for {}: {:#}", did, e);
+    let commit = verify_commit(&mut car, did, signing_key).map_err(verification_failed)?;
+
+    let mut updates = Vec::new();
+    let mut update = BigUpdate::default();
+    let mut rows = 0;
+    let mut skipped = Vec::new();
+    let mut walker = MstWalker::new(commit.data, partial);
+    while let Some((key, cid)) = walker.next(&mut car).map_err(verification_failed)? {
+        let block = match car.get(&cid)? {
+            Some(block) => block,
+            None if partial => continue,
+            None => {
+                return Err(anyhow::anyhow!(
+                    "Repo verification failed for {}: the record {} is missing",
+                    did,
+                    key
+                ))
+            }
+        };
+        let Some((collection, rkey)) = key.split_once('/') else {
+            continue;
+        };
+        if !options().indexes_collection(collection) {
+            continue;
+        }
+        // Records of collections we do not index are skipped
+        let Ok(record) = from_reader::<KnownRecord, _>(&block[..]) else {
+            record_ignored(collection);
+            continue;
+        };
+        let Ok(rkey) = RecordKey::new(rkey.to_string()) else {
+            continue;
+        };
+        let mut record_update = create_big_update(
+            Did::new(did.to_string()).map_err(|e| anyhow::anyhow!(e))?,
+            did_key.clone(),
+            collection.to_string(),
+            rkey,
+            record,
+        );
+        skipped.append(&mut record_update.skipped);
+        rows += record_update.row_count();
+        update.merge(record_update);
+        if rows >= max_rows {
+            updates.push(std::mem::take(&mut update));
+            rows = 0;
+        }
+    }
 
-    Ok(update)
+    // Records that could not be converted were left out of the updates
+    update.skipped = skipped;
+    update.log_skipped(did);
+
+    // Remember the rev of the repo, so a later rebackfill can skip it if nothing changed
+    update.add_timestamp(did_key, retrieval_time, Some(commit.rev));
+    updates.push(update);
+
+    Ok(updates)
 }
 
 #[derive(Debug)]
@@ -152,20 +236,35 @@ pub struct DownloadService {
 #[derive(Debug)]
 pub struct DownloadRepo {
     common: CommonState,
-    service: PlcDirectoryDidResponseService,
+    pub(super) pds_endpoint: String,
+    pub(super) signing_key: Option<String>,
+}
+/// Repo as returned by the download stage
+#[derive(Debug)]
+enum DownloadedRepo {
+    /// The repo as a temporary CAR file
+    Car {
+        file: File,
+        /// The rev the download was requested since. The CAR file may then only contain the changed blocks
+        since: Option<String>,
+    },
+    /// The repo did not change since the last backfill
+    Unchanged { rev: String },
 }
 /// Third pipeline stage
 #[derive(Debug)]
 pub struct ProcessRepo {
     common: CommonState,
-    repo: Vec<u8>,
+    repo: DownloadedRepo,
+    signing_key: Option<String>,
     retrieval_time: DateTime<Utc>,
 }
 /// Fourth pipeline stage
 #[derive(Debug)]
 pub struct ApplyUpdates {
     common: CommonState,
-    update: BigUpdate,
+    /// Applied in order, only the last one marks the repo as backfilled
+    updates: Vec<BigUpdate>,
 }
 
 impl DownloadService {
@@ -186,76 +285,455 @@ impl DownloadService {
     }
 }
 
+/// Get the URL of the DID document for a did:plc or did:web DID
+fn did_document_url(did: &str) -> anyhow::Result<String> {
+    if did.starts_with("did:plc:") {
+        return Ok(format!(
+            "{}/{}",
+            ARGS.plc_directory_url.trim_end_matches('/'),
+            did
+        ));
+    }
+    let Some(host) = did.strip_prefix("did:web:") else {
+        return Err(anyhow::anyhow!("Unsupported DID method in {}", did));
+    };
+    // Colons separate path segments, a port is percent-encoded
+    if host.contains(':') {
+        return Err(anyhow::anyhow!(
+            "Path based did:web {} is not supported by atproto",
+            did
+        ));
+    }
+    let host = host.replace("%3A", ":").replace("%3a", ":");
+    Ok(format!("https://{}/.well-known/did.json", host))
+}
+
 impl Stage for DownloadService {
     type Next = DownloadRepo;
     const NAME: &str = "download_information";
 
+    fn backfill_target(&self) -> Option<(PgPool, String)> {
+        Some((self.common.database.clone(), self.common.did.clone()))
+    }
+
     #[instrument(skip(self), fields(did = self.common.did), parent = self.common.span.clone())]
     async fn run(self) -> anyhow::Result<Self::Next> {
-        let resp = self
-            .common
-            .http_client
-            .get(format!("https://plc.directory/{}", self.common.did))
-            .timeout(Duration::from_secs(ARGS.directory_download_timeout))
-            .send()
+        // Use the cached DID document if it is fresh enough
+        let ttl = Duration::from_secs(ARGS.did_document_ttl.max(0) as u64);
+        let document = cached_did_document(&DID_DOCUMENT_CACHE, &self.common.did, ttl, || async {
+            match crate::database::fetch_cached_did_document(
+                &self.common.database,
+                &self.common.did,
+                ARGS.did_document_ttl,
+            )
             .await?
-            .json::<PlcDirectoryDidResponse>()
-            .await?;
-        let service = resp.service.into_iter().next().ok_or(anyhow::anyhow!(
-            "Failed to get a plc service for {}",
-            self.common.did
-        ))?;
+            {
+                Some(document) => Ok(document),
+                None => self.resolve_did_document().await,
+            }
+        })
+        .await?;
+
         Ok(DownloadRepo {
-            service,
+            pds_endpoint: document.pds_endpoint,
+            signing_key: document.signing_key,
             common: self.common,
         })
     }
 }
 
+/// Resolved DID documents by DID with the time they were cached
+type DidDocumentCache = Mutex<LruCache<String, (Instant, CachedDidDocument)>>;
+
+/// Sits in front of the did_document table, so requeued DIDs do not even need a database lookup
+static DID_DOCUMENT_CACHE: LazyLock<DidDocumentCache> = LazyLock::new(|| {
+    Mutex::new(LruCache::new(
+        NonZeroUsize::new(ARGS.did_document_cache_capacity).unwrap(),
+    ))
+});
+
+/// Get a DID document from the in-memory cache if it is younger than `ttl`
+fn memory_cached_did_document(
+    cache: &DidDocumentCache,
+    did: &str,
+    ttl: Duration,
+) -> Option<CachedDidDocument> {
+    let mut cache = cache.lock().unwrap();
+    let (cached_at, document) = cache.get(did)?;
+    if cached_at.elapsed() < ttl {
+        return Some(document.clone());
+    }
+    cache.pop(did);
+    None
+}
+
+/// Get a DID document from the in-memory cache, or `resolve` it and cache the result
+async fn cached_did_document<F, Fut>(
+    cache: &DidDocumentCache,
+    did: &str,
+    ttl: Duration,
+    resolve: F,
+) -> anyhow::Result<CachedDidDocument>
+where
+    F: FnOnce() -> Fut,
+    Fut: std::future::Future<Output = anyhow::Result<CachedDidDocument>>,
+{
+    if let Some(document) = memory_cached_did_document(cache, did, ttl) {
+        return Ok(document);
+    }
+    let document = resolve().await?;
+    cache
+        .lock()
+        .unwrap()
+        .put(did.to_string(), (Instant::now(), document.clone()));
+    Ok(document)
+}
+
+impl DownloadService {
+    /// Fetch the DID document from the plc directory or the did:web host and store it in the database
+    ///
+    /// Timeouts and server errors are retried. If the directory does not know the DID, it is marked as unbackfillable.
+    async fn resolve_did_document(&self) -> anyhow::Result<CachedDidDocument> {
+        let url = did_document_url(&self.common.did)?;
+        let mut attempts_left = ARGS.directory_download_attempts;
+        let mut attempt = 0;
+        let resp = loop {
+            let error = match self.fetch_did_document(&url).await {
+                Ok(resp) => break resp,
+                Err(error) => error,
+            };
+
+            let status = error.status();
+            if status == Some(StatusCode::NOT_FOUND) || status == Some(StatusCode::GONE) {
+                crate::database::mark_unbackfillable(
+                    &self.common.database,
+                    &self.common.did,
+                    &error.to_string(),
+                )
+                .await?;
+                anyhow::bail!("DID {} does not exist: {}", self.common.did, error);
+            }
+            // Other client errors and invalid documents will not go away by retrying
+            let retryable = error.is_timeout()
+                || error.is_connect()
+                || status.is_some_and(|status| {
+                    status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS
+                });
+            if !retryable {
+                anyhow::bail!(
+                    "Failed to download the DID document of {}: {}",
+                    self.common.did,
+                    error
+                );
+            }
+
+            attempts_left = attempts_left.saturating_sub(1);
+            trace!(
+                "Failed to download the DID document of {} with error: {}, Retrying {} more times",
+                self.common.did,
+                error,
+                attempts_left
+            );
+            let reason = match status {
+                Some(_) => "status",
+                None if error.is_timeout() => "timeout",
+                None => "request",
+            };
+            DIRECTORY_DOWNLOAD_RETRIES.add(1, &[KeyValue::new("reason", reason)]);
+            if attempts_left == 0 {
+                anyhow::bail!(
+                    "Failed to download the DID document of {} after {} attempts",
+                    self.common.did,
+                    ARGS.directory_download_attempts
+                );
+            }
+            tokio::time::sleep(backoff_delay(attempt)).await;
+            attempt += 1;
+        };
+        // The document can list other services before the PDS
+        let service = resp
+            .service
+            .into_iter()
+            .find(|service| {
+                service.type_ == "AtprotoPersonalDataServer" || service.id.ends_with("#atproto_pds")
+            })
+            .ok_or(anyhow::anyhow!(
+                "Failed to find a PDS service for {} in its DID document",
+                self.common.did
+            ))?;
+        let handle = resp
+            .also_known_as
+            .iter()
+            .find_map(|alias| alias.strip_prefix("at://"));
+        let signing_key = resp
+            .verification_method
+            .into_iter()
+            .find(|method| method.id.ends_with("#atproto"))
+            .and_then(|method| method.public_key_multibase);
+        crate::database::write_did_document(
+            &self.common.database,
+            &self.common.did,
+            &service.service_endpoint,
+            handle,
+            signing_key.as_deref(),
+        )
+        .await?;
+
+        Ok(CachedDidDocument {
+            pds_endpoint: service.service_endpoint,
+            signing_key,
+        })
+    }
+
+    /// Make a single request for the DID document
+    async fn fetch_did_document(&self, url: &str) -> Result<DidDocument, reqwest::Error> {
+        self.common
+            .http_client
+            .get(url)
+            .timeout(Duration::from_secs(ARGS.directory_download_timeout))
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<DidDocument>()
+            .await
+    }
+}
+
+static DIRECTORY_DOWNLOAD_RETRIES: LazyLock<Counter<u64>> = LazyLock::new(|| {
+    global::meter("indexer")
+        .u64_counter(instrument_name(
+            "indexer.pipeline.directory_download_retries",
+        ))
+        .with_unit("{retry}")
+        .with_description("Number of retries for downloading a DID document")
+        .build()
+});
+
 static DOWNLOAD_REPO_RETRIES: LazyLock<Counter<u64>> = LazyLock::new(|| {
     global::meter("indexer")
-        .u64_counter("indexer.pipeline.download_repo_retries")
+        .u64_counter(instrument_name("indexer.pipeline.download_repo_retries"))
         .with_unit("{retry}")
         .with_description("Number of retries for downloading a repo")
         .build()
 });
 
-async fn attempt_download(
+/// Upper bound for the delay between two download attempts
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+/// Reasons why a single repo download attempt failed
+#[derive(Debug)]
+pub(super) enum DownloadError {
+    /// The request could not be sent or the body could not be read
+    Request(reqwest::Error),
+    /// The PDS responded with a non-success status code
+    Status {
+        status: StatusCode,
+        retry_after: Option<Duration>,
+    },
+    /// The PDS responded with an empty repo
+    EmptyBody,
+    /// The repo is larger than `--max-repo-size-bytes`
+    TooLarge,
+    /// The repo could not be written to the temporary file
+    Spool(std::io::Error),
+}
+
+impl DownloadError {
+    /// Short reason used as a metric attribute
+    fn reason(&self) -> &'static str {
+        match self {
+            DownloadError::Request(error) if error.is_timeout() => "timeout",
+            DownloadError::Request(_) => "request",
+            DownloadError::Status { status, .. } if *status == StatusCode::TOO_MANY_REQUESTS => {
+                "rate-limited"
+            }
+            DownloadError::Status { status, .. } if status.is_server_error() => "status-5xx",
+            DownloadError::Status { .. } => "status-4xx",
+            DownloadError::EmptyBody => "empty-body",
+            DownloadError::TooLarge => "too-large",
+            DownloadError::Spool(_) => "spool",
+        }
+    }
+
+    /// Whether the repo is gone for good and retrying is pointless
+    fn is_permanent(&self) -> bool {
+        matches!(
+            self,
+            DownloadError::Status { status, .. }
+                if *status == StatusCode::NOT_FOUND || *status == StatusCode::GONE
+        )
+    }
+
+    /// Delay before the next attempt
+    ///
+    /// Honors `Retry-After` if the PDS sent one, otherwise backs off exponentially with jitter
+    fn retry_delay(&self, attempt: u32) -> Duration {
+        if let DownloadError::Status {
+            retry_after: Some(retry_after),
+            ..
+        } = self
+        {
+            return (*retry_after).min(MAX_RETRY_DELAY);
+        }
+        backoff_delay(attempt)
+    }
+}
+
+/// Exponential backoff with jitter for the given attempt, starting at `--download-repo-retry-delay`
+fn backoff_delay(attempt: u32) -> Duration {
+    exponential_backoff(
+        Duration::from_millis(ARGS.download_repo_retry_delay),
+        attempt,
+    )
+}
+
+/// `base` doubled for every attempt up to `MAX_RETRY_DELAY`, randomized to between half and all of it
+fn exponential_backoff(base: Duration, attempt: u32) -> Duration {
+    let delay = base
+        .saturating_mul(1 << attempt.min(16))
+        .min(MAX_RETRY_DELAY);
+    delay / 2 + delay.mul_f64(fastrand::f64() / 2.0)
+}
+
+impl std::fmt::Display for DownloadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DownloadError::Request(error) => write!(f, "{}", error),
+            DownloadError::Status { status, .. } => write!(f, "Statuscode {}", status),
+            DownloadError::EmptyBody => write!(f, "Downloaded repo is empty"),
+            DownloadError::TooLarge => write!(
+                f,
+                "Repo is larger than {} bytes",
+                ARGS.max_repo_size_bytes.unwrap_or_default()
+            ),
+            DownloadError::Spool(error) => write!(f, "Failed to spool repo: {}", error),
+        }
+    }
+}
+
+impl From<reqwest::Error> for DownloadError {
+    fn from(error: reqwest::Error) -> Self {
+        DownloadError::Request(error)
+    }
+}
+
+impl From<std::io::Error> for DownloadError {
+    fn from(error: std::io::Error) -> Self {
+        DownloadError::Spool(error)
+    }
+}
+
+/// Parse a Retry-After header, it is either a number of seconds or an HTTP date
+fn parse_retry_after(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = DateTime::parse_from_rfc2822(value).ok()?;
+    // A date in the past means we can retry right away
+    Some(
+        (date.with_timezone(&Utc) - Utc::now())
+            .to_std()
+            .unwrap_or_default(),
+    )
+}
+
+/// Download a repo into a temporary file
+///
+/// Returns the file and its size in bytes
+pub(super) async fn attempt_download(
     client: &Client,
     url: &str,
     timeout: Duration,
-) -> anyhow::Result<Vec<u8>> {
-    let get_repo_response = client.get(url).timeout(timeout).send().await?;
-    if !get_repo_response.status().is_success() {
-        return Err(anyhow::anyhow!("Statuscode {}", get_repo_response.status()));
+) -> Result<(File, u64), DownloadError> {
+    let mut get_repo_response = client.get(url).timeout(timeout).send().await?;
+    let status = get_repo_response.status();
+    if !status.is_success() {
+        let retry_after = (status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error())
+            .then(|| get_repo_response.headers().get(RETRY_AFTER))
+            .flatten()
+            .and_then(|value| parse_retry_after(value.to_str().ok()?));
+        return Err(DownloadError::Status {
+            status,
+            retry_after,
+        });
     }
-    let repo: Vec<u8> = get_repo_response.bytes().await?.into();
-    if repo.is_empty() {
-        return Err(anyhow::anyhow!("Downloaded repo is empty"));
+    let max_size = ARGS.max_repo_size_bytes.unwrap_or(u64::MAX);
+    if get_repo_response
+        .content_length()
+        .is_some_and(|size| size > max_size)
+    {
+        return Err(DownloadError::TooLarge);
     }
-    Ok(repo)
+
+    // Write the body to a file as it arrives instead of buffering it in memory
+    let mut file = tokio::fs::File::from_std(tempfile::tempfile()?);
+    let mut size = 0;
+    while let Some(chunk) = get_repo_response.chunk().await? {
+        size += chunk.len() as u64;
+        if size > max_size {
+            return Err(DownloadError::TooLarge);
+        }
+        file.write_all(&chunk).await?;
+    }
+    if size == 0 {
+        return Err(DownloadError::EmptyBody);
+    }
+    file.flush().await?;
+    Ok((file.into_std().await, size))
 }
 
 impl Stage for DownloadRepo {
     type Next = ProcessRepo;
     const NAME: &str = "download_repo";
 
+    fn backfill_target(&self) -> Option<(PgPool, String)> {
+        Some((self.common.database.clone(), self.common.did.clone()))
+    }
+
     #[instrument(skip(self), fields(did = self.common.did), parent = self.common.span.clone())]
     async fn run(self) -> anyhow::Result<Self::Next> {
         let retrival_time = chrono::Utc::now();
+        let last_known_rev =
+            crate::database::fetch_last_known_rev(&self.common.database, &self.common.did).await?;
+
+        // Skip the download if the repo did not change since the last backfill
+        if let Some(last_known_rev) = &last_known_rev {
+            if ARGS.rebackfill_older_than.is_some() && self.is_unchanged(last_known_rev).await {
+                trace!(
+                    "Repo {} is unchanged at rev {}",
+                    self.common.did,
+                    last_known_rev
+                );
+                return Ok(ProcessRepo {
+                    repo: DownloadedRepo::Unchanged {
+                        rev: last_known_rev.clone(),
+                    },
+                    signing_key: self.signing_key,
+                    common: self.common,
+                    retrieval_time: retrival_time,
+                });
+            }
+        }
 
         // Download the repo
         let mut attempts_left = ARGS.download_repo_attempts;
-        let repo = loop {
+        let mut attempt = 0;
+        let host_semaphore = host_semaphore(&self.pds_endpoint);
+        let (repo, size) = loop {
+            let permit = host_semaphore.acquire().await?;
             let get_repo_response = attempt_download(
                 &self.common.http_client,
-                &format!(
-                    "{}/xrpc/com.atproto.sync.getRepo?did={}",
-                    self.service.service_endpoint, self.common.did,
+                &get_repo_url(
+                    &self.pds_endpoint,
+                    &self.common.did,
+                    last_known_rev.as_deref(),
                 ),
                 Duration::from_secs(ARGS.download_repo_timeout),
             )
             .await;
+            // Do not block other downloads from the same PDS while waiting for a retry
+            drop(permit);
 
             let error = match get_repo_response {
                 Ok(resp) => {
@@ -264,6 +742,29 @@ impl Stage for DownloadRepo {
                 Err(error) => error,
             };
 
+            // The repo will not get smaller by retrying
+            if let DownloadError::TooLarge = error {
+                break Err(anyhow::anyhow!(
+                    "Repo {} is too large: {}",
+                    self.common.did,
+                    error
+                ));
+            }
+
+            if error.is_permanent() {
+                crate::database::mark_unbackfillable(
+                    &self.common.database,
+                    &self.common.did,
+                    &error.to_string(),
+                )
+                .await?;
+                break Err(anyhow::anyhow!(
+                    "Repo {} is not available: {}",
+                    self.common.did,
+                    error
+                ));
+            }
+
             attempts_left -= 1;
             trace!(
                 "Failed to download repo {} with error: {}, Retrying {} more times",
@@ -271,7 +772,7 @@ impl Stage for DownloadRepo {
                 error,
                 attempts_left
             );
-            DOWNLOAD_REPO_RETRIES.add(1, &[]);
+            DOWNLOAD_REPO_RETRIES.add(1, &[KeyValue::new("reason", error.reason())]);
             if attempts_left == 0 {
                 break Err(anyhow::anyhow!(
                     "Failed to download repo {} after {} attempts",
@@ -279,34 +780,146 @@ impl Stage for DownloadRepo {
                     ARGS.download_repo_attempts
                 ));
             }
+            tokio::time::sleep(error.retry_delay(attempt)).await;
+            attempt += 1;
         }?;
 
         trace!(
             "Downloaded repo {} with size {:.2} MB",
             self.common.did,
-            repo.len() as f64 / (1000.0 * 1000.0)
+            size as f64 / (1000.0 * 1000.0)
         );
         Ok(ProcessRepo {
-            repo,
+            repo: DownloadedRepo::Car {
+                file: repo,
+                since: last_known_rev,
+            },
+            signing_key: self.signing_key,
             common: self.common,
             retrieval_time: retrival_time,
         })
     }
 }
 
+/// Semaphores that limit the concurrent downloads per PDS
+type HostSemaphores = Mutex<HashMap<String, Arc<Semaphore>>>;
+
+static HOST_SEMAPHORES: LazyLock<HostSemaphores> = LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Get the semaphore that limits the concurrent downloads from a PDS
+///
+/// Most repos are hosted on a few large PDSes, the global concurrency alone would send all downloads to them
+fn host_semaphore(pds_endpoint: &str) -> Arc<Semaphore> {
+    semaphore_for_host(&HOST_SEMAPHORES, pds_endpoint, ARGS.max_per_host_downloads)
+}
+
+/// Get the semaphore of a PDS from `semaphores`, a new one starts with `permits` permits
+fn semaphore_for_host(
+    semaphores: &HostSemaphores,
+    pds_endpoint: &str,
+    permits: usize,
+) -> Arc<Semaphore> {
+    semaphores
+        .lock()
+        .unwrap()
+        .entry(pds_endpoint.trim_end_matches('/').to_string())
+        .or_insert_with(|| Arc::new(Semaphore::new(permits)))
+        .clone()
+}
+
+/// Get the URL to download a repo from its PDS
+///
+/// With `since` the PDS only returns the blocks that changed after that rev
+pub(super) fn get_repo_url(pds_endpoint: &str, did: &str, since: Option<&str>) -> String {
+    let mut url = format!("{}/xrpc/com.atproto.sync.getRepo?did={}", pds_endpoint, did);
+    if let Some(since) = since {
+        url.push_str("&since=");
+        url.push_str(since);
+    }
+    url
+}
+
+impl DownloadRepo {
+    /// Check if the repo is still at the rev of the last backfill
+    async fn is_unchanged(&self, last_known_rev: &str) -> bool {
+        // Fall back to a full download if the PDS does not tell us the latest commit
+        let latest_commit = match self
+            .common
+            .http_client
+            .get(format!(
+                "{}/xrpc/com.atproto.sync.getLatestCommit?did={}",
+                self.pds_endpoint, self.common.did,
+            ))
+            .timeout(Duration::from_secs(ARGS.directory_download_timeout))
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+        {
+            Ok(response) => response.json::<LatestCommit>().await,
+            Err(error) => Err(error),
+        };
+        let latest_commit = match latest_commit {
+            Ok(latest_commit) => latest_commit,
+            Err(error) => {
+                trace!(
+                    "Failed to get the latest commit of {}: {}",
+                    self.common.did,
+                    error
+                );
+                return false;
+            }
+        };
+
+        latest_commit.rev == last_known_rev
+    }
+}
+
 impl Stage for ProcessRepo {
     type Next = ApplyUpdates;
     const NAME: &str = "process_repo";
 
+    fn backfill_target(&self) -> Option<(PgPool, String)> {
+        Some((self.common.database.clone(), self.common.did.clone()))
+    }
+
     #[instrument(skip(self), fields(did = self.common.did), parent = self.common.span.clone())]
     async fn run(self) -> anyhow::Result<Self::Next> {
         let did = self.common.did.clone();
-        let big_update =
-            spawn_blocking(move || convert_repo_to_update(self.repo, &did, self.retrieval_time))
-                .await??;
+        let retrieval_time = self.retrieval_time;
+        let updates = match self.repo {
+            DownloadedRepo::Car { file, since } => {
+                // Without a signing key the commit can not be verified
+                let signing_key = match self.signing_key {
+                    Some(signing_key) if ARGS.verify_signatures => Some(signing_key),
+                    None if ARGS.verify_signatures => {
+                        anyhow::bail!(
+                            "Repo verification failed for {}: the DID document has no signing key",
+                            did
+                        )
+                    }
+                    _ => None,
+                };
+                spawn_blocking(move || {
+                    convert_repo_to_update(
+                        file,
+                        &did,
+                        signing_key.as_deref(),
+                        since.is_some(),
+                        retrieval_time,
+                    )
+                })
+                .await??
+            }
+            // Only move the backfill timestamp forward
+            DownloadedRepo::Unchanged { rev } => {
+                let mut update = BigUpdate::default();
+                update.add_timestamp(&did_to_key(&did)?, retrieval_time, Some(rev));
+                vec![update]
+            }
+        };
 
         Ok(ApplyUpdates {
-            update: big_update,
+            updates,
             common: self.common,
         })
     }
@@ -316,16 +929,436 @@ impl Stage for ApplyUpdates {
     type Next = NoNextStage;
     const NAME: &str = "apply_updates";
 
+    fn backfill_target(&self) -> Option<(PgPool, String)> {
+        Some((self.common.database.clone(), self.common.did.clone()))
+    }
+
     #[instrument(skip(self), fields(did = self.common.did), parent = self.common.span.clone())]
     async fn run(self) -> anyhow::Result<Self::Next> {
-        if !ARGS.no_write_when_backfilling {
-            self.update
+        self.apply(ARGS.validate_only).await?;
+        Ok(NoNextStage {})
+    }
+}
+
+impl ApplyUpdates {
+    /// Write the updates, or only count their rows without beginning a transaction if `validate_only` is set
+    async fn apply(self, validate_only: bool) -> anyhow::Result<()> {
+        if validate_only {
+            let rows: u64 = self.updates.into_iter().map(BigUpdate::validate).sum();
+            debug!(
+                "Validated {} rows of {} without writing them",
+                rows, self.common.did
+            );
+            return Ok(());
+        }
+        for update in self.updates {
+            update
                 .apply(self.common.database.clone(), "backfill")
                 .await?;
-        } else {
-            warn!("Skipping writing to the database and sleeping instead");
-            std::thread::sleep(Duration::from_secs(2));
         }
-        Ok(NoNextStage {})
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ipld_core::ipld::Ipld;
+
+    #[test]
+    fn retry_after_in_seconds() {
+        assert_eq!(parse_retry_after("120"), Some(Duration::from_secs(120)));
+        assert_eq!(parse_retry_after(" 0 "), Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn retry_after_as_http_date() {
+        let date = (Utc::now() + chrono::Duration::seconds(120))
+            .format("%a, %d %b %Y %H:%M:%S GMT")
+            .to_string();
+        let delay = parse_retry_after(&date).unwrap();
+        assert!(delay > Duration::from_secs(115), "{:?}", delay);
+        assert!(delay <= Duration::from_secs(120), "{:?}", delay);
+    }
+
+    #[test]
+    fn retry_after_in_the_past_retries_right_away() {
+        assert_eq!(
+            parse_retry_after("Sun, 06 Nov 1994 08:49:37 GMT"),
+            Some(Duration::ZERO)
+        );
+    }
+
+    #[test]
+    fn invalid_retry_after_is_ignored() {
+        assert_eq!(parse_retry_after("soon"), None);
+        assert_eq!(parse_retry_after("-5"), None);
+        assert_eq!(parse_retry_after(""), None);
+    }
+
+    #[test]
+    fn retry_after_is_capped() {
+        let error = DownloadError::Status {
+            status: StatusCode::TOO_MANY_REQUESTS,
+            retry_after: Some(Duration::from_secs(3600)),
+        };
+        assert_eq!(error.retry_delay(0), MAX_RETRY_DELAY);
+    }
+
+    #[test]
+    fn backoff_doubles_with_jitter() {
+        let base = Duration::from_millis(500);
+        for attempt in 0..6 {
+            let delay = base * (1 << attempt);
+            for _ in 0..100 {
+                let backoff = exponential_backoff(base, attempt);
+                assert!(
+                    backoff >= delay / 2,
+                    "{:?} for attempt {}",
+                    backoff,
+                    attempt
+                );
+                assert!(backoff <= delay, "{:?} for attempt {}", backoff, attempt);
+            }
+        }
+    }
+
+    #[test]
+    fn backoff_is_capped() {
+        for attempt in [7, 16, 40, u32::MAX] {
+            let backoff = exponential_backoff(Duration::from_millis(500), attempt);
+            assert!(backoff >= MAX_RETRY_DELAY / 2, "{:?}", backoff);
+            assert!(backoff <= MAX_RETRY_DELAY, "{:?}", backoff);
+        }
+    }
+
+    /// Run one fake download per host at the same time and return the most that ran at once
+    async fn concurrent_downloads(hosts: [&str; 2]) -> usize {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let semaphores = Mutex::new(HashMap::new());
+        let running = AtomicUsize::new(0);
+        let max_running = AtomicUsize::new(0);
+        let download = |host| {
+            let semaphore = semaphore_for_host(&semaphores, host, 1);
+            let (running, max_running) = (&running, &max_running);
+            async move {
+                let _permit = semaphore.acquire().await.unwrap();
+                let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                max_running.fetch_max(now, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                running.fetch_sub(1, Ordering::SeqCst);
+            }
+        };
+        tokio::join!(download(hosts[0]), download(hosts[1]));
+        max_running.load(Ordering::SeqCst)
+    }
+
+    #[tokio::test]
+    async fn downloads_from_the_same_host_are_serialized() {
+        assert_eq!(
+            concurrent_downloads(["https://pds.example.com", "https://pds.example.com/"]).await,
+            1
+        );
+        assert_eq!(
+            concurrent_downloads(["https://pds.example.com", "https://other.example.com"]).await,
+            2
+        );
+    }
+
+    #[tokio::test]
+    async fn did_documents_are_resolved_once_per_ttl() {
+        let cache = Mutex::new(LruCache::new(NonZeroUsize::new(10).unwrap()));
+        let requests = std::sync::atomic::AtomicUsize::new(0);
+        // Stands in for the HTTP request to the directory
+        let resolve = || async {
+            requests.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            Ok(CachedDidDocument {
+                pds_endpoint: "https://pds.example.com".to_string(),
+                signing_key: None,
+            })
+        };
+        let did = "did:plc:z72i7hdynmk6r22z27h6tvur";
+        let ttl = Duration::from_secs(60);
+
+        let first = cached_did_document(&cache, did, ttl, resolve)
+            .await
+            .unwrap();
+        let second = cached_did_document(&cache, did, ttl, resolve)
+            .await
+            .unwrap();
+        assert_eq!(first.pds_endpoint, second.pds_endpoint);
+        assert_eq!(requests.load(std::sync::atomic::Ordering::SeqCst), 1);
+
+        // An expired entry is resolved again
+        cached_did_document(&cache, did, Duration::ZERO, resolve)
+            .await
+            .unwrap();
+        assert_eq!(requests.load(std::sync::atomic::Ordering::SeqCst), 2);
+    }
+
+    fn string(value: &str) -> Ipld {
+        Ipld::String(value.to_string())
+    }
+
+    fn map<const N: usize>(fields: [(&str, Ipld); N]) -> Ipld {
+        Ipld::Map(
+            fields
+                .into_iter()
+                .map(|(key, value)| (key.to_string(), value))
+                .collect(),
+        )
+    }
+
+    /// A repo of `count` posts with an image, every post after the first replies to the one before
+    fn repo_of_posts(did: &str, count: usize) -> File {
+        use super::super::car_file::testing::block;
+
+        let image = block(&string("image")).0;
+        let mut records = Vec::new();
+        for i in 0..count {
+            let reply = |i: usize| {
+                let (cid, _) = block(&Ipld::Integer(i as i128));
+                map([
+                    (
+                        "uri",
+                        string(&format!("at://{}/app.bsky.feed.post/3lpost{:06}", did, i)),
+                    ),
+                    ("cid", string(&cid.to_string())),
+                ])
+            };
+            let mut post = vec![
+                ("$type", string("app.bsky.feed.post")),
+                ("text", string("hello")),
+                ("createdAt", string("2024-01-01T00:00:00.000Z")),
+                (
+                    "embed",
+                    map([
+                        ("$type", string("app.bsky.embed.images")),
+                        (
+                            "images",
+                            Ipld::List(vec![map([
+                                ("alt", string("an image")),
+                                (
+                                    "image",
+                                    map([
+                                        ("$type", string("blob")),
+                                        ("ref", Ipld::Link(image)),
+                                        ("mimeType", string("image/jpeg")),
+                                        ("size", Ipld::Integer(1234)),
+                                    ]),
+                                ),
+                            ])]),
+                        ),
+                    ]),
+                ),
+            ];
+            if i > 0 {
+                post.push(("reply", map([("root", reply(0)), ("parent", reply(i - 1))])));
+            }
+            records.push((
+                format!("app.bsky.feed.post/3lpost{:06}", i),
+                Ipld::Map(
+                    post.into_iter()
+                        .map(|(key, value)| (key.to_string(), value))
+                        .collect(),
+                ),
+            ));
+        }
+        repo_of_records(did, records)
+    }
+
+    /// A repo with a single MST node holding `records`, which have to be sorted by their key
+    fn repo_of_records(did: &str, records: Vec<(String, Ipld)>) -> File {
+        use super::super::car_file::testing::{block, car};
+        use std::io::Write;
+
+        let mut blocks = Vec::new();
+        let mut entries = Vec::new();
+        for (key, record) in records {
+            let (cid, record) = block(&record);
+            blocks.push((cid, record));
+            // Keys are not prefix compressed, which is valid as well
+            entries.push(map([
+                ("p", Ipld::Integer(0)),
+                ("k", Ipld::Bytes(key.into_bytes())),
+                ("v", Ipld::Link(cid)),
+                ("t", Ipld::Null),
+            ]));
+        }
+        let (root, node) = block(&map([("l", Ipld::Null), ("e", Ipld::List(entries))]));
+        blocks.push((root, node));
+        let (commit_cid, commit) = block(&map([
+            ("did", string(did)),
+            ("version", Ipld::Integer(3)),
+            ("data", Ipld::Link(root)),
+            ("rev", string("3lrev")),
+            ("prev", Ipld::Null),
+            ("sig", Ipld::Bytes(vec![0; 64])),
+        ]));
+        blocks.push((commit_cid, commit));
+
+        let mut file = tempfile::tempfile().unwrap();
+        file.write_all(&car(commit_cid, &blocks)).unwrap();
+        file
+    }
+
+    /// Ids of the rows of a table in an update
+    fn row_ids(update: &BigUpdate, table: &str) -> Vec<String> {
+        let update = serde_json::to_value(update).unwrap();
+        update[table]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|row| row["id"].as_str().unwrap().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn a_likes_only_policy_only_indexes_likes() {
+        use crate::database::big_update::options::{
+            testing::with_options, ApplyOptions, RecordType,
+        };
+
+        let created_at = || ("createdAt", string("2024-01-01T00:00:00.000Z"));
+        let like = |i: usize| {
+            let (cid, _) = super::super::car_file::testing::block(&Ipld::Integer(i as i128));
+            map([
+                ("$type", string("app.bsky.feed.like")),
+                (
+                    "subject",
+                    map([
+                        (
+                            "uri",
+                            string(&format!(
+                                "at://did:plc:other/app.bsky.feed.post/3lpost{:06}",
+                                i
+                            )),
+                        ),
+                        ("cid", string(&cid.to_string())),
+                    ]),
+                ),
+                created_at(),
+            ])
+        };
+        let records = vec![
+            ("app.bsky.feed.like/3llike000000".to_string(), like(0)),
+            ("app.bsky.feed.like/3llike000001".to_string(), like(1)),
+            (
+                "app.bsky.feed.post/3lpost000000".to_string(),
+                map([
+                    ("$type", string("app.bsky.feed.post")),
+                    ("text", string("hello")),
+                    created_at(),
+                ]),
+            ),
+            (
+                "app.bsky.graph.follow/3lfollow0000".to_string(),
+                map([
+                    ("$type", string("app.bsky.graph.follow")),
+                    ("subject", string("did:plc:other")),
+                    created_at(),
+                ]),
+            ),
+        ];
+        let repo = repo_of_records("did:plc:liker", records);
+        let options = ApplyOptions {
+            record_types: vec![RecordType::Likes],
+            ..Default::default()
+        };
+        let updates = with_options(options, || {
+            split_repo_into_updates(repo, "did:plc:liker", None, false, Utc::now(), 1000)
+        })
+        .unwrap();
+
+        assert_eq!(updates.len(), 1);
+        let update = serde_json::to_value(&updates[0]).unwrap();
+        let tables = update
+            .as_object()
+            .unwrap()
+            .iter()
+            .filter(|(_, rows)| rows.as_array().is_some_and(|rows| !rows.is_empty()))
+            .map(|(table, _)| table.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(tables, ["overwrite_latest_backfills", "likes"]);
+        assert_eq!(
+            row_ids(&updates[0], "likes"),
+            ["3llike000000_plc_liker", "3llike000001_plc_liker"]
+        );
+    }
+
+    #[test]
+    fn relations_stay_in_the_chunk_of_their_post() {
+        let repo = repo_of_posts("did:plc:poster", 100);
+        let updates =
+            split_repo_into_updates(repo, "did:plc:poster", None, false, Utc::now(), 25).unwrap();
+
+        // Every reply has four rows, so a chunk is cut after the seventh post
+        assert_eq!(updates.len(), 15);
+        let mut posts = 0;
+        for update in &updates {
+            assert!(update.row_count() < 25 + 4);
+            let ids = row_ids(update, "posts");
+            assert!(update.posts().iter().all(|post| post.data.images.is_some()));
+            assert_eq!(row_ids(update, "posts_relations"), ids);
+            let replies = ids.iter().filter(|id| !id.starts_with("3lpost000000"));
+            assert!(replies
+                .clone()
+                .eq(row_ids(update, "replies_relations").iter()));
+            assert!(replies.eq(row_ids(update, "reply_to_relations").iter()));
+            posts += ids.len();
+        }
+        assert_eq!(posts, 100);
+
+        // Only the last chunk marks the repo as backfilled
+        let (last, chunks) = updates.split_last().unwrap();
+        assert_eq!(row_ids(last, "overwrite_latest_backfills"), ["plc_poster"]);
+        assert!(chunks
+            .iter()
+            .all(|update| row_ids(update, "overwrite_latest_backfills").is_empty()));
+    }
+
+    #[test]
+    fn repo_url_with_and_without_since() {
+        assert_eq!(
+            get_repo_url("https://pds.example.com", "did:plc:abc", None),
+            "https://pds.example.com/xrpc/com.atproto.sync.getRepo?did=did:plc:abc"
+        );
+        assert_eq!(
+            get_repo_url("https://pds.example.com", "did:plc:abc", Some("3lrev")),
+            "https://pds.example.com/xrpc/com.atproto.sync.getRepo?did=did:plc:abc&since=3lrev"
+        );
+    }
+
+    #[test]
+    fn full_repos_are_indexed_when_the_pds_ignores_since() {
+        let repo = repo_of_posts("did:plc:poster", 10);
+        let updates =
+            split_repo_into_updates(repo, "did:plc:poster", None, true, Utc::now(), 1000).unwrap();
+        assert_eq!(updates.len(), 1);
+        assert_eq!(updates[0].posts().len(), 10);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn validate_only_begins_no_transaction() {
+        // Nothing listens on port 1, so beginning a transaction would fail
+        let database = sqlx::postgres::PgPoolOptions::new()
+            .acquire_timeout(Duration::from_millis(100))
+            .connect_lazy("postgres://indexer@127.0.0.1:1/indexer")
+            .unwrap();
+        let repo = repo_of_posts("did:plc:poster", 10);
+        let stage = ApplyUpdates {
+            common: CommonState {
+                database: database.clone(),
+                http_client: Client::new(),
+                did: "did:plc:poster".to_string(),
+                span: Span::none(),
+            },
+            updates: split_repo_into_updates(repo, "did:plc:poster", None, false, Utc::now(), 1000)
+                .unwrap(),
+        };
+
+        stage.apply(true).await.unwrap();
+        assert_eq!(database.size(), 0);
     }
 }
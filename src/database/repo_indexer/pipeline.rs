@@ -1,17 +1,50 @@
-use crate::config::ARGS;
+use crate::{config::ARGS, database::record_backfill_failure, observability::instrument_name};
 use futures::FutureExt;
 use opentelemetry::{
     global,
     metrics::{Counter, Histogram, UpDownCounter},
     KeyValue,
 };
+use sqlx::PgPool;
 use std::{
+    collections::BTreeMap,
     future::Future,
     marker::PhantomData,
     pin::Pin,
-    sync::{Arc, LazyLock},
+    sync::{Arc, LazyLock, Mutex},
 };
-use tracing::{error, trace};
+use tracing::{error, trace, warn};
+
+static TRACKER: LazyLock<UpDownCounter<i64>> = LazyLock::new(|| {
+    global::meter("indexer")
+        .i64_up_down_counter(instrument_name("indexer.pipeline.location"))
+        .with_description("Track the number of tasks in the pipeline")
+        .with_unit("tasks")
+        .build()
+});
+
+/// Number of tasks per stage and state, mirrors the TRACKER metric for the status server
+static LOCATIONS: LazyLock<Mutex<BTreeMap<(&'static str, &'static str), i64>>> =
+    LazyLock::new(|| Mutex::new(BTreeMap::new()));
+
+/// Move `delta` tasks into the given stage and state
+fn track_location(stage: &'static str, state: &'static str, delta: i64) {
+    TRACKER.add(
+        delta,
+        &[KeyValue::new("stage", stage), KeyValue::new("state", state)],
+    );
+    *LOCATIONS.lock().unwrap().entry((stage, state)).or_default() += delta;
+}
+
+/// Get the number of tasks per stage and state
+pub fn pipeline_locations() -> Vec<(&'static str, &'static str, i64)> {
+    LOCATIONS
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|((stage, state), count)| (*stage, *state, *count))
+        .collect()
+}
 
 pub struct NoNextStage {}
 pub trait NextStage {
@@ -31,7 +64,21 @@ pub trait Stage {
     type Next: NextStage + Sync + Send + 'static;
     const NAME: &'static str;
     const FIRST: bool = false;
-    fn run(self) -> impl Future<Output = anyhow::Result<Self::Next>> + Send + Sync + 'static;
+    fn run(self) -> impl Future<Output = anyhow::Result<Self::Next>> + Send + 'static;
+    /// Database and DID to record a failure of this stage for
+    fn backfill_target(&self) -> Option<(PgPool, String)> {
+        None
+    }
+}
+
+/// Record a failed stage in the dead-letter table, so the repo stream backs off from the DID
+async fn record_failure(target: Option<(PgPool, String)>, stage: &'static str, error: String) {
+    let Some((database, did)) = target else {
+        return;
+    };
+    if let Err(e) = record_backfill_failure(&database, &did, stage, &error).await {
+        warn!("Failed to record backfill failure for {}: {}", did, e);
+    }
 }
 
 pub struct FirstStage<
@@ -83,16 +130,9 @@ where
     FROM: Stage + Send + Sync + 'static,
     FROM::Next: Send + Sync + 'static,
 {
-    static TRACKER: LazyLock<UpDownCounter<i64>> = LazyLock::new(|| {
-        global::meter("indexer")
-            .i64_up_down_counter("indexer.pipeline.location")
-            .with_description("Track the number of tasks in the pipeline")
-            .with_unit("tasks")
-            .build()
-    });
     static RUNTIME_METRIC: LazyLock<Histogram<u64>> = LazyLock::new(|| {
         global::meter("indexer")
-            .u64_histogram("indexer.pipeline.duration")
+            .u64_histogram(instrument_name("indexer.pipeline.duration"))
             .with_unit("ms")
             .with_description("Pipeline job duration")
             .with_boundaries(vec![
@@ -104,14 +144,14 @@ where
     });
     static COMPLETED: LazyLock<Counter<u64>> = LazyLock::new(|| {
         global::meter("indexer")
-            .u64_counter("indexer.pipeline.completed")
+            .u64_counter(instrument_name("indexer.pipeline.completed"))
             .with_description("Pipelines finished")
             .with_unit("tasks")
             .build()
     });
     static FAILED: LazyLock<Counter<u64>> = LazyLock::new(|| {
         global::meter("indexer")
-            .u64_counter("indexer.pipeline.failed")
+            .u64_counter(instrument_name("indexer.pipeline.failed"))
             .with_description("Pipelines failed")
             .with_unit("tasks")
             .build()
@@ -121,23 +161,12 @@ where
             tokio::task::spawn(async move {
                 // Move from queued to active
                 if !FROM::FIRST {
-                    TRACKER.add(
-                        -1,
-                        &[
-                            KeyValue::new("stage", FROM::NAME),
-                            KeyValue::new("state", "queued"),
-                        ],
-                    );
+                    track_location(FROM::NAME, "queued", -1);
                 }
-                TRACKER.add(
-                    1,
-                    &[
-                        KeyValue::new("stage", FROM::NAME),
-                        KeyValue::new("state", "active"),
-                    ],
-                );
+                track_location(FROM::NAME, "active", 1);
 
                 // Run the stage
+                let target = x.backfill_target();
                 let before = std::time::Instant::now();
                 let result = tokio::time::timeout(
                     tokio::time::Duration::from_secs(ARGS.pipeline_stage_timeout),
@@ -147,13 +176,7 @@ where
                 let duration = before.elapsed();
 
                 // Move away from active
-                TRACKER.add(
-                    -1,
-                    &[
-                        KeyValue::new("stage", FROM::NAME),
-                        KeyValue::new("state", "active"),
-                    ],
-                );
+                track_location(FROM::NAME, "active", -1);
 
                 // Check if the stage timed out
                 let Ok(result) = result else {
@@ -176,6 +199,7 @@ where
                             KeyValue::new("result", "timeout"),
                         ],
                     );
+                    record_failure(target, FROM::NAME, "Timed out".to_string()).await;
                     return None;
                 };
 
@@ -203,6 +227,7 @@ where
                             ],
                         );
                         // error!(target: "indexer", "Failed to index repo: {}", error);
+                        record_failure(target, FROM::NAME, error.to_string()).await;
                         return None;
                     }
                     Ok(result) => result,
@@ -210,13 +235,7 @@ where
 
                 // If we are done, we track as a completed pipeline. Otherwise track as queued for the next stage.
                 if !FROM::Next::DONE {
-                    TRACKER.add(
-                        1,
-                        &[
-                            KeyValue::new("stage", FROM::Next::NAME),
-                            KeyValue::new("state", "queued"),
-                        ],
-                    );
+                    track_location(FROM::Next::NAME, "queued", 1);
                 } else {
                     COMPLETED.add(1, &[]);
                 }
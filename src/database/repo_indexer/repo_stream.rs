@@ -1,29 +1,45 @@
-use crate::{config::ARGS, database::utils::unsafe_user_key_to_did};
+use crate::{config::ARGS, database::utils::key_to_did};
 use chrono::{DateTime, Utc};
 use futures::{FutureExt, Stream};
 use sqlx::PgPool;
-use std::{
-    collections::{HashSet, VecDeque},
-    future::{Future, IntoFuture},
-    pin::Pin,
-    task::Poll,
-};
-use tracing::{error, trace};
+use std::{collections::VecDeque, future::Future, pin::Pin, task::Poll, time::Duration};
+use tracing::{error, trace, warn};
 
+/// Delay before starting over after a pass over all unbackfilled DIDs returned nothing
+const MIN_IDLE_DELAY: Duration = Duration::from_secs(5);
+/// Upper bound for the idle delay
+const MAX_IDLE_DELAY: Duration = Duration::from_secs(300);
+
+/// Stream of DIDs that still need to be backfilled
+///
+/// Pages through `latest_backfill` ordered by id, so rows that are still in flight are not fetched again on the next page.
+/// With `--rebackfill-older-than` it then pages through the repos that were backfilled too long ago, oldest first.
+/// After reaching the end it waits and starts over to pick up new DIDs and failed DIDs whose cooldown passed.
+/// DIDs that are still in the pipeline at that point may be fetched again, which is fine because backfilling is idempotent.
 pub struct RepoStream {
     buffer: VecDeque<String>,
-    processed_dids: HashSet<String>,
     db: sqlx::PgPool,
     db_future: Option<Pin<Box<dyn Future<Output = Result<Vec<DbBackfill>, sqlx::Error>> + Send>>>,
+    /// Whether we are currently paging through repos that are backfilled again
+    stale: bool,
+    /// Id of the last fetched row, empty to start from the beginning
+    last_id: String,
+    /// Backfill time of the last fetched stale row
+    last_at: DateTime<Utc>,
+    /// Delay before the next query, set after reaching the end
+    idle_delay: Option<Duration>,
 }
 
 impl RepoStream {
     pub fn new(db: PgPool) -> Self {
         Self {
             buffer: VecDeque::new(),
-            processed_dids: HashSet::new(),
             db,
             db_future: None,
+            stale: false,
+            last_id: String::new(),
+            last_at: DateTime::UNIX_EPOCH,
+            idle_delay: None,
         }
     }
 }
@@ -34,6 +50,95 @@ struct DbBackfill {
     of_did_id: String,
 }
 
+/// Fetch the next page of unbackfilled DIDs after `last_id`
+async fn fetch_page(
+    db: PgPool,
+    last_id: String,
+    delay: Option<Duration>,
+) -> Result<Vec<DbBackfill>, sqlx::Error> {
+    if let Some(delay) = delay {
+        tokio::time::sleep(delay).await;
+    }
+    sqlx::query_as!(
+        DbBackfill,
+        // Skip DIDs that jetstream reported as inactive or whose repos are permanently gone.
+        // Failed DIDs are skipped until their cooldown passed and for good after too many attempts
+        r"
+SELECT id, of_did_id, at FROM latest_backfill
+WHERE at IS NULL AND unbackfillable_reason IS NULL AND id > $4::TEXT AND NOT EXISTS (
+    SELECT 1 FROM jetstream_account_event
+    WHERE jetstream_account_event.id = latest_backfill.of_did_id
+    AND NOT jetstream_account_event.active
+) AND NOT EXISTS (
+    SELECT 1 FROM backfill_failure
+    WHERE backfill_failure.of_did_id = latest_backfill.of_did_id
+    AND (
+        backfill_failure.attempts >= $2::INT
+        OR backfill_failure.last_attempt_at > NOW() - $3::BIGINT * INTERVAL '1 second'
+    )
+)
+ORDER BY id
+LIMIT $1",
+        &(*&ARGS.repo_stream_buffer_size as i64),
+        ARGS.backfill_max_attempts,
+        ARGS.backfill_retry_cooldown,
+        last_id
+    )
+    .fetch_all(&db)
+    .await
+}
+
+/// Fetch the next page of DIDs that were backfilled more than `days` ago, oldest first
+async fn fetch_stale_page(
+    db: PgPool,
+    days: i64,
+    last_at: DateTime<Utc>,
+    last_id: String,
+) -> Result<Vec<DbBackfill>, sqlx::Error> {
+    sqlx::query_as!(
+        DbBackfill,
+        r"
+SELECT id, of_did_id, at FROM latest_backfill
+WHERE at < NOW() - $2::BIGINT * INTERVAL '1 day' AND (at, id) > ($3::TIMESTAMPTZ, $4::TEXT)
+AND unbackfillable_reason IS NULL AND NOT EXISTS (
+    SELECT 1 FROM jetstream_account_event
+    WHERE jetstream_account_event.id = latest_backfill.of_did_id
+    AND NOT jetstream_account_event.active
+) AND NOT EXISTS (
+    SELECT 1 FROM backfill_failure
+    WHERE backfill_failure.of_did_id = latest_backfill.of_did_id
+    AND (
+        backfill_failure.attempts >= $5::INT
+        OR backfill_failure.last_attempt_at > NOW() - $6::BIGINT * INTERVAL '1 second'
+    )
+)
+ORDER BY at, id
+LIMIT $1",
+        &(*&ARGS.repo_stream_buffer_size as i64),
+        days,
+        last_at,
+        last_id,
+        ARGS.backfill_max_attempts,
+        ARGS.backfill_retry_cooldown
+    )
+    .fetch_all(&db)
+    .await
+}
+
+/// DIDs of the fetched rows, rows with an invalid DID key are skipped
+fn decode_dids(rows: &[DbBackfill]) -> impl Iterator<Item = String> + '_ {
+    // TODO: Investigate if we can just use the RecordId directly
+    rows.iter().filter_map(
+        |latest_backfill| match key_to_did(&latest_backfill.of_did_id) {
+            Ok(did) => Some(did),
+            Err(err) => {
+                warn!("RepoStream skipping invalid DID key: {:?}", err);
+                None
+            }
+        },
+    )
+}
+
 impl Stream for RepoStream {
     type Item = String;
 
@@ -51,17 +156,17 @@ impl Stream for RepoStream {
             let db_future = match &mut self.db_future {
                 Some(db_future) => db_future,
                 _ => {
-                    // Totally unsafe cast where we create a static ref to self.db using transmute
-                    let static_db_ref =
-                        unsafe { std::mem::transmute::<&PgPool, &'static PgPool>(&self.db) };
-                    let db_future = sqlx::query_as!(
-                        DbBackfill,
-                        "SELECT * FROM latest_backfill WHERE at IS NULL LIMIT $1",
-                        &(*&ARGS.repo_stream_buffer_size as i64)
-                    )
-                    .fetch_all(static_db_ref)
-                    .into_future()
-                    .boxed();
+                    let db_future = match (self.stale, ARGS.rebackfill_older_than) {
+                        (true, Some(days)) => fetch_stale_page(
+                            self.db.clone(),
+                            days,
+                            self.last_at,
+                            self.last_id.clone(),
+                        )
+                        .boxed(),
+                        _ => fetch_page(self.db.clone(), self.last_id.clone(), self.idle_delay)
+                            .boxed(),
+                    };
 
                     self.db_future = Some(db_future);
                     self.db_future.as_mut().unwrap()
@@ -81,17 +186,36 @@ impl Stream for RepoStream {
                 }
             };
 
-            let starttime = std::time::Instant::now();
-            for latest_backfill in &follows {
-                let key = latest_backfill.of_did_id.clone();
-                if self.processed_dids.contains(&key) {
-                    continue;
-                }
-                self.processed_dids.insert(key.clone());
-                // TODO: Investigate if we can just use the RecordId directly
-                let did = unsafe_user_key_to_did(&format!("{}", key));
-                self.buffer.push_back(did);
+            // Continue with the stale repos once all new ones are fetched
+            if follows.is_empty() && !self.stale && ARGS.rebackfill_older_than.is_some() {
+                trace!("RepoStream fetched all new DIDs, continuing with stale ones");
+                self.stale = true;
+                self.last_id.clear();
+                self.last_at = DateTime::UNIX_EPOCH;
+                continue;
             }
+
+            // Start over after a delay once we reached the end
+            let Some(last) = follows.last() else {
+                let delay = self
+                    .idle_delay
+                    .map_or(MIN_IDLE_DELAY, |delay| delay * 2)
+                    .min(MAX_IDLE_DELAY);
+                trace!(
+                    "RepoStream reached the end, starting over in {}s",
+                    delay.as_secs()
+                );
+                self.stale = false;
+                self.last_id.clear();
+                self.idle_delay = Some(delay);
+                continue;
+            };
+            self.last_id = last.id.clone();
+            self.last_at = last.at.unwrap_or(DateTime::UNIX_EPOCH);
+            self.idle_delay = None;
+
+            let starttime = std::time::Instant::now();
+            self.buffer.extend(decode_dids(&follows));
             let duration = starttime.elapsed();
             trace!(
                 "RepoStream processed {} records in {}ms",
@@ -103,3 +227,30 @@ impl Stream for RepoStream {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn row(of_did_id: &str) -> DbBackfill {
+        DbBackfill {
+            id: of_did_id.to_string(),
+            at: None,
+            of_did_id: of_did_id.to_string(),
+        }
+    }
+
+    #[test]
+    fn keys_with_query_syntax_are_rejected() {
+        let rows = [
+            row("plc_abc"),
+            row("plc_abc;DROP TABLE follow"),
+            row("web_example_com;--"),
+            row("web_example_com"),
+        ];
+        assert_eq!(
+            decode_dids(&rows).collect::<Vec<_>>(),
+            ["did:plc:abc", "did:web:example.com"]
+        );
+    }
+}
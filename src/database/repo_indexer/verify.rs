@@ -0,0 +1,273 @@
+use super::{car_file::CarFile, index_repo::NodeData};
+use anyhow::Context;
+use ipld_core::{cid::Cid, ipld::Ipld};
+use serde::Deserialize;
+use serde_ipld_dagcbor::from_reader;
+use std::collections::BTreeMap;
+
+/// Multicodec prefix of a compressed secp256k1 public key
+const SECP256K1_PREFIX: [u8; 2] = [0xe7, 0x01];
+/// Multicodec prefix of a compressed p256 public key
+const P256_PREFIX: [u8; 2] = [0x80, 0x24];
+
+/// Signed commit object at the root of a repo
+///
+/// https://atproto.com/specs/repository
+#[derive(Deserialize, Debug)]
+#[allow(dead_code)]
+pub struct RepoCommit {
+    pub did: String,
+    pub version: i64,
+    pub data: Cid,
+    pub rev: String,
+    #[serde(with = "serde_bytes")]
+    pub sig: Vec<u8>,
+}
+
+/// Load the commit at the root of a repo and check that it belongs to `did`
+///
+/// If a signing key is given, the commit signature is verified as well
+pub fn verify_commit(
+    car: &mut CarFile,
+    did: &str,
+    signing_key: Option<&str>,
+) -> anyhow::Result<RepoCommit> {
+    let root = *car.roots().first().context("The CAR file has no root")?;
+    let block = car
+        .get(&root)?
+        .with_context(|| format!("The commit block {} is missing", root))?;
+    let commit = from_reader::<RepoCommit, _>(&block[..]).context("Invalid commit block")?;
+
+    anyhow::ensure!(
+        commit.did == did,
+        "The commit belongs to {} instead of {}",
+        commit.did,
+        did
+    );
+    anyhow::ensure!(
+        commit.version == 3,
+        "Unsupported repo version {}",
+        commit.version
+    );
+    anyhow::ensure!(!commit.rev.is_empty(), "The commit has no rev");
+
+    if let Some(signing_key) = signing_key {
+        verify_signature(&block, &commit.sig, signing_key)?;
+    }
+
+    Ok(commit)
+}
+
+/// Verify the signature of a commit block against a multikey encoded public key
+fn verify_signature(block: &[u8], sig: &[u8], signing_key: &str) -> anyhow::Result<()> {
+    // The signature covers the commit without the sig field
+    let mut unsigned =
+        from_reader::<BTreeMap<String, Ipld>, _>(block).context("Invalid commit block")?;
+    unsigned.remove("sig");
+    let unsigned = serde_ipld_dagcbor::to_vec(&unsigned).context("Failed to encode commit")?;
+
+    let (_, key) = multibase::decode(signing_key).context("Invalid signing key")?;
+    if let Some(key) = key.strip_prefix(&SECP256K1_PREFIX) {
+        use k256::ecdsa::{signature::Verifier, Signature, VerifyingKey};
+        let key = VerifyingKey::from_sec1_bytes(key).context("Invalid secp256k1 signing key")?;
+        let sig = Signature::from_slice(sig).context("Invalid commit signature")?;
+        key.verify(&unsigned, &sig)
+            .context("The commit signature does not match")?;
+    } else if let Some(key) = key.strip_prefix(&P256_PREFIX) {
+        use p256::ecdsa::{signature::Verifier, Signature, VerifyingKey};
+        let key = VerifyingKey::from_sec1_bytes(key).context("Invalid p256 signing key")?;
+        let sig = Signature::from_slice(sig).context("Invalid commit signature")?;
+        key.verify(&unsigned, &sig)
+            .context("The commit signature does not match")?;
+    } else {
+        anyhow::bail!("Unsupported signing key type");
+    }
+
+    Ok(())
+}
+
+/// An item on the stack of an [`MstWalker`]
+enum MstItem {
+    /// A node that was not loaded yet
+    Node(Cid),
+    /// The key and record CID of an entry
+    Entry(String, Cid),
+}
+
+/// Walks the MST from the commit and yields the keys and record CIDs of all entries in order
+///
+/// Only records reachable from the signed commit are part of the repo. Nodes are loaded lazily, so only the path to
+/// the current entry is kept in memory.
+pub struct MstWalker {
+    stack: Vec<MstItem>,
+    /// Skip nodes that are missing from the CAR file instead of failing
+    partial: bool,
+}
+
+impl MstWalker {
+    pub fn new(root: Cid, partial: bool) -> MstWalker {
+        MstWalker {
+            stack: vec![MstItem::Node(root)],
+            partial,
+        }
+    }
+
+    /// Get the next entry, returns None once the whole tree was walked
+    pub fn next(&mut self, car: &mut CarFile) -> anyhow::Result<Option<(String, Cid)>> {
+        while let Some(item) = self.stack.pop() {
+            let node = match item {
+                MstItem::Entry(key, value) => return Ok(Some((key, value))),
+                MstItem::Node(node) => node,
+            };
+            let block = match car.get(&node)? {
+                Some(block) => block,
+                // Unchanged subtrees are not part of a partial CAR file
+                None if self.partial => continue,
+                None => anyhow::bail!("The MST node {} is missing", node),
+            };
+            let node_data = from_reader::<NodeData, _>(&block[..])
+                .with_context(|| format!("Invalid MST node {}", node))?;
+
+            // Keys are prefix compressed against the previous entry of the same node
+            let mut items = Vec::with_capacity(node_data.entries.len() * 2 + 1);
+            items.extend(node_data.left.map(MstItem::Node));
+            let mut key = Vec::new();
+            for entry in node_data.entries {
+                anyhow::ensure!(
+                    entry.prefix_len as usize <= key.len(),
+                    "Invalid key prefix in MST node {}",
+                    node
+                );
+                key.truncate(entry.prefix_len as usize);
+                key.extend_from_slice(&entry.key_suffix);
+                let full_key = String::from_utf8(key.clone())
+                    .with_context(|| format!("Invalid key in MST node {}", node))?;
+                items.push(MstItem::Entry(full_key, entry.value));
+                items.extend(entry.tree.map(MstItem::Node));
+            }
+            // The stack is popped from the end, so the left subtree has to be on top
+            self.stack.extend(items.into_iter().rev());
+        }
+
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::repo_indexer::car_file::testing::{block, car};
+    use std::io::Write;
+
+    /// Entries per MST node of the synthetic repo
+    const FANOUT: usize = 4;
+
+    /// Synthetic MST where every node has `FANOUT` entries and, above the leaves, a subtree next to every entry
+    struct Repo {
+        blocks: Vec<(Cid, Vec<u8>)>,
+        keys: Vec<String>,
+        record: Cid,
+    }
+
+    impl Repo {
+        fn node(&mut self, depth: usize) -> Cid {
+            let left = (depth > 0).then(|| self.node(depth - 1));
+            let mut entries = Vec::new();
+            let mut previous = String::new();
+            for _ in 0..FANOUT {
+                let key = format!("app.bsky.feed.like/{:08}", self.keys.len());
+                let prefix_len = key
+                    .bytes()
+                    .zip(previous.bytes())
+                    .take_while(|(a, b)| a == b)
+                    .count();
+                self.keys.push(key.clone());
+                let tree = (depth > 0).then(|| self.node(depth - 1));
+                entries.push(Ipld::Map(
+                    [
+                        ("p".to_string(), Ipld::Integer(prefix_len as i128)),
+                        (
+                            "k".to_string(),
+                            Ipld::Bytes(key.as_bytes()[prefix_len..].to_vec()),
+                        ),
+                        ("v".to_string(), Ipld::Link(self.record)),
+                        ("t".to_string(), tree.map_or(Ipld::Null, Ipld::Link)),
+                    ]
+                    .into(),
+                ));
+                previous = key;
+            }
+            let (cid, node) = block(&Ipld::Map(
+                [
+                    ("l".to_string(), left.map_or(Ipld::Null, Ipld::Link)),
+                    ("e".to_string(), Ipld::List(entries)),
+                ]
+                .into(),
+            ));
+            self.blocks.push((cid, node));
+            cid
+        }
+    }
+
+    #[test]
+    fn large_repos_are_walked_without_loading_all_nodes() {
+        let depth = 5;
+        let record = block(&Ipld::Map(
+            [(
+                "$type".to_string(),
+                Ipld::String("app.bsky.feed.like".to_string()),
+            )]
+            .into(),
+        ));
+        let mut repo = Repo {
+            blocks: vec![record.clone()],
+            keys: Vec::new(),
+            record: record.0,
+        };
+        let root = repo.node(depth);
+        let mut file = tempfile::tempfile().unwrap();
+        file.write_all(&car(root, &repo.blocks)).unwrap();
+        let mut car = CarFile::index(file).unwrap();
+        let mut expected = repo.keys;
+        expected.sort();
+        assert!(expected.len() > 10_000);
+
+        let mut walker = MstWalker::new(root, false);
+        let mut keys = Vec::new();
+        let mut max_stack = 0;
+        while let Some((key, value)) = walker.next(&mut car).unwrap() {
+            assert_eq!(value, record.0);
+            keys.push(key);
+            max_stack = max_stack.max(walker.stack.len());
+        }
+
+        assert_eq!(keys, expected);
+        // Only the unvisited siblings along the path to the current entry are on the stack
+        assert!(max_stack <= (depth + 1) * (2 * FANOUT + 1), "{}", max_stack);
+    }
+
+    #[test]
+    fn missing_nodes_fail_unless_the_car_file_is_partial() {
+        let mut repo = Repo {
+            blocks: Vec::new(),
+            keys: Vec::new(),
+            record: block(&Ipld::Null).0,
+        };
+        let root = repo.node(1);
+        // Drop one of the leaves
+        repo.blocks.remove(0);
+        let mut file = tempfile::tempfile().unwrap();
+        file.write_all(&car(root, &repo.blocks)).unwrap();
+        let mut car = CarFile::index(file).unwrap();
+
+        let mut walker = MstWalker::new(root, false);
+        assert!(std::iter::from_fn(|| walker.next(&mut car).transpose())
+            .collect::<anyhow::Result<Vec<_>>>()
+            .is_err());
+        let mut walker = MstWalker::new(root, true);
+        let keys = std::iter::from_fn(|| walker.next(&mut car).transpose())
+            .collect::<anyhow::Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(keys.len(), repo.keys.len() - FANOUT);
+    }
+}
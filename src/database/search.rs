@@ -0,0 +1,107 @@
+use anyhow::Result;
+use sqlx::PgPool;
+
+/// Get the postgres text search configuration for a BCP-47 language tag
+///
+/// Languages without a stemmer in postgres use the simple configuration
+pub(crate) fn text_search_config(lang: Option<&str>) -> &'static str {
+    let Some(lang) = lang else {
+        return "simple";
+    };
+    let primary = lang.split('-').next().unwrap_or_default();
+    match primary.to_ascii_lowercase().as_str() {
+        "ar" => "arabic",
+        "da" => "danish",
+        "de" => "german",
+        "el" => "greek",
+        "en" => "english",
+        "es" => "spanish",
+        "fi" => "finnish",
+        "fr" => "french",
+        "hu" => "hungarian",
+        "id" => "indonesian",
+        "it" => "italian",
+        "nl" => "dutch",
+        "no" | "nb" | "nn" => "norwegian",
+        "pt" => "portuguese",
+        "ro" => "romanian",
+        "ru" => "russian",
+        "sv" => "swedish",
+        "tr" => "turkish",
+        _ => "simple",
+    }
+}
+
+/// Search the text of posts, returns the ids of the best matching posts first
+///
+/// `query` uses the websearch syntax, so quoted phrases, `or` and `-word` work. If `lang` is given, only posts in that
+/// language are returned and the query is stemmed like the text of posts in that language. Without `lang`, the words
+/// of the query have to appear unstemmed.
+pub async fn search_posts(
+    db: &PgPool,
+    query: &str,
+    lang: Option<&str>,
+    limit: i64,
+) -> Result<Vec<String>> {
+    let ids = sqlx::query_scalar!(
+        r#"SELECT id FROM post, websearch_to_tsquery($3::TEXT::regconfig, $1::TEXT) query WHERE text_search @@ query AND ($2::TEXT IS NULL OR EXISTS (SELECT 1 FROM post_lang WHERE post_lang.post_id = post.id AND post_lang.lang = $2::TEXT)) ORDER BY ts_rank(text_search, query) DESC LIMIT $4::BIGINT"#,
+        query,
+        lang,
+        text_search_config(lang),
+        limit
+    )
+    .fetch_all(db)
+    .await?;
+
+    Ok(ids)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{search_posts, text_search_config};
+    use sqlx::PgPool;
+
+    #[test]
+    fn languages_map_to_their_stemmer() {
+        assert_eq!(text_search_config(Some("en")), "english");
+        assert_eq!(text_search_config(Some("de-AT")), "german");
+        assert_eq!(text_search_config(Some("ja")), "simple");
+        assert_eq!(text_search_config(None), "simple");
+    }
+
+    #[sqlx::test]
+    #[ignore = "needs a postgres server in DATABASE_URL"]
+    async fn keyword_search_finds_the_matching_post(db: PgPool) -> anyhow::Result<()> {
+        let mut connection = db.acquire().await?;
+        sqlx::query("SET indexer.post_search = 'on'")
+            .execute(&mut *connection)
+            .await?;
+        sqlx::query(
+            "INSERT INTO post (id, author, created_at, text, text_search_config) VALUES
+                ('1_plc_a', 'plc_a', now(), 'The cats are running through the garden', 'english'),
+                ('2_plc_a', 'plc_a', now(), 'Die Katze schläft auf dem Sofa', 'german'),
+                ('3_plc_b', 'plc_b', now(), 'A quiet morning with coffee', 'english')",
+        )
+        .execute(&mut *connection)
+        .await?;
+        sqlx::query(
+            "INSERT INTO post_lang (post_id, lang) VALUES ('1_plc_a', 'en'), ('2_plc_a', 'de'), ('3_plc_b', 'en')",
+        )
+        .execute(&mut *connection)
+        .await?;
+
+        assert_eq!(search_posts(&db, "coffee", None, 10).await?, ["3_plc_b"]);
+        // Stemmed like the text of english posts
+        assert_eq!(
+            search_posts(&db, "cat run", Some("en"), 10).await?,
+            ["1_plc_a"]
+        );
+        assert_eq!(
+            search_posts(&db, "sofa", Some("de"), 10).await?,
+            ["2_plc_a"]
+        );
+        assert!(search_posts(&db, "sofa", Some("en"), 10).await?.is_empty());
+        assert!(search_posts(&db, "tea", None, 10).await?.is_empty());
+        Ok(())
+    }
+}
@@ -6,9 +6,10 @@ use anyhow::{Context, Result};
 use lazy_static::lazy_static;
 use regex::Regex;
 use surrealdb::RecordId;
+use thiserror::Error;
 
 lazy_static! {
-    static ref VALID_DID_KEY_REGEX: Regex = Regex::new(r"^(plc|web)_[a-z0-9_]+$").unwrap();
+    static ref VALID_DID_KEY_REGEX: Regex = Regex::new(r"^(plc|web|webx)_[a-z0-9_]+$").unwrap();
 }
 
 /// Extracts the self labels from a profile record labels refs
@@ -55,39 +56,91 @@ pub fn extract_self_labels_post(
 
 // TODO self labels for feed generators and labeller services
 
-/// Converts a DID to a key
-pub fn did_to_key(did: &str) -> Result<String> {
-    did_to_key_impl(did, false)
+/// Check if a did:web identifier is a plain lowercase hostname
+///
+/// The `web_` encoding of these is unambiguous: labels are separated by a single `_` and hyphens can only occur inside a
+/// label, so they always become an even number of underscores.
+fn is_plain_hostname(id: &str) -> bool {
+    id.split('.').all(|label| {
+        !label.is_empty()
+            && !label.starts_with('-')
+            && !label.ends_with('-')
+            && label
+                .bytes()
+                .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-')
+    })
 }
 
-/// Converts a DID to a (full) key
-pub fn did_to_key_impl(did: &str, full: bool) -> Result<String> {
+/// Converts a DID to a key
+///
+/// did:web DIDs with a plain hostname use the readable `web_` encoding. Everything else (ports, paths, underscores,
+/// uppercase letters) is hex encoded with the `webx_` prefix, so every key maps back to exactly one DID.
+pub fn did_to_key(did: &str) -> Result<String> {
     // did:plc covers 99.99% of all DIDs
     let val = if let Some(id) = did.strip_prefix("did:plc:") {
         format!("plc_{}", id)
     } else if let Some(id) = did.strip_prefix("did:web:") {
-        format!("web_{}", &id.replace('.', "_").replace('-', "__"))
+        if is_plain_hostname(id) {
+            format!("web_{}", &id.replace('.', "_").replace('-', "__"))
+        } else {
+            let hex: String = id.bytes().map(|b| format!("{:02x}", b)).collect();
+            format!("webx_{}", hex)
+        }
     } else {
         anyhow::bail!("Invalid DID {}", did);
     };
 
     if !VALID_DID_KEY_REGEX.is_match(&val) {
-        anyhow::bail!("Found invalid DID: {} {} {}", did, full, val);
+        anyhow::bail!("Found invalid DID: {} {}", did, val);
     }
 
-    if full {
-        return Ok(format!("did:{}", val));
+    Ok(val)
+}
+
+/// Converts a key back to a DID, the inverse of [did_to_key]
+///
+/// Keys that [did_to_key] would never produce, like `web_` keys of hostnames that need the hex encoding, are rejected
+pub fn key_to_did(key: &str) -> Result<String> {
+    let did = decode_key(key)?;
+    if did_to_key(&did)? != key {
+        anyhow::bail!("DID key {} is not in canonical form", key);
     }
+    Ok(did)
+}
 
-    Ok(val)
+fn decode_key(key: &str) -> Result<String> {
+    if let Some(id) = key.strip_prefix("plc_") {
+        return Ok(format!("did:plc:{}", id));
+    }
+    if let Some(id) = key.strip_prefix("webx_") {
+        let bytes = (0..id.len())
+            .step_by(2)
+            .map(|i| {
+                id.get(i..i + 2)
+                    .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+                    .with_context(|| format!("Invalid hex in DID key {}", key))
+            })
+            .collect::<Result<Vec<u8>>>()?;
+        let id = String::from_utf8(bytes).context("DID key is not valid UTF-8")?;
+        return Ok(format!("did:web:{}", id));
+    }
+    if let Some(id) = key.strip_prefix("web_") {
+        return Ok(format!(
+            "did:web:{}",
+            id.replace("__", "-").replace('_', ".")
+        ));
+    }
+    anyhow::bail!("Invalid DID key {}", key);
 }
 
-pub fn unsafe_user_key_to_did(key: &str) -> String {
-    // TODO replace this implementation with something better
-    key.replace("web_", "did:web:")
-        .replace("plc_", "did:plc:")
-        .replace("__", "-")
-        .replace("_", ".")
+/// An AT URI points to a collection that is not indexed
+///
+/// Records referencing these are skipped instead of failing the whole update
+#[derive(Debug, Error)]
+#[error("Unsupported collection {collection} in URI {uri}")]
+pub struct UnsupportedCollection {
+    pub collection: String,
+    pub uri: String,
 }
 
 /// Converts a strong ref to a record ID
@@ -96,8 +149,10 @@ pub fn strong_ref_to_record_id(sr: &Main) -> Result<RecordId> {
 }
 
 /// Converts an AT URI to a record ID
+///
+/// The record id of a profile is the DID key, all other records use `<rkey>_<DID key>`
 pub fn at_uri_to_record_id(uri: &str) -> Result<RecordId> {
-    let u: Vec<&str> = uri.split('/').collect();
+    let u: Vec<&str> = uri.trim_end_matches('/').split('/').collect();
     let u_hostname = u.get(2).context("Hostname missing")?.to_string();
     let u_collection = *u.get(3).context("Collection type missing")?;
     let u_rkey = u.get(4).context("Rkey missing")?.to_string();
@@ -108,16 +163,32 @@ pub fn at_uri_to_record_id(uri: &str) -> Result<RecordId> {
         "app.bsky.graph.list" => "list",
         "app.bsky.graph.starterpack" => "starterpack",
         "app.bsky.labeler.service" => "labeler",
-        _ => anyhow::bail!("Unsupported URI {}", uri),
+        "app.bsky.feed.like" => "like",
+        "app.bsky.feed.repost" => "repost",
+        "app.bsky.feed.threadgate" => "threadgate",
+        "app.bsky.feed.postgate" => "postgate",
+        "app.bsky.graph.follow" => "follow",
+        "app.bsky.graph.block" => "block",
+        "app.bsky.graph.listblock" => "listblock",
+        "app.bsky.graph.listitem" => "listitem",
+        "chat.bsky.actor.declaration" => "chat_actor_declaration",
+        "app.bsky.actor.profile" => "did",
+        _ => {
+            return Err(UnsupportedCollection {
+                collection: u_collection.to_string(),
+                uri: uri.to_string(),
+            }
+            .into())
+        }
     };
 
-    let mut did = did_to_key(&u_hostname)?;
-    if did.starts_with("plc_did:plc:") {
-        did = format!("plc_{}", &did[12..]);
-    }
+    let did = did_to_key(&u_hostname)?;
 
     ensure_valid_rkey(u_rkey.to_string())?;
 
+    if table == "did" {
+        return Ok(RecordId::from_table_key(table, did));
+    }
     Ok(RecordId::from_table_key(
         table,
         format!("{}_{}", u_rkey, did),
@@ -133,6 +204,25 @@ pub fn ensure_valid_rkey(rkey: String) -> Result<()> {
     Ok(())
 }
 
+/// Ensures that the provided rkey follows the atproto record key syntax
+///
+/// Record keys are 1 to 512 characters of `A-Za-z0-9._:~-` and can not be `.` or `..`
+pub fn ensure_valid_rkey_strict(rkey: &str) -> Result<()> {
+    if rkey.is_empty() || rkey.len() > 512 {
+        anyhow::bail!("Rkey must be between 1 and 512 characters long: {}", rkey);
+    }
+    if rkey == "." || rkey == ".." {
+        anyhow::bail!("Rkey can not be {}", rkey);
+    }
+    if let Some(c) = rkey
+        .chars()
+        .find(|c| !c.is_ascii_alphanumeric() && !matches!(c, '.' | '-' | '_' | ':' | '~'))
+    {
+        anyhow::bail!("Rkey {} contains the invalid character {:?}", rkey, c);
+    }
+    Ok(())
+}
+
 pub fn blob_ref_to_record_id(blob: &BlobRef) -> RecordId {
     match blob {
         BlobRef::Typed(a) => match a {
@@ -141,3 +231,234 @@ pub fn blob_ref_to_record_id(blob: &BlobRef) -> RecordId {
         BlobRef::Untyped(a) => RecordId::from_table_key("blob", a.cid.clone()),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(did: &str) -> String {
+        let key = did_to_key(did).unwrap();
+        assert_eq!(key_to_did(&key).unwrap(), did, "key {}", key);
+        key
+    }
+
+    #[test]
+    fn plc_keys_round_trip() {
+        assert_eq!(
+            round_trip("did:plc:z72i7hdynmk6r22z27h6tvur"),
+            "plc_z72i7hdynmk6r22z27h6tvur"
+        );
+    }
+
+    #[test]
+    fn plain_web_keys_round_trip() {
+        assert_eq!(round_trip("did:web:example.com"), "web_example_com");
+        assert_eq!(
+            round_trip("did:web:my-host.example.com"),
+            "web_my__host_example_com"
+        );
+        assert_eq!(round_trip("did:web:a--b.de"), "web_a____b_de");
+    }
+
+    #[test]
+    fn other_web_keys_use_hex() {
+        assert_eq!(
+            round_trip("did:web:localhost%3A8080"),
+            "webx_6c6f63616c686f737425334138303830"
+        );
+        assert!(round_trip("did:web:Example.com").starts_with("webx_"));
+        assert!(round_trip("did:web:under_score.com").starts_with("webx_"));
+        assert!(round_trip("did:web:example.com:user:alice").starts_with("webx_"));
+    }
+
+    /// A random did:web, mostly plain hostnames but also with ports, paths and unusual characters
+    fn random_web_did(rng: &mut fastrand::Rng) -> String {
+        const CHARS: &[u8] = b"abcdefghijklmnopqrstuvwxyz0123456789----____....::%3AZ";
+        let labels = (0..rng.usize(1..4))
+            .map(|_| {
+                (0..rng.usize(1..12))
+                    .map(|_| CHARS[rng.usize(..CHARS.len())] as char)
+                    .collect::<String>()
+            })
+            .collect::<Vec<_>>();
+        format!("did:web:{}", labels.join("."))
+    }
+
+    #[test]
+    fn random_web_dids_round_trip() {
+        let mut rng = fastrand::Rng::with_seed(41);
+        let (mut readable, mut hex) = (0, 0);
+        for _ in 0..10_000 {
+            let did = random_web_did(&mut rng);
+            if did_to_key(&did).is_err() {
+                continue;
+            }
+            match round_trip(&did).starts_with("webx_") {
+                true => hex += 1,
+                false => readable += 1,
+            }
+        }
+        // Both encodings are covered
+        assert!(
+            readable > 100 && hex > 100,
+            "{} readable, {} hex",
+            readable,
+            hex
+        );
+    }
+
+    /// A random did:plc, 24 characters of lowercase base32
+    fn random_plc_did(rng: &mut fastrand::Rng) -> String {
+        const CHARS: &[u8] = b"abcdefghijklmnopqrstuvwxyz234567";
+        let id: String = (0..24)
+            .map(|_| CHARS[rng.usize(..CHARS.len())] as char)
+            .collect();
+        format!("did:plc:{}", id)
+    }
+
+    #[test]
+    fn random_plc_dids_round_trip() {
+        let mut rng = fastrand::Rng::with_seed(43);
+        for _ in 0..10_000 {
+            let did = random_plc_did(&mut rng);
+            assert_eq!(round_trip(&did), did.replacen("did:plc:", "plc_", 1));
+        }
+    }
+
+    #[test]
+    fn random_keys_map_back_to_themselves() {
+        const CHARS: &[u8] = b"0123456789abcdef____";
+        let mut rng = fastrand::Rng::with_seed(43);
+        let (mut valid, mut invalid) = (0, 0);
+        for _ in 0..10_000 {
+            let prefix = ["plc_", "web_", "webx_"][rng.usize(..3)];
+            let id: String = (0..rng.usize(1..16))
+                .map(|_| CHARS[rng.usize(..CHARS.len())] as char)
+                .collect();
+            let key = format!("{}{}", prefix, id);
+            match key_to_did(&key) {
+                Ok(did) => {
+                    assert_eq!(did_to_key(&did).unwrap(), key, "did {}", did);
+                    valid += 1;
+                }
+                Err(_) => invalid += 1,
+            }
+        }
+        assert!(
+            valid > 100 && invalid > 100,
+            "{} valid, {} invalid",
+            valid,
+            invalid
+        );
+    }
+
+    #[test]
+    fn invalid_dids_have_no_key() {
+        assert!(did_to_key("did:key:z6MkhaXgBZDvotDkL5257faiztiGiC2QtKLGpbnnEGta2doK").is_err());
+        assert!(did_to_key("did:plc:UPPERCASE").is_err());
+        assert!(did_to_key("did:plc:").is_err());
+        assert!(did_to_key("example.com").is_err());
+    }
+
+    #[test]
+    fn non_canonical_keys_are_rejected() {
+        // A plain hostname has to use the readable encoding
+        let hex: String = "example.com"
+            .bytes()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        assert!(key_to_did(&format!("webx_{}", hex)).is_err());
+        // Decodes to a-.b, which is not a plain hostname
+        assert!(key_to_did("web_a___b").is_err());
+        assert!(key_to_did("web_example_").is_err());
+    }
+
+    #[test]
+    fn malformed_keys_are_rejected() {
+        assert!(key_to_did("webx_zz").is_err());
+        assert!(key_to_did("webx_6c6").is_err());
+        assert!(key_to_did("webx_ff").is_err());
+        assert!(key_to_did("key_abc").is_err());
+        assert!(key_to_did("plc_").is_err());
+    }
+
+    #[test]
+    fn at_uris_map_to_record_ids() {
+        for (uri, expected) in [
+            (
+                "at://did:plc:z72i7hdynmk6r22z27h6tvur/app.bsky.feed.post/3jzfcijpj2z2a",
+                Some(("post", "3jzfcijpj2z2a_plc_z72i7hdynmk6r22z27h6tvur")),
+            ),
+            (
+                "at://did:web:example.com/app.bsky.feed.post/3jzfcijpj2z2a",
+                Some(("post", "3jzfcijpj2z2a_web_example_com")),
+            ),
+            (
+                "at://did:web:localhost%3A8080/app.bsky.graph.list/self",
+                Some(("list", "self_webx_6c6f63616c686f737425334138303830")),
+            ),
+            (
+                "at://did:web:example.com/app.bsky.actor.profile/self",
+                Some(("did", "web_example_com")),
+            ),
+            (
+                "at://did:plc:z72i7hdynmk6r22z27h6tvur/app.bsky.feed.post/3jzfcijpj2z2a/",
+                Some(("post", "3jzfcijpj2z2a_plc_z72i7hdynmk6r22z27h6tvur")),
+            ),
+            (
+                "at://did:plc:z72i7hdynmk6r22z27h6tvur/app.bsky.feed.post",
+                None,
+            ),
+            (
+                "at://did:plc:z72i7hdynmk6r22z27h6tvur/app.bsky.feed.post/",
+                None,
+            ),
+            ("at://did:plc:z72i7hdynmk6r22z27h6tvur", None),
+            ("at://did:web:Example.com:8080/app.bsky.feed.post/a b", None),
+        ] {
+            let expected = expected.map(|(table, key)| RecordId::from_table_key(table, key));
+            assert_eq!(at_uri_to_record_id(uri).ok(), expected, "{}", uri);
+        }
+    }
+
+    #[test]
+    fn unknown_collections_are_unsupported() {
+        let uri = "at://did:plc:z72i7hdynmk6r22z27h6tvur/com.example.record/3jzfcijpj2z2a";
+        let error = at_uri_to_record_id(uri).unwrap_err();
+        let unsupported = error.downcast_ref::<UnsupportedCollection>().unwrap();
+        assert_eq!(unsupported.collection, "com.example.record");
+        assert_eq!(unsupported.uri, uri);
+    }
+
+    #[test]
+    fn valid_rkeys() {
+        for rkey in [
+            "3jzfcijpj2z2a",
+            "self",
+            "a",
+            "..a",
+            "with:colon~tilde_-.",
+            &"a".repeat(512),
+        ] {
+            assert!(ensure_valid_rkey_strict(rkey).is_ok(), "{}", rkey);
+            assert!(ensure_valid_rkey(rkey.to_string()).is_ok(), "{}", rkey);
+        }
+    }
+
+    #[test]
+    fn invalid_rkeys() {
+        for rkey in [
+            "",
+            ".",
+            "..",
+            "with/slash",
+            "with space",
+            "ümlaut",
+            "a#b",
+            &"a".repeat(513),
+        ] {
+            assert!(ensure_valid_rkey_strict(rkey).is_err(), "{}", rkey);
+            assert!(ensure_valid_rkey(rkey.to_string()).is_err(), "{}", rkey);
+        }
+    }
+}
@@ -0,0 +1,296 @@
+//! Publishes the rows written by applied updates
+//!
+//! Rows are published after their transaction was committed, as JSON arrays on `<prefix>.<table>`, and the ids of
+//! deleted rows on `<prefix>.<table>.deleted`. Every row of a committed update is published, even if its insert was
+//! skipped because the row already existed, for example when a repo is backfilled again or a window of the jetstream
+//! is replayed. So the same row can be published more than once and consumers should treat rows as upserts by id.
+//! Publishing is best effort, a sink that can not keep up drops messages.
+
+use crate::observability::instrument_name;
+use anyhow::Context;
+use opentelemetry::{global, metrics::Counter, KeyValue};
+use serde::{Deserialize, Serialize};
+use std::{
+    sync::{LazyLock, OnceLock},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::TcpStream,
+    sync::mpsc,
+    time::sleep,
+};
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+/// Number of messages that can wait for the sink before new messages are dropped
+const QUEUE_SIZE: usize = 10_000;
+/// Rows per message, so a message of a large backfill stays below the payload limit of the server
+const ROWS_PER_MESSAGE: usize = 500;
+
+static PUBLISHED_ROWS_METRIC: LazyLock<Counter<u64>> = LazyLock::new(|| {
+    global::meter("indexer")
+        .u64_counter(instrument_name("indexer.event_sink.published_rows"))
+        .with_unit("{row}")
+        .with_description("Number of written rows that were published to the event sink")
+        .build()
+});
+static DROPPED_ROWS_METRIC: LazyLock<Counter<u64>> = LazyLock::new(|| {
+    global::meter("indexer")
+        .u64_counter(instrument_name("indexer.event_sink.dropped_rows"))
+        .with_unit("{row}")
+        .with_description("Number of written rows that could not be published to the event sink")
+        .build()
+});
+
+/// A message with written rows
+#[derive(Debug, Clone)]
+pub struct Message {
+    /// `<prefix>.<table>` or `<prefix>.<table>.deleted`
+    pub subject: String,
+    /// The rows as a JSON array
+    pub payload: Vec<u8>,
+    /// Number of rows in the payload
+    pub rows: usize,
+}
+
+/// A destination for the rows written by applied updates
+pub trait EventSink: Send + Sync {
+    /// Hand over a message. Called while applying updates, so this must not wait
+    fn publish(&self, message: Message);
+}
+
+/// The sink all updates publish to, with the prefix of its subjects
+struct InstalledSink {
+    prefix: String,
+    sink: Box<dyn EventSink>,
+}
+
+static SINK: OnceLock<InstalledSink> = OnceLock::new();
+
+/// Publish the rows of all following updates to `sink`
+///
+/// Can only be called once per process
+pub fn set_event_sink(prefix: String, sink: impl EventSink + 'static) -> anyhow::Result<()> {
+    SINK.set(InstalledSink {
+        prefix,
+        sink: Box::new(sink),
+    })
+    .map_err(|_| anyhow::anyhow!("The event sink was already started"))
+}
+
+/// The INFO message the NATS server sends after connecting
+#[derive(Deserialize, Debug)]
+struct ServerInfo {
+    max_payload: usize,
+}
+
+/// Publish rows that were written to a table on `<prefix>.<table>`
+///
+/// Does nothing if no sink is set
+pub fn publish_rows<T: Serialize>(table: &str, rows: &[T]) {
+    publish(table.to_string(), rows);
+}
+
+/// Publish the ids of rows that were deleted from a table on `<prefix>.<table>.deleted`
+pub fn publish_deletes(table: &str, ids: &[String]) {
+    publish(format!("{}.deleted", table), ids);
+}
+
+fn publish<T: Serialize>(subject: String, rows: &[T]) {
+    if let Some(sink) = SINK.get() {
+        publish_to(sink, &subject, rows);
+    }
+}
+
+fn publish_to<T: Serialize>(sink: &InstalledSink, subject: &str, rows: &[T]) {
+    for chunk in rows.chunks(ROWS_PER_MESSAGE) {
+        let payload = match serde_json::to_vec(chunk) {
+            Ok(payload) => payload,
+            Err(e) => {
+                warn!("Failed to serialize rows for the event sink: {:?}", e);
+                DROPPED_ROWS_METRIC
+                    .add(chunk.len() as u64, &[KeyValue::new("reason", "serialize")]);
+                continue;
+            }
+        };
+        sink.sink.publish(Message {
+            subject: format!("{}.{}", sink.prefix, subject),
+            payload,
+            rows: chunk.len(),
+        });
+    }
+}
+
+/// Queues messages for the connection to the NATS server
+///
+/// Messages are dropped if the queue is full, for example while the server is not reachable
+struct NatsSink {
+    sender: mpsc::Sender<Message>,
+}
+
+impl EventSink for NatsSink {
+    fn publish(&self, message: Message) {
+        if let Err(e) = self.sender.try_send(message) {
+            DROPPED_ROWS_METRIC.add(
+                e.into_inner().rows as u64,
+                &[KeyValue::new("reason", "queue_full")],
+            );
+        }
+    }
+}
+
+/// Publish the rows written by applied updates to a NATS server until shutdown is requested
+///
+/// `url` has the form `nats://host:port`, the port defaults to 4222. The rows are serialized like the rows of a
+/// `BigUpdate`. The connection is reestablished after a failure, and rows written while the queue is full are dropped
+/// and counted in `indexer.event_sink.dropped_rows`
+pub async fn run_event_sink(
+    url: String,
+    prefix: String,
+    shutdown: CancellationToken,
+) -> anyhow::Result<()> {
+    let address = url
+        .strip_prefix("nats://")
+        .with_context(|| format!("Unsupported event sink {}, only nats:// is supported", url))?
+        .trim_end_matches('/');
+    let address = if address.contains(':') {
+        address.to_string()
+    } else {
+        format!("{}:4222", address)
+    };
+
+    let (sender, mut receiver) = mpsc::channel(QUEUE_SIZE);
+    set_event_sink(prefix, NatsSink { sender })?;
+
+    while !shutdown.is_cancelled() {
+        if let Err(e) = publish_to_nats(&address, &mut receiver, &shutdown).await {
+            warn!(target: "indexer", "Event sink connection to {} failed: {:?}", address, e);
+        }
+        tokio::select! {
+            _ = sleep(Duration::from_secs(5)) => {}
+            _ = shutdown.cancelled() => {}
+        }
+    }
+    Ok(())
+}
+
+/// Publish queued messages over a single connection until it fails or shutdown is requested
+///
+/// Only the parts of the NATS protocol that a publisher needs are implemented: the INFO and CONNECT handshake, PUB,
+/// and answering the PINGs of the server
+async fn publish_to_nats(
+    address: &str,
+    receiver: &mut mpsc::Receiver<Message>,
+    shutdown: &CancellationToken,
+) -> anyhow::Result<()> {
+    let stream = TcpStream::connect(address)
+        .await
+        .with_context(|| format!("Unable to connect to the NATS server at {}", address))?;
+    let (read, mut write) = stream.into_split();
+    let mut lines = BufReader::new(read).lines();
+
+    let info = lines
+        .next_line()
+        .await?
+        .context("The NATS server closed the connection")?;
+    let info: ServerInfo = serde_json::from_str(
+        info.strip_prefix("INFO ")
+            .with_context(|| format!("Expected INFO from the NATS server, got {}", info))?,
+    )
+    .context("Failed to parse the INFO of the NATS server")?;
+    let connect = serde_json::json!({
+        "verbose": false,
+        "pedantic": false,
+        "name": "indexer",
+        "lang": "rust",
+        "version": env!("CARGO_PKG_VERSION"),
+    });
+    write
+        .write_all(format!("CONNECT {}\r\n", connect).as_bytes())
+        .await?;
+    info!(target: "indexer", "Publishing written rows to the NATS server at {}", address);
+
+    loop {
+        tokio::select! {
+            line = lines.next_line() => {
+                let line = line?.context("The NATS server closed the connection")?;
+                if line == "PING" {
+                    write.write_all(b"PONG\r\n").await?;
+                } else if line.starts_with("-ERR") {
+                    anyhow::bail!("The NATS server reported an error: {}", line);
+                }
+            }
+            message = receiver.recv() => {
+                let Some(message) = message else {
+                    return Ok(());
+                };
+                if message.payload.len() > info.max_payload {
+                    warn!("Dropping a message of {} bytes for {}, the NATS server allows at most {} bytes", message.payload.len(), message.subject, info.max_payload);
+                    DROPPED_ROWS_METRIC.add(message.rows as u64, &[KeyValue::new("reason", "too_large")]);
+                    continue;
+                }
+                let mut frame = format!("PUB {} {}\r\n", message.subject, message.payload.len()).into_bytes();
+                frame.extend_from_slice(&message.payload);
+                frame.extend_from_slice(b"\r\n");
+                if let Err(e) = write.write_all(&frame).await {
+                    DROPPED_ROWS_METRIC.add(message.rows as u64, &[KeyValue::new("reason", "disconnected")]);
+                    return Err(e.into());
+                }
+                PUBLISHED_ROWS_METRIC.add(message.rows as u64, &[]);
+            }
+            _ = shutdown.cancelled() => return Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    /// Keeps all published messages
+    #[derive(Clone, Default)]
+    struct MemorySink(Arc<Mutex<Vec<Message>>>);
+
+    impl EventSink for MemorySink {
+        fn publish(&self, message: Message) {
+            self.0.lock().unwrap().push(message);
+        }
+    }
+
+    #[test]
+    fn rows_are_published_in_chunks() {
+        let memory = MemorySink::default();
+        let sink = InstalledSink {
+            prefix: "indexer".to_string(),
+            sink: Box::new(memory.clone()),
+        };
+        let ids = (0..1201).map(|i| i.to_string()).collect::<Vec<_>>();
+
+        publish_to(&sink, "follow.deleted", &ids);
+
+        let messages = memory.0.lock().unwrap();
+        assert_eq!(
+            messages.iter().map(|m| m.rows).collect::<Vec<_>>(),
+            [500, 500, 201]
+        );
+        assert!(messages
+            .iter()
+            .all(|m| m.subject == "indexer.follow.deleted"));
+        let last: Vec<String> = serde_json::from_slice(&messages[2].payload).unwrap();
+        assert_eq!(last.first().map(String::as_str), Some("1000"));
+        assert_eq!(last.last().map(String::as_str), Some("1200"));
+    }
+
+    #[test]
+    fn nothing_is_published_without_rows() {
+        let memory = MemorySink::default();
+        let sink = InstalledSink {
+            prefix: "indexer".to_string(),
+            sink: Box::new(memory.clone()),
+        };
+        publish_to::<String>(&sink, "follow", &[]);
+        assert!(memory.0.lock().unwrap().is_empty());
+    }
+}
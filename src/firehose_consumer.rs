@@ -0,0 +1,149 @@
+use crate::{
+    config::ARGS,
+    database::{
+        self,
+        big_update::{flush_small_updates, write_cursor_when_applied},
+        definitions::JetstreamCursor,
+        handlers::handle_firehose_event,
+    },
+    websocket,
+};
+use anyhow::Context;
+use fastwebsockets::{OpCode, WebSocket};
+use hyper::upgrade::Upgraded;
+use hyper_util::rt::TokioIo;
+use sqlx::PgPool;
+use std::{
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, Instant},
+};
+use tokio::time::sleep;
+use tokio_util::sync::CancellationToken;
+use tracing::{info, trace, warn};
+
+pub mod events;
+
+/// Whether the firehose consumer is currently connected to the relay
+static CONNECTED: AtomicBool = AtomicBool::new(false);
+
+/// Check if the firehose consumer is connected
+pub fn firehose_connected() -> bool {
+    CONNECTED.load(Ordering::Relaxed)
+}
+
+/// Consume the firehose of a relay until shutdown is requested
+///
+/// Used instead of the jetstream with `--firehose`. The cursor is the sequence number of the last event and is
+/// stored under `firehose:<host>`. On shutdown the accumulated updates are flushed before the final cursor is written
+pub async fn attach_firehose(database: PgPool, shutdown: CancellationToken) -> anyhow::Result<()> {
+    let host = &ARGS.firehose_host;
+    let cursor_key = format!("firehose:{}", host.authority());
+    let mut cursor = database::fetch_cursor(&database, &cursor_key)
+        .await
+        .context("Failed to fetch cursor from database")?
+        .map(|cursor| cursor.time_us);
+    let connector = websocket::tls_connector()?;
+
+    while !shutdown.is_cancelled() {
+        let path = format!(
+            "/xrpc/com.atproto.sync.subscribeRepos{}",
+            cursor.map_or_else(String::new, |c| format!("?cursor={}", c))
+        );
+        info!(target: "indexer", "Connecting to the firehose of {} starting at cursor: {:?}", host, cursor);
+        match websocket::connect(host, &connector, &path).await {
+            Ok(ws) => {
+                CONNECTED.store(true, Ordering::Relaxed);
+                let res =
+                    consume_firehose(&database, ws, &cursor_key, &mut cursor, &shutdown).await;
+                CONNECTED.store(false, Ordering::Relaxed);
+                if let Err(e) = res {
+                    warn!(target: "indexer", "Firehose connection failed: {:?}", e);
+                }
+            }
+            Err(e) => {
+                warn!(target: "indexer", "Unable to open firehose connection to {}: {:?}", host, e);
+            }
+        }
+
+        tokio::select! {
+            _ = sleep(Duration::from_secs(5)) => {}
+            _ = shutdown.cancelled() => {}
+        }
+    }
+
+    // Only write the cursor after all events up to it are in the database
+    flush_small_updates(database.clone())
+        .await
+        .context("Failed to flush accumulated updates")?;
+    if let Some(seq) = cursor {
+        info!(target: "indexer", "Writing cursor {} for {}", seq, cursor_key);
+        database::write_cursor(
+            &database,
+            JetstreamCursor {
+                host: cursor_key,
+                time_us: seq,
+            },
+        )
+        .await
+        .context("Unable to write cursor to database!")?;
+    }
+
+    Ok(())
+}
+
+/// Handle firehose frames until the connection fails or shutdown is requested
+async fn consume_firehose(
+    database: &PgPool,
+    mut ws: WebSocket<TokioIo<Upgraded>>,
+    cursor_key: &str,
+    cursor: &mut Option<i64>,
+    shutdown: &CancellationToken,
+) -> anyhow::Result<()> {
+    let mut last_cursor_write = Instant::now();
+    loop {
+        let frame = tokio::select! {
+            frame = ws.read_frame() => frame.context("Failed to read frame from firehose")?,
+            _ = shutdown.cancelled() => return Ok(()),
+        };
+
+        match frame.opcode {
+            OpCode::Binary => {}
+            OpCode::Close => {
+                anyhow::bail!("Unexpected connection close received: {:?}", frame.payload);
+            }
+            // pings are answered automatically
+            _ => {
+                trace!(target: "indexer", "Received {:?}", frame.opcode);
+                continue;
+            }
+        }
+
+        let event = match events::parse_frame(&frame.payload) {
+            Ok(Some(event)) => event,
+            Ok(None) => continue,
+            Err(e) => {
+                warn!("error while parsing firehose frame {:?}", e);
+                continue;
+            }
+        };
+        *cursor = Some(event.seq());
+
+        if let Err(e) = handle_firehose_event(database.clone(), event).await {
+            warn!("error while handling {}", e);
+        }
+
+        // persist the cursor every minute
+        if last_cursor_write.elapsed().as_secs() >= 60 {
+            last_cursor_write = Instant::now();
+            write_cursor_when_applied(
+                database,
+                JetstreamCursor {
+                    host: cursor_key.to_string(),
+                    time_us: cursor.unwrap_or_default(),
+                },
+            )
+            .await
+            .context("Unable to write cursor to database!")?;
+        }
+    }
+}
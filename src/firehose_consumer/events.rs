@@ -0,0 +1,130 @@
+use anyhow::Context;
+use atrium_api::types::string::{Did, Handle};
+use chrono::DateTime;
+use ipld_core::cid::Cid;
+use serde::Deserialize;
+
+use crate::websocket::events::Account;
+
+/// Header of a firehose frame
+///
+/// https://atproto.com/specs/event-stream
+#[derive(Deserialize, Debug)]
+struct Header {
+    /// 1 for messages, -1 for errors
+    op: i64,
+    /// Message type, for example `#commit`
+    t: Option<String>,
+}
+
+/// Body of an error frame
+#[derive(Deserialize, Debug)]
+struct ErrorBody {
+    error: String,
+    message: Option<String>,
+}
+
+/// A `#commit` message
+#[derive(Deserialize, Debug)]
+#[allow(dead_code)]
+pub struct Commit {
+    pub seq: i64,
+    pub repo: Did,
+    pub rev: String,
+    pub time: String,
+    /// CAR slice with the blocks of the changed records
+    #[serde(with = "serde_bytes")]
+    pub blocks: Vec<u8>,
+    pub ops: Vec<RepoOp>,
+    /// The blocks were too large to include, the commit has to be fetched from the PDS
+    #[serde(rename = "tooBig", default)]
+    pub too_big: bool,
+}
+
+/// A single record operation in a commit
+#[derive(Deserialize, Debug)]
+pub struct RepoOp {
+    /// `create`, `update` or `delete`
+    pub action: String,
+    /// `<collection>/<rkey>`
+    pub path: String,
+    /// CID of the new record, missing for deletes
+    pub cid: Option<Cid>,
+}
+
+/// An `#identity` message
+#[derive(Deserialize, Debug)]
+pub struct Identity {
+    pub seq: i64,
+    pub did: Did,
+    pub time: String,
+    pub handle: Option<Handle>,
+}
+
+/// A firehose event we handle
+#[derive(Debug)]
+pub enum Event {
+    Commit(Commit),
+    Identity(Identity),
+    Account(Account),
+}
+
+impl Event {
+    /// Sequence number of the event, used as the cursor
+    pub fn seq(&self) -> i64 {
+        match self {
+            Event::Commit(commit) => commit.seq,
+            Event::Identity(identity) => identity.seq,
+            Event::Account(account) => account.seq as i64,
+        }
+    }
+}
+
+/// Parse a firehose time into microseconds since the epoch
+pub fn parse_time_us(time: &str) -> anyhow::Result<i64> {
+    Ok(DateTime::parse_from_rfc3339(time)
+        .with_context(|| format!("Invalid firehose time: {}", time))?
+        .timestamp_micros())
+}
+
+/// Split a binary event stream frame into its message type and body
+///
+/// A frame consists of two concatenated DAG-CBOR objects, the header and the body. Error frames are returned as errors
+pub fn split_frame(frame: &[u8]) -> anyhow::Result<(Option<String>, &[u8])> {
+    let mut body = frame;
+    let header: Header =
+        ciborium::de::from_reader(&mut body).context("Failed to parse frame header")?;
+
+    if header.op == -1 {
+        let error: ErrorBody =
+            serde_ipld_dagcbor::from_slice(body).context("Failed to parse error frame")?;
+        anyhow::bail!(
+            "Server sent error {}: {}",
+            error.error,
+            error.message.unwrap_or_default()
+        );
+    }
+
+    Ok((header.t, body))
+}
+
+/// Parse a binary firehose frame
+///
+/// Returns None for message types we do not handle
+pub fn parse_frame(frame: &[u8]) -> anyhow::Result<Option<Event>> {
+    let (t, body) = split_frame(frame)?;
+
+    let event = match t.as_deref() {
+        Some("#commit") => {
+            Event::Commit(serde_ipld_dagcbor::from_slice(body).context("Failed to parse commit")?)
+        }
+        Some("#identity") => Event::Identity(
+            serde_ipld_dagcbor::from_slice(body).context("Failed to parse identity")?,
+        ),
+        Some("#account") => {
+            Event::Account(serde_ipld_dagcbor::from_slice(body).context("Failed to parse account")?)
+        }
+        _ => return Ok(None),
+    };
+    Ok(Some(event))
+}
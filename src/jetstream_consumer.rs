@@ -1,49 +1,247 @@
-use crate::{database, websocket};
+use crate::{
+    config::ARGS,
+    database::{self, big_update::flush_small_updates, definitions::JetstreamCursor},
+    websocket::{self, JetstreamHost},
+};
 use anyhow::Context;
 use futures::{stream::FuturesUnordered, StreamExt};
 use sqlx::PgPool;
-use tracing::error;
-
-const JETSTREAM_HOSTS: [&str; 5] = [
-    "jetstream1.us-west.bsky.network",
-    "jetstream2.us-east.bsky.network",
-    "test-jetstream.skyfeed.moe",
-    "jetstream2.us-west.bsky.network",
-    "jetstream1.us-east.bsky.network",
-];
-
-pub async fn attach_jetstream(database: PgPool) -> anyhow::Result<()> {
-    let mut jetstream_tasks = JETSTREAM_HOSTS
-        .iter()
-        .map(|host| {
-            tokio::task::spawn(start_jetstream_consumer(database.clone(), host.to_string()))
-        })
-        .collect::<FuturesUnordered<_>>();
-
-    loop {
-        let result = jetstream_tasks.next().await;
-        let Some(Ok(Ok(_))) = result else {
-            error!("Jetstream consumer task failed");
-            break;
-        };
+use std::collections::HashSet;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info};
+
+/// Key of the cursor that is shared by all hosts when failing over between them
+const FAILOVER_CURSOR_KEY: &str = "failover";
+
+/// Consume the jetstream until shutdown is requested
+///
+/// By default a single connection is kept that fails over between the hosts.
+/// With `--jetstream-parallel` every host is consumed by its own connection and resumes from its own cursor.
+///
+/// On shutdown the accumulated updates are flushed before the final cursors are written. If a consumer fails, the
+/// others are stopped as well, so their final cursors are still written
+pub async fn attach_jetstream(database: PgPool, shutdown: CancellationToken) -> anyhow::Result<()> {
+    if let (Some(host), Some(from), Some(to)) =
+        (&ARGS.replay_host, ARGS.replay_from, ARGS.replay_to)
+    {
+        return replay_jetstream(
+            database,
+            host.clone(),
+            from.timestamp_micros(),
+            to.timestamp_micros(),
+            shutdown,
+        )
+        .await;
     }
 
-    error!("All jetstream consumer task failed");
+    let mut hosts = ARGS.jetstream_hosts.clone();
+    let mut jetstream_tasks = if ARGS.jetstream_parallel {
+        // two consumers with the same cursor key would overwrite each others cursor
+        let mut seen = HashSet::new();
+        hosts.retain(|host| seen.insert(host.authority()));
+        hosts
+            .iter()
+            .map(|host| {
+                tokio::task::spawn(start_jetstream_consumer(
+                    database.clone(),
+                    vec![host.clone()],
+                    host.authority(),
+                    shutdown.clone(),
+                ))
+            })
+            .collect::<FuturesUnordered<_>>()
+    } else {
+        std::iter::once(tokio::task::spawn(start_jetstream_consumer(
+            database.clone(),
+            hosts,
+            FAILOVER_CURSOR_KEY.to_string(),
+            shutdown.clone(),
+        )))
+        .collect::<FuturesUnordered<_>>()
+    };
+
+    let mut cursors = Vec::new();
+    let mut failed = false;
+    while let Some(result) = jetstream_tasks.next().await {
+        match result {
+            Ok(Ok(cursor)) => cursors.push(cursor),
+            Ok(Err(e)) => {
+                error!("Jetstream consumer task failed: {:?}", e);
+                failed = true;
+            }
+            Err(e) => {
+                error!("Jetstream consumer task panicked: {:?}", e);
+                failed = true;
+            }
+        }
+        // stop the other consumers, but keep collecting their cursors
+        if !shutdown.is_cancelled() {
+            shutdown.cancel();
+        }
+    }
 
+    // Only write the cursors after all events up to them are in the database
+    flush_small_updates(database.clone())
+        .await
+        .context("Failed to flush accumulated updates")?;
+    for cursor in cursors {
+        info!(target: "indexer", "Writing cursor {} for {}", cursor.time_us, cursor.host);
+        database::write_cursor(&database, cursor)
+            .await
+            .context("Unable to write cursor to database!")?;
+    }
+
+    anyhow::ensure!(!failed, "A jetstream consumer failed");
     Ok(())
 }
 
-async fn start_jetstream_consumer(database: PgPool, host: String) -> anyhow::Result<()> {
-    // fetch initial cursor
-    let cursor = database::fetch_cursor(&database, &host)
+/// Index the events of a host between two times in microseconds, then request shutdown
+///
+/// The replay starts at `from` instead of the stored cursor and its cursor is never written, so the live consumer
+/// continues where it stopped before the replay. Events that were already indexed are indexed again
+async fn replay_jetstream(
+    database: PgPool,
+    host: JetstreamHost,
+    from: i64,
+    to: i64,
+    shutdown: CancellationToken,
+) -> anyhow::Result<()> {
+    anyhow::ensure!(from < to, "--replay-from has to be before --replay-to");
+    info!(target: "indexer", "Replaying {} from {} to {}", host, from, to);
+    let cursor_key = format!("replay:{}", host.authority());
+    let time_us = websocket::start(
+        vec![host],
+        cursor_key,
+        from,
+        Some(to),
+        database.clone(),
+        shutdown.clone(),
+    )
+    .await
+    .context("WebSocket event loop failed")?;
+
+    flush_small_updates(database)
         .await
-        .context("Failed to fetch cursor from database")?
-        .map_or(0, |e| e.time_us);
+        .context("Failed to flush accumulated updates")?;
+    if !shutdown.is_cancelled() {
+        info!(target: "indexer", "Replay finished at {}, shutting down", time_us);
+        shutdown.cancel();
+    }
+    Ok(())
+}
+
+/// Consume the jetstream from one of the given hosts
+///
+/// The cursor is stored under `cursor_key`. Returns the current cursor once shutdown is requested
+async fn start_jetstream_consumer(
+    database: PgPool,
+    hosts: Vec<JetstreamHost>,
+    cursor_key: String,
+    shutdown: CancellationToken,
+) -> anyhow::Result<JetstreamCursor> {
+    let cursor = resume_cursor(&database, &hosts, &cursor_key).await?;
 
     // enter websocket event loop
-    websocket::start(host, cursor, database)
+    let time_us = websocket::start(hosts, cursor_key.clone(), cursor, None, database, shutdown)
         .await
         .context("WebSocket event loop failed")?;
 
-    Ok(())
+    Ok(JetstreamCursor {
+        host: cursor_key,
+        time_us,
+    })
+}
+
+/// Get the cursor a consumer of `hosts` resumes from, 0 if there is none
+async fn resume_cursor(
+    database: &PgPool,
+    hosts: &[JetstreamHost],
+    cursor_key: &str,
+) -> anyhow::Result<i64> {
+    // fetch initial cursor
+    let mut cursor = database::fetch_cursor(database, cursor_key)
+        .await
+        .context("Failed to fetch cursor from database")?
+        .map_or(0, |e| e.time_us);
+
+    // if there is no cursor yet, continue from the newest cursor of the other mode, so switching between failover
+    // and parallel consumption does not skip events
+    if cursor == 0 {
+        let fallback_keys = hosts
+            .iter()
+            .map(|host| host.authority())
+            .chain(std::iter::once(FAILOVER_CURSOR_KEY.to_string()))
+            .filter(|key| key != cursor_key);
+        for key in fallback_keys {
+            let other_cursor = database::fetch_cursor(database, &key)
+                .await
+                .context("Failed to fetch cursor from database")?
+                .map_or(0, |e| e.time_us);
+            cursor = cursor.max(other_cursor);
+        }
+        if cursor != 0 {
+            info!(target: "indexer", "No cursor stored for {}, continuing from {}", cursor_key, cursor);
+        }
+    }
+
+    Ok(cursor)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[sqlx::test]
+    #[ignore = "needs a postgres server in DATABASE_URL"]
+    async fn parallel_hosts_resume_from_their_own_cursor(database: PgPool) -> anyhow::Result<()> {
+        let hosts = ["wss://a.example", "wss://b.example:4443"]
+            .map(|host| host.parse::<JetstreamHost>().unwrap());
+        // The final cursors that attach_jetstream writes for both consumers
+        for (host, time_us) in hosts
+            .iter()
+            .zip([1_700_000_000_000_000, 1_700_000_500_000_000])
+        {
+            database::write_cursor(
+                &database,
+                JetstreamCursor {
+                    host: host.authority(),
+                    time_us,
+                },
+            )
+            .await?;
+        }
+
+        let resumed = |host: &JetstreamHost| {
+            let database = database.clone();
+            let host = host.clone();
+            async move { resume_cursor(&database, std::slice::from_ref(&host), &host.authority()).await }
+        };
+        assert_eq!(resumed(&hosts[0]).await?, 1_700_000_000_000_000);
+        assert_eq!(resumed(&hosts[1]).await?, 1_700_000_500_000_000);
+
+        // Another write of one host leaves the other one alone
+        database::write_cursor(
+            &database,
+            JetstreamCursor {
+                host: hosts[0].authority(),
+                time_us: 1_700_000_900_000_000,
+            },
+        )
+        .await?;
+        assert_eq!(resumed(&hosts[0]).await?, 1_700_000_900_000_000);
+        assert_eq!(resumed(&hosts[1]).await?, 1_700_000_500_000_000);
+
+        // A host without a cursor falls back to the failover cursor instead of starting at the live tip
+        database::write_cursor(
+            &database,
+            JetstreamCursor {
+                host: FAILOVER_CURSOR_KEY.to_string(),
+                time_us: 1_600_000_000_000_000,
+            },
+        )
+        .await?;
+        let new_host = "wss://c.example".parse::<JetstreamHost>().unwrap();
+        assert_eq!(resumed(&new_host).await?, 1_600_000_000_000_000);
+        Ok(())
+    }
 }
@@ -0,0 +1,208 @@
+use crate::{
+    database::{
+        self,
+        definitions::JetstreamCursor,
+        labels::{insert_labels, Label},
+    },
+    firehose_consumer::events::split_frame,
+    observability::instrument_name,
+    websocket::{self, JetstreamHost},
+};
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use fastwebsockets::{OpCode, WebSocket};
+use hyper::upgrade::Upgraded;
+use hyper_util::rt::TokioIo;
+use opentelemetry::{global, metrics::Counter, KeyValue};
+use serde::Deserialize;
+use sqlx::PgPool;
+use std::{
+    sync::LazyLock,
+    time::{Duration, Instant},
+};
+use tokio::time::sleep;
+use tokio_util::sync::CancellationToken;
+use tracing::{info, trace, warn};
+
+static RECEIVED_LABELS_METRIC: LazyLock<Counter<u64>> = LazyLock::new(|| {
+    global::meter("indexer")
+        .u64_counter(instrument_name("indexer.labels.received"))
+        .with_unit("{label}")
+        .with_description("Number of labels received from labelers")
+        .build()
+});
+
+/// A `#labels` message of com.atproto.label.subscribeLabels
+#[derive(Deserialize, Debug)]
+struct Labels {
+    seq: i64,
+    labels: Vec<LabelEvent>,
+}
+
+/// A single label, the signature is not checked
+///
+/// https://atproto.com/specs/label
+#[derive(Deserialize, Debug)]
+struct LabelEvent {
+    src: String,
+    uri: String,
+    cid: Option<String>,
+    val: String,
+    #[serde(default)]
+    neg: bool,
+    cts: String,
+    exp: Option<String>,
+}
+
+impl TryFrom<LabelEvent> for Label {
+    type Error = anyhow::Error;
+
+    fn try_from(label: LabelEvent) -> anyhow::Result<Label> {
+        let parse_time = |time: &str| {
+            DateTime::parse_from_rfc3339(time)
+                .map(|time| time.with_timezone(&Utc))
+                .with_context(|| format!("Invalid label time: {}", time))
+        };
+        Ok(Label {
+            created_at: parse_time(&label.cts)?,
+            expires_at: label.exp.as_deref().map(parse_time).transpose()?,
+            src: label.src,
+            uri: label.uri,
+            cid: label.cid,
+            val: label.val,
+            neg: label.neg,
+        })
+    }
+}
+
+/// Consume the labels of a labeler until shutdown is requested
+///
+/// The cursor is the sequence number of the last `#labels` message and is stored under `labeler:<host>`. Labels are
+/// written directly instead of going through the update accumulator, so the cursor can be written at any time
+pub async fn attach_labeler(
+    database: PgPool,
+    host: JetstreamHost,
+    shutdown: CancellationToken,
+) -> anyhow::Result<()> {
+    let cursor_key = format!("labeler:{}", host.authority());
+    let mut cursor = database::fetch_cursor(&database, &cursor_key)
+        .await
+        .context("Failed to fetch cursor from database")?
+        .map(|cursor| cursor.time_us);
+    let connector = websocket::tls_connector()?;
+
+    while !shutdown.is_cancelled() {
+        let path = format!(
+            "/xrpc/com.atproto.label.subscribeLabels{}",
+            cursor.map_or_else(String::new, |c| format!("?cursor={}", c))
+        );
+        info!(target: "indexer", "Connecting to the labeler {} starting at cursor: {:?}", host, cursor);
+        match websocket::connect(&host, &connector, &path).await {
+            Ok(ws) => {
+                if let Err(e) =
+                    consume_labels(&database, ws, &host, &cursor_key, &mut cursor, &shutdown).await
+                {
+                    warn!(target: "indexer", "Labeler connection failed: {:?}", e);
+                }
+            }
+            Err(e) => {
+                warn!(target: "indexer", "Unable to open labeler connection to {}: {:?}", host, e);
+            }
+        }
+
+        tokio::select! {
+            _ = sleep(Duration::from_secs(5)) => {}
+            _ = shutdown.cancelled() => {}
+        }
+    }
+
+    if let Some(seq) = cursor {
+        info!(target: "indexer", "Writing cursor {} for {}", seq, cursor_key);
+        database::write_cursor(
+            &database,
+            JetstreamCursor {
+                host: cursor_key,
+                time_us: seq,
+            },
+        )
+        .await
+        .context("Unable to write cursor to database!")?;
+    }
+
+    Ok(())
+}
+
+/// Handle labeler frames until the connection fails or shutdown is requested
+async fn consume_labels(
+    database: &PgPool,
+    mut ws: WebSocket<TokioIo<Upgraded>>,
+    host: &JetstreamHost,
+    cursor_key: &str,
+    cursor: &mut Option<i64>,
+    shutdown: &CancellationToken,
+) -> anyhow::Result<()> {
+    let attributes = [KeyValue::new("host", host.authority())];
+    let mut last_cursor_write = Instant::now();
+    loop {
+        let frame = tokio::select! {
+            frame = ws.read_frame() => frame.context("Failed to read frame from labeler")?,
+            _ = shutdown.cancelled() => return Ok(()),
+        };
+
+        match frame.opcode {
+            OpCode::Binary => {}
+            OpCode::Close => {
+                anyhow::bail!("Unexpected connection close received: {:?}", frame.payload);
+            }
+            // pings are answered automatically
+            _ => {
+                trace!(target: "indexer", "Received {:?}", frame.opcode);
+                continue;
+            }
+        }
+
+        let (t, body) = split_frame(&frame.payload)?;
+        if t.as_deref() != Some("#labels") {
+            // #info messages only tell us that the cursor was too old
+            trace!(target: "indexer", "Ignoring labeler message {:?}", t);
+            continue;
+        }
+        let message: Labels = match serde_ipld_dagcbor::from_slice(body) {
+            Ok(message) => message,
+            Err(e) => {
+                warn!("error while parsing labeler frame {:?}", e);
+                continue;
+            }
+        };
+
+        RECEIVED_LABELS_METRIC.add(message.labels.len() as u64, &attributes);
+        let labels = message
+            .labels
+            .into_iter()
+            .filter_map(|label| {
+                Label::try_from(label)
+                    .inspect_err(|e| warn!("Skipping invalid label: {:?}", e))
+                    .ok()
+            })
+            .collect::<Vec<_>>();
+        // Labels are rare compared to records, so a failed insert is worth a reconnect instead of losing them
+        insert_labels(database, &labels)
+            .await
+            .context("Failed to insert labels")?;
+        *cursor = Some(message.seq);
+
+        // persist the cursor every minute
+        if last_cursor_write.elapsed().as_secs() >= 60 {
+            last_cursor_write = Instant::now();
+            database::write_cursor(
+                database,
+                JetstreamCursor {
+                    host: cursor_key.to_string(),
+                    time_us: message.seq,
+                },
+            )
+            .await
+            .context("Unable to write cursor to database!")?;
+        }
+    }
+}
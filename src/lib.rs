@@ -0,0 +1,18 @@
+//! Indexer for the records of the AT Protocol network
+//!
+//! The binary in `main.rs` wires the consumers, the backfill and the status server together. Other binaries can reuse
+//! the conversion of records into database rows from [`database::big_update`] and the event handlers from
+//! [`database::handlers`]. They have to call [`database::big_update::options::configure`] before the first update
+//! and should not touch [`config::ARGS`], which parses the command line of the indexer.
+
+pub mod admin;
+pub mod config;
+pub mod database;
+pub mod event_sink;
+pub mod firehose_consumer;
+pub mod jetstream_consumer;
+pub mod label_consumer;
+pub mod metrics_reporter;
+pub mod observability;
+pub mod status;
+pub mod websocket;
@@ -1,9 +1,24 @@
-use config::ARGS;
-use database::{connect, repo_indexer::start_full_repo_indexer};
 use futures::{stream::FuturesUnordered, FutureExt, StreamExt};
-use jetstream_consumer::attach_jetstream;
-use metrics_reporter::export_system_metrics;
-use observability::init_observability;
+use indexer::{
+    config::{Command, ARGS},
+    database::{
+        self,
+        big_update::{flush_stale_small_updates, options::configure},
+        connect, export_pool_metrics,
+        repo_enumerator::enumerate_repos,
+        repo_indexer::{
+            consistency::{check_repo_consistency, format_report},
+            import_car_files, start_full_repo_indexer,
+        },
+    },
+    event_sink::run_event_sink,
+    firehose_consumer::attach_firehose,
+    jetstream_consumer::attach_jetstream,
+    label_consumer::attach_labeler,
+    metrics_reporter::export_system_metrics,
+    observability::init_observability,
+    status::serve_status,
+};
 use std::{
     process::exit,
     sync::atomic::{AtomicUsize, Ordering},
@@ -11,15 +26,9 @@ use std::{
 };
 use tokio::runtime::Builder;
 use tokio_rustls::rustls::crypto::aws_lc_rs::default_provider;
+use tokio_util::sync::CancellationToken;
 use tracing::error;
 
-mod config;
-mod database;
-mod jetstream_consumer;
-mod metrics_reporter;
-mod observability;
-mod websocket;
-
 /// Override the global allocator with mimalloc
 #[global_allocator]
 static GLOBAL: mimalloc::MiMalloc = mimalloc::MiMalloc;
@@ -54,32 +63,92 @@ fn main() {
         error!(target: "indexer", "{:?}", e);
         exit(1);
     } else {
-        eprintln!("A task exited successfully, shutting down");
+        eprintln!("Shutdown complete");
         exit(0);
     }
 }
 
 /// Asynchronous main function
 async fn application_main() -> anyhow::Result<()> {
-    let _otel_guard = init_observability().await;
+    // Cancelled when the application should shut down
+    let shutdown = CancellationToken::new();
+    let otel_providers = init_observability(shutdown.clone()).await;
+    configure(ARGS.apply_options())?;
+
+    if let Some(Command::ImportCar {
+        did,
+        dry_run,
+        paths,
+    }) = &ARGS.command
+    {
+        let database = if *dry_run {
+            None
+        } else {
+            Some(connect().await?)
+        };
+        let result = import_car_files(database, did, paths).await;
+        otel_providers.shutdown();
+        return result;
+    }
+    if let Some(Command::Verify {
+        did,
+        max_discrepancies,
+    }) = &ARGS.command
+    {
+        let database = connect().await?;
+        let report = check_repo_consistency(&database, did).await?;
+        println!("{}", serde_json::to_string_pretty(&report)?);
+        eprintln!("{}", format_report(&report));
+        otel_providers.shutdown();
+        anyhow::ensure!(
+            report.discrepancies() <= *max_discrepancies,
+            "The repo of {} has {} differences to the database",
+            did,
+            report.discrepancies()
+        );
+        return Ok(());
+    }
 
     // Connect to the database
     let database = connect().await?;
 
     // Create tasks
-    let metrics_task = export_system_metrics().boxed();
-    let jetstream_task = attach_jetstream(database.clone()).boxed();
-    let indexer_task = start_full_repo_indexer(database.clone()).boxed_local();
+    let metrics_task = export_system_metrics(shutdown.clone()).boxed();
+    let jetstream_task = attach_jetstream(database.clone(), shutdown.clone()).boxed();
+    let indexer_task = start_full_repo_indexer(database.clone(), shutdown.clone()).boxed_local();
 
     // Add all tasks to a list
     let mut tasks: FuturesUnordered<_> = FuturesUnordered::new();
     if !ARGS.no_backfill {
         tasks.push(indexer_task);
     }
-    if !ARGS.no_jetstream {
+    if ARGS.firehose {
+        tasks.push(attach_firehose(database.clone(), shutdown.clone()).boxed());
+    } else if !ARGS.no_jetstream {
         tasks.push(jetstream_task);
     }
+    for host in &ARGS.labeler_hosts {
+        tasks.push(attach_labeler(database.clone(), host.clone(), shutdown.clone()).boxed());
+    }
     tasks.push(metrics_task);
+    tasks.push(export_pool_metrics(database.clone(), shutdown.clone()).boxed());
+    tasks.push(flush_stale_small_updates(database.clone(), shutdown.clone()).boxed());
+    if let Some(relay) = &ARGS.enumerate_repos_from {
+        tasks.push(enumerate_repos(database.clone(), relay.clone(), shutdown.clone()).boxed());
+    }
+    if let Some(url) = &ARGS.event_sink {
+        tasks.push(
+            run_event_sink(
+                url.clone(),
+                ARGS.event_sink_prefix.clone(),
+                shutdown.clone(),
+            )
+            .boxed(),
+        );
+    }
+    if let Some(address) = ARGS.status_listen {
+        tasks.push(serve_status(database.clone(), address, shutdown.clone()).boxed());
+    }
 
     // Wait for the first task to exit
     let first_exited_task = tasks.next().await;
@@ -88,5 +157,23 @@ async fn application_main() -> anyhow::Result<()> {
             "It seems like there were no tasks. This should never happen."
         ));
     };
-    task_result
+    if !shutdown.is_cancelled() {
+        eprintln!("A task exited unexpectedly, shutting down");
+        shutdown.cancel();
+    }
+
+    // Let the remaining tasks finish their work
+    let mut result = task_result;
+    while let Some(task_result) = tasks.next().await {
+        if let Err(e) = task_result {
+            error!(target: "indexer", "{:?}", e);
+            result = result.and(Err(e));
+        }
+    }
+
+    // Flush whatever the backfill left in the accumulator
+    database::big_update::flush_small_updates(database.clone()).await?;
+
+    otel_providers.shutdown();
+    result
 }
@@ -1,5 +1,6 @@
 use std::time::Duration;
 
+use crate::{config::ARGS, observability::instrument_name};
 use opentelemetry::{global, KeyValue};
 use opentelemetry_semantic_conventions::{
     attribute::{
@@ -18,9 +19,10 @@ use tokio::{
     task::{block_in_place, yield_now},
     time::{interval_at, Instant},
 };
+use tokio_util::sync::CancellationToken;
 
-const METRICS_INTERVAL: Duration = Duration::from_secs(2);
-pub async fn export_system_metrics() -> anyhow::Result<()> {
+/// Export system metrics until shutdown is requested
+pub async fn export_system_metrics(shutdown: CancellationToken) -> anyhow::Result<()> {
     let meter = global::meter("system");
 
     let mut system = System::new_all();
@@ -32,48 +34,51 @@ pub async fn export_system_metrics() -> anyhow::Result<()> {
     yield_now().await;
 
     let cpu_utilization_meter = meter
-        .f64_gauge(SYSTEM_CPU_UTILIZATION)
+        .f64_gauge(instrument_name(SYSTEM_CPU_UTILIZATION))
         .with_description("Difference in system.cpu.time since the last measurement, divided by the elapsed time and number of logical CPUs")
         .with_unit("1")
         .build();
     let cpu_logical_count_meter = meter
-    .i64_up_down_counter(SYSTEM_CPU_LOGICAL_COUNT)
+    .i64_up_down_counter(instrument_name(SYSTEM_CPU_LOGICAL_COUNT))
     .with_description("Reports the number of logical (virtual) processor cores created by the operating system to manage multitasking")
     .with_unit("{cpu}")
     .build();
     let cpu_frequency_meter = meter
-        .f64_gauge(SYSTEM_CPU_FREQUENCY)
+        .f64_gauge(instrument_name(SYSTEM_CPU_FREQUENCY))
         .with_description("Reports the current frequency of the CPU in Hz")
         .with_unit("{Hz}")
         .build();
     let memory_usage_meter = meter
-        .i64_up_down_counter(SYSTEM_MEMORY_USAGE)
+        .i64_up_down_counter(instrument_name(SYSTEM_MEMORY_USAGE))
         .with_description("Reports memory in use by state")
         .with_unit("By")
         .build();
     let memory_limit_meter = meter
-        .i64_up_down_counter(SYSTEM_MEMORY_LIMIT)
+        .i64_up_down_counter(instrument_name(SYSTEM_MEMORY_LIMIT))
         .with_description("Total memory available in the system")
         .with_unit("By")
         .build();
     let memory_utilization_meter = meter
-        .f64_gauge(SYSTEM_MEMORY_UTILIZATION)
+        .f64_gauge(instrument_name(SYSTEM_MEMORY_UTILIZATION))
         .with_unit("1")
         .build();
     let memory_available_meter = meter
-        .i64_up_down_counter(SYSTEM_LINUX_MEMORY_AVAILABLE)
+        .i64_up_down_counter(instrument_name(SYSTEM_LINUX_MEMORY_AVAILABLE))
         .with_description("An estimate of how much memory is available for starting new applications, without causing swapping")
         .with_unit("By")
         .build();
     let network_packets_meter = meter
-        .u64_counter(SYSTEM_NETWORK_PACKETS)
+        .u64_counter(instrument_name(SYSTEM_NETWORK_PACKETS))
         .with_unit("{packet}")
         .build();
     let network_errors_meter = meter
-        .u64_counter(SYSTEM_NETWORK_ERRORS)
+        .u64_counter(instrument_name(SYSTEM_NETWORK_ERRORS))
         .with_unit("{error}")
         .build();
-    let network_io_meter = meter.u64_counter(SYSTEM_NETWORK_IO).with_unit("By").build();
+    let network_io_meter = meter
+        .u64_counter(instrument_name(SYSTEM_NETWORK_IO))
+        .with_unit("By")
+        .build();
 
     let mut previous_cpu_logical_count = 0;
     let mut previous_free_memory = 0u64;
@@ -81,9 +86,15 @@ pub async fn export_system_metrics() -> anyhow::Result<()> {
     let mut previous_used_memory = 0u64;
     let mut previous_availabe_memory = 0u64;
 
-    let mut interval = interval_at(Instant::now(), METRICS_INTERVAL);
+    let mut interval = interval_at(
+        Instant::now(),
+        Duration::from_secs(ARGS.system_metrics_interval_secs),
+    );
     loop {
-        interval.tick().await;
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = shutdown.cancelled() => return Ok(()),
+        }
 
         block_in_place(|| {
             system.refresh_cpu_all();
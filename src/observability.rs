@@ -1,14 +1,21 @@
 use crate::config::ARGS;
 use console_subscriber::ConsoleLayer;
 use otel_providers::OtelProviders;
-use std::{process::exit, sync::Arc};
+use std::{
+    collections::HashSet,
+    process::exit,
+    sync::{Arc, LazyLock, Mutex},
+    time::Duration,
+};
 use tokio::signal::ctrl_c;
-use tracing::Subscriber;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, Subscriber};
 use tracing_subscriber::{
     layer::SubscriberExt, registry::LookupSpan, util::SubscriberInitExt, EnvFilter, Layer,
 };
 
 mod otel_providers;
+mod prometheus;
 
 /// Layer for enabling tokio-console
 pub fn tokio_console_layer<S>() -> Option<impl Layer<S>>
@@ -33,7 +40,44 @@ where
     Box::new(stdout_layer)
 }
 
-pub async fn init_observability() -> Arc<OtelProviders> {
+/// Names of all instruments created through [`instrument_name`]
+static INSTRUMENT_NAMES: LazyLock<Mutex<HashSet<&'static str>>> =
+    LazyLock::new(|| Mutex::new(HashSet::new()));
+
+/// Register the name of a metric instrument
+///
+/// Instruments with the same name are silently merged by opentelemetry, so this panics in debug builds if a name is
+/// used twice. Instruments are created once in statics, so a second registration is always a bug.
+pub fn instrument_name(name: &'static str) -> &'static str {
+    let inserted = INSTRUMENT_NAMES.lock().unwrap().insert(name);
+    debug_assert!(inserted, "Metric instrument {} is registered twice", name);
+    name
+}
+
+/// Time the application gets to shut down gracefully before it is killed
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Wait for Ctrl-C or, on unix, for SIGTERM
+///
+/// Container runtimes and systemd stop processes with SIGTERM, so it has to trigger the graceful shutdown as well
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut sigterm = signal(SignalKind::terminate()).unwrap();
+        tokio::select! {
+            _ = ctrl_c() => {}
+            _ = sigterm.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    ctrl_c().await.unwrap();
+}
+
+/// Initialize tracing and the otel providers
+///
+/// `shutdown` gets cancelled when the user requests a shutdown with Ctrl-C or SIGTERM
+pub async fn init_observability(shutdown: CancellationToken) -> Arc<OtelProviders> {
     let otel_providers = Arc::new(OtelProviders::new());
 
     // Initialize the tracing subscribers
@@ -43,16 +87,40 @@ pub async fn init_observability() -> Arc<OtelProviders> {
         .with(otel_providers.tracing_layers())
         .init();
 
+    if let (Some(reader), Some(address)) = (
+        otel_providers.prometheus_reader(),
+        ARGS.metrics_prometheus_listen,
+    ) {
+        tokio::task::Builder::new()
+            .name("Prometheus exporter")
+            .spawn(async move {
+                if let Err(e) = prometheus::serve_prometheus(reader, address).await {
+                    error!(target: "indexer", "Prometheus exporter failed: {:?}", e);
+                }
+            })
+            .unwrap();
+    }
+
     let handler_otel_providers = otel_providers.clone();
     tokio::task::Builder::new()
         .name("Observability shutdown hook")
         .spawn(async move {
-            // TODO: Properly manage application shutdown
-            ctrl_c().await.unwrap();
-            eprintln!("Preparing for unclean exit");
+            shutdown_signal().await;
+            eprintln!("Shutting down, press Ctrl-C again to force exit");
+            shutdown.cancel();
+
+            // Fall back to an unclean exit if the graceful shutdown takes too long
+            tokio::select! {
+                _ = tokio::time::sleep(SHUTDOWN_TIMEOUT) => {
+                    eprintln!("Graceful shutdown timed out, preparing for unclean exit");
+                }
+                _ = shutdown_signal() => {
+                    eprintln!("Preparing for unclean exit");
+                }
+            }
 
             handler_otel_providers.shutdown();
-            tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+            tokio::time::sleep(Duration::from_secs(1)).await;
 
             eprintln!("Exiting");
             exit(1);
@@ -60,3 +128,33 @@ pub async fn init_observability() -> Arc<OtelProviders> {
         .unwrap();
     otel_providers
 }
+
+#[cfg(test)]
+pub(crate) mod testing {
+    use super::prometheus::PrometheusReader;
+    use opentelemetry_sdk::metrics::SdkMeterProvider;
+    use std::sync::OnceLock;
+
+    static READER: OnceLock<PrometheusReader> = OnceLock::new();
+
+    /// Set a global meter provider that records into a prometheus reader, once
+    ///
+    /// Instruments are statics that keep the provider they were created with, so tests that check metrics have to call
+    /// this before the instruments are used
+    pub fn install() -> &'static PrometheusReader {
+        READER.get_or_init(|| {
+            let reader = PrometheusReader::new();
+            opentelemetry::global::set_meter_provider(
+                SdkMeterProvider::builder()
+                    .with_reader(reader.clone())
+                    .build(),
+            );
+            reader
+        })
+    }
+
+    /// Return the metrics of the global meter provider in the prometheus text format
+    pub fn rendered_metrics() -> String {
+        install().render().unwrap()
+    }
+}
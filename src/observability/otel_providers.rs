@@ -1,3 +1,4 @@
+use super::prometheus::PrometheusReader;
 use crate::config::ARGS;
 use opentelemetry::trace::TracerProvider;
 use opentelemetry::{global, KeyValue};
@@ -27,11 +28,15 @@ use tracing::Subscriber;
 use tracing_subscriber::{registry::LookupSpan, EnvFilter, Layer};
 
 static RESOURCE: LazyLock<Resource> = LazyLock::new(|| {
-    let mut attributes = vec![
+    let schema_attributes = [
         KeyValue::new(SERVICE_NAME, env!("CARGO_PKG_NAME")),
         KeyValue::new(SERVICE_VERSION, env!("CARGO_PKG_VERSION")),
-        KeyValue::new(DEPLOYMENT_ENVIRONMENT_NAME, "develop"),
+        KeyValue::new(
+            DEPLOYMENT_ENVIRONMENT_NAME,
+            ARGS.deployment_environment.clone(),
+        ),
     ];
+    let mut attributes = schema_attributes.to_vec();
 
     if let Ok(linux_sys_info) = sys_info::linux_os_release() {
         if let Some(build_id) = linux_sys_info.build_id {
@@ -60,14 +65,7 @@ static RESOURCE: LazyLock<Resource> = LazyLock::new(|| {
     }
 
     Resource::builder()
-        .with_schema_url(
-            [
-                KeyValue::new(SERVICE_NAME, env!("CARGO_PKG_NAME")),
-                KeyValue::new(SERVICE_VERSION, env!("CARGO_PKG_VERSION")),
-                KeyValue::new(DEPLOYMENT_ENVIRONMENT_NAME, "develop"),
-            ],
-            SCHEMA_URL,
-        )
+        .with_schema_url(schema_attributes, SCHEMA_URL)
         .with_attributes(attributes)
         .with_detectors(&[
             Box::new(EnvResourceDetector::new()),
@@ -92,26 +90,37 @@ fn init_logger() -> Option<SdkLoggerProvider> {
     Some(logger_provider)
 }
 
-fn init_meter() -> Option<SdkMeterProvider> {
-    if ARGS.no_otel_metrics {
-        return None;
+/// Initialize the meter provider with an OTLP exporter and/or a prometheus reader
+///
+/// If neither is enabled, no meter provider is installed and recording metrics is a no-op
+fn init_meter() -> (Option<SdkMeterProvider>, Option<PrometheusReader>) {
+    let otlp_enabled = !ARGS.no_otel_metrics;
+    let prometheus_enabled = ARGS.metrics_prometheus_listen.is_some();
+    if !otlp_enabled && !prometheus_enabled {
+        return (None, None);
     }
-    let otlp_metric_exporter = MetricExporter::builder()
-        .with_tonic()
-        .with_temporality(opentelemetry_sdk::metrics::Temporality::Cumulative)
-        .build()
-        .unwrap();
 
-    let periodic_reader = PeriodicReader::builder(otlp_metric_exporter)
-        .with_interval(std::time::Duration::from_secs(5))
-        .build();
+    let mut meter_provider_builder = SdkMeterProvider::builder().with_resource(RESOURCE.clone());
+    if otlp_enabled {
+        let otlp_metric_exporter = MetricExporter::builder()
+            .with_tonic()
+            .with_temporality(opentelemetry_sdk::metrics::Temporality::Cumulative)
+            .build()
+            .unwrap();
+
+        let periodic_reader = PeriodicReader::builder(otlp_metric_exporter)
+            .with_interval(std::time::Duration::from_secs(5))
+            .build();
+        meter_provider_builder = meter_provider_builder.with_reader(periodic_reader);
+    }
+    let prometheus_reader = prometheus_enabled.then(PrometheusReader::new);
+    if let Some(prometheus_reader) = &prometheus_reader {
+        meter_provider_builder = meter_provider_builder.with_reader(prometheus_reader.clone());
+    }
 
-    let meter_provider_builder = SdkMeterProvider::builder()
-        .with_resource(RESOURCE.clone())
-        .with_reader(periodic_reader);
     let meter_provider = meter_provider_builder.build();
     global::set_meter_provider(meter_provider.clone());
-    Some(meter_provider)
+    (Some(meter_provider), prometheus_reader)
 }
 
 fn init_tracer() -> Option<SdkTracerProvider> {
@@ -139,6 +148,7 @@ pub struct OtelProviders {
     tracer_provider: Option<SdkTracerProvider>,
     meter_provider: Option<SdkMeterProvider>,
     logger_provider: Option<SdkLoggerProvider>,
+    prometheus_reader: Option<PrometheusReader>,
     /// Flag to indicate if the observability providers have been shutdown
     shutdown: Mutex<bool>,
 }
@@ -151,22 +161,28 @@ impl OtelProviders {
     /// The providers will be shutdown automatically when the last reference to this struct is dropped
     pub fn new() -> Self {
         static ALREADY_INITIALIZED: AtomicBool = AtomicBool::new(false);
-        if ALREADY_INITIALIZED.fetch_and(true, Ordering::SeqCst) {
+        if ALREADY_INITIALIZED.swap(true, Ordering::SeqCst) {
             panic!("OtelProviders::new() called more than once");
         }
 
         let tracer_provider = init_tracer();
-        let meter_provider = init_meter();
+        let (meter_provider, prometheus_reader) = init_meter();
         let logger_provider = init_logger();
 
         Self {
             tracer_provider,
             meter_provider,
             logger_provider,
+            prometheus_reader,
             shutdown: Mutex::new(false),
         }
     }
 
+    /// The reader for serving metrics to prometheus, if enabled
+    pub fn prometheus_reader(&self) -> Option<PrometheusReader> {
+        self.prometheus_reader.clone()
+    }
+
     /// Shutdown the observability providers
     ///
     /// Does nothing if already shutdown
@@ -0,0 +1,319 @@
+use anyhow::Context;
+use hyper::{
+    body::Incoming, header::CONTENT_TYPE, server::conn::http1, service::service_fn, Request,
+    Response, StatusCode,
+};
+use hyper_util::rt::TokioIo;
+use opentelemetry_sdk::{
+    error::OTelSdkResult,
+    metrics::{
+        data::{Gauge, Histogram, Metric, ResourceMetrics, Sum},
+        reader::MetricReader,
+        InstrumentKind, ManualReader, MetricResult, Pipeline, Temporality,
+    },
+    Resource,
+};
+use std::{
+    convert::Infallible,
+    fmt::{Display, Write},
+    net::SocketAddr,
+    sync::{Arc, Weak},
+};
+use tokio::net::TcpListener;
+use tracing::{debug, info, warn};
+
+/// Metric reader that collects the metrics when prometheus scrapes them
+#[derive(Debug, Clone)]
+pub struct PrometheusReader {
+    reader: Arc<ManualReader>,
+}
+
+impl PrometheusReader {
+    pub fn new() -> Self {
+        Self {
+            reader: Arc::new(
+                ManualReader::builder()
+                    .with_temporality(Temporality::Cumulative)
+                    .build(),
+            ),
+        }
+    }
+
+    /// Collect all metrics and encode them in the prometheus text format
+    pub(super) fn render(&self) -> MetricResult<String> {
+        let mut resource_metrics = ResourceMetrics {
+            resource: Resource::builder_empty().build(),
+            scope_metrics: Vec::new(),
+        };
+        self.reader.collect(&mut resource_metrics)?;
+
+        let mut output = String::new();
+        for metric in resource_metrics
+            .scope_metrics
+            .iter()
+            .flat_map(|scope| scope.metrics.iter())
+        {
+            write_metric(&mut output, metric);
+        }
+        Ok(output)
+    }
+}
+
+impl MetricReader for PrometheusReader {
+    fn register_pipeline(&self, pipeline: Weak<Pipeline>) {
+        self.reader.register_pipeline(pipeline)
+    }
+
+    fn collect(&self, rm: &mut ResourceMetrics) -> MetricResult<()> {
+        self.reader.collect(rm)
+    }
+
+    fn force_flush(&self) -> OTelSdkResult {
+        self.reader.force_flush()
+    }
+
+    fn shutdown(&self) -> OTelSdkResult {
+        self.reader.shutdown()
+    }
+
+    fn temporality(&self, kind: InstrumentKind) -> Temporality {
+        self.reader.temporality(kind)
+    }
+}
+
+/// Serve the metrics of `reader` on `/metrics`
+pub async fn serve_prometheus(reader: PrometheusReader, address: SocketAddr) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(address)
+        .await
+        .with_context(|| format!("Unable to listen for prometheus scrapes on: {}", address))?;
+    info!(target: "indexer", "Serving prometheus metrics on http://{}/metrics", address);
+
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                warn!(target: "indexer", "Failed to accept prometheus connection: {:?}", e);
+                continue;
+            }
+        };
+
+        let reader = reader.clone();
+        tokio::task::spawn(async move {
+            let service = service_fn(move |request| handle_request(request, reader.clone()));
+            if let Err(e) = http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service)
+                .await
+            {
+                debug!(target: "indexer", "Prometheus connection failed: {:?}", e);
+            }
+        });
+    }
+}
+
+async fn handle_request(
+    request: Request<Incoming>,
+    reader: PrometheusReader,
+) -> Result<Response<String>, Infallible> {
+    let (status, body) = if request.uri().path() != "/metrics" {
+        (StatusCode::NOT_FOUND, "not found".to_string())
+    } else {
+        match reader.render() {
+            Ok(body) => (StatusCode::OK, body),
+            Err(e) => {
+                warn!(target: "indexer", "Failed to collect metrics: {:?}", e);
+                (StatusCode::INTERNAL_SERVER_ERROR, format!("{:?}", e))
+            }
+        }
+    };
+
+    Ok(Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "text/plain; version=0.0.4")
+        .body(body)
+        .unwrap())
+}
+
+/// Write a single metric in the prometheus text format
+fn write_metric(output: &mut String, metric: &Metric) {
+    let name = sanitize_name(&metric.name);
+    let data = metric.data.as_any();
+    if let Some(sum) = data.downcast_ref::<Sum<u64>>() {
+        write_sum(output, &name, &metric.description, sum);
+    } else if let Some(sum) = data.downcast_ref::<Sum<i64>>() {
+        write_sum(output, &name, &metric.description, sum);
+    } else if let Some(sum) = data.downcast_ref::<Sum<f64>>() {
+        write_sum(output, &name, &metric.description, sum);
+    } else if let Some(gauge) = data.downcast_ref::<Gauge<u64>>() {
+        write_gauge(output, &name, &metric.description, gauge);
+    } else if let Some(gauge) = data.downcast_ref::<Gauge<i64>>() {
+        write_gauge(output, &name, &metric.description, gauge);
+    } else if let Some(gauge) = data.downcast_ref::<Gauge<f64>>() {
+        write_gauge(output, &name, &metric.description, gauge);
+    } else if let Some(histogram) = data.downcast_ref::<Histogram<u64>>() {
+        write_histogram(output, &name, &metric.description, histogram);
+    } else if let Some(histogram) = data.downcast_ref::<Histogram<i64>>() {
+        write_histogram(output, &name, &metric.description, histogram);
+    } else if let Some(histogram) = data.downcast_ref::<Histogram<f64>>() {
+        write_histogram(output, &name, &metric.description, histogram);
+    } else {
+        debug!(target: "indexer", "Skipping metric {} with unsupported aggregation", metric.name);
+    }
+}
+
+fn write_sum<T: Display>(output: &mut String, name: &str, description: &str, sum: &Sum<T>) {
+    // Monotonic sums are counters, others can go up and down like a gauge
+    let (name, kind) = if sum.is_monotonic {
+        let name = if name.ends_with("_total") {
+            name.to_string()
+        } else {
+            format!("{}_total", name)
+        };
+        (name, "counter")
+    } else {
+        (name.to_string(), "gauge")
+    };
+    write_header(output, &name, description, kind);
+    for point in &sum.data_points {
+        let labels = format_labels(&point.attributes, None);
+        writeln!(output, "{}{} {}", name, labels, point.value).unwrap();
+    }
+}
+
+fn write_gauge<T: Display>(output: &mut String, name: &str, description: &str, gauge: &Gauge<T>) {
+    write_header(output, name, description, "gauge");
+    for point in &gauge.data_points {
+        let labels = format_labels(&point.attributes, None);
+        writeln!(output, "{}{} {}", name, labels, point.value).unwrap();
+    }
+}
+
+fn write_histogram<T: Display>(
+    output: &mut String,
+    name: &str,
+    description: &str,
+    histogram: &Histogram<T>,
+) {
+    write_header(output, name, description, "histogram");
+    for point in &histogram.data_points {
+        // Prometheus buckets are cumulative
+        let mut cumulative_count = 0;
+        for (index, count) in point.bucket_counts.iter().enumerate() {
+            cumulative_count += count;
+            let bound = point
+                .bounds
+                .get(index)
+                .map_or_else(|| "+Inf".to_string(), |bound| bound.to_string());
+            let labels = format_labels(&point.attributes, Some(&bound));
+            writeln!(output, "{}_bucket{} {}", name, labels, cumulative_count).unwrap();
+        }
+        let labels = format_labels(&point.attributes, None);
+        writeln!(output, "{}_sum{} {}", name, labels, point.sum).unwrap();
+        writeln!(output, "{}_count{} {}", name, labels, point.count).unwrap();
+    }
+}
+
+fn write_header(output: &mut String, name: &str, description: &str, kind: &str) {
+    if !description.is_empty() {
+        writeln!(
+            output,
+            "# HELP {} {}",
+            name,
+            description.replace('\\', "\\\\").replace('\n', "\\n")
+        )
+        .unwrap();
+    }
+    writeln!(output, "# TYPE {} {}", name, kind).unwrap();
+}
+
+/// Format attributes as prometheus labels, optionally with a histogram bucket bound
+fn format_labels(attributes: &[opentelemetry::KeyValue], bucket_bound: Option<&str>) -> String {
+    let mut labels = attributes
+        .iter()
+        .map(|attribute| {
+            format!(
+                "{}=\"{}\"",
+                sanitize_name(attribute.key.as_str()),
+                escape_label_value(&attribute.value.as_str())
+            )
+        })
+        .collect::<Vec<_>>();
+    if let Some(bound) = bucket_bound {
+        labels.push(format!("le=\"{}\"", bound));
+    }
+    if labels.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", labels.join(","))
+    }
+}
+
+/// Replace all characters that are not allowed in prometheus names
+fn sanitize_name(name: &str) -> String {
+    name.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '_' || c == ':' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+fn escape_label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opentelemetry::metrics::MeterProvider;
+    use opentelemetry_sdk::metrics::SdkMeterProvider;
+
+    #[tokio::test]
+    async fn metrics_endpoint_renders_counters() {
+        let reader = PrometheusReader::new();
+        let provider = SdkMeterProvider::builder()
+            .with_reader(reader.clone())
+            .build();
+        // The same name as the pipeline counter, without registering it in the global provider
+        let completed = provider
+            .meter("indexer")
+            .u64_counter("indexer.pipeline.completed")
+            .with_description("Pipelines finished")
+            .build();
+        completed.add(3, &[]);
+
+        // Find a free port, serve_prometheus binds it again
+        let address = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let server = tokio::spawn(serve_prometheus(reader, address));
+        let url = format!("http://{}/metrics", address);
+        let mut response = reqwest::get(&url).await;
+        for _ in 0..50 {
+            if response.is_ok() {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+            response = reqwest::get(&url).await;
+        }
+        let body = response.unwrap().text().await.unwrap();
+        server.abort();
+
+        assert!(
+            body.contains("# TYPE indexer_pipeline_completed_total counter\n"),
+            "{}",
+            body
+        );
+        assert!(
+            body.contains("\nindexer_pipeline_completed_total 3\n"),
+            "{}",
+            body
+        );
+    }
+}
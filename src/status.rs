@@ -0,0 +1,188 @@
+use crate::{
+    admin::{handle_admin_request, is_admin_path},
+    config::ARGS,
+    database::{
+        big_update::accumulated_rows, repo_indexer::pipeline_locations, search::search_posts,
+    },
+    firehose_consumer::firehose_connected,
+    websocket::jetstream_status,
+};
+use anyhow::Context;
+use hyper::{
+    body::Incoming, header::CONTENT_TYPE, server::conn::http1, service::service_fn, Request,
+    Response, StatusCode,
+};
+use hyper_util::rt::TokioIo;
+use serde_json::json;
+use sqlx::PgPool;
+use std::{convert::Infallible, net::SocketAddr};
+use tokio::net::TcpListener;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, warn};
+
+/// Serve the indexer status over http until shutdown is requested
+///
+/// `/status` returns a JSON document with the backfill progress, jetstream lag, pipeline and accumulator sizes.
+/// `/healthz` returns 200 if at least one jetstream or firehose connection is open.
+/// `/search?q=<query>&lang=<lang>&limit=<limit>` returns the ids of matching posts as JSON.
+/// The admin routes are handled by the admin module.
+pub async fn serve_status(
+    database: PgPool,
+    address: SocketAddr,
+    shutdown: CancellationToken,
+) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(address)
+        .await
+        .with_context(|| format!("Unable to listen for status requests on: {}", address))?;
+    info!(target: "indexer", "Serving status on http://{}", address);
+
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = shutdown.cancelled() => return Ok(()),
+        };
+        let stream = match accepted {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                warn!(target: "indexer", "Failed to accept status connection: {:?}", e);
+                continue;
+            }
+        };
+
+        let database = database.clone();
+        tokio::task::spawn(async move {
+            let service = service_fn(move |request| handle_request(request, database.clone()));
+            if let Err(e) = http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service)
+                .await
+            {
+                debug!(target: "indexer", "Status connection failed: {:?}", e);
+            }
+        });
+    }
+}
+
+/// Route a status request
+async fn handle_request(
+    request: Request<Incoming>,
+    database: PgPool,
+) -> Result<Response<String>, Infallible> {
+    let (status, content_type, body) = match request.uri().path() {
+        "/healthz" => {
+            let connected = firehose_connected()
+                || jetstream_status()
+                    .iter()
+                    .any(|consumer| consumer.connected_host.is_some());
+            if connected {
+                (StatusCode::OK, "text/plain", "ok".to_string())
+            } else {
+                (
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "text/plain",
+                    "no jetstream connection".to_string(),
+                )
+            }
+        }
+        "/" | "/status" => match status_json(&database).await {
+            Ok(json) => (StatusCode::OK, "application/json", json.to_string()),
+            Err(e) => {
+                warn!(target: "indexer", "Failed to collect status: {:?}", e);
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "text/plain",
+                    format!("{:?}", e),
+                )
+            }
+        },
+        "/search" => match search_json(&request, &database).await {
+            Ok(json) => (StatusCode::OK, "application/json", json.to_string()),
+            Err(e) => (StatusCode::BAD_REQUEST, "text/plain", format!("{:?}", e)),
+        },
+        path if is_admin_path(path) => {
+            let (status, body) = handle_admin_request(&request, &database).await;
+            (status, "text/plain", body)
+        }
+        _ => (StatusCode::NOT_FOUND, "text/plain", "not found".to_string()),
+    };
+
+    Ok(Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, content_type)
+        .body(body)
+        .unwrap())
+}
+
+/// Maximum number of posts returned by a single search
+const MAX_SEARCH_LIMIT: i64 = 100;
+
+/// Search posts with the query parameters of the request
+async fn search_json(
+    request: &Request<Incoming>,
+    database: &PgPool,
+) -> anyhow::Result<serde_json::Value> {
+    // The uri of a request only contains the path and query, so a dummy base is needed for parsing
+    let url = reqwest::Url::parse("http://localhost")?.join(&request.uri().to_string())?;
+    let mut query = None;
+    let mut lang = None;
+    let mut limit = 25;
+    for (key, value) in url.query_pairs() {
+        match key.as_ref() {
+            "q" => query = Some(value.into_owned()),
+            "lang" => lang = Some(value.into_owned()),
+            "limit" => limit = value.parse::<i64>().context("Invalid limit")?,
+            _ => {}
+        }
+    }
+    let query = query.context("Missing query parameter q")?;
+    if !ARGS.enable_post_search {
+        anyhow::bail!("Post search is disabled, start the indexer with --enable-post-search");
+    }
+
+    let posts = search_posts(
+        database,
+        &query,
+        lang.as_deref(),
+        limit.clamp(1, MAX_SEARCH_LIMIT),
+    )
+    .await?;
+    Ok(json!({ "posts": posts }))
+}
+
+/// Collect the current status of the indexer
+async fn status_json(database: &PgPool) -> anyhow::Result<serde_json::Value> {
+    let backfill = sqlx::query!(
+        r#"SELECT COUNT(*) FILTER (WHERE at IS NULL) AS "pending!", COUNT(*) AS "total!" FROM latest_backfill"#
+    )
+    .fetch_one(database)
+    .await
+    .context("Failed to count backfilled repos")?;
+
+    let now_us = chrono::Utc::now().timestamp_micros();
+    let jetstream = jetstream_status()
+        .into_iter()
+        .map(|consumer| {
+            json!({
+                "cursor_key": consumer.cursor_key,
+                "connected_host": consumer.connected_host,
+                "cursor": consumer.cursor,
+                "lag_seconds": (consumer.cursor > 0)
+                    .then(|| (now_us - consumer.cursor) as f64 / 1_000_000.0),
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let pipeline = pipeline_locations()
+        .into_iter()
+        .map(|(stage, state, tasks)| json!({ "stage": stage, "state": state, "tasks": tasks }))
+        .collect::<Vec<_>>();
+
+    Ok(json!({
+        "backfill": {
+            "pending": backfill.pending,
+            "total": backfill.total,
+        },
+        "jetstream": jetstream,
+        "pipeline": pipeline,
+        "accumulated_rows": accumulated_rows().await,
+    }))
+}
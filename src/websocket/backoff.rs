@@ -0,0 +1,107 @@
+use std::time::{Duration, Instant};
+
+/// Delay after the first failed connection to a host
+const MIN_DELAY: Duration = Duration::from_millis(500);
+/// Upper bound of the delay between two connection attempts to a host
+const MAX_DELAY: Duration = Duration::from_secs(60);
+/// A connection has to stay up this long to reset the backoff of its host
+pub const GRACE_PERIOD: Duration = Duration::from_secs(30);
+
+/// Randomize a delay to between half and all of it, so consumers that failed together do not retry together
+pub fn jittered(delay: Duration) -> Duration {
+    delay / 2 + delay.mul_f64(fastrand::f64() / 2.0)
+}
+
+/// Exponential backoff of the connection attempts to a single host
+#[derive(Debug)]
+pub struct Backoff {
+    failures: u32,
+    /// The host should not be tried again before this time
+    next_attempt: Option<Instant>,
+}
+
+impl Backoff {
+    pub fn new() -> Backoff {
+        Backoff {
+            failures: 0,
+            next_attempt: None,
+        }
+    }
+
+    /// Record a failed connection and return the delay before the host should be tried again
+    ///
+    /// The delay doubles with every failure until it reaches the cap
+    pub fn failure(&mut self) -> Duration {
+        let delay = jittered(
+            MIN_DELAY
+                .saturating_mul(1 << self.failures.min(16))
+                .min(MAX_DELAY),
+        );
+        self.failures = self.failures.saturating_add(1);
+        self.next_attempt = Some(Instant::now() + delay);
+        delay
+    }
+
+    /// Record a connection that stayed up for at least the grace period
+    pub fn reset(&mut self) {
+        self.failures = 0;
+        self.next_attempt = None;
+    }
+
+    /// Time left until the host should be tried again
+    pub fn remaining(&self) -> Option<Duration> {
+        self.next_attempt
+            .map(|next_attempt| next_attempt.saturating_duration_since(Instant::now()))
+            .filter(|remaining| !remaining.is_zero())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn jitter_keeps_at_least_half_of_the_delay() {
+        let delay = Duration::from_secs(10);
+        for _ in 0..1000 {
+            let jittered = jittered(delay);
+            assert!(jittered >= delay / 2, "{:?}", jittered);
+            assert!(jittered <= delay, "{:?}", jittered);
+        }
+    }
+
+    #[test]
+    fn delay_doubles_until_the_cap() {
+        let mut backoff = Backoff::new();
+        assert_eq!(backoff.remaining(), None);
+        for failures in 0..20 {
+            let delay = MIN_DELAY.saturating_mul(1 << failures).min(MAX_DELAY);
+            let jittered = backoff.failure();
+            assert!(
+                jittered >= delay / 2,
+                "{:?} after {} failures",
+                jittered,
+                failures
+            );
+            assert!(
+                jittered <= delay,
+                "{:?} after {} failures",
+                jittered,
+                failures
+            );
+        }
+        assert!(backoff.remaining().unwrap() <= MAX_DELAY);
+    }
+
+    #[test]
+    fn reset_starts_over() {
+        let mut backoff = Backoff::new();
+        for _ in 0..10 {
+            backoff.failure();
+        }
+        backoff.reset();
+        assert_eq!(backoff.remaining(), None);
+        assert!(backoff.failure() <= MIN_DELAY);
+        assert!(backoff.remaining().is_some());
+    }
+}
@@ -1,4 +1,4 @@
-use std::future::Future;
+use std::{fmt::Display, future::Future, str::FromStr, sync::Arc};
 
 use anyhow::Context;
 use fastwebsockets::{handshake, WebSocket};
@@ -10,9 +10,17 @@ use hyper::{
 };
 use hyper_util::rt::TokioIo;
 use tokio::{net::TcpStream, task};
-use tokio_rustls::{rustls::pki_types::ServerName, TlsConnector};
+use tokio_rustls::{
+    rustls::{
+        pki_types::{pem::PemObject, CertificateDer, ServerName},
+        ClientConfig, RootCertStore,
+    },
+    TlsConnector,
+};
 use tracing::{debug, info};
 
+use crate::config::ARGS;
+
 /// A tokio executor for hyper
 struct TokioExecutor;
 
@@ -26,36 +34,127 @@ where
     }
 }
 
-// TODO perf: use the zstd-compressed jetstream
-/// Connect to a websocket server
-pub async fn connect_tls(
-    host: &String,
+/// A jetstream host
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JetstreamHost {
+    /// Connect with tls (wss://) or without (ws://)
+    pub tls: bool,
+    pub host: String,
+    pub port: u16,
+}
+
+impl JetstreamHost {
+    /// Host and port, the port is omitted if it is the default for the scheme
+    pub fn authority(&self) -> String {
+        let default_port = if self.tls { 443 } else { 80 };
+        if self.port == default_port {
+            self.host.clone()
+        } else {
+            format!("{}:{}", self.host, self.port)
+        }
+    }
+}
+
+impl FromStr for JetstreamHost {
+    type Err = anyhow::Error;
+
+    /// Parse a host like `jetstream1.us-east.bsky.network`, `wss://host:port` or `ws://localhost:6008`
+    fn from_str(value: &str) -> anyhow::Result<Self> {
+        let (tls, rest) = if let Some(rest) = value.strip_prefix("wss://") {
+            (true, rest)
+        } else if let Some(rest) = value.strip_prefix("ws://") {
+            (false, rest)
+        } else if value.contains("://") {
+            anyhow::bail!("Unsupported scheme in jetstream host: {}", value);
+        } else {
+            (true, value)
+        };
+        let rest = rest.trim_end_matches('/');
+
+        let (host, port) = match rest.rsplit_once(':') {
+            Some((host, port)) => (
+                host,
+                port.parse::<u16>()
+                    .with_context(|| format!("Invalid port in jetstream host: {}", value))?,
+            ),
+            None => (rest, if tls { 443 } else { 80 }),
+        };
+        anyhow::ensure!(
+            !host.is_empty()
+                && host
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-'),
+            "Invalid jetstream host: {}",
+            value
+        );
+        if tls {
+            ServerName::try_from(host.to_string())
+                .with_context(|| format!("Invalid dns name: {}", host))?;
+        }
+
+        Ok(Self {
+            tls,
+            host: host.to_string(),
+            port,
+        })
+    }
+}
+
+impl Display for JetstreamHost {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let scheme = if self.tls { "wss" } else { "ws" };
+        write!(f, "{}://{}", scheme, self.authority())
+    }
+}
+
+/// Create a tls connector that trusts the configured or the bundled root certificate
+pub fn tls_connector() -> anyhow::Result<TlsConnector> {
+    let mut tls_store = RootCertStore::empty();
+    let tls_cert = if let Some(certificate) = &ARGS.certificate {
+        debug!(target: "indexer", "Using the root certificate from {}", &certificate);
+        CertificateDer::from_pem_file(certificate)
+            .with_context(|| format!("Unable to parse certificate from: {}", certificate))?
+    } else {
+        debug!(target: "indexer", "Using the bundled ISRG Root X1 certificate");
+        CertificateDer::from_pem_slice(include_bytes!("../../ISRG_Root_X1.pem"))
+            .with_context(|| "Unable to bundled certificate")?
+    };
+    tls_store
+        .add(tls_cert)
+        .with_context(|| "Unable to add certificate to tls store.")?;
+    for certificate in &ARGS.extra_ca_certificate {
+        debug!(target: "indexer", "Also trusting the certificate from {}", &certificate);
+        let tls_cert = CertificateDer::from_pem_file(certificate)
+            .with_context(|| format!("Unable to parse certificate from: {}", certificate))?;
+        tls_store
+            .add(tls_cert)
+            .with_context(|| "Unable to add certificate to tls store.")?;
+    }
+    let tls_config = Arc::new(
+        ClientConfig::builder()
+            .with_root_certificates(Arc::new(tls_store))
+            .with_no_client_auth(),
+    );
+    Ok(TlsConnector::from(tls_config))
+}
+
+/// Connect to a websocket server, using tls if the host requires it
+///
+/// `path` is the path and query of the websocket endpoint, for example `/subscribe?cursor=0`
+pub async fn connect(
+    host: &JetstreamHost,
     connector: &TlsConnector,
-    cursor: Option<i64>,
+    path: &str,
 ) -> anyhow::Result<WebSocket<TokioIo<Upgraded>>> {
     // create tcp connection to server
     debug!(target: "indexer", "Connecting to: {}", host);
-    let addr = format!("{}:443", host);
+    let addr = format!("{}:{}", host.host, host.port);
     let tcp_stream = TcpStream::connect(&addr)
         .await
         .with_context(|| format!("Unable to open tcp connection to: {}", addr))?;
 
-    // encrypt the tcp stream with tls
-    debug!(target: "indexer", "Establishing tls connection to: {}", host);
-
-    let tls_domain = ServerName::try_from(host.clone())
-        .with_context(|| format!("Invalid dns name: {}", host))?;
-    let tls_stream = connector
-        .connect(tls_domain, tcp_stream)
-        .await
-        .with_context(|| format!("Unable to establish tls connection to: {}", host))?;
-
     // build uri
-    let uri = format!(
-        "wss://{}/subscribe?maxMessageSizeBytes=1048576{}",
-        host,
-        cursor.map_or_else(String::new, |c| format!("&cursor={}", c))
-    );
+    let uri = format!("{}{}", host, path);
     info!(target: "indexer", "Connecting to {}", uri);
 
     // upgrade the connection to a websocket
@@ -63,7 +162,7 @@ pub async fn connect_tls(
     let req = Request::builder()
         .method("GET")
         .uri(&uri)
-        .header(HOST, host)
+        .header(HOST, host.authority())
         .header(UPGRADE, "websocket")
         .header(CONNECTION, "upgrade")
         .header(SEC_WEBSOCKET_KEY, handshake::generate_key())
@@ -71,9 +170,87 @@ pub async fn connect_tls(
         .body(String::new())
         .with_context(|| format!("Unable to build websocket upgrade request for: {}", uri))?;
 
-    let (ws, _) = handshake::client(&TokioExecutor, req, tls_stream)
-        .await
-        .with_context(|| format!("Unable to upgrade connection to websocket: {}", uri))?;
+    let (ws, _) = if host.tls {
+        // encrypt the tcp stream with tls
+        debug!(target: "indexer", "Establishing tls connection to: {}", host);
+        let tls_domain = ServerName::try_from(host.host.clone())
+            .with_context(|| format!("Invalid dns name: {}", host.host))?;
+        let tls_stream = connector
+            .connect(tls_domain, tcp_stream)
+            .await
+            .with_context(|| format!("Unable to establish tls connection to: {}", host))?;
+
+        handshake::client(&TokioExecutor, req, tls_stream).await
+    } else {
+        handshake::client(&TokioExecutor, req, tcp_stream).await
+    }
+    .with_context(|| format!("Unable to upgrade connection to websocket: {}", uri))?;
 
     Ok(ws)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn host(tls: bool, host: &str, port: u16) -> JetstreamHost {
+        JetstreamHost {
+            tls,
+            host: host.to_string(),
+            port,
+        }
+    }
+
+    #[test]
+    fn hosts_without_scheme_use_tls() {
+        assert_eq!(
+            "localhost:6008".parse::<JetstreamHost>().unwrap(),
+            host(true, "localhost", 6008)
+        );
+        assert_eq!(
+            "jetstream1.us-east.bsky.network"
+                .parse::<JetstreamHost>()
+                .unwrap(),
+            host(true, "jetstream1.us-east.bsky.network", 443)
+        );
+    }
+
+    #[test]
+    fn ws_hosts_connect_without_tls() {
+        let parsed = "ws://localhost:6008".parse::<JetstreamHost>().unwrap();
+        assert_eq!(parsed, host(false, "localhost", 6008));
+        assert_eq!(parsed.to_string(), "ws://localhost:6008");
+        assert_eq!(
+            "ws://localhost/".parse::<JetstreamHost>().unwrap(),
+            host(false, "localhost", 80)
+        );
+    }
+
+    #[test]
+    fn wss_hosts_default_to_port_443() {
+        let parsed = "wss://jetstream1.us-east.bsky.network"
+            .parse::<JetstreamHost>()
+            .unwrap();
+        assert_eq!(parsed, host(true, "jetstream1.us-east.bsky.network", 443));
+        assert_eq!(parsed.authority(), "jetstream1.us-east.bsky.network");
+        assert_eq!(
+            "wss://jetstream.example.com:8443"
+                .parse::<JetstreamHost>()
+                .unwrap()
+                .authority(),
+            "jetstream.example.com:8443"
+        );
+    }
+
+    #[test]
+    fn invalid_hosts_are_rejected() {
+        for invalid in [
+            "http://localhost:6008",
+            "ws://localhost:port",
+            "ws://",
+            "wss://local host",
+        ] {
+            assert!(invalid.parse::<JetstreamHost>().is_err(), "{}", invalid);
+        }
+    }
+}
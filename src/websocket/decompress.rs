@@ -0,0 +1,39 @@
+use std::io::Read;
+
+use anyhow::Context;
+use ruzstd::decoding::{Dictionary, FrameDecoder, StreamingDecoder};
+
+/// Decompresses messages from the zstd compressed jetstream
+///
+/// Every message is a separate zstd frame compressed with the jetstream dictionary
+pub struct Decompressor {
+    decoder: FrameDecoder,
+}
+
+impl Decompressor {
+    /// Create a new decompressor using the dictionary at `path`
+    pub fn load(path: &str) -> anyhow::Result<Self> {
+        let raw_dictionary = std::fs::read(path)
+            .with_context(|| format!("Unable to read zstd dictionary from: {}", path))?;
+        let dictionary = Dictionary::decode_dict(&raw_dictionary)
+            .with_context(|| format!("Invalid zstd dictionary: {}", path))?;
+
+        let mut decoder = FrameDecoder::new();
+        decoder
+            .add_dict(dictionary)
+            .context("Unable to add zstd dictionary")?;
+
+        Ok(Self { decoder })
+    }
+
+    /// Decompress a single binary message into its json text
+    pub fn decompress(&mut self, payload: &[u8]) -> anyhow::Result<String> {
+        let mut stream = StreamingDecoder::new_with_decoder(payload, &mut self.decoder)
+            .context("Failed to read zstd frame header")?;
+        let mut text = String::new();
+        stream
+            .read_to_string(&mut text)
+            .context("Failed to decompress binary message")?;
+        Ok(text)
+    }
+}
@@ -0,0 +1,97 @@
+use std::time::{Duration, Instant};
+
+use crate::config::ARGS;
+
+/// Length of the window the failure ratio is measured over
+const WINDOW: Duration = Duration::from_secs(60);
+/// Minimum number of events in a window before the failure ratio is checked
+const MIN_WINDOW_EVENTS: u64 = 100;
+
+/// Tracks infrastructure failures while handling events
+///
+/// Data errors like an invalid record only affect a single event and are not counted. Infrastructure errors like an
+/// unreachable database affect every event, so the consumer has to stop instead of dropping the whole stream.
+#[derive(Debug)]
+pub struct ErrorBudget {
+    window_start: Instant,
+    events: u64,
+    failures: u64,
+    consecutive_failures: u64,
+    /// Time of the oldest failed event that still counts against the budget
+    oldest_failure: Option<i64>,
+    exhausted: bool,
+}
+
+impl ErrorBudget {
+    pub fn new() -> ErrorBudget {
+        ErrorBudget {
+            window_start: Instant::now(),
+            events: 0,
+            failures: 0,
+            consecutive_failures: 0,
+            oldest_failure: None,
+            exhausted: false,
+        }
+    }
+
+    /// Start a new window once the current one is over
+    ///
+    /// Failures of the old window are accepted as lost, unless they are part of an ongoing streak
+    fn roll_window(&mut self) {
+        if self.window_start.elapsed() < WINDOW {
+            return;
+        }
+        self.window_start = Instant::now();
+        self.events = 0;
+        self.failures = 0;
+        if self.consecutive_failures == 0 {
+            self.oldest_failure = None;
+        }
+    }
+
+    pub fn record_success(&mut self) {
+        self.roll_window();
+        self.events += 1;
+        self.consecutive_failures = 0;
+    }
+
+    /// Record an infrastructure failure, returns true if it exhausted the budget
+    pub fn record_failure(&mut self, time_us: i64) -> bool {
+        self.roll_window();
+        self.events += 1;
+        self.failures += 1;
+        self.consecutive_failures += 1;
+        self.oldest_failure = Some(
+            self.oldest_failure
+                .map_or(time_us, |oldest| oldest.min(time_us)),
+        );
+
+        let ratio_exceeded = self.events >= MIN_WINDOW_EVENTS
+            && self.failures as f64 / self.events as f64 > ARGS.jetstream_max_failure_ratio;
+        let streak_exceeded = self.consecutive_failures >= ARGS.jetstream_max_consecutive_failures;
+        if self.exhausted || !(ratio_exceeded || streak_exceeded) {
+            return false;
+        }
+        self.exhausted = true;
+        true
+    }
+
+    pub fn is_exhausted(&self) -> bool {
+        self.exhausted
+    }
+
+    /// The cursor must not advance past failed events that still count against the budget
+    pub fn cursor_limit(&self) -> Option<i64> {
+        self.oldest_failure.map(|oldest| oldest - 1)
+    }
+
+    /// Start over if the budget is exhausted, returns the cursor to replay the failed events from
+    pub fn take_exhausted(&mut self) -> Option<i64> {
+        if !self.exhausted {
+            return None;
+        }
+        let cursor_limit = self.cursor_limit();
+        *self = ErrorBudget::new();
+        cursor_limit
+    }
+}
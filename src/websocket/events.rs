@@ -41,6 +41,9 @@ pub struct Account {
     pub did: Did,
     pub seq: u64,
     pub time: String,
+    /// Why the account is inactive, for example deleted, takendown or deactivated
+    #[serde(default)]
+    pub status: Option<String>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -67,6 +70,24 @@ pub enum Kind {
     },
 }
 
+impl Kind {
+    /// The DID the event belongs to
+    pub fn did(&self) -> &Did {
+        match self {
+            Kind::Commit { did, .. } | Kind::Identity { did, .. } | Kind::Key { did, .. } => did,
+        }
+    }
+
+    /// Time the jetstream received the event in microseconds
+    pub fn time_us(&self) -> i64 {
+        match self {
+            Kind::Commit { time_us, .. }
+            | Kind::Identity { time_us, .. }
+            | Kind::Key { time_us, .. } => *time_us,
+        }
+    }
+}
+
 /// Parse an event from a string
 pub fn parse_event(mut msg: String) -> anyhow::Result<Kind> {
     unsafe { simd_json::from_str(msg.as_mut_str()) }.context("Failed to parse event")
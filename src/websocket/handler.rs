@@ -1,35 +1,119 @@
+use std::sync::LazyLock;
+
 use anyhow::Context;
+use opentelemetry::{
+    global,
+    metrics::{Counter, Gauge, Histogram},
+    KeyValue,
+};
+use tracing::{error, warn};
 
-use crate::database::{self, definitions::JetstreamCursor};
+use crate::{
+    database::{self, error::DbError},
+    observability::instrument_name,
+};
 
 use super::{events, SharedState};
 
-/// Handle a message from the websocket in parallel
-pub async fn handle_message(
-    state: &SharedState,
-    msg: String,
-    update_cursor: bool,
-) -> anyhow::Result<()> {
-    // parse event
-    let event = events::parse_event(msg)?;
-
-    // update cursor
-    let time = match &event {
-        events::Kind::Commit { time_us, .. } => *time_us,
-        events::Kind::Identity { time_us, .. } => *time_us,
-        events::Kind::Key { time_us, .. } => *time_us,
-    } as i64;
-    state.update_cursor(time);
-    if update_cursor {
-        database::write_cursor(
-            &state.database.clone(),
-            JetstreamCursor {
-                host: state.host.clone(),
-                time_us: time,
-            },
+static LAG_METRIC: LazyLock<Gauge<f64>> = LazyLock::new(|| {
+    global::meter("indexer")
+        .f64_gauge(instrument_name("indexer.jetstream.lag"))
+        .with_unit("s")
+        .with_description("Time between the jetstream receiving an event and us reading it")
+        .build()
+});
+static LAG_HISTOGRAM_METRIC: LazyLock<Histogram<u64>> = LazyLock::new(|| {
+    global::meter("indexer")
+        .u64_histogram(instrument_name("indexer.jetstream.lag_ms"))
+        .with_unit("ms")
+        .with_description(
+            "Distribution of the time between the jetstream receiving an event and us reading it",
         )
-        .await
-        .context("Unable to write cursor to database!")?;
+        .with_boundaries(vec![
+            10.0, 50.0, 100.0, 250.0, 500.0, 1000.0, 2500.0, 5000.0, 10000.0, 30000.0, 60000.0,
+            300000.0, 900000.0, 3600000.0,
+        ])
+        .build()
+});
+static CURSOR_METRIC: LazyLock<Gauge<u64>> = LazyLock::new(|| {
+    global::meter("indexer")
+        .u64_gauge(instrument_name("indexer.jetstream.cursor"))
+        .with_unit("us")
+        .with_description("Time of the latest read jetstream event in microseconds")
+        .build()
+});
+static RECEIVED_EVENTS_METRIC: LazyLock<Counter<u64>> = LazyLock::new(|| {
+    global::meter("indexer")
+        .u64_counter(instrument_name("indexer.jetstream.received_events"))
+        .with_unit("{event}")
+        .with_description("Number of messages received from the jetstream")
+        .build()
+});
+static PARSE_ERRORS_METRIC: LazyLock<Counter<u64>> = LazyLock::new(|| {
+    global::meter("indexer")
+        .u64_counter(instrument_name("indexer.jetstream.parse_errors"))
+        .with_unit("{event}")
+        .with_description("Number of jetstream messages that could not be parsed")
+        .build()
+});
+static HANDLED_EVENTS_METRIC: LazyLock<Counter<u64>> = LazyLock::new(|| {
+    global::meter("indexer")
+        .u64_counter(instrument_name("indexer.jetstream.handled_events"))
+        .with_unit("{event}")
+        .with_description("Number of jetstream events that were handled successfully")
+        .build()
+});
+
+static FAILED_EVENTS_METRIC: LazyLock<Counter<u64>> = LazyLock::new(|| {
+    global::meter("indexer")
+        .u64_counter(instrument_name("indexer.jetstream.failed_events"))
+        .with_unit("{event}")
+        .with_description("Number of jetstream events that could not be handled")
+        .build()
+});
+
+/// Parse a message from the websocket and remember its time as the cursor
+pub fn parse_message(state: &SharedState, msg: String) -> anyhow::Result<events::Kind> {
+    let attributes = state.host_attributes();
+    RECEIVED_EVENTS_METRIC.add(1, &attributes);
+    let event =
+        events::parse_event(msg).inspect_err(|_| PARSE_ERRORS_METRIC.add(1, &attributes))?;
+
+    let time = event.time_us();
+    state.update_cursor(time);
+    CURSOR_METRIC.record(time.max(0) as u64, &attributes);
+    let now_us = chrono::Utc::now().timestamp_micros();
+    LAG_METRIC.record((now_us - time) as f64 / 1_000_000.0, &attributes);
+    // the clocks of the jetstream and us can disagree, so the lag can be slightly negative
+    LAG_HISTOGRAM_METRIC.record(((now_us - time) / 1000).max(0) as u64, &attributes);
+
+    Ok(event)
+}
+
+/// Handle a parsed event, unless it is a commit that was already handled
+pub async fn handle_event(state: &SharedState, event: events::Kind) -> anyhow::Result<()> {
+    // skip commits that were already handled before a reconnect
+    if let events::Kind::Commit { did, commit, .. } = &event {
+        let (events::Commit::CreateOrUpdate {
+            rev,
+            collection,
+            rkey,
+            ..
+        }
+        | events::Commit::Delete {
+            rev,
+            collection,
+            rkey,
+        }) = commit;
+        let key = (
+            did.as_str().to_string(),
+            collection.clone(),
+            rkey.as_str().to_string(),
+            rev.clone(),
+        );
+        if state.is_duplicate_commit(key) {
+            return Ok(());
+        }
     }
 
     database::handlers::handle_event(state.database.clone(), event)
@@ -38,3 +122,89 @@ pub async fn handle_message(
 
     Ok(())
 }
+
+/// Check if an error is caused by the infrastructure instead of the event itself
+///
+/// Infrastructure errors affect all events, data errors like an invalid record only the one event
+fn is_infrastructure_error(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| {
+        if let Some(error) = cause.downcast_ref::<DbError>() {
+            return !matches!(error, DbError::ConstraintViolation(_) | DbError::Other(_));
+        }
+        cause.is::<sqlx::Error>() || cause.is::<std::io::Error>()
+    })
+}
+
+/// Count the outcome of handling an event and charge infrastructure errors to the error budget
+pub fn record_outcome(state: &SharedState, time_us: i64, result: anyhow::Result<()>) {
+    let [host] = state.host_attributes();
+    let Err(e) = result else {
+        HANDLED_EVENTS_METRIC.add(1, &[host]);
+        state.error_budget.lock().unwrap().record_success();
+        return;
+    };
+
+    if !is_infrastructure_error(&e) {
+        FAILED_EVENTS_METRIC.add(1, &[host, KeyValue::new("kind", "data")]);
+        warn!("error while handling {}", e);
+        return;
+    }
+    FAILED_EVENTS_METRIC.add(1, &[host, KeyValue::new("kind", "infrastructure")]);
+    if state.error_budget.lock().unwrap().record_failure(time_us) {
+        error!(target: "indexer", "Too many events could not be handled, reconnecting: {:?}", e);
+    } else {
+        warn!("error while handling {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A jetstream message deleting a like at `time_us`
+    fn like_deletion(time_us: i64) -> String {
+        serde_json::json!({
+            "did": "did:plc:liker",
+            "time_us": time_us,
+            "kind": "commit",
+            "commit": {
+                "rev": "3lrev",
+                "operation": "delete",
+                "collection": "app.bsky.feed.like",
+                "rkey": "3jzfcijpj2z2a",
+            },
+        })
+        .to_string()
+    }
+
+    /// The value of the line of a metric for `host`
+    fn metric_value(metrics: &str, name: &str, host: &str) -> f64 {
+        let prefix = format!("{}{{host=\"{}\"}} ", name, host);
+        metrics
+            .lines()
+            .find_map(|line| line.strip_prefix(&prefix))
+            .unwrap_or_else(|| panic!("{} of {} is missing in {}", name, host, metrics))
+            .parse()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn parsing_an_event_records_its_lag() {
+        let host = "wss://lag.example";
+        let state = SharedState::for_tests(host, None);
+        let five_seconds_ago = chrono::Utc::now().timestamp_micros() - 5_000_000;
+
+        let event = parse_message(&state, like_deletion(five_seconds_ago)).unwrap();
+        assert_eq!(event.time_us(), five_seconds_ago);
+
+        let metrics = crate::observability::testing::rendered_metrics();
+        let lag = metric_value(&metrics, "indexer_jetstream_lag", host);
+        assert!((5.0..60.0).contains(&lag), "{}", lag);
+        assert_eq!(
+            metric_value(&metrics, "indexer_jetstream_lag_ms_count", host),
+            1.0
+        );
+        let lag_ms = metric_value(&metrics, "indexer_jetstream_lag_ms_sum", host);
+        assert!((5000.0..60000.0).contains(&lag_ms), "{}", lag_ms);
+    }
+}
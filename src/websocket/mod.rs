@@ -1,37 +1,224 @@
 use anyhow::Context;
-use fastwebsockets::{OpCode, WebSocket};
+use fastwebsockets::{Frame, OpCode, Payload, WebSocket};
 use hyper::upgrade::Upgraded;
 use hyper_util::rt::TokioIo;
+use lru::LruCache;
+use opentelemetry::{
+    global,
+    metrics::{Counter, Gauge, Histogram},
+    KeyValue,
+};
 use sqlx::PgPool;
 use std::{
+    collections::VecDeque,
+    hash::{DefaultHasher, Hash, Hasher},
+    num::NonZeroUsize,
     sync::{
-        atomic::{AtomicI64, Ordering},
-        Arc,
+        atomic::{AtomicBool, AtomicI64, Ordering},
+        Arc, LazyLock, Mutex,
     },
     time::{Duration, Instant},
 };
-use tokio::time::sleep;
-use tokio_rustls::{
-    rustls::{
-        pki_types::{pem::PemObject, CertificateDer},
-        ClientConfig, RootCertStore,
-    },
-    TlsConnector,
+use tokio::{
+    sync::mpsc,
+    time::{sleep, sleep_until},
 };
-use tracing::{debug, info, trace, warn};
+use tokio_util::sync::CancellationToken;
+use tracing::{info, trace, warn};
 
-use crate::config::ARGS;
+use crate::{
+    config::ARGS,
+    database::{big_update::write_cursor_when_applied, definitions::JetstreamCursor},
+    observability::instrument_name,
+};
+use backoff::{jittered, Backoff, GRACE_PERIOD};
+use decompress::Decompressor;
+use error_budget::ErrorBudget;
 
+mod backoff;
 mod conn;
+mod decompress;
+mod error_budget;
+pub use conn::{connect, tls_connector, JetstreamHost};
 pub mod events;
 mod handler;
 
+/// Number of recent commits that are remembered to skip duplicates after a reconnect
+const RECENT_COMMITS_CAPACITY: usize = 100_000;
+
+/// Identifies a commit by did, collection, rkey and rev
+type CommitKey = (String, String, String, String);
+
 /// Shared state for the websocket module
 #[derive(Debug)]
 struct SharedState {
-    host: String,
+    /// Key the cursor is stored under in the database
+    cursor_key: String,
     database: PgPool,
     cursor: AtomicI64,
+    /// Recently handled commits, used to skip events that are replayed after a reconnect
+    recent_commits: Mutex<LruCache<CommitKey, ()>>,
+    /// Host of the currently open connection
+    connected_host: Mutex<Option<String>>,
+    /// Failures of the database workers
+    error_budget: Mutex<ErrorBudget>,
+    /// Stop once an event after this time in microseconds is received. Cursors of a replay are not written
+    replay_to: Option<i64>,
+    /// Set once an event after `replay_to` was received
+    replay_finished: AtomicBool,
+}
+
+static EVENT_QUEUE_DEPTH_METRIC: LazyLock<Gauge<u64>> = LazyLock::new(|| {
+    global::meter("indexer")
+        .u64_gauge(instrument_name("indexer.jetstream.event_queue_depth"))
+        .with_unit("{event}")
+        .with_description("Number of received jetstream events that are not handled yet")
+        .build()
+});
+static CONNECTIONS_METRIC: LazyLock<Counter<u64>> = LazyLock::new(|| {
+    global::meter("indexer")
+        .u64_counter(instrument_name("indexer.jetstream.connections"))
+        .with_unit("{connection}")
+        .with_description("Number of opened jetstream connections")
+        .build()
+});
+static DISCONNECTS_METRIC: LazyLock<Counter<u64>> = LazyLock::new(|| {
+    global::meter("indexer")
+        .u64_counter(instrument_name("indexer.jetstream.disconnects"))
+        .with_unit("{connection}")
+        .with_description("Number of closed or failed jetstream connections")
+        .build()
+});
+static CONNECTED_METRIC: LazyLock<Gauge<u64>> = LazyLock::new(|| {
+    global::meter("indexer")
+        .u64_gauge(instrument_name("indexer.jetstream.connected"))
+        .with_unit("{connection}")
+        .with_description("1 while a jetstream connection to the host is open, otherwise 0")
+        .build()
+});
+static CONNECTION_LIFETIME_METRIC: LazyLock<Histogram<f64>> = LazyLock::new(|| {
+    global::meter("indexer")
+        .f64_histogram(instrument_name("indexer.jetstream.connection_lifetime"))
+        .with_unit("s")
+        .with_description("Time a jetstream connection stayed open")
+        .with_boundaries(vec![
+            1.0, 5.0, 10.0, 30.0, 60.0, 300.0, 900.0, 3600.0, 21600.0, 86400.0,
+        ])
+        .build()
+});
+
+/// Records the metrics of a single jetstream connection
+struct ConnectionMetrics {
+    host_attributes: [KeyValue; 1],
+    connected_at: Instant,
+}
+
+impl ConnectionMetrics {
+    fn opened(host: &str) -> ConnectionMetrics {
+        let host_attributes = [KeyValue::new("host", host.to_string())];
+        CONNECTIONS_METRIC.add(1, &host_attributes);
+        CONNECTED_METRIC.record(1, &host_attributes);
+        ConnectionMetrics {
+            host_attributes,
+            connected_at: Instant::now(),
+        }
+    }
+
+    /// Record that the connection was closed, returns how long it was open
+    fn closed(self) -> Duration {
+        let lifetime = self.connected_at.elapsed();
+        DISCONNECTS_METRIC.add(1, &self.host_attributes);
+        CONNECTED_METRIC.record(0, &self.host_attributes);
+        CONNECTION_LIFETIME_METRIC.record(lifetime.as_secs_f64(), &self.host_attributes);
+        lifetime
+    }
+}
+
+/// Tracks the events each worker still has to handle, so the cursor never advances past an unhandled event
+struct Watermarks {
+    /// Times of the queued and running events of each worker, oldest first
+    pending: Vec<Mutex<VecDeque<i64>>>,
+}
+
+impl Watermarks {
+    fn new(workers: usize) -> Watermarks {
+        Watermarks {
+            pending: (0..workers).map(|_| Mutex::new(VecDeque::new())).collect(),
+        }
+    }
+
+    /// Remember that an event was queued for a worker
+    fn queued(&self, worker: usize, time_us: i64) {
+        self.pending[worker].lock().unwrap().push_back(time_us);
+    }
+
+    /// Remember that a worker handled its oldest event
+    fn handled(&self, worker: usize) {
+        self.pending[worker].lock().unwrap().pop_front();
+    }
+
+    /// Number of events that are queued or running
+    fn len(&self) -> usize {
+        self.pending
+            .iter()
+            .map(|pending| pending.lock().unwrap().len())
+            .sum()
+    }
+
+    /// Get the latest cursor up to which every event is handled
+    ///
+    /// Each worker handles its events in order, so everything before the oldest pending event of all workers is done.
+    /// `read` is the time of the latest read event.
+    fn safe_cursor(&self, read: i64) -> i64 {
+        self.pending
+            .iter()
+            .filter_map(|pending| pending.lock().unwrap().front().copied())
+            .min()
+            .map_or(read, |oldest| oldest - 1)
+    }
+}
+
+/// Time the cursor is rewound by on reconnect, 10 seconds in microseconds
+const REWIND_TIME: i64 = 10_000_000;
+
+/// Cursor to resume from after a reconnect, replayed commits are skipped
+fn rewind(cursor: i64) -> i64 {
+    cursor.saturating_sub(REWIND_TIME).max(0)
+}
+
+/// Pick the worker for the events of a DID
+fn worker_for(did: &str, workers: usize) -> usize {
+    let mut hasher = DefaultHasher::new();
+    did.hash(&mut hasher);
+    (hasher.finish() % workers as u64) as usize
+}
+
+/// States of all started jetstream consumers, used by the status server
+static CONSUMERS: LazyLock<Mutex<Vec<Arc<SharedState>>>> = LazyLock::new(|| Mutex::new(Vec::new()));
+
+/// Status of a jetstream consumer
+#[derive(Debug, Clone)]
+pub struct JetstreamStatus {
+    /// Key the cursor is stored under in the database
+    pub cursor_key: String,
+    /// Host of the currently open connection, if there is one
+    pub connected_host: Option<String>,
+    /// Time of the latest received event in microseconds
+    pub cursor: i64,
+}
+
+/// Get the status of all jetstream consumers
+pub fn jetstream_status() -> Vec<JetstreamStatus> {
+    CONSUMERS
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|state| JetstreamStatus {
+            cursor_key: state.cursor_key.clone(),
+            connected_host: state.connected_host.lock().unwrap().clone(),
+            cursor: state.cursor.load(Ordering::Relaxed),
+        })
+        .collect()
 }
 
 impl SharedState {
@@ -39,41 +226,123 @@ impl SharedState {
     pub fn update_cursor(&self, cursor: i64) {
         self.cursor.store(cursor, Ordering::Relaxed);
     }
+
+    /// Get the cursor to resume from, it never points past failed events that count against the error budget
+    fn final_cursor(&self) -> i64 {
+        let cursor = self.cursor.load(Ordering::Relaxed);
+        match self.error_budget.lock().unwrap().cursor_limit() {
+            Some(limit) => cursor.min(limit),
+            None => cursor,
+        }
+    }
+
+    /// Metric attributes of the currently connected host
+    fn host_attributes(&self) -> [KeyValue; 1] {
+        let host = self.connected_host.lock().unwrap().clone();
+        [KeyValue::new("host", host.unwrap_or_default())]
+    }
+
+    /// Check if an event is after the end of the replay and remember that the replay is finished if it is
+    fn ends_replay(&self, event: &events::Kind) -> bool {
+        let past_replay = self
+            .replay_to
+            .is_some_and(|replay_to| event.time_us() > replay_to);
+        if past_replay {
+            self.replay_finished.store(true, Ordering::Relaxed);
+        }
+        past_replay
+    }
+
+    /// Remember a commit and return whether it was already handled before
+    pub fn is_duplicate_commit(&self, key: CommitKey) -> bool {
+        let mut recent_commits = self.recent_commits.lock().unwrap();
+        recent_commits.put(key, ()).is_some()
+    }
 }
 
-/// Subscribe to a websocket server
-pub async fn start(host: String, cursor: i64, database: PgPool) -> anyhow::Result<()> {
-    // prepare tls store
-    let mut tls_store = RootCertStore::empty();
-    let tls_cert = if let Some(certificate) = &ARGS.certificate {
-        debug!(target: "indexer", "Using the root certificate from {}", &certificate);
-        CertificateDer::from_pem_file(certificate)
-            .with_context(|| format!("Unable to parse certificate from: {}", certificate))?
+#[cfg(test)]
+impl SharedState {
+    /// State of a consumer connected to `host`, with a database that is never reachable
+    fn for_tests(host: &str, replay_to: Option<i64>) -> SharedState {
+        // The metrics of received events are recorded, so the provider has to exist before
+        crate::observability::testing::install();
+        SharedState {
+            cursor_key: host.to_string(),
+            database: sqlx::postgres::PgPoolOptions::new()
+                .connect_lazy("postgres://indexer@127.0.0.1:1/indexer")
+                .unwrap(),
+            cursor: AtomicI64::new(0),
+            recent_commits: Mutex::new(LruCache::new(
+                NonZeroUsize::new(RECENT_COMMITS_CAPACITY).unwrap(),
+            )),
+            connected_host: Mutex::new(Some(host.to_string())),
+            error_budget: Mutex::new(ErrorBudget::new()),
+            replay_to,
+            replay_finished: AtomicBool::new(false),
+        }
+    }
+}
+
+/// Subscribe to a jetstream
+///
+/// Connects to the first host and fails over to the next one whenever the connection fails.
+/// Each host is retried with exponential backoff and jitter, which is reset once a connection stayed up for a while.
+/// The cursor is carried over between hosts. Returns the current cursor once shutdown is requested.
+///
+/// If `replay_to` is set, this also returns once an event after it was received, without handling that event
+pub async fn start(
+    hosts: Vec<JetstreamHost>,
+    cursor_key: String,
+    cursor: i64,
+    replay_to: Option<i64>,
+    database: PgPool,
+    shutdown: CancellationToken,
+) -> anyhow::Result<i64> {
+    anyhow::ensure!(!hosts.is_empty(), "No jetstream hosts configured");
+
+    let connector = conn::tls_connector()?;
+
+    // load the zstd dictionary for the compressed jetstream
+    let mut decompressor = if ARGS.jetstream_compress {
+        Some(Decompressor::load(&ARGS.jetstream_zstd_dictionary)?)
     } else {
-        debug!(target: "indexer", "Using the bundled ISRG Root X1 certificate");
-        CertificateDer::from_pem_slice(include_bytes!("../../ISRG_Root_X1.pem"))
-            .with_context(|| "Unable to bundled certificate")?
+        None
     };
-    tls_store
-        .add(tls_cert)
-        .with_context(|| "Unable to add certificate to tls store.")?;
-    let tls_config = Arc::new(
-        ClientConfig::builder()
-            .with_root_certificates(Arc::new(tls_store))
-            .with_no_client_auth(),
-    );
-    let connector = TlsConnector::from(tls_config.clone());
 
     // create a shared state
     info!(target: "indexer", "Entering websocket loop");
     let state = Arc::new(SharedState {
-        host: host.clone(),
+        cursor_key,
         cursor: AtomicI64::new(cursor),
         database,
+        recent_commits: Mutex::new(LruCache::new(
+            NonZeroUsize::new(RECENT_COMMITS_CAPACITY).unwrap(),
+        )),
+        connected_host: Mutex::new(None),
+        error_budget: Mutex::new(ErrorBudget::new()),
+        replay_to,
+        replay_finished: AtomicBool::new(false),
     });
+    CONSUMERS.lock().unwrap().push(state.clone());
 
     // loop infinitely, ensuring connection aborts are handled
+    let mut host_index = 0;
+    let mut backoffs = hosts.iter().map(|_| Backoff::new()).collect::<Vec<_>>();
     loop {
+        let host = &hosts[host_index];
+
+        // wait until the host may be tried again
+        if let Some(remaining) = backoffs[host_index].remaining() {
+            trace!(target: "indexer", "Waiting {:?} before connecting to {}", remaining, host);
+            tokio::select! {
+                _ = sleep(remaining) => {}
+                _ = shutdown.cancelled() => {}
+            }
+        }
+        if shutdown.is_cancelled() {
+            return Ok(state.final_cursor());
+        }
+
         // get current cursor
         let cursor = {
             let c = state.cursor.load(Ordering::Relaxed);
@@ -86,44 +355,184 @@ pub async fn start(host: String, cursor: i64, database: PgPool) -> anyhow::Resul
 
         // create websocket connection
         info!(target: "indexer", "Establishing new connection to: {}", host);
-        let ws = conn::connect_tls(&host, &connector, cursor).await;
+        // Identity and account events are sent regardless of wantedCollections
+        let wanted_collections = ARGS
+            .wanted_collections()
+            .iter()
+            .map(|collection| format!("&wantedCollections={}", collection))
+            .collect::<String>();
+        let path = format!(
+            "/subscribe?maxMessageSizeBytes=1048576{}{}{}",
+            cursor.map_or_else(String::new, |c| format!("&cursor={}", c)),
+            wanted_collections,
+            if ARGS.jetstream_compress {
+                "&compress=true"
+            } else {
+                ""
+            }
+        );
+        let ws = conn::connect(host, &connector, &path).await;
         if let Err(e) = ws {
-            warn!(target: "indexer", "Unable to open websocket connection to {}: {:?}", host, e);
-            sleep(Duration::from_secs(5)).await;
+            let delay = backoffs[host_index].failure();
+            warn!(target: "indexer", "Unable to open websocket connection to {}, retrying it in {:?}: {:?}", host, delay, e);
+            // the next host is tried right away, unless it failed recently as well
+            host_index = (host_index + 1) % hosts.len();
             continue;
         }
         let ws = ws.unwrap();
 
         // handle the websocket connection
         info!(target: "indexer", "Handling websocket connection starting at cursor: {:?}", cursor);
-        let res = manage_ws(&state, ws).await;
+        *state.connected_host.lock().unwrap() = Some(host.to_string());
+        let connection = ConnectionMetrics::opened(&host.to_string());
+        let res = manage_ws(&state, ws, decompressor.as_mut(), &shutdown).await;
+        *state.connected_host.lock().unwrap() = None;
+        let lifetime = connection.closed();
+        // a connection that drops right away counts as a failure of the host
+        if lifetime >= GRACE_PERIOD {
+            backoffs[host_index].reset();
+        } else {
+            backoffs[host_index].failure();
+        }
         if let Err(e) = res {
             warn!(target: "indexer", "Websocket connection failed: {:?}", e);
         }
+        if state.replay_finished.load(Ordering::Relaxed) {
+            info!(target: "indexer", "Replay of {} reached its end", host);
+            return Ok(state.final_cursor());
+        }
+        if shutdown.is_cancelled() {
+            info!(target: "indexer", "Closed websocket connection to {}", host);
+            return Ok(state.final_cursor());
+        }
+
+        // replay the failed events, unless the database is gone
+        let exhausted = state.error_budget.lock().unwrap().take_exhausted();
+        if let Some(cursor_limit) = exhausted {
+            state.cursor.fetch_min(cursor_limit, Ordering::Relaxed);
+            sqlx::query("SELECT 1")
+                .execute(&state.database)
+                .await
+                .context("Too many jetstream events failed and the database is unreachable")?;
+        }
+
+        // fail over to the next host
+        host_index = (host_index + 1) % hosts.len();
 
-        // rewind cursor by 10 seconds
-        {
-            const REWIND_TIME: i64 = 10_000_000; // 10 seconds in microseconds
-            let cursor = state.cursor.fetch_sub(REWIND_TIME, Ordering::Relaxed);
-            info!(target: "indexer", "Rewinding cursor by 10 seconds: {} -> {}", cursor, cursor - REWIND_TIME);
+        // a cursor of 0 means there is no cursor yet, so there is nothing to rewind
+        let rewound = state
+            .cursor
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |cursor| {
+                (cursor > 0).then(|| rewind(cursor))
+            });
+        if let Ok(cursor) = rewound {
+            info!(target: "indexer", "Rewinding cursor by 10 seconds: {} -> {}", cursor, rewind(cursor));
         }
 
-        // let the server breathe
-        sleep(Duration::from_millis(200)).await;
+        // let the server breathe, with jitter so consumers that lost their connection together do not reconnect together
+        sleep(jittered(Duration::from_millis(400))).await;
     }
 }
 
+/// Handle messages until the connection fails or shutdown is requested
+///
+/// Received events are handed to `--jetstream-workers` database workers. All events of a DID go to the same worker and
+/// each worker handles its events in order, so the events of a DID are applied in the order they were received. Once
+/// the queue of a worker is full, no more frames are read, so TCP flow control slows down the jetstream instead of
+/// events piling up in memory. The queued events are still handled before this returns.
+///
+/// During a replay, reading stops at the first event after the end of the replay.
 async fn manage_ws(
     state: &SharedState,
-    mut ws: WebSocket<TokioIo<Upgraded>>,
+    ws: WebSocket<TokioIo<Upgraded>>,
+    decompressor: Option<&mut Decompressor>,
+    shutdown: &CancellationToken,
 ) -> anyhow::Result<()> {
+    let queue_size = (ARGS.jetstream_queue_size / ARGS.jetstream_workers).max(1);
+    let watermarks = Watermarks::new(ARGS.jetstream_workers);
+    let (queues, workers): (Vec<_>, Vec<_>) = (0..ARGS.jetstream_workers)
+        .map(|worker| {
+            let (sender, receiver) = mpsc::channel(queue_size);
+            (sender, handle_events(state, worker, receiver, &watermarks))
+        })
+        .unzip();
+
+    let (result, _) = tokio::join!(
+        read_ws(state, ws, decompressor, shutdown, queues, &watermarks),
+        futures::future::join_all(workers)
+    );
+    result
+}
+
+/// Handle the queued events of a worker until its queue is closed and empty
+async fn handle_events(
+    state: &SharedState,
+    worker: usize,
+    mut queue: mpsc::Receiver<events::Kind>,
+    watermarks: &Watermarks,
+) {
+    while let Some(event) = queue.recv().await {
+        let time_us = event.time_us();
+        let result = handler::handle_event(state, event).await;
+        handler::record_outcome(state, time_us, result);
+        watermarks.handled(worker);
+    }
+}
+
+/// Read messages and queue their events until the connection fails or shutdown is requested
+///
+/// Pings from the server are answered automatically. If no frame was received for a while, we send a ping ourselves
+/// and consider the connection dead if nothing arrives within the pong timeout.
+async fn read_ws(
+    state: &SharedState,
+    ws: WebSocket<TokioIo<Upgraded>>,
+    mut decompressor: Option<&mut Decompressor>,
+    shutdown: &CancellationToken,
+    queues: Vec<mpsc::Sender<events::Kind>>,
+    watermarks: &Watermarks,
+) -> anyhow::Result<()> {
+    let queue_attributes = [KeyValue::new("cursor_key", state.cursor_key.clone())];
+    let ping_interval = Duration::from_secs(ARGS.jetstream_ping_interval);
+    let pong_timeout = Duration::from_secs(ARGS.jetstream_pong_timeout);
+
+    // split the websocket, so we can send pings while waiting for a frame
+    let (mut ws_read, ws_write) = ws.split(tokio::io::split);
+    let ws_write = Arc::new(tokio::sync::Mutex::new(ws_write));
+    let pong_writer = ws_write.clone();
+    let mut send_pong = move |frame| {
+        let writer = pong_writer.clone();
+        async move { writer.lock().await.write_frame(frame).await }
+    };
+
     let mut time = Instant::now();
     loop {
-        // try to read a message
-        let msg = ws
-            .read_frame()
-            .await
-            .context("Failed to read frame from websocket")?;
+        // try to read a message, sending a ping after some inactivity
+        let msg = {
+            let read = ws_read.read_frame(&mut send_pong);
+            tokio::pin!(read);
+            let mut deadline = tokio::time::Instant::now() + ping_interval;
+            let mut awaiting_pong = false;
+            loop {
+                tokio::select! {
+                    msg = &mut read => break msg.context("Failed to read frame from websocket")?,
+                    _ = shutdown.cancelled() => return Ok(()),
+                    _ = sleep_until(deadline) => {
+                        if awaiting_pong {
+                            anyhow::bail!("No pong received within {:?}", pong_timeout);
+                        }
+                        trace!(target: "indexer", "Sending keepalive ping");
+                        ws_write
+                            .lock()
+                            .await
+                            .write_frame(Frame::new(true, OpCode::Ping, None, Payload::Borrowed(&[])))
+                            .await
+                            .context("Failed to send ping")?;
+                        awaiting_pong = true;
+                        deadline = tokio::time::Instant::now() + pong_timeout;
+                    }
+                }
+            }
+        };
 
         // check if cursor needs an update
         let update_cursor = if time.elapsed().as_secs() >= 60 {
@@ -133,11 +542,32 @@ async fn manage_ws(
             false
         };
 
-        // handle message
-        match msg.opcode {
+        // reconnect instead of dropping every event while the database is failing
+        if state.error_budget.lock().unwrap().is_exhausted() {
+            anyhow::bail!("Too many events could not be handled");
+        }
+
+        // parse message
+        let text = match msg.opcode {
+            // the compressed jetstream sends zstd compressed binary frames
+            OpCode::Binary if decompressor.is_some() => {
+                trace!(target: "indexer", "Received binary message: {}", msg.payload.len());
+                let text = decompressor
+                    .as_mut()
+                    .unwrap()
+                    .decompress(&msg.payload)
+                    .context("Failed to decompress binary message")?;
+                Some(text)
+            }
+            // pings are answered automatically and pongs only reset the keepalive timer
+            OpCode::Ping | OpCode::Pong => {
+                trace!(target: "indexer", "Received {:?}", msg.opcode);
+                None
+            }
             // spec states only text frames are allowed
-            OpCode::Continuation | OpCode::Binary | OpCode::Ping | OpCode::Pong => {
+            OpCode::Continuation | OpCode::Binary => {
                 warn!(target: "indexer", "Unexpected opcode received: {:?}", msg.opcode);
+                None
             }
             // can be emitted by the server
             OpCode::Close => {
@@ -148,13 +578,205 @@ async fn manage_ws(
                 trace!(target: "indexer", "Received text message: {}", msg.payload.len());
                 let text = String::from_utf8(msg.payload.to_vec())
                     .context("Failed to decode text message")?;
+                Some(text)
+            }
+        };
 
-                let res = handler::handle_message(state, text, update_cursor).await;
-
-                if res.is_err() {
-                    warn!("error while handling {}", res.unwrap_err());
+        // queue the event, this waits while the workers are busy
+        if let Some(text) = text {
+            match handler::parse_message(state, text) {
+                Ok(event) if state.ends_replay(&event) => return Ok(()),
+                Ok(event) => {
+                    let worker = worker_for(event.did().as_str(), queues.len());
+                    watermarks.queued(worker, event.time_us());
+                    queues[worker]
+                        .send(event)
+                        .await
+                        .context("A database worker stopped")?;
+                    EVENT_QUEUE_DEPTH_METRIC.record(watermarks.len() as u64, &queue_attributes);
                 }
+                Err(e) => warn!("error while parsing {}", e),
             }
+        }
+
+        // only write the cursor up to the oldest event that is not handled yet, a replay keeps the stored cursor
+        let cursor = watermarks.safe_cursor(state.final_cursor());
+        if update_cursor && cursor > 0 && state.replay_to.is_none() {
+            write_cursor_when_applied(
+                &state.database,
+                JetstreamCursor {
+                    host: state.cursor_key.clone(),
+                    time_us: cursor,
+                },
+            )
+            .await
+            .context("Unable to write cursor to database!")?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_fake_connection_records_connects_and_disconnects() {
+        let metrics = crate::observability::testing::rendered_metrics;
+        // Install the provider before the connection metrics are created
+        metrics();
+        let host = "wss://fake.example";
+
+        let connection = ConnectionMetrics::opened(host);
+        let opened = metrics();
+        assert!(
+            opened.contains("indexer_jetstream_connections_total{host=\"wss://fake.example\"} 1\n"),
+            "{}",
+            opened
+        );
+        assert!(
+            opened.contains("indexer_jetstream_connected{host=\"wss://fake.example\"} 1\n"),
+            "{}",
+            opened
+        );
+        assert!(
+            !opened.contains("indexer_jetstream_disconnects_total"),
+            "{}",
+            opened
+        );
+
+        connection.closed();
+        let closed = metrics();
+        assert!(
+            closed.contains("indexer_jetstream_disconnects_total{host=\"wss://fake.example\"} 1\n"),
+            "{}",
+            closed
+        );
+        assert!(
+            closed.contains("indexer_jetstream_connected{host=\"wss://fake.example\"} 0\n"),
+            "{}",
+            closed
+        );
+        assert!(
+            closed.contains(
+                "indexer_jetstream_connection_lifetime_count{host=\"wss://fake.example\"} 1\n"
+            ),
+            "{}",
+            closed
+        );
+    }
+
+    #[tokio::test]
+    async fn replay_stops_after_the_end() {
+        let replay_to = 1_700_000_000_000_000;
+        let state = SharedState::for_tests("wss://replay.example", Some(replay_to));
+        let like_deletion = |time_us: i64| {
+            serde_json::json!({
+                "did": "did:plc:liker",
+                "time_us": time_us,
+                "kind": "commit",
+                "commit": {
+                    "rev": "3lrev",
+                    "operation": "delete",
+                    "collection": "app.bsky.feed.like",
+                    "rkey": "3jzfcijpj2z2a",
+                },
+            })
+            .to_string()
         };
+
+        // The same order of checks as in read_ws
+        let mut handled = Vec::new();
+        for time_us in [replay_to - 1, replay_to, replay_to + 1, replay_to + 2] {
+            let event = handler::parse_message(&state, like_deletion(time_us)).unwrap();
+            if state.ends_replay(&event) {
+                break;
+            }
+            handled.push(event.time_us());
+        }
+
+        assert_eq!(handled, vec![replay_to - 1, replay_to]);
+        assert!(state.replay_finished.load(Ordering::Relaxed));
+    }
+
+    #[tokio::test]
+    async fn without_a_replay_every_event_is_handled() {
+        let state = SharedState::for_tests("wss://live.example", None);
+        let event = handler::parse_message(
+            &state,
+            serde_json::json!({
+                "did": "did:plc:liker",
+                "time_us": i64::MAX,
+                "kind": "identity",
+                "identity": {
+                    "did": "did:plc:liker",
+                    "handle": "liker.example.com",
+                    "seq": 1,
+                    "time": "2025-01-01T00:00:00.000Z",
+                },
+            })
+            .to_string(),
+        )
+        .unwrap();
+
+        assert!(!state.ends_replay(&event));
+        assert!(!state.replay_finished.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn rewinding_from_0_yields_0() {
+        assert_eq!(rewind(0), 0);
+        assert_eq!(rewind(REWIND_TIME / 2), 0);
+    }
+
+    #[test]
+    fn rewinding_goes_back_10_seconds() {
+        assert_eq!(
+            rewind(1_700_000_000_000_000),
+            1_700_000_000_000_000 - 10_000_000
+        );
+    }
+
+    #[test]
+    fn safe_cursor_without_pending_events_is_the_read_cursor() {
+        let watermarks = Watermarks::new(4);
+        assert_eq!(watermarks.len(), 0);
+        assert_eq!(watermarks.safe_cursor(1000), 1000);
+    }
+
+    #[test]
+    fn safe_cursor_stops_before_the_oldest_pending_event() {
+        let watermarks = Watermarks::new(3);
+        watermarks.queued(0, 100);
+        watermarks.queued(1, 110);
+        watermarks.queued(0, 120);
+        watermarks.queued(2, 130);
+        assert_eq!(watermarks.len(), 4);
+        assert_eq!(watermarks.safe_cursor(130), 99);
+
+        // Worker 1 finishing does not help while worker 0 still has the oldest event
+        watermarks.handled(1);
+        assert_eq!(watermarks.safe_cursor(130), 99);
+
+        watermarks.handled(0);
+        assert_eq!(watermarks.safe_cursor(130), 119);
+
+        watermarks.handled(0);
+        assert_eq!(watermarks.safe_cursor(130), 129);
+
+        watermarks.handled(2);
+        assert_eq!(watermarks.len(), 0);
+        assert_eq!(watermarks.safe_cursor(130), 130);
+    }
+
+    #[test]
+    fn a_slow_worker_holds_back_the_cursor() {
+        let watermarks = Watermarks::new(2);
+        watermarks.queued(0, 10);
+        for time_us in 11..100 {
+            watermarks.queued(1, time_us);
+            watermarks.handled(1);
+        }
+        assert_eq!(watermarks.len(), 1);
+        assert_eq!(watermarks.safe_cursor(99), 9);
     }
 }
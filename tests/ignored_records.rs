@@ -0,0 +1,105 @@
+//! The metric instruments are statics created from the global meter provider, so this runs in its own test binary
+//! where the provider can be set before any instrument is created.
+
+use atrium_api::types::string::{Did, RecordKey};
+use indexer::database::big_update::{create_big_delete, create_big_update};
+use opentelemetry::global;
+use opentelemetry_sdk::{
+    error::OTelSdkResult,
+    metrics::{
+        data::{ResourceMetrics, Sum},
+        reader::MetricReader,
+        InstrumentKind, ManualReader, MetricResult, Pipeline, SdkMeterProvider, Temporality,
+    },
+    Resource,
+};
+use std::sync::{Arc, Weak};
+
+/// Lets the test collect from the reader that the meter provider owns
+#[derive(Debug, Clone)]
+struct SharedReader(Arc<ManualReader>);
+
+impl MetricReader for SharedReader {
+    fn register_pipeline(&self, pipeline: Weak<Pipeline>) {
+        self.0.register_pipeline(pipeline)
+    }
+
+    fn collect(&self, rm: &mut ResourceMetrics) -> MetricResult<()> {
+        self.0.collect(rm)
+    }
+
+    fn force_flush(&self) -> OTelSdkResult {
+        self.0.force_flush()
+    }
+
+    fn shutdown(&self) -> OTelSdkResult {
+        self.0.shutdown()
+    }
+
+    fn temporality(&self, kind: InstrumentKind) -> Temporality {
+        self.0.temporality(kind)
+    }
+}
+
+/// The number of ignored records of `collection`
+fn ignored_records(reader: &SharedReader, collection: &str) -> u64 {
+    let mut metrics = ResourceMetrics {
+        resource: Resource::builder_empty().build(),
+        scope_metrics: Vec::new(),
+    };
+    reader.collect(&mut metrics).unwrap();
+    metrics
+        .scope_metrics
+        .iter()
+        .flat_map(|scope| scope.metrics.iter())
+        .filter(|metric| metric.name == "indexer.database.ignored_records")
+        .filter_map(|metric| metric.data.as_any().downcast_ref::<Sum<u64>>())
+        .flat_map(|sum| sum.data_points.iter())
+        .filter(|point| {
+            point
+                .attributes
+                .iter()
+                .any(|attribute| attribute.value.as_str() == collection)
+        })
+        .map(|point| point.value)
+        .sum()
+}
+
+#[test]
+fn unknown_collections_increment_the_ignored_records_counter() {
+    let reader = SharedReader(Arc::new(ManualReader::builder().build()));
+    global::set_meter_provider(
+        SdkMeterProvider::builder()
+            .with_reader(reader.clone())
+            .build(),
+    );
+
+    let record = serde_json::from_value(serde_json::json!({
+        "$type": "app.bsky.feed.post",
+        "text": "with an embed from another lexicon",
+        "embed": { "$type": "com.example.embed", "value": 1 },
+        "createdAt": "2025-01-01T00:00:00.000Z",
+    }))
+    .unwrap();
+    let update = create_big_update(
+        Did::new("did:plc:author".to_string()).unwrap(),
+        "plc_author".to_string(),
+        "app.bsky.feed.post".to_string(),
+        RecordKey::new("3jzfcijpj2z2a".to_string()).unwrap(),
+        record,
+    );
+
+    // The post itself is still indexed
+    assert_eq!(update.posts().len(), 1);
+    assert_eq!(ignored_records(&reader, "com.example.embed"), 1);
+
+    for _ in 0..2 {
+        create_big_delete(
+            Did::new("did:plc:author".to_string()).unwrap(),
+            "plc_author".to_string(),
+            "com.example.record".to_string(),
+            RecordKey::new("3jzfcijpj2z2a".to_string()).unwrap(),
+        );
+    }
+    assert_eq!(ignored_records(&reader, "com.example.record"), 2);
+}